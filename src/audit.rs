@@ -0,0 +1,176 @@
+use crate::json;
+use crate::shell::CommandHook;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+
+/// How large the log gets before it is rotated, unless
+/// $SHELL_AUDIT_LOG_SIZE says otherwise.
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// How many rotated logs are kept, as `log.1` (the newest) to `log.3`.
+const ROTATED_LOGS: usize = 3;
+
+/// Logs every command entered with when it started, the working directory
+/// it ran in, its exit status and how long it took, as a line of JSON.
+/// Turned on by setting $SHELL_AUDIT_LOG to the file to append them to, or
+/// to `syslog`.
+pub struct AuditLog {
+    to: Destination,
+    /// When the running commands started, and where: a command sourcing a
+    /// file is still running while the commands of the file run.
+    running: Vec<Running>,
+}
+
+enum Destination {
+    /// Rotated once it would grow past `max_size` bytes.
+    File { path: PathBuf, max_size: u64 },
+    #[cfg(unix)]
+    Syslog,
+}
+
+struct Running {
+    time: SystemTime,
+    start: Instant,
+    cwd: PathBuf,
+}
+
+impl AuditLog {
+    /// The log $SHELL_AUDIT_LOG asks for, if any.
+    pub fn from_env() -> Option<Self> {
+        let path = env::var_os("SHELL_AUDIT_LOG").filter(|path| !path.is_empty())?;
+        #[cfg(unix)]
+        if path == "syslog" {
+            return Some(Self::syslog());
+        }
+
+        let max_size = env::var("SHELL_AUDIT_LOG_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_MAX_SIZE);
+        Some(Self::file(PathBuf::from(path), max_size))
+    }
+
+    pub fn file(path: PathBuf, max_size: u64) -> Self {
+        Self {
+            to: Destination::File { path, max_size },
+            running: Vec::new(),
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn syslog() -> Self {
+        // SAFETY: the identifier is a static string, as openlog keeps it.
+        unsafe {
+            libc::openlog(
+                c"codecrafters-shell".as_ptr(),
+                libc::LOG_PID,
+                libc::LOG_USER,
+            )
+        };
+        Self {
+            to: Destination::Syslog,
+            running: Vec::new(),
+        }
+    }
+
+    fn write(&self, entry: &str) -> io::Result<()> {
+        match &self.to {
+            Destination::File { path, max_size } => {
+                let size = fs::metadata(path).map_or(0, |attr| attr.len());
+                if size > 0 && size + entry.len() as u64 + 1 > *max_size {
+                    rotate(path)?;
+                }
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{entry}")
+            }
+            #[cfg(unix)]
+            Destination::Syslog => {
+                let entry = std::ffi::CString::new(entry).map_err(io::Error::other)?;
+                // SAFETY: both strings are NUL-terminated, and the format
+                // takes exactly the one argument given.
+                unsafe { libc::syslog(libc::LOG_INFO, c"%s".as_ptr(), entry.as_ptr()) };
+                Ok(())
+            }
+        }
+    }
+}
+
+impl CommandHook for AuditLog {
+    fn preexec(&mut self, _command: &str) {
+        self.running.push(Running {
+            time: SystemTime::now(),
+            start: Instant::now(),
+            cwd: env::current_dir().unwrap_or_default(),
+        });
+    }
+
+    fn postexec(&mut self, command: &str, status: i32) {
+        let Some(running) = self.running.pop() else {
+            return;
+        };
+
+        let time = running
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let entry = format!(
+            "{{\"time\":{:.3},\"cwd\":{},\"command\":{},\"status\":{status},\"duration_ms\":{}}}",
+            time.as_secs_f64(),
+            json::string(&running.cwd.to_string_lossy()),
+            json::string(command),
+            running.start.elapsed().as_millis()
+        );
+        if let Err(err) = self.write(&entry) {
+            tracing::warn!(%err, "writing the audit log");
+        }
+    }
+}
+
+/// Moves `path` to `path.1`, and the logs rotated before it one further,
+/// dropping the oldest.
+fn rotate(path: &Path) -> io::Result<()> {
+    let rotated = |n: usize| {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(format!(".{n}"));
+        PathBuf::from(rotated)
+    };
+
+    for n in (1..ROTATED_LOGS).rev() {
+        match fs::rename(rotated(n), rotated(n + 1)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    fs::rename(path, rotated(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_commands_and_rotates_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        // every entry is too large to join another
+        let mut log = AuditLog::file(path.clone(), 1);
+
+        for command in ["echo \"one\"", "false", "echo three"] {
+            log.preexec(command);
+            log.postexec(command, i32::from(command == "false"));
+        }
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+        let logs = [read("audit.log.2"), read("audit.log.1"), read("audit.log")];
+
+        let cwd = json::string(&env::current_dir().unwrap().to_string_lossy());
+        assert!(logs.iter().all(|log| log.lines().count() == 1));
+        assert!(logs[0].starts_with("{\"time\":"));
+        assert!(logs[0].contains(&format!(
+            ",\"cwd\":{cwd},\"command\":\"echo \\\"one\\\"\",\"status\":0,\"duration_ms\":"
+        )));
+        assert!(logs[1].contains(",\"command\":\"false\",\"status\":1,"));
+        assert!(logs[2].contains(",\"command\":\"echo three\","));
+    }
+}
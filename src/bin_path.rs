@@ -1,118 +1,445 @@
+use crate::environment::Env;
+use indexmap::IndexSet;
+use std::collections::HashMap;
 use std::env;
+use std::ffi::OsString;
 use std::fs;
 use std::io;
-use std::os::unix::fs::PermissionsExt;
-use std::path::{Path, PathBuf};
-use std::slice::Iter;
-use std::sync;
+use std::path::{self, Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::SystemTime;
 
 pub struct BinPath {
-    env_once: sync::Once,
+    env: Env,
+    path_var: Option<OsString>,
     path: Vec<String>,
+    index: ExecutableIndex,
+    /// Where commands were last found, like bash's hash table. Forgotten
+    /// when PATH or one of its directories changes.
+    hits: HashMap<String, PathBuf>,
+}
+
+impl Default for BinPath {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BinPath {
     pub fn new() -> Self {
+        Self::with_env(Env::default())
+    }
+
+    /// Looks commands up in the PATH of `env`.
+    pub fn with_env(env: Env) -> Self {
         Self {
-            env_once: sync::Once::new(),
+            env,
+            path_var: None,
             path: Vec::new(),
+            index: ExecutableIndex::default(),
+            hits: HashMap::new(),
         }
     }
 
+    /// The first executable named `bin` in PATH, or `bin` itself if it is a
+    /// path to an executable. Once found in PATH, it is only checked to
+    /// still be there, so a repeated lookup costs a single stat, like in
+    /// bash: one added to an earlier directory since is only noticed once
+    /// the index sees that directory change.
+    #[tracing::instrument(level = "debug", skip(self), ret)]
     pub fn lookup(&mut self, bin: &str) -> io::Result<Option<PathBuf>> {
-        self.load_path();
-        for dir in &self.path {
-            let path = Path::new(&dir).join(bin);
-            let result = fs::metadata(path.clone());
-            if matches!(result, Err(ref err) if err.kind() == io::ErrorKind::NotFound) {
-                continue;
-            }
+        // a path isn't looked up, even with PATH empty
+        if bin.contains(path::is_separator) {
+            let path = PathBuf::from(bin);
+            let executable = fs::metadata(&path).is_ok_and(|attr| is_executable(&path, &attr));
+            return Ok(executable.then_some(path));
+        }
 
-            if has_execute_permission(&result?) {
-                return Ok(Some(path));
-            }
+        self.load_path();
+        if let Some(path) = self.hits.get(bin)
+            && fs::metadata(path).is_ok_and(|attr| is_executable(path, &attr))
+        {
+            return Ok(Some(path.clone()));
         }
 
-        Ok(None)
+        self.index_executables();
+        let found = self.executables(bin).next().transpose()?;
+        match &found {
+            Some(path) => self.hits.insert(bin.to_string(), path.clone()),
+            None => self.hits.remove(bin),
+        };
+        Ok(found)
     }
 
-    pub fn bins(&mut self) -> Bins<'_> {
+    /// Every executable named `bin` in PATH order, including shadowed ones.
+    pub fn lookup_all(&mut self, bin: &str) -> io::Result<Vec<PathBuf>> {
+        self.index_executables();
+        self.executables(bin).collect()
+    }
+
+    /// Only directories the index can't rule out are looked into.
+    fn executables<'a>(&'a self, bin: &'a str) -> impl Iterator<Item = io::Result<PathBuf>> + 'a {
+        let file_names = platform::file_names(bin);
+        let command = platform::command_name(bin).unwrap_or(bin);
+        self.path
+            .iter()
+            .filter(move |dir| self.index.may_contain(dir, command))
+            .flat_map(move |dir| {
+                file_names
+                    .clone()
+                    .into_iter()
+                    .map(move |name| Path::new(dir).join(name))
+            })
+            .filter_map(|path| match fs::metadata(&path) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => Some(Err(err)),
+                Ok(attr) if is_executable(&path, &attr) => Some(Ok(path)),
+                Ok(_) => None,
+            })
+    }
+
+    /// Basenames of every executable in PATH, served from an index that is
+    /// only rebuilt for directories whose mtime changed since the last call.
+    /// Directories still being read contribute their previous names.
+    pub fn executable_names(&mut self) -> impl Iterator<Item = &str> {
+        self.index_executables();
+
+        self.index.names(&self.path)
+    }
+
+    /// Like `executable_names`, but waits for the directories still being
+    /// read, for when missing a name matters more than blocking.
+    pub fn all_executable_names(&mut self) -> impl Iterator<Item = &str> {
+        self.index_executables();
+        self.index.wait();
+
+        self.index.names(&self.path)
+    }
+
+    /// Starts indexing the executables in PATH, so they are ready by the
+    /// time they are first completed.
+    pub fn index_executables(&mut self) {
         self.load_path();
+        if self.index.refresh(&self.path) {
+            self.hits.clear();
+        }
+    }
+
+    /// Whether `name` runs something: a path to an executable file or the name
+    /// of one in PATH, checked against the index so it's cheap enough to call
+    /// on every keystroke.
+    pub fn resolves(&mut self, name: &str) -> bool {
+        if name.contains(path::is_separator) {
+            return fs::metadata(name).is_ok_and(|attr| is_executable(Path::new(name), &attr));
+        }
 
-        Bins::new(self.path.iter())
+        self.index_executables();
+        self.path.iter().any(|dir| self.index.contains(dir, name))
     }
 
+    /// Re-splits PATH whenever its value differs from the one last seen.
     fn load_path(&mut self) {
-        self.env_once.call_once(|| {
-            self.path = env::var("PATH")
-                .unwrap()
-                .split(':')
-                .map(String::from)
-                .collect();
-        })
+        let path_var = self.env.var_os("PATH").unwrap_or_default();
+        if self.path_var.as_ref() == Some(&path_var) {
+            return;
+        }
+
+        // an empty PATH leaves only builtins and commands given by path,
+        // where splitting it would give the current directory
+        self.path = if path_var.is_empty() {
+            Vec::new()
+        } else {
+            env::split_paths(&path_var)
+                .map(|dir| dir.to_string_lossy().into_owned())
+                .collect()
+        };
+        self.path_var = Some(path_var);
+        self.hits.clear();
     }
 }
 
-pub struct Bins<'a> {
-    paths: Iter<'a, String>,
-    dir_data: Option<fs::ReadDir>,
+/// Executable names by PATH directory. Directories are read on background
+/// threads, so a huge PATH never blocks the prompt: until a directory is
+/// read, lookups see its previous names, or none.
+struct ExecutableIndex {
+    dirs: HashMap<String, IndexedDir>,
+    /// Directories being read, with the mtime they had when it started.
+    pending: HashMap<String, Option<SystemTime>>,
+    sender: Sender<(String, IndexedDir)>,
+    receiver: Receiver<(String, IndexedDir)>,
 }
 
-impl<'a> Bins<'a> {
-    fn new(paths: Iter<'a, String>) -> Self {
+impl Default for ExecutableIndex {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel();
         Self {
-            paths,
-            dir_data: None,
+            dirs: HashMap::new(),
+            pending: HashMap::new(),
+            sender,
+            receiver,
         }
     }
 }
 
-impl<'a> Iterator for Bins<'a> {
-    type Item = anyhow::Result<PathBuf>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            let dir_data = self.dir_data.take();
-            match dir_data {
-                Some(mut read_dir) => match read_dir.next() {
-                    Some(Ok(dir_entry)) => {
-                        self.dir_data = Some(read_dir);
-
-                        match dir_entry.metadata() {
-                            Ok(metadata) if has_execute_permission(&metadata) => {
-                                return Some(Ok(dir_entry.path()));
-                            }
-                            Ok(_) => {}
-                            Err(err) => {
-                                return Some(Err(anyhow::anyhow!(
-                                    "read {} metadata: {err}",
-                                    dir_entry.path().display()
-                                )));
-                            }
-                        }
-                    }
-                    Some(Err(err)) => {
-                        self.dir_data = Some(read_dir);
-                        return Some(Err(anyhow::anyhow!("read dir next: {err}")));
-                    }
-                    None => self.dir_data = None,
-                },
-                None => {
-                    let Some(dir) = self.paths.next() else {
-                        return None;
-                    };
-
-                    match fs::read_dir(dir) {
-                        Ok(data) => self.dir_data = Some(data),
-                        Err(err) => return Some(Err(anyhow::anyhow!("read dir: {err}"))),
-                    };
-                }
+struct IndexedDir {
+    mtime: Option<SystemTime>,
+    names: IndexSet<String>,
+}
+
+impl ExecutableIndex {
+    /// Takes in the directories read since the last call, and starts reading
+    /// the ones that are new or whose mtime changed. Returns whether any
+    /// directory changed.
+    fn refresh(&mut self, path: &[String]) -> bool {
+        let mut changed = false;
+        while let Ok((dir, indexed)) = self.receiver.try_recv() {
+            self.store(dir, indexed);
+            changed = true;
+        }
+        self.dirs.retain(|dir, _| path.contains(dir));
+
+        for dir in path {
+            let mtime = fs::metadata(dir).and_then(|attr| attr.modified()).ok();
+            let up_to_date = |indexed: Option<Option<SystemTime>>| indexed == Some(mtime);
+            if up_to_date(self.dirs.get(dir).map(|indexed| indexed.mtime))
+                || up_to_date(self.pending.get(dir).copied())
+            {
+                continue;
             }
+
+            changed = true;
+            self.pending.insert(dir.clone(), mtime);
+            let sender = self.sender.clone();
+            let dir = dir.clone();
+            thread::spawn(move || {
+                let names = read_executable_names(&dir);
+                // the index may be gone by now
+                let _ = sender.send((dir, IndexedDir { mtime, names }));
+            });
+        }
+
+        changed
+    }
+
+    /// Whether `dir` is known to have an executable named `name`.
+    fn contains(&self, dir: &str, name: &str) -> bool {
+        self.dirs
+            .get(dir)
+            .is_some_and(|indexed| indexed.names.contains(name))
+    }
+
+    /// False only when `dir` is indexed, not being read again, and has no
+    /// executable named `name`.
+    fn may_contain(&self, dir: &str, name: &str) -> bool {
+        self.pending.contains_key(dir) || !self.dirs.contains_key(dir) || self.contains(dir, name)
+    }
+
+    fn store(&mut self, dir: String, indexed: IndexedDir) {
+        if self.pending.get(&dir) == Some(&indexed.mtime) {
+            self.pending.remove(&dir);
+        }
+        self.dirs.insert(dir, indexed);
+    }
+
+    /// Blocks until every directory being read is indexed.
+    fn wait(&mut self) {
+        while !self.pending.is_empty() {
+            let (dir, indexed) = self.receiver.recv().unwrap();
+            self.store(dir, indexed);
+        }
+    }
+
+    fn names<'a>(&'a self, path: &'a [String]) -> impl Iterator<Item = &'a str> {
+        path.iter()
+            .filter_map(|dir| self.dirs.get(dir))
+            .flat_map(|indexed| indexed.names.iter().map(String::as_str))
+    }
+}
+
+/// Unreadable directories simply contribute no names.
+fn read_executable_names(dir: &str) -> IndexSet<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return IndexSet::new();
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let path = entry.path();
+            fs::metadata(&path).is_ok_and(|attr| is_executable(&path, &attr))
+        })
+        .filter_map(|entry| {
+            let file_name = entry.file_name().into_string().ok()?;
+            platform::command_name(&file_name).map(String::from)
+        })
+        .collect()
+}
+
+/// Whether the file at `path`, with the metadata `attr` it has after
+/// following symlinks, can be run as a command. Directories can't, even
+/// though they have execute permission.
+pub(crate) fn is_executable(path: &Path, attr: &fs::Metadata) -> bool {
+    attr.is_file() && platform::is_executable(path, attr)
+}
+
+/// What makes a file a command, and the names it is run by.
+#[cfg(unix)]
+mod platform {
+    use std::ffi::CString;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    /// Asks the kernel, so the owner and group execute bits count for the
+    /// effective user and groups, and root can run anything executable by
+    /// someone.
+    pub fn is_executable(path: &Path, _attr: &fs::Metadata) -> bool {
+        let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+            return false;
+        };
+        // SAFETY: path is a NUL-terminated string that outlives the call.
+        unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), libc::X_OK, libc::AT_EACCESS) == 0 }
+    }
+
+    /// The names of the files in a PATH directory that `command` runs.
+    pub fn file_names(command: &str) -> Vec<String> {
+        vec![command.to_string()]
+    }
+
+    /// The command an executable file in PATH is run as.
+    pub fn command_name(file_name: &str) -> Option<&str> {
+        Some(file_name)
+    }
+}
+
+/// Files are executable by their extension, one of $PATHEXT, which may be
+/// left out when running them.
+#[cfg(windows)]
+mod platform {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    const DEFAULT_PATHEXT: &str = ".COM;.EXE;.BAT;.CMD";
+
+    /// The executable extensions, lowercase with their dot.
+    fn extensions() -> Vec<String> {
+        env::var("PATHEXT")
+            .unwrap_or_else(|_| String::from(DEFAULT_PATHEXT))
+            .split(';')
+            .filter(|ext| ext.starts_with('.'))
+            .map(str::to_ascii_lowercase)
+            .collect()
+    }
+
+    fn executable_extension(name: &str) -> Option<usize> {
+        let lower = name.to_ascii_lowercase();
+        extensions()
+            .iter()
+            .find(|ext| lower.len() > ext.len() && lower.ends_with(ext.as_str()))
+            .map(|ext| name.len() - ext.len())
+    }
+
+    pub fn is_executable(path: &Path, _attr: &fs::Metadata) -> bool {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| executable_extension(name).is_some())
+    }
+
+    pub fn file_names(command: &str) -> Vec<String> {
+        if executable_extension(command).is_some() {
+            return vec![command.to_string()];
         }
+
+        extensions()
+            .iter()
+            .map(|ext| format!("{command}{ext}"))
+            .collect()
+    }
+
+    pub fn command_name(file_name: &str) -> Option<&str> {
+        executable_extension(file_name).map(|end| &file_name[..end])
     }
 }
 
-//TODO: handle user and group permissions
-fn has_execute_permission(attr: &fs::Metadata) -> bool {
-    attr.permissions().mode() & 0o001 != 0
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn index_picks_up_changed_dirs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let create = |name: &str, mode: u32| {
+            let file = dir.join(name);
+            fs::write(&file, "").unwrap();
+            fs::set_permissions(&file, fs::Permissions::from_mode(mode)).unwrap();
+        };
+        create("first", 0o755);
+        create("data", 0o644);
+        create("owner_only", 0o700);
+        fs::create_dir(dir.join("subdir")).unwrap();
+        std::os::unix::fs::symlink(dir.join("first"), dir.join("link")).unwrap();
+
+        let path = vec![dir.display().to_string()];
+        let mut index = ExecutableIndex::default();
+        index.refresh(&path);
+        index.wait();
+        let mut names: Vec<_> = index.names(&path).collect();
+        names.sort();
+        assert_eq!(names, vec!["first", "link", "owner_only"]);
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        create("second", 0o755);
+        index.refresh(&path);
+        index.wait();
+        let mut names: Vec<_> = index.names(&path).collect();
+        names.sort();
+        assert_eq!(names, vec!["first", "link", "owner_only", "second"]);
+
+        index.refresh(&[]);
+        assert_eq!(index.names(&path).count(), 0);
+    }
+
+    #[test]
+    fn empty_path_only_finds_paths() {
+        let mut bin_path = BinPath::with_env(Env::new([("PATH", "")]));
+        assert_eq!(bin_path.lookup("sh").unwrap(), None);
+        assert_eq!(
+            bin_path.lookup("/bin/sh").unwrap(),
+            Some(PathBuf::from("/bin/sh"))
+        );
+        assert_eq!(bin_path.lookup("/nonexistent/sh").unwrap(), None);
+    }
+
+    #[test]
+    fn lookup_remembers_and_revalidates_hits() {
+        let root = tempfile::tempdir().unwrap();
+        let (first, second) = (root.path().join("first"), root.path().join("second"));
+        fs::create_dir(&first).unwrap();
+        fs::create_dir(&second).unwrap();
+        let create = |dir: &Path| {
+            let file = dir.join("tool");
+            fs::write(&file, "").unwrap();
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o755)).unwrap();
+            file
+        };
+        let in_second = create(&second);
+
+        let path = env::join_paths([&first, &second]).unwrap();
+        let mut bin_path = BinPath::with_env(Env::new([("PATH", path.to_str().unwrap())]));
+        // a scan finishing later would forget the hits
+        bin_path.index_executables();
+        bin_path.index.wait();
+        assert_eq!(bin_path.lookup("tool").unwrap(), Some(in_second.clone()));
+        assert_eq!(bin_path.lookup("nope").unwrap(), None);
+
+        let in_first = create(&first);
+        assert_eq!(bin_path.lookup("tool").unwrap(), Some(in_second.clone()));
+        fs::remove_file(&in_second).unwrap();
+        assert_eq!(bin_path.lookup("tool").unwrap(), Some(in_first));
+    }
 }
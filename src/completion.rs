@@ -1,8 +1,20 @@
+use crate::bin_path;
 use crate::editor::Helper;
+use crate::glob;
+use crate::lexer::{Lexer, TokenKind};
+use crate::named_dirs;
+use crate::parser::{self, Expander};
+use crate::tool_completion;
 use crate::BUILTIN_COMMANDS;
 use indexmap::IndexSet;
 use rustyline::completion;
-use std::path;
+use rustyline::history::{History, SearchDirection};
+use rustyline::line_buffer::LineBuffer;
+use rustyline::Changeset;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 
 impl completion::Completer for Helper {
     type Candidate = Pair;
@@ -11,45 +23,783 @@ impl completion::Completer for Helper {
         &self,
         line: &str,
         pos: usize,
-        _ctx: &rustyline::Context<'_>,
+        ctx: &rustyline::Context<'_>,
     ) -> rustyline::Result<(usize, Vec<Self::Candidate>)> {
-        let (start, word) = completion::extract_word(line, pos, None, |c| c == ' ');
-        let mut candidates = IndexSet::new();
+        if self.pairing.lock().unwrap().is_some() {
+            // one candidate, for `update` to make the edit
+            return Ok((pos, vec![Pair::line(String::new())]));
+        }
+
+        let CurrentWord { start, word, quote } = current_word(&line[..pos]);
+        let word = word.as_str();
+        let matcher = self.matcher();
+        let insert_all = self.insert_completions.swap(false, Ordering::Relaxed);
+
+        let raw = &line[start..pos];
+        if quote.is_none() && glob::has_glob(raw) {
+            let matches = self.glob_candidates(raw);
+            return Ok(self.glob_completion(start, pos, matches, insert_all));
+        }
+        if quote != Some('\'')
+            && let Some(dollar) = raw.rfind('$')
+            && !raw[..dollar].ends_with('\\')
+        {
+            let name = &raw[dollar + 1..];
+            if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Ok((start + dollar, self.variable_candidates(matcher, name)));
+            }
+        }
+
+        let candidates = match completing(&line[..start]) {
+            Completing::Command => self.command_candidates(matcher, word),
+            Completing::File => self.file_candidates(matcher, word),
+            Completing::Argument(args) => {
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                match args[0] {
+                    "cd" if is_dir_history_index(word) => self.dir_history_candidates(word),
+                    "cd" | "pushd" | "popd" => self.dir_candidates(matcher, word),
+                    command => match self.providers.candidates(command, &args, word) {
+                        Some(candidates) => provider_candidates(matcher, candidates, word),
+                        None => self.file_candidates(matcher, word),
+                    },
+                }
+            }
+        };
+
+        if candidates.is_empty() {
+            let history = history_candidates(ctx.history(), &line[..pos]);
+            if !history.is_empty() {
+                return Ok((0, history));
+            }
+        }
+
+        let candidates: Vec<Pair> = candidates
+            .into_iter()
+            .map(|pair| pair.quoted(quote))
+            .collect();
+        if insert_all && candidates.len() > 1 {
+            return Ok((start, vec![Pair::all(&candidates)]));
+        }
+        self.note_kinds(&candidates);
+
+        Ok((start, candidates))
+    }
+
+    /// Puts `elected` in place of the word, or makes the edit `auto_pairs`
+    /// asked for.
+    fn update(&self, line: &mut LineBuffer, start: usize, elected: &str, cl: &mut Changeset) {
+        match self.pairing.lock().unwrap().take() {
+            Some(pairing) => pairing.apply(line, cl),
+            None => {
+                let end = line.pos();
+                line.replace(start..end, elected, cl);
+            }
+        }
+    }
+}
+
+/// The word under the cursor with its quoting and escapes removed.
+#[derive(Debug, PartialEq)]
+struct CurrentWord {
+    /// Byte offset in the line where the word, including any opening quote,
+    /// starts.
+    start: usize,
+    word: String,
+    /// The quote the word is still inside of.
+    quote: Option<char>,
+}
+
+fn current_word(line: &str) -> CurrentWord {
+    let mut current = CurrentWord {
+        start: 0,
+        word: String::new(),
+        quote: None,
+    };
+
+    let mut chars = line.char_indices().peekable();
+    while let Some((index, char)) = chars.next() {
+        match (current.quote, char) {
+            (None, ' ' | '\t' | '\n' | '|' | '&' | ';' | '<' | '>') => {
+                current.start = index + 1;
+                current.word.clear();
+            }
+            (None, '\'' | '"') => current.quote = Some(char),
+            (Some(quote), _) if quote == char => current.quote = None,
+            (None, '\\') => current.word.extend(chars.next().map(|(_, char)| char)),
+            (Some('"'), '\\') => match chars.peek() {
+                Some((_, escaped @ ('"' | '\\' | '$' | '`'))) => {
+                    current.word.push(*escaped);
+                    chars.next();
+                }
+                _ => current.word.push(char),
+            },
+            _ => current.word.push(char),
+        }
+    }
+
+    current
+}
+
+/// Commands that run the word after them, and their options, as a command.
+/// `env` also takes `NAME=value` words before it.
+static COMMAND_PREFIXES: &[&str] = &[
+    "builtin", "command", "env", "exec", "nohup", "spawn", "sudo", "time",
+];
+
+/// Options of the command prefixes that take the word after them as their
+/// value, like the user in `sudo -u root`.
+static PREFIX_OPTION_ARGUMENTS: &[(&str, &[&str])] = &[("env", &["-u"]), ("sudo", &["-u", "-g"])];
+
+/// The number of words after the command prefix `prefix` at the start of
+/// `words` that are its options and their values, not the command it runs.
+fn prefix_arguments(prefix: &str, words: &[String]) -> usize {
+    let takes_value = |option: &str| {
+        PREFIX_OPTION_ARGUMENTS
+            .iter()
+            .any(|(command, options)| *command == prefix && options.contains(&option))
+    };
+
+    let mut count = 0;
+    while let Some(word) = words.get(count) {
+        if takes_value(word) {
+            count += 2;
+        } else if word.starts_with('-') || (prefix == "env" && parser::is_assignment(word)) {
+            count += 1;
+        } else {
+            break;
+        }
+    }
+    count
+}
+
+/// What the word under the cursor is completed as.
+#[derive(Debug, PartialEq)]
+enum Completing {
+    Command,
+    /// The target of a redirection.
+    File,
+    /// An argument following the words of its command, unquoted.
+    Argument(Vec<String>),
+}
+
+/// Lexes `line`, everything before the word under the cursor, to find the
+/// words of the simple command the cursor is in: `|`, `&&`, `;` and `&`
+/// start a new command, `<` and `>` make the next word a file name.
+fn completing(line: &str) -> Completing {
+    let mut words = CommandWords::default();
+    let mut quote: Option<TokenKind> = None;
+
+    for token in Lexer::new(line).lex() {
+        match (&token.kind, &quote) {
+            (TokenKind::SingleQuote | TokenKind::DoubleQuote, Some(open))
+                if *open == token.kind =>
+            {
+                quote = None
+            }
+            (_, Some(_)) => words.push_str(token.lexeme),
+            (TokenKind::SingleQuote | TokenKind::DoubleQuote, None) => {
+                words.push_str("");
+                quote = Some(token.kind);
+            }
+            (TokenKind::Whitespace | TokenKind::EOF, None) => words.end_word(),
+            (TokenKind::EscapeSequence, None) => {
+                words.push_str(&token.lexeme[1..]);
+            }
+            (TokenKind::Dollar | TokenKind::String, None) => {
+                for char in token.lexeme.chars() {
+                    words.push_unquoted(char);
+                }
+            }
+        }
+    }
+    words.end_word();
+
+    let CommandWords {
+        mut words,
+        redirect,
+        ..
+    } = words;
+    let mut prefixes = 0;
+    while let Some(word) = words.get(prefixes)
+        && COMMAND_PREFIXES.contains(&word.as_str())
+    {
+        prefixes += 1 + prefix_arguments(word, &words[prefixes + 1..]);
+    }
+
+    if redirect {
+        Completing::File
+    } else if prefixes > words.len() {
+        // the value of an option like `sudo -u`, not a command
+        Completing::Argument(words)
+    } else if prefixes == words.len() {
+        Completing::Command
+    } else {
+        words.drain(..prefixes);
+        Completing::Argument(words)
+    }
+}
+
+#[derive(Default)]
+struct CommandWords {
+    words: Vec<String>,
+    /// The word being read, if any of it was yet.
+    word: Option<String>,
+    /// Whether the next word is the target of a redirection.
+    redirect: bool,
+}
+
+impl CommandWords {
+    fn push_str(&mut self, string: &str) {
+        self.word.get_or_insert_default().push_str(string);
+    }
+
+    fn push_unquoted(&mut self, char: char) {
+        match char {
+            // the `&` of `>&2` is part of the redirection
+            '&' if self.redirect && self.word.is_none() => {}
+            '|' | '&' | ';' => {
+                self.end_word();
+                self.words.clear();
+                self.redirect = false;
+            }
+            '<' | '>' => {
+                // a file descriptor number, as in `2>`
+                if let Some(word) = &self.word
+                    && word.chars().all(|c| c.is_ascii_digit())
+                {
+                    self.word = None;
+                }
+                self.end_word();
+                self.redirect = true;
+            }
+            char => self.word.get_or_insert_default().push(char),
+        }
+    }
+
+    /// Redirection targets aren't words of the command.
+    fn end_word(&mut self) {
+        if let Some(word) = self.word.take() {
+            if self.redirect {
+                self.redirect = false;
+            } else {
+                self.words.push(word);
+            }
+        }
+    }
+}
+
+/// Backslash-escapes what the shell would otherwise interpret, or only what
+/// is special inside `quote`.
+fn escape(word: &str, quote: Option<char>) -> String {
+    let mut escaped = String::with_capacity(word.len());
+    for char in word.chars() {
+        match (quote, char) {
+            (Some('\''), '\'') => escaped.push_str("'\\''"),
+            (Some('"'), '"' | '\\' | '$' | '`') => {
+                escaped.push('\\');
+                escaped.push(char);
+            }
+            (None, char) if char.is_whitespace() || "'\"\\$`|&;<>()*?[]{}!#".contains(char) => {
+                escaped.push('\\');
+                escaped.push(char);
+            }
+            _ => escaped.push(char),
+        }
+    }
+
+    escaped
+}
+
+/// Completes the arguments of one particular command, registered under its
+/// name in [`Providers`].
+pub trait CompletionProvider {
+    /// Every candidate for `word`, which follows `args` (starting with the
+    /// command name); the completer filters them by the typed word. `None`
+    /// falls back to file name completion.
+    fn candidates(&self, args: &[&str], word: &str) -> Option<Vec<Pair>>;
+}
+
+/// Per-command completion providers, dispatched by the first word of the
+/// line, as registered with [`crate::line_editor::LineEditor::register_completion`].
+/// Commands without one are completed by the external completers that
+/// know them.
+pub struct Providers {
+    providers: HashMap<String, Box<dyn CompletionProvider>>,
+    external: Vec<Box<dyn CompletionProvider>>,
+}
+
+impl Default for Providers {
+    fn default() -> Self {
+        Self {
+            providers: HashMap::new(),
+            external: vec![
+                Box::new(tool_completion::Carapace),
+                Box::new(tool_completion::BashCompletion::default()),
+            ],
+        }
+    }
+}
+
+impl Providers {
+    pub fn register(&mut self, command: &str, provider: Box<dyn CompletionProvider>) {
+        self.providers.insert(command.to_string(), provider);
+    }
+
+    fn candidates(&self, command: &str, args: &[&str], word: &str) -> Option<Vec<Pair>> {
+        if let Some(provider) = self.providers.get(command) {
+            return provider.candidates(args, word);
+        }
+
+        let candidates: Vec<Pair> = self
+            .external
+            .iter()
+            .filter_map(|provider| provider.candidates(args, word))
+            .flatten()
+            .collect();
+        (!candidates.is_empty()).then_some(candidates)
+    }
+}
+
+impl Helper {
+    /// Remembers the kinds of the candidates offered, to color their list.
+    fn note_kinds(&self, candidates: &[Pair]) {
+        self.candidate_kinds.replace(
+            candidates
+                .iter()
+                .filter_map(|pair| Some((pair.display.clone(), pair.kind?)))
+                .collect(),
+        );
+    }
+
+    /// The paths the glob `pattern` matches, as the word would expand to.
+    fn glob_candidates(&self, pattern: &str) -> Vec<Pair> {
+        let dotglob = self.options.borrow().dotglob();
+        glob::expand(pattern, dotglob)
+            .into_iter()
+            .map(|path| {
+                let kind = file_kind(Path::new(&path));
+                Pair::file("", path, kind).quoted(None)
+            })
+            .collect()
+    }
+
+    /// A single match replaces the pattern, and so do all of them with
+    /// `insert_all`. Otherwise they are listed, leaving the pattern as it
+    /// is, which completing to their common prefix would replace; cycling
+    /// through them with `menu_complete` puts each in its place in turn.
+    fn glob_completion(
+        &self,
+        start: usize,
+        pos: usize,
+        mut matches: Vec<Pair>,
+        insert_all: bool,
+    ) -> (usize, Vec<Pair>) {
+        if matches.len() == 1 {
+            return (start, matches);
+        }
+        if insert_all && !matches.is_empty() {
+            return (start, vec![Pair::all(&matches)]);
+        }
+
+        self.note_kinds(&matches);
+        if self.options.borrow().menu_complete() {
+            return (start, matches);
+        }
+        for pair in &mut matches {
+            pair.replacement.clear();
+        }
+        (pos, matches)
+    }
+
+    fn matcher(&self) -> Matcher {
+        let options = self.options.borrow();
+        if options.fuzzy_completion() {
+            Matcher::Fuzzy
+        } else if options.nocase_completion() {
+            Matcher::IgnoreCase
+        } else {
+            Matcher::Prefix
+        }
+    }
+
+    fn command_candidates(&self, matcher: Matcher, word: &str) -> Vec<Pair> {
+        let mut candidates = Vec::new();
 
         for comp in BUILTIN_COMMANDS {
-            if comp.starts_with(word) {
-                candidates.insert(Pair::new(comp.to_string()));
+            if let Some(score) = matcher.score(comp, word) {
+                candidates.push((score, Pair::command(comp, Kind::Builtin)));
             }
         }
 
         let mut bin_path = self.bin_path.borrow_mut();
-        for bin in bin_path.bins() {
-            let bin_path = bin.unwrap().display().to_string();
+        for name in bin_path.executable_names() {
+            if BUILTIN_COMMANDS.contains(&name) {
+                continue;
+            }
+            if let Some(score) = matcher.score(name, word) {
+                candidates.push((score, Pair::command(name, Kind::Executable)));
+            }
+        }
 
-            if let Some(basename) = path::Path::new(&bin_path).file_name() {
-                let basename = basename.display().to_string();
-                if basename.starts_with(word) {
-                    candidates.insert(Pair::new(basename));
-                }
+        ranked(candidates)
+    }
+
+    /// Directories for `cd`-like commands: `~name` completes named
+    /// directories, bare names also match directories inside $CDPATH.
+    fn dir_candidates(&self, matcher: Matcher, word: &str) -> Vec<Pair> {
+        if let Some(candidates) = self.tilde_candidates(matcher, word) {
+            return candidates;
+        }
+
+        let (dir, prefix) = split_dir(word);
+        let mut candidates = dir_entries(matcher, &self.read_dir(dir), dir, prefix, true);
+        if dir.is_empty() && !word.starts_with('.') {
+            for cdpath in named_dirs::cdpath() {
+                candidates.extend(dir_entries(matcher, &cdpath, "", word, true));
             }
         }
 
-        candidates.sort();
-        Ok((start, candidates.into_iter().collect()))
+        ranked(candidates)
+    }
+
+    /// `cd -N` for the recently visited directories, listed with where
+    /// each goes.
+    fn dir_history_candidates(&self, word: &str) -> Vec<Pair> {
+        let named_dirs = self.named_dirs.borrow();
+        named_dirs
+            .visited()
+            .back()
+            .into_iter()
+            .zip(1..)
+            .filter_map(|(dir, n)| {
+                let index = format!("-{n}");
+                index.starts_with(word).then(|| Pair {
+                    display: format!("{index} -- {}", named_dirs.abbreviate(dir)),
+                    replacement: append_trailing_space(&index),
+                    kind: Some(Kind::Directory),
+                })
+            })
+            .collect()
+    }
+
+    /// Variables of the shell matching `prefix`, inherited or assigned, as
+    /// `$NAME`.
+    fn variable_candidates(&self, matcher: Matcher, prefix: &str) -> Vec<Pair> {
+        let candidates = self.env.vars().into_iter().filter_map(|(name, _)| {
+            let score = matcher.score(&name, prefix)?;
+            Some((score, Pair::new(format!("${name}"))))
+        });
+
+        ranked(candidates)
+    }
+
+    fn file_candidates(&self, matcher: Matcher, word: &str) -> Vec<Pair> {
+        if let Some(candidates) = self.tilde_candidates(matcher, word) {
+            return candidates;
+        }
+
+        let (dir, prefix) = split_dir(word);
+        ranked(dir_entries(
+            matcher,
+            &self.read_dir(dir),
+            dir,
+            prefix,
+            false,
+        ))
+    }
+
+    /// A bare `~name` completes named directories and user names.
+    fn tilde_candidates(&self, matcher: Matcher, word: &str) -> Option<Vec<Pair>> {
+        let name = word.strip_prefix('~')?;
+        if name.contains('/') {
+            return None;
+        }
+
+        let named_dirs = self.named_dirs.borrow();
+        let names = named_dirs
+            .iter()
+            .map(|(dir_name, _)| dir_name.to_string())
+            .chain(named_dirs::user_names());
+        let candidates = names.filter_map(|dir_name| {
+            let score = matcher.score(&dir_name, name)?;
+            Some((score, Pair::file("~", dir_name, Kind::Directory)))
+        });
+
+        Some(ranked(candidates))
+    }
+
+    /// The directory to list for the `dir` part of a word, with a leading
+    /// `~` or `~name` expanded; the candidates keep it as typed.
+    fn read_dir(&self, dir: &str) -> PathBuf {
+        if dir.is_empty() {
+            return PathBuf::from(".");
+        }
+
+        if let Some(tilde) = dir.strip_prefix('~') {
+            let (name, rest) = tilde.split_once('/').unwrap_or((tilde, ""));
+            if let Some(home) = self.named_dirs.borrow().expand_tilde(name) {
+                return Path::new(&home).join(rest);
+            }
+        }
+
+        PathBuf::from(dir)
     }
 }
 
+/// How a typed word selects completion candidates, chosen with the
+/// `nocase_completion` and `fuzzy_completion` shell options.
+#[derive(Clone, Copy, Debug)]
+enum Matcher {
+    Prefix,
+    IgnoreCase,
+    /// The word's characters appear in order anywhere in the candidate,
+    /// ignoring case.
+    Fuzzy,
+}
+
+impl Matcher {
+    /// `None` if `candidate` doesn't match `word`, otherwise a rank where
+    /// lower is better.
+    fn score(self, candidate: &str, word: &str) -> Option<usize> {
+        match self {
+            Matcher::Prefix => candidate.starts_with(word).then_some(0),
+            Matcher::IgnoreCase => candidate
+                .to_lowercase()
+                .starts_with(&word.to_lowercase())
+                .then_some(0),
+            Matcher::Fuzzy => fuzzy_score(candidate, word),
+        }
+    }
+}
+
+/// Greedily matches `word` as a subsequence of `candidate`, scoring the
+/// offset of the first match plus every skipped character between matches,
+/// so prefixes score 0 and scattered matches score worse.
+fn fuzzy_score(candidate: &str, word: &str) -> Option<usize> {
+    let mut chars = candidate.chars().flat_map(char::to_lowercase).enumerate();
+    let mut score = 0;
+    let mut last = None;
+
+    for wanted in word.chars().flat_map(char::to_lowercase) {
+        let (index, _) = chars.find(|&(_, c)| c == wanted)?;
+        score += match last {
+            Some(last) => index - last - 1,
+            None => index,
+        };
+        last = Some(index);
+    }
+
+    Some(score)
+}
+
+/// Sorts by score and then alphabetically, keeping the best scored copy of
+/// candidates that were found more than once.
+fn ranked(candidates: impl IntoIterator<Item = (usize, Pair)>) -> Vec<Pair> {
+    let mut candidates: Vec<(usize, Pair)> = candidates.into_iter().collect();
+    candidates.sort();
+
+    let candidates: IndexSet<Pair> = candidates.into_iter().map(|(_, pair)| pair).collect();
+    candidates.into_iter().collect()
+}
+
+fn provider_candidates(matcher: Matcher, candidates: Vec<Pair>, word: &str) -> Vec<Pair> {
+    let candidates = candidates.into_iter().filter_map(|pair| {
+        let score = matcher.score(pair.replacement.trim_end(), word)?;
+        Some((score, pair))
+    });
+
+    ranked(candidates)
+}
+
+/// Past command lines extending `line`, most recent first, offered like zsh's
+/// history completion when nothing else matched.
+fn history_candidates(history: &dyn History, line: &str) -> Vec<Pair> {
+    if line.trim().is_empty() {
+        return Vec::new();
+    }
+
+    let mut candidates = IndexSet::new();
+    for index in (0..history.len()).rev() {
+        let Ok(Some(entry)) = history.get(index, SearchDirection::Reverse) else {
+            continue;
+        };
+        if entry.entry.starts_with(line) && entry.entry != line {
+            candidates.insert(Pair::line(entry.entry.into_owned()));
+        }
+    }
+
+    candidates.into_iter().collect()
+}
+
+/// Whether `word` is `-` or `-N`, for `cd` to complete from the directory
+/// history.
+fn is_dir_history_index(word: &str) -> bool {
+    word.strip_prefix('-')
+        .is_some_and(|n| n.bytes().all(|byte| byte.is_ascii_digit()))
+}
+
+/// Entries of the directory part of `word` (the cwd if it has none) whose
+/// names match the rest of it.
+fn split_dir(word: &str) -> (&str, &str) {
+    match word.rfind('/') {
+        Some(slash) => word.split_at(slash + 1),
+        None => ("", word),
+    }
+}
+
+/// Hidden files are only offered when explicitly asked for with a leading dot.
+fn dir_entries(
+    matcher: Matcher,
+    read_dir: &Path,
+    dir: &str,
+    prefix: &str,
+    dirs_only: bool,
+) -> Vec<(usize, Pair)> {
+    let Ok(entries) = fs::read_dir(read_dir) else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if name.starts_with('.') && !prefix.starts_with('.') {
+                return None;
+            }
+            let score = matcher.score(&name, prefix)?;
+
+            let kind = file_kind(&entry.path());
+            if dirs_only && kind != Kind::Directory {
+                return None;
+            }
+
+            Some((score, Pair::file(dir, name, kind)))
+        })
+        .collect()
+}
+
+fn file_kind(path: &Path) -> Kind {
+    match fs::metadata(path) {
+        Ok(attr) if attr.is_dir() => Kind::Directory,
+        Ok(attr) if bin_path::is_executable(path, &attr) => Kind::Executable,
+        _ => Kind::File,
+    }
+}
+
+/// What a candidate names, shown in the completion list as a label after
+/// builtins, a color for executables and an `ls -F` style marker after
+/// files.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum Kind {
+    Builtin,
+    Executable,
+    Directory,
+    File,
+}
+
 #[derive(Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct Pair {
     pub display: String,
     pub replacement: String,
+    pub kind: Option<Kind>,
 }
 
 impl Pair {
-    fn new(display: String) -> Pair {
+    /// Escapes the replacement for the word it completes. Inside quotes the
+    /// quote is reopened, and closed again when the candidate is complete
+    /// rather than a directory to descend into.
+    fn quoted(mut self, quote: Option<char>) -> Pair {
+        let (body, complete) = match self.replacement.strip_suffix(' ') {
+            Some(body) => (body, true),
+            None => (self.replacement.as_str(), false),
+        };
+
+        let mut replacement = String::with_capacity(self.replacement.len() + 2);
+        if let Some(quote) = quote {
+            replacement.push(quote);
+        }
+        replacement.push_str(&escape(body, quote));
+        if let Some(quote) = quote
+            && complete
+        {
+            replacement.push(quote);
+        }
+        if complete {
+            replacement.push(' ');
+        }
+
+        self.replacement = replacement;
+        self
+    }
+
+    pub(crate) fn new(display: String) -> Pair {
         Self {
             replacement: append_trailing_space(&display),
             display,
+            kind: None,
+        }
+    }
+
+    /// Completes to `word` but lists it together with a description.
+    pub(crate) fn annotated(word: String, note: &str) -> Pair {
+        Self {
+            display: format!("{word} ({note})"),
+            replacement: append_trailing_space(&word),
+            kind: None,
+        }
+    }
+
+    /// Only builtins are labeled: executables are listed by name, like
+    /// bash lists them, colored by their kind.
+    fn command(name: &str, kind: Kind) -> Pair {
+        let pair = match kind {
+            Kind::Builtin => Self::annotated(name.to_string(), "builtin"),
+            _ => Self::new(name.to_string()),
+        };
+
+        Self {
+            kind: Some(kind),
+            ..pair
+        }
+    }
+
+    /// Every candidate at once, separated by spaces, like readline's
+    /// `insert-completions`.
+    fn all(candidates: &[Pair]) -> Pair {
+        let words: Vec<&str> = candidates
+            .iter()
+            .map(|pair| pair.replacement.trim_end_matches(' '))
+            .collect();
+        Self::line(append_trailing_space(&words.join(" ")))
+    }
+
+    /// A whole command line, replaced as is.
+    fn line(line: String) -> Pair {
+        Self {
+            replacement: line.clone(),
+            display: line,
+            kind: None,
+        }
+    }
+
+    /// Directories are completed with a trailing `/` so the next Tab can
+    /// descend into them, files with a space like commands.
+    fn file(dir: &str, name: String, kind: Kind) -> Pair {
+        match kind {
+            Kind::Directory => Self {
+                replacement: format!("{dir}{name}/"),
+                display: format!("{name}/"),
+                kind: Some(kind),
+            },
+            _ => Self {
+                replacement: append_trailing_space(&format!("{dir}{name}")),
+                display: if kind == Kind::Executable {
+                    format!("{name}*")
+                } else {
+                    name
+                },
+                kind: Some(kind),
+            },
         }
     }
 }
@@ -71,3 +821,307 @@ fn append_trailing_space(path: &str) -> String {
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bin_path::BinPath;
+    use crate::environment::Env;
+    use crate::named_dirs::NamedDirs;
+    use crate::options::Options;
+    use completion::Completer;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+    use rustyline::history::MemHistory;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[rstest]
+    #[case("ls My\\ Do", 3, "My Do", None)]
+    #[case("ls 'My Do", 3, "My Do", Some('\''))]
+    #[case("ls \"a\\\"b", 3, "a\"b", Some('"'))]
+    #[case("cat a'b c'd e", 12, "e", None)]
+    #[case("cat a'b c'd", 4, "ab cd", None)]
+    #[case("echo ", 5, "", None)]
+    #[case("ls|gr", 3, "gr", None)]
+    #[case("echo x >fi", 8, "fi", None)]
+    fn current_word_test(
+        #[case] line: &str,
+        #[case] start: usize,
+        #[case] word: &str,
+        #[case] quote: Option<char>,
+    ) {
+        let expected = CurrentWord {
+            start,
+            word: word.to_string(),
+            quote,
+        };
+        assert_eq!(current_word(line), expected);
+    }
+
+    #[rstest]
+    #[case("", Completing::Command)]
+    #[case("ls ", Completing::Argument(vec!["ls".into()]))]
+    #[case("ls -l | ", Completing::Command)]
+    #[case("ls|", Completing::Command)]
+    #[case("make && ", Completing::Command)]
+    #[case("cd /; ", Completing::Command)]
+    #[case("sudo ", Completing::Command)]
+    #[case("sudo command git ", Completing::Argument(vec!["git".into()]))]
+    #[case("time -p ", Completing::Command)]
+    #[case("nohup exec ", Completing::Command)]
+    #[case("builtin cd ", Completing::Argument(vec!["cd".into()]))]
+    #[case("ls | time ", Completing::Command)]
+    #[case("sudo -u root ", Completing::Command)]
+    #[case("sudo -g wheel -u root git ", Completing::Argument(vec!["git".into()]))]
+    #[case("sudo -u ", Completing::Argument(vec!["sudo".into(), "-u".into()]))]
+    #[case("env ", Completing::Command)]
+    #[case("env -i LANG=C A=1 ", Completing::Command)]
+    #[case("env -u HOME ls ", Completing::Argument(vec!["ls".into()]))]
+    #[case("sudo env A=1 git ", Completing::Argument(vec!["git".into()]))]
+    #[case("time A=1 ", Completing::Argument(vec!["A=1".into()]))]
+    #[case("echo x > ", Completing::File)]
+    #[case("sort <", Completing::File)]
+    #[case("make 2>", Completing::File)]
+    #[case("cat <in ", Completing::Argument(vec!["cat".into()]))]
+    #[case("ls >&2 ", Completing::Argument(vec!["ls".into()]))]
+    #[case("echo 'a | b' ", Completing::Argument(vec!["echo".into(), "a | b".into()]))]
+    #[case("git \\; ", Completing::Argument(vec!["git".into(), ";".into()]))]
+    fn completing_test(#[case] line: &str, #[case] expected: Completing) {
+        assert_eq!(completing(line), expected);
+    }
+
+    #[rstest]
+    #[case("My Documents ", None, "My\\ Documents ")]
+    #[case("My Documents/", None, "My\\ Documents/")]
+    #[case("it's (1).txt ", None, "it\\'s\\ \\(1\\).txt ")]
+    #[case("My Documents ", Some('\''), "'My Documents' ")]
+    #[case("My Documents/", Some('"'), "\"My Documents/")]
+    #[case("it's ", Some('\''), "'it'\\''s' ")]
+    #[case("a\"$b ", Some('"'), "\"a\\\"\\$b\" ")]
+    #[case("~proj/", None, "~proj/")]
+    fn quoted_test(#[case] replacement: &str, #[case] quote: Option<char>, #[case] expected: &str) {
+        let pair = Pair {
+            display: String::new(),
+            replacement: replacement.to_string(),
+            kind: None,
+        };
+        assert_eq!(pair.quoted(quote).replacement, expected);
+    }
+
+    #[rstest]
+    #[case(Pair::command("cd", Kind::Builtin), "cd (builtin)", "cd ")]
+    #[case(Pair::command("ls", Kind::Executable), "ls", "ls ")]
+    #[case(Pair::file("src/", "bin".into(), Kind::Directory), "bin/", "src/bin/")]
+    #[case(Pair::file("", "run.sh".into(), Kind::Executable), "run.sh*", "run.sh ")]
+    #[case(Pair::file("", "a.txt".into(), Kind::File), "a.txt", "a.txt ")]
+    fn kind_display_test(#[case] pair: Pair, #[case] display: &str, #[case] replacement: &str) {
+        assert_eq!(
+            (pair.display.as_str(), pair.replacement.as_str()),
+            (display, replacement)
+        );
+    }
+
+    #[test]
+    fn all_test() {
+        let candidates = [
+            Pair::file("", "a\\ b.txt".into(), Kind::File),
+            Pair::file("", "src".into(), Kind::Directory),
+            Pair::file("", "run.sh".into(), Kind::Executable),
+        ];
+        assert_eq!(Pair::all(&candidates).replacement, "a\\ b.txt src/ run.sh ");
+    }
+
+    #[rstest]
+    #[case(Matcher::Prefix, "docker", "doc", Some(0))]
+    #[case(Matcher::Prefix, "docker", "DOC", None)]
+    #[case(Matcher::IgnoreCase, "docker", "DOC", Some(0))]
+    #[case(Matcher::IgnoreCase, "git-status-helper", "gst", None)]
+    #[case(Matcher::Fuzzy, "git-status-helper", "gst", Some(3))]
+    #[case(Matcher::Fuzzy, "git-stash", "gsh", Some(6))]
+    #[case(Matcher::Fuzzy, "docker", "DOC", Some(0))]
+    #[case(Matcher::Fuzzy, "docker", "ker", Some(3))]
+    #[case(Matcher::Fuzzy, "docker", "dx", None)]
+    fn matcher_test(
+        #[case] matcher: Matcher,
+        #[case] candidate: &str,
+        #[case] word: &str,
+        #[case] expected: Option<usize>,
+    ) {
+        assert_eq!(matcher.score(candidate, word), expected);
+    }
+
+    /// Completes `line` at its end like the editor does, with `dir` as the
+    /// only PATH directory, returning where the replaced word starts and
+    /// the candidates with `dir` written as `D` in their replacements.
+    fn complete(dir: &Path, line: &str) -> (usize, Vec<Pair>) {
+        complete_with(dir, line, Options::new())
+    }
+
+    fn complete_with(dir: &Path, line: &str, options: Options) -> (usize, Vec<Pair>) {
+        let dir = dir.to_str().unwrap();
+        let env = Env::new([("PATH", dir)]);
+        env.set("foo_var", "1");
+        let mut bin_path = BinPath::with_env(env.clone());
+        // read PATH before completing, as the editor does while it starts
+        bin_path.all_executable_names().count();
+        let helper = Helper::new(
+            Rc::new(RefCell::new(bin_path)),
+            Rc::new(RefCell::new(NamedDirs::new())),
+            Rc::new(RefCell::new(options)),
+            env,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+        let history = MemHistory::new();
+        let line = line.replace('D', dir);
+        let ctx = rustyline::Context::new(&history);
+        let (start, candidates) = helper.complete(&line, line.len(), &ctx).unwrap();
+
+        let candidates = candidates
+            .into_iter()
+            .map(|pair| Pair {
+                replacement: pair.replacement.replace(dir, "D"),
+                ..pair
+            })
+            .collect();
+        (start, candidates)
+    }
+
+    fn replacements(candidates: &[Pair]) -> Vec<&str> {
+        candidates
+            .iter()
+            .map(|pair| pair.replacement.as_str())
+            .collect()
+    }
+
+    /// A directory with `a.txt`, the executable `run.sh` and `src/lib.rs`.
+    fn tree() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+        fs::write(dir.path().join("a.txt"), "").unwrap();
+        fs::write(dir.path().join(".hidden"), "").unwrap();
+        let run = dir.path().join("run.sh");
+        fs::write(&run, "").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&run, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        dir
+    }
+
+    #[rstest]
+    #[case("cat D/", vec!["D/a.txt ", "D/run.sh ", "D/src/"])]
+    #[case("cat D/s", vec!["D/src/"])]
+    #[case("cat D/src/", vec!["D/src/lib.rs "])]
+    #[case("cat D/.", vec!["D/.hidden "])]
+    #[case("cat D/a.txt D/r", vec!["D/run.sh "])]
+    #[case("ls | grep x D/a", vec!["D/a.txt "])]
+    #[case("echo > D/a", vec!["D/a.txt "])]
+    #[case("cd D/", vec!["D/src/"])]
+    #[case("pushd D/s", vec!["D/src/"])]
+    #[case("cd D/a", vec![])]
+    #[case("sudo cd D/", vec!["D/src/"])]
+    fn complete_files_test(#[case] line: &str, #[case] expected: Vec<&str>) {
+        let dir = tree();
+        let (_, candidates) = complete(dir.path(), line);
+        assert_eq!(replacements(&candidates), expected);
+    }
+
+    #[rstest]
+    #[case("echo $PAT", Some(5))]
+    #[case("echo \"$PAT", Some(6))]
+    #[case("echo a$PAT", Some(6))]
+    #[case("echo '$PAT", None)]
+    #[case("echo \\$PAT", None)]
+    fn complete_variables_test(#[case] line: &str, #[case] expected: Option<usize>) {
+        let dir = tree();
+        let (start, candidates) = complete(dir.path(), line);
+        let completed = replacements(&candidates).contains(&"$PATH ");
+        assert_eq!(completed.then_some(start), expected);
+    }
+
+    #[test]
+    fn complete_shell_variables() {
+        let dir = tree();
+        let (_, candidates) = complete(dir.path(), "echo $foo");
+        assert_eq!(replacements(&candidates), vec!["$foo_var "]);
+    }
+
+    #[rstest]
+    #[case("ech", vec![("echo (builtin)", "echo ")])]
+    #[case("ru", vec![("run.sh", "run.sh ")])]
+    #[case("sudo ru", vec![("run.sh", "run.sh ")])]
+    fn complete_commands_test(#[case] line: &str, #[case] expected: Vec<(&str, &str)>) {
+        let dir = tree();
+        let (_, candidates) = complete(dir.path(), line);
+        let candidates: Vec<_> = candidates
+            .iter()
+            .map(|pair| (pair.display.as_str(), pair.replacement.as_str()))
+            .collect();
+        assert_eq!(candidates, expected);
+    }
+
+    #[rstest]
+    #[case("-", true)]
+    #[case("-12", true)]
+    #[case("-1a", false)]
+    #[case("--", false)]
+    #[case("1", false)]
+    fn is_dir_history_index_test(#[case] word: &str, #[case] expected: bool) {
+        assert_eq!(is_dir_history_index(word), expected);
+    }
+
+    #[rstest]
+    #[case("-", vec!["-1 -- D", "-2 -- D/src"])]
+    #[case("-2", vec!["-2 -- D/src"])]
+    #[case("-3", vec![])]
+    fn dir_history_candidates_test(#[case] word: &str, #[case] expected: Vec<&str>) {
+        let dir = tree();
+        let gone = dir.path().join("gone");
+        fs::create_dir(&gone).unwrap();
+        let mut named_dirs = NamedDirs::new();
+        for visited in [&dir.path().join("src"), &gone, dir.path()] {
+            named_dirs.visited_mut().visit(visited).unwrap();
+        }
+        // `cd -N` skips it, so it isn't numbered either
+        fs::remove_dir(&gone).unwrap();
+        let helper = Helper::new(
+            Rc::new(RefCell::new(BinPath::with_env(Env::default()))),
+            Rc::new(RefCell::new(named_dirs)),
+            Rc::new(RefCell::new(Options::new())),
+            Env::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+
+        let root = dir.path().to_str().unwrap();
+        let candidates: Vec<_> = helper
+            .dir_history_candidates(word)
+            .into_iter()
+            .map(|pair| pair.display.replace(root, "D"))
+            .collect();
+        assert_eq!(candidates, expected);
+    }
+
+    #[rstest]
+    #[case(false, vec!["", ""])]
+    #[case(true, vec!["D/a.txt ", "D/run.sh "])]
+    fn complete_globs_test(#[case] menu_complete: bool, #[case] expected: Vec<&str>) {
+        let dir = tree();
+        let mut options = Options::new();
+        options.set_shopt("menu_complete", menu_complete);
+        let (start, candidates) = complete_with(dir.path(), "cat D/*.*", options);
+
+        // cycling puts each match in place of the pattern, listing leaves it
+        let replaces_pattern = start == "cat ".len();
+        assert_eq!(
+            (replaces_pattern, replacements(&candidates)),
+            (menu_complete, expected)
+        );
+    }
+}
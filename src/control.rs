@@ -0,0 +1,80 @@
+use crate::eprint;
+use crate::json;
+use crate::shell::{EvalResult, Shell};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+/// Runs the command lines clients send to a unix socket at `path`, instead
+/// of reading commands from stdin, for editor integrations and tests
+/// without a terminal. Every line sent is answered with one line of JSON
+/// like `{"stdout":"a\n","stderr":"","status":0}`. Clients are served one
+/// at a time, until the shell is killed.
+pub fn serve(shell: &mut Shell, path: &Path) -> io::Result<()> {
+    // a socket left by an earlier shell would make binding fail
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    for stream in listener.incoming() {
+        if let Err(err) = serve_client(shell, stream?) {
+            eprint!("control socket: {err}\n");
+        }
+    }
+
+    Ok(())
+}
+
+fn serve_client(shell: &mut Shell, stream: UnixStream) -> io::Result<()> {
+    let mut responses = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let result = shell.eval_str(&line?);
+        responses.write_all(format!("{}\n", response(&result)).as_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn response(result: &EvalResult) -> String {
+    format!(
+        r#"{{"stdout":{},"stderr":{},"status":{}}}"#,
+        json::string(&result.stdout),
+        json::string(&result.stderr),
+        result.status
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Read;
+    use std::net::Shutdown;
+
+    #[test]
+    fn serve_client_answers_every_line() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(b"echo hi\nnosuch\nexit 3\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let mut shell = Shell::non_interactive().unwrap();
+        serve_client(&mut shell, server).unwrap();
+
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        assert_eq!(
+            responses,
+            concat!(
+                r#"{"stdout":"hi\n","stderr":"","status":0}"#,
+                "\n",
+                r#"{"stdout":"","stderr":"nosuch: command not found\n","status":127}"#,
+                "\n",
+                r#"{"stdout":"","stderr":"","status":3}"#,
+                "\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use indexmap::IndexMap;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{env, fs, io};
+
+/// Once the ranks add up to more than this, they are all aged.
+const MAX_TOTAL_RANK: f64 = 9000.0;
+
+/// The directories `cd` went to, ranked by how often and how recently like
+/// z ranks them, for `j` to jump to and `cd -N` to go back to. Saved as
+/// `path|rank|time` lines, oldest visit first.
+#[derive(Default)]
+pub struct DirHistory {
+    /// By when they were last visited, oldest first.
+    dirs: IndexMap<PathBuf, Visits>,
+    /// Where they are saved after every visit, if anywhere.
+    file: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Visits {
+    rank: f64,
+    /// Seconds since the epoch.
+    last: u64,
+}
+
+impl Visits {
+    /// The rank weighted by how long ago the last visit was.
+    fn frecency(&self, now: u64) -> f64 {
+        match now.saturating_sub(self.last) {
+            age if age < 3600 => self.rank * 4.0,
+            age if age < 86400 => self.rank * 2.0,
+            age if age < 604800 => self.rank / 2.0,
+            _ => self.rank / 4.0,
+        }
+    }
+}
+
+impl DirHistory {
+    /// The directories saved in `path`, saving them there again from now
+    /// on. A missing file is an empty history, and bad lines are skipped.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+
+        let mut history = Self::parse(&contents);
+        history.file = Some(path.to_path_buf());
+        Ok(history)
+    }
+
+    fn parse(contents: &str) -> Self {
+        let dirs = contents
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.rsplitn(3, '|');
+                let last = fields.next()?.parse().ok()?;
+                let rank = fields.next()?.parse().ok()?;
+                let dir = PathBuf::from(fields.next()?);
+                Some((dir, Visits { rank, last }))
+            })
+            .collect();
+
+        Self { dirs, file: None }
+    }
+
+    /// Records a visit to `dir`, saving the history if it has a file.
+    pub fn visit(&mut self, dir: &Path) -> io::Result<()> {
+        self.visit_at(dir, now());
+        match &self.file {
+            Some(file) => self.save(file),
+            None => Ok(()),
+        }
+    }
+
+    fn visit_at(&mut self, dir: &Path, now: u64) {
+        let rank = self
+            .dirs
+            .shift_remove(dir)
+            .map_or(0.0, |visits| visits.rank);
+        self.dirs.insert(
+            dir.to_path_buf(),
+            Visits {
+                rank: rank + 1.0,
+                last: now,
+            },
+        );
+
+        if self.dirs.values().map(|visits| visits.rank).sum::<f64>() > MAX_TOTAL_RANK {
+            for visits in self.dirs.values_mut() {
+                visits.rank *= 0.99;
+            }
+            self.dirs.retain(|_, visits| visits.rank >= 1.0);
+        }
+    }
+
+    /// Written to another file first, for other shells never to read half
+    /// of it.
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(format!(".{}", std::process::id()));
+        let temp = PathBuf::from(temp);
+
+        let mut writer = BufWriter::new(fs::File::create(&temp)?);
+        for (dir, visits) in &self.dirs {
+            writeln!(writer, "{}|{}|{}", dir.display(), visits.rank, visits.last)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temp, path)
+    }
+
+    /// The directories, the most recently visited first.
+    pub fn recent(&self) -> impl Iterator<Item = &Path> {
+        self.dirs.keys().rev().map(PathBuf::as_path)
+    }
+
+    /// The directories `cd -N` goes back to, the Nth one Nth: the ones that
+    /// still exist other than the current directory, the most recently
+    /// visited first.
+    pub fn back(&self) -> Vec<&Path> {
+        let current = env::current_dir().ok();
+        self.back_from(current.as_deref(), |dir| dir.is_dir())
+    }
+
+    fn back_from(&self, current: Option<&Path>, exists: impl Fn(&Path) -> bool) -> Vec<&Path> {
+        self.recent()
+            .filter(|&dir| Some(dir) != current && exists(dir))
+            .collect()
+    }
+
+    /// The directories with their frecency, the highest last, like `z -l`
+    /// lists them.
+    pub fn ranked(&self) -> Vec<(f64, &Path)> {
+        let now = now();
+        let mut ranked: Vec<(f64, &Path)> = self
+            .dirs
+            .iter()
+            .map(|(dir, visits)| (visits.frecency(now), dir.as_path()))
+            .collect();
+        ranked.sort_by(|a, b| a.0.total_cmp(&b.0));
+        ranked
+    }
+
+    /// The existing directory with the highest frecency whose path has all
+    /// of `keywords` in it in order. Ones matching in the same case win over
+    /// ones only matching ignoring it.
+    pub fn best_match(&self, keywords: &[String]) -> Option<&Path> {
+        self.best_match_at(keywords, now(), |dir| dir.is_dir())
+    }
+
+    fn best_match_at(
+        &self,
+        keywords: &[String],
+        now: u64,
+        exists: impl Fn(&Path) -> bool,
+    ) -> Option<&Path> {
+        let best = |ignore_case: bool| {
+            self.dirs
+                .iter()
+                .filter(|(dir, _)| matches_in_order(dir, keywords, ignore_case) && exists(dir))
+                .max_by(|a, b| a.1.frecency(now).total_cmp(&b.1.frecency(now)))
+                .map(|(dir, _)| dir.as_path())
+        };
+        best(false).or_else(|| best(true))
+    }
+}
+
+fn matches_in_order(dir: &Path, keywords: &[String], ignore_case: bool) -> bool {
+    let mut path = dir.to_string_lossy().into_owned();
+    if ignore_case {
+        path = path.to_lowercase();
+    }
+
+    let mut rest = path.as_str();
+    keywords.iter().all(|keyword| {
+        let keyword = if ignore_case {
+            keyword.to_lowercase()
+        } else {
+            keyword.clone()
+        };
+        match rest.find(&keyword) {
+            Some(index) => {
+                rest = &rest[index + keyword.len()..];
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn history() -> DirHistory {
+        DirHistory::parse(
+            "/srv/project|10|1699000000\n\
+             bad line\n\
+             /home/me/Projects/shell|2|1699999000\n\
+             /home/me/src/project-notes|3|1699990000\n",
+        )
+    }
+
+    #[rstest]
+    #[case(&["proj"], Some("/home/me/src/project-notes"))]
+    #[case(&["srv"], Some("/srv/project"))]
+    #[case(&["me", "shell"], Some("/home/me/Projects/shell"))]
+    #[case(&["shell", "me"], None)]
+    #[case(&["Proj"], Some("/home/me/Projects/shell"))]
+    #[case(&["SHELL"], Some("/home/me/Projects/shell"))]
+    fn best_match_test(#[case] keywords: &[&str], #[case] expected: Option<&str>) {
+        let keywords: Vec<String> = keywords.iter().map(|keyword| keyword.to_string()).collect();
+        assert_eq!(
+            history().best_match_at(&keywords, NOW, |_| true),
+            expected.map(Path::new)
+        );
+    }
+
+    #[test]
+    fn visits_move_to_the_front() {
+        let mut history = history();
+        history.visit_at(Path::new("/srv/project"), NOW);
+        history.visit_at(Path::new("/tmp"), NOW);
+
+        assert_eq!(
+            history.recent().collect::<Vec<_>>(),
+            vec![
+                Path::new("/tmp"),
+                Path::new("/srv/project"),
+                Path::new("/home/me/src/project-notes"),
+                Path::new("/home/me/Projects/shell"),
+            ]
+        );
+        assert_eq!(
+            history.dirs[Path::new("/srv/project")],
+            Visits {
+                rank: 11.0,
+                last: NOW
+            }
+        );
+    }
+
+    #[test]
+    fn back_skips_the_current_and_missing_directories() {
+        let history = history();
+        let current = Path::new("/home/me/Projects/shell");
+        let exists = |dir: &Path| dir != Path::new("/srv/project");
+
+        assert_eq!(
+            history.back_from(Some(current), exists),
+            vec![Path::new("/home/me/src/project-notes")]
+        );
+    }
+
+    #[test]
+    fn load_reads_what_visit_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dir_history");
+        let mut history = DirHistory::load(&path).unwrap();
+        history.visit(Path::new("/a|b")).unwrap();
+        history.visit(Path::new("/c")).unwrap();
+
+        let loaded = DirHistory::load(&path).unwrap();
+
+        assert_eq!(
+            loaded.recent().collect::<Vec<_>>(),
+            vec![Path::new("/c"), Path::new("/a|b")]
+        );
+    }
+}
@@ -1,47 +1,653 @@
 use crate::bin_path::BinPath;
-use rustyline::history::DefaultHistory;
-use std::cell::RefCell;
+use crate::completion::{CompletionProvider, Kind, Providers};
+use crate::environment::Env;
+use crate::eprint;
+use crate::history::{HistControl, ShellHistory, SubstringSearch};
+use crate::keybindings::{self, EditorVars, HandlerState, KeyBinding, NotePaste, Pairing};
+use crate::lexer;
+use crate::line_editor::{LineEditor, ReadError};
+use crate::named_dirs::{self, NamedDirs};
+use crate::options::Options;
+use crate::prompt::Prompt;
+use crate::terminal;
+use rustyline::config::Configurer;
+use rustyline::error::ReadlineError;
+use rustyline::history::History;
+use rustyline::validate::{ValidationContext, ValidationResult};
+use rustyline::{Cmd, CompletionType, EditMode, Event, EventHandler, KeyCode, KeyEvent, Modifiers};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+#[cfg(unix)]
+use std::thread;
+use std::time::Duration;
+use std::{fs, io};
 
 pub struct Helper {
     pub(crate) bin_path: Rc<RefCell<BinPath>>,
+    pub(crate) named_dirs: Rc<RefCell<NamedDirs>>,
+    pub(crate) options: Rc<RefCell<Options>>,
+    /// The variables of the shell.
+    pub(crate) env: Env,
+    pub(crate) providers: Providers,
+    /// Kinds of the candidates last offered, by display text, for coloring
+    /// the completion list.
+    pub(crate) candidate_kinds: RefCell<HashMap<String, Kind>>,
+    /// The prompt being read with, including its non-printing sequences.
+    pub(crate) styled_prompt: RefCell<String>,
+    /// Lines of the command read so far when continuing it on another line.
+    pub(crate) pending: RefCell<String>,
+    continued: Cell<bool>,
+    /// The history search, whose match is highlighted while it is shown.
+    pub(crate) substring_search: Arc<Mutex<SubstringSearch>>,
+    /// Whether the next completion inserts all the candidates.
+    pub(crate) insert_completions: Arc<AtomicBool>,
+    /// The edit of `auto_pairs` the next completion makes instead.
+    pub(crate) pairing: Arc<Mutex<Option<Pairing>>>,
+}
+
+impl Helper {
+    pub(crate) fn new(
+        bin_path: Rc<RefCell<BinPath>>,
+        named_dirs: Rc<RefCell<NamedDirs>>,
+        options: Rc<RefCell<Options>>,
+        env: Env,
+        substring_search: Arc<Mutex<SubstringSearch>>,
+        insert_completions: Arc<AtomicBool>,
+        pairing: Arc<Mutex<Option<Pairing>>>,
+    ) -> Self {
+        Self {
+            bin_path,
+            named_dirs,
+            options,
+            env,
+            providers: Providers::default(),
+            candidate_kinds: RefCell::default(),
+            styled_prompt: RefCell::new(String::new()),
+            pending: RefCell::new(String::new()),
+            continued: Cell::new(false),
+            substring_search,
+            insert_completions,
+            pairing,
+        }
+    }
 }
 
 impl rustyline::hint::Hinter for Helper {
     type Hint = String;
 }
 
-impl rustyline::highlight::Highlighter for Helper {}
-impl rustyline::validate::Validator for Helper {}
+impl rustyline::validate::Validator for Helper {
+    /// Flags an unfinished command, e.g. an open quote or a trailing pipe, so
+    /// [`Editor::readline`] asks for another line with the PS2 prompt instead
+    /// of returning it.
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        let input = format!("{}{}", self.pending.borrow(), ctx.input());
+        self.continued.set(lexer::is_incomplete(&input));
+
+        Ok(ValidationResult::Valid(None))
+    }
+}
 
 impl rustyline::Helper for Helper {}
 
 pub struct Editor {
-    editor: rustyline::Editor<Helper, DefaultHistory>,
+    editor: rustyline::Editor<Helper, ShellHistory>,
+    /// The file $HISTFILE named when the history was last loaded from it
+    /// or saved to it.
+    history_file: Option<PathBuf>,
+    /// Whether the history was loaded, which waits for the first command
+    /// to be read so startup files can set $HISTFILE.
+    history_loaded: bool,
+    /// Size of the history file when this shell last read or appended to
+    /// it; what follows was written by other shells.
+    history_offset: u64,
+    /// Parts of the history file other shells wrote before this one last
+    /// appended to it, not read yet.
+    unread_history: Vec<Range<u64>>,
+    handler_state: HandlerState,
+    timeout: Option<Duration>,
+    /// Whether Up and Down are bound to the substring search, as the
+    /// `history_substring_search` option has them.
+    substring_search_keys: bool,
+    /// Whether quotes, brackets and Backspace are bound to pair, as the
+    /// `auto_pairs` option has them.
+    auto_pair_keys: bool,
 }
 
 impl Editor {
-    pub fn new(bin_path: Rc<RefCell<BinPath>>) -> anyhow::Result<Self> {
+    pub fn new(
+        bin_path: Rc<RefCell<BinPath>>,
+        named_dirs: Rc<RefCell<NamedDirs>>,
+        options: Rc<RefCell<Options>>,
+        env: Env,
+    ) -> anyhow::Result<Self> {
+        // pasted text is inserted rather than typed, so its newlines don't
+        // run anything before Enter is pressed
         let config = rustyline::Config::builder()
-            .completion_type(rustyline::CompletionType::List)
-            .auto_add_history(true)
+            .completion_type(CompletionType::List)
+            .bracketed_paste(true)
             .build();
 
-        let mut editor = rustyline::Editor::<Helper, DefaultHistory>::with_config(config)?;
-        editor.set_helper(Some(Helper { bin_path }));
+        let history = ShellHistory::new();
+        let substring_search = history.substring_search();
+        let insert_completions = Arc::new(AtomicBool::new(false));
+        let pairing = Arc::new(Mutex::new(None));
+        let mut editor = rustyline::Editor::with_history(config, history)?;
+        bin_path.borrow_mut().index_executables();
+        editor.set_helper(Some(Helper::new(
+            bin_path,
+            named_dirs,
+            options,
+            env,
+            Arc::clone(&substring_search),
+            Arc::clone(&insert_completions),
+            Arc::clone(&pairing),
+        )));
+        bind_history_search(&mut editor);
+
+        let mut editor = Self {
+            editor,
+            history_file: None,
+            history_loaded: false,
+            history_offset: 0,
+            unread_history: Vec::new(),
+            handler_state: HandlerState::default(),
+            timeout: None,
+            substring_search_keys: false,
+            auto_pair_keys: false,
+        };
+        editor.handler_state.substring_search = substring_search;
+        editor.handler_state.insert_completions = insert_completions;
+        editor.handler_state.pairing = pairing;
+        let paste = NotePaste(Arc::clone(&editor.handler_state.pasted));
+        editor.editor.bind_sequence(
+            KeyEvent(KeyCode::BracketedPasteStart, Modifiers::NONE),
+            EventHandler::Conditional(Box::new(paste)),
+        );
+        let default_bindings = [
+            (vec![KeyEvent::alt('.')], "insert-last-argument"),
+            (vec![KeyEvent::alt('_')], "insert-last-argument"),
+            (
+                vec![KeyEvent(KeyCode::Up, Modifiers::NONE)],
+                "up-line-or-beginning-search",
+            ),
+            (
+                vec![KeyEvent(KeyCode::Down, Modifiers::NONE)],
+                "down-line-or-beginning-search",
+            ),
+            (
+                vec![KeyEvent::ctrl('X'), KeyEvent::ctrl('E')],
+                "edit-and-execute-command",
+            ),
+            (vec![KeyEvent::alt('*')], "insert-completions"),
+            (
+                vec![KeyEvent::ctrl('X'), KeyEvent::from('*')],
+                "glob-expand-word",
+            ),
+        ];
+        for (keys, function) in default_bindings {
+            editor.bind_keys(KeyBinding {
+                keys,
+                function: function.to_string(),
+            });
+        }
+        editor.load_keybindings();
+
+        Ok(editor)
+    }
+
+    /// Asks whether to run `input` anyway, showing its control characters
+    /// like `^M`, as e.g. a carriage return can hide what comes before it.
+    fn confirm_paste(&mut self, input: &str) -> Result<bool, ReadError> {
+        eprint!(
+            "pasted text holds control characters:\n{}\n",
+            caret_notation(input)
+        );
+        let question = "run it anyway? [y/N] ";
+        self.helper().styled_prompt.replace(question.to_string());
+        let answer = self.editor.readline(question).map_err(read_error)?;
+
+        Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+    }
+
+    fn editor_vars(&self) -> EditorVars {
+        let env = &self.helper().env;
+        let editor = ["VISUAL", "EDITOR"]
+            .into_iter()
+            .filter_map(|name| env.var(name))
+            .find(|editor| !editor.is_empty());
+        EditorVars {
+            editor,
+            exported: env.exported(),
+        }
+    }
+
+    fn helper(&self) -> &Helper {
+        self.editor.helper().expect("helper is set in new")
+    }
+
+    fn bind_keys(&mut self, binding: KeyBinding) {
+        if let Some(handler) = keybindings::handler(&binding.function, &self.handler_state) {
+            self.editor
+                .bind_sequence(Event::KeySeq(binding.keys), handler);
+        }
+    }
+
+    /// Binds the keys configured in the inputrc file. Bindings to functions
+    /// this shell doesn't implement are ignored, so a bash inputrc still works.
+    fn load_keybindings(&mut self) {
+        let Some(path) = keybindings::inputrc_path() else {
+            return;
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                for binding in keybindings::parse_inputrc(&contents) {
+                    self.bind_keys(binding);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => eprint!("inputrc: {}: {err}\n", path.display()),
+        }
+    }
+
+    /// A missing history file just means there is no history yet, other
+    /// errors are reported but don't prevent the shell from starting.
+    fn load_history(&mut self) {
+        self.history_loaded = true;
+        self.history_file = history_file(&self.helper().env);
+        let Some(path) = &self.history_file else {
+            return;
+        };
+
+        match self.editor.history_mut().load(path) {
+            Ok(()) => self.history_offset = file_len(path),
+            Err(ReadlineError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => eprint!("history: {}: {err}\n", path.display()),
+        }
+    }
+
+    /// Switches to the file $HISTFILE names now, if it changed since the
+    /// history was loaded. What the new file already holds is left unread.
+    fn follow_history_file(&mut self) {
+        let path = history_file(&self.helper().env);
+        if path != self.history_file {
+            self.history_offset = path.as_deref().map_or(0, file_len);
+            self.unread_history.clear();
+            self.history_file = path;
+        }
+    }
+}
+
+impl LineEditor for Editor {
+    fn readline(
+        &mut self,
+        prompt: &Prompt,
+        continuation_prompt: &Prompt,
+    ) -> Result<String, ReadError> {
+        self.helper().pending.take();
+        self.handler_state.pasted.store(false, Ordering::Relaxed);
+        *self.handler_state.substring_search.lock().unwrap() = SubstringSearch::default();
+        *self.handler_state.cooked_mode.lock().unwrap() = terminal::Mode::current();
+        *self.handler_state.editor_vars.lock().unwrap() = self.editor_vars();
+        if !self.history_loaded {
+            self.load_history();
+        }
+
+        let mut prompt = prompt;
+        loop {
+            self.helper().styled_prompt.replace(prompt.styled.clone());
+            self.helper().continued.set(false);
 
-        Ok(Self { editor })
+            #[cfg(unix)]
+            let watchdog = self.timeout.map(Watchdog::start);
+            let line = self.editor.readline(&prompt.plain);
+            // a line entered just as the timeout passed is still run
+            #[cfg(unix)]
+            if watchdog.is_some_and(Watchdog::stop)
+                && matches!(line, Err(ReadlineError::Interrupted))
+            {
+                return Err(ReadError::TimedOut);
+            }
+            let mut line = line.map_err(read_error)?;
+            let mut input = self.helper().pending.take();
+            // the line accepted is the one before it was edited, so it was
+            // validated as that
+            if let Some(edited) = self.handler_state.edited.lock().unwrap().take() {
+                eprint!("{edited}\n");
+                line = edited;
+                let continued = lexer::is_incomplete(&format!("{input}{line}"));
+                self.helper().continued.set(continued);
+            }
+            input.push_str(&line);
+
+            if !self.helper().continued.get() {
+                if self.handler_state.pasted.load(Ordering::Relaxed)
+                    && self.helper().options.borrow().confirm_paste()
+                    && has_control_chars(&input)
+                    && !self.confirm_paste(&input)?
+                {
+                    return Ok(String::new());
+                }
+                return Ok(input);
+            }
+
+            input.push('\n');
+            self.helper().pending.replace(input);
+            prompt = continuation_prompt;
+        }
     }
 
-    pub fn readline(&mut self, prompt: &str) -> rustyline::Result<String> {
-        self.editor.readline(prompt)
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
     }
 
-    pub fn history(&mut self) -> &DefaultHistory {
+    /// Switches between the vi and emacs editing modes, between listing
+    /// and cycling through ambiguous completions, between searching
+    /// history for a prefix or a substring on Up and Down, and pairing
+    /// quotes and brackets or not.
+    fn reconfigure(&mut self, options: &Options) {
+        let edit_mode = if options.vi() {
+            EditMode::Vi
+        } else {
+            EditMode::Emacs
+        };
+        self.editor.set_edit_mode(edit_mode);
+
+        let completion_type = if options.menu_complete() {
+            CompletionType::Circular
+        } else {
+            CompletionType::List
+        };
+        self.editor.set_completion_type(completion_type);
+
+        let substring_search = options.history_substring_search();
+        if substring_search != self.substring_search_keys {
+            self.substring_search_keys = substring_search;
+            let (up, down) = if substring_search {
+                (
+                    "history-substring-search-up",
+                    "history-substring-search-down",
+                )
+            } else {
+                (
+                    "up-line-or-beginning-search",
+                    "down-line-or-beginning-search",
+                )
+            };
+            for (key, function) in [(KeyCode::Up, up), (KeyCode::Down, down)] {
+                self.bind_keys(KeyBinding {
+                    keys: vec![KeyEvent(key, Modifiers::NONE)],
+                    function: function.to_string(),
+                });
+            }
+        }
+
+        let auto_pairs = options.auto_pairs();
+        if auto_pairs != self.auto_pair_keys {
+            self.auto_pair_keys = auto_pairs;
+            for (key, typed) in keybindings::auto_pair_keys() {
+                if auto_pairs {
+                    let handler = keybindings::auto_pair_handler(typed, &self.handler_state);
+                    self.editor.bind_sequence(key, handler);
+                } else {
+                    self.editor.unbind_sequence(key);
+                }
+            }
+        }
+    }
+
+    fn register_completion(&mut self, command: &str, provider: Box<dyn CompletionProvider>) {
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.providers.register(command, provider);
+        }
+    }
+
+    fn history(&self) -> &ShellHistory {
         self.editor.history()
     }
 
-    pub fn history_mut(&mut self) -> &mut DefaultHistory {
+    fn history_mut(&mut self) -> &mut ShellHistory {
         self.editor.history_mut()
     }
+
+    fn add_history_entry(&mut self, line: &str, control: &HistControl) -> bool {
+        if !line.trim().is_empty() {
+            *self.handler_state.last_argument.lock().unwrap() =
+                keybindings::last_word(line).to_string();
+        }
+
+        let added = control.add(self.editor.history_mut(), line);
+
+        let inc_append = self.helper().options.borrow().inc_append_history();
+        if added
+            && inc_append
+            && let Err(err) = self.save_history()
+        {
+            let path = self.history_file().unwrap();
+            eprint!("history: {}: {err}\n", path.display());
+        }
+
+        added
+    }
+
+    fn history_file(&self) -> Option<PathBuf> {
+        history_file(&self.helper().env)
+    }
+
+    /// Appends the entries not yet written to the history file. Other
+    /// shells' entries since the last sync stay unread for `history -n`.
+    fn save_history(&mut self) -> anyhow::Result<()> {
+        self.follow_history_file();
+        let Some(path) = &self.history_file else {
+            return Ok(());
+        };
+
+        let len_before = file_len(path);
+        self.editor.history_mut().append(path)?;
+        if len_before > self.history_offset {
+            self.unread_history.push(self.history_offset..len_before);
+        }
+        self.history_offset = file_len(path);
+        Ok(())
+    }
+
+    fn read_new_history(&mut self) -> anyhow::Result<()> {
+        self.follow_history_file();
+        let Some(path) = &self.history_file else {
+            return Ok(());
+        };
+
+        let history = self.editor.history_mut();
+        for range in self.unread_history.drain(..) {
+            history.load_range(path, range)?;
+        }
+        self.history_offset = history.load_range(path, self.history_offset..u64::MAX)?;
+        Ok(())
+    }
+}
+
+/// Interrupts reading a line once a timeout passes, which rustyline handles
+/// like Ctrl-C, restoring the terminal.
+///
+/// The SIGINT is only sent while the reading thread is still in readline,
+/// as `reading` says, and that thread blocks SIGINT before it stops saying
+/// so. One sent as readline returns stays pending then, and is discarded
+/// instead of interrupting whatever the shell does next.
+#[cfg(unix)]
+struct Watchdog {
+    reading: Arc<Mutex<bool>>,
+    cancel: mpsc::Sender<()>,
+    thread: thread::JoinHandle<bool>,
+}
+
+#[cfg(unix)]
+impl Watchdog {
+    fn start(timeout: Duration) -> Self {
+        // SAFETY: pthread_self has no preconditions. pthread_t is a pointer
+        // on some platforms, which isn't Send.
+        let reader = unsafe { libc::pthread_self() } as usize;
+        let reading = Arc::new(Mutex::new(true));
+        let (cancel, cancelled) = mpsc::channel();
+        let thread = thread::spawn({
+            let reading = Arc::clone(&reading);
+            move || {
+                if cancelled.recv_timeout(timeout) != Err(RecvTimeoutError::Timeout) {
+                    return false;
+                }
+
+                let reading = reading.lock().unwrap();
+                if *reading {
+                    // SAFETY: the reading thread is alive until it joins this
+                    // one, so `reader` still names it.
+                    unsafe { libc::pthread_kill(reader as libc::pthread_t, libc::SIGINT) };
+                }
+                *reading
+            }
+        });
+
+        Self {
+            reading,
+            cancel,
+            thread,
+        }
+    }
+
+    /// Whether the timeout passed and SIGINT was sent to interrupt the line.
+    fn stop(self) -> bool {
+        let sigint = sigint_set();
+        let mut mask = sigint;
+        // SAFETY: both sets are initialized, and this only changes the
+        // mask of the current thread.
+        unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &sigint, &mut mask) };
+        *self.reading.lock().unwrap() = false;
+
+        // the thread is gone if it timed out
+        let _ = self.cancel.send(());
+        let signalled = self.thread.join().unwrap();
+        if signalled {
+            let zero = libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            };
+            // SAFETY: only takes a pending SIGINT, if readline returned
+            // before it was delivered, without waiting.
+            unsafe { libc::sigtimedwait(&sigint, std::ptr::null_mut(), &zero) };
+        }
+
+        // SAFETY: restores the mask saved above for the current thread.
+        unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &mask, std::ptr::null_mut()) };
+        signalled
+    }
+}
+
+/// A signal set with only SIGINT in it.
+#[cfg(unix)]
+fn sigint_set() -> libc::sigset_t {
+    // SAFETY: sigemptyset initializes the set before sigaddset adds to it.
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        set
+    }
+}
+
+fn read_error(err: ReadlineError) -> ReadError {
+    match err {
+        ReadlineError::Eof => ReadError::Eof,
+        ReadlineError::Interrupted => ReadError::Interrupted,
+        err => ReadError::Backend(Box::new(err)),
+    }
+}
+
+/// Ctrl-R / Ctrl-S start (or, pressed again, continue) an incremental search
+/// through history, shown as `(reverse-i-search)` in place of the prompt.
+/// Bound explicitly so the keys work the same in every edit mode.
+fn bind_history_search(editor: &mut rustyline::Editor<Helper, ShellHistory>) {
+    editor.bind_sequence(
+        KeyEvent::ctrl('R'),
+        EventHandler::Simple(Cmd::ReverseSearchHistory),
+    );
+    editor.bind_sequence(
+        KeyEvent::ctrl('S'),
+        EventHandler::Simple(Cmd::ForwardSearchHistory),
+    );
+}
+
+/// Whether `text` has ASCII control characters other than tabs and
+/// newlines.
+fn has_control_chars(text: &str) -> bool {
+    text.chars().any(is_hidden_control)
+}
+
+fn is_hidden_control(char: char) -> bool {
+    char.is_ascii_control() && !matches!(char, '\t' | '\n')
+}
+
+/// `text` with the control characters [`has_control_chars`] looks for in
+/// caret notation, like `^C` for Ctrl-C.
+fn caret_notation(text: &str) -> String {
+    let mut shown = String::with_capacity(text.len());
+    for char in text.chars() {
+        if is_hidden_control(char) {
+            shown.push('^');
+            shown.push(char::from(char as u8 ^ 0x40));
+        } else {
+            shown.push(char);
+        }
+    }
+
+    shown
+}
+
+fn file_len(path: &Path) -> u64 {
+    fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+/// $HISTFILE, defaulting to ~/.history. Setting it to an empty value
+/// disables persistent history.
+fn history_file(env: &Env) -> Option<PathBuf> {
+    match env.var_os("HISTFILE") {
+        Some(path) if path.is_empty() => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None => env
+            .var_os("HOME")
+            .map(PathBuf::from)
+            .or_else(named_dirs::own_home)
+            .map(|home| home.join(".history")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(&[("HISTFILE", "/tmp/hist"), ("HOME", "/home/me")], Some("/tmp/hist"))]
+    #[case(&[("HISTFILE", ""), ("HOME", "/home/me")], None)]
+    #[case(&[("HOME", "/home/me")], Some("/home/me/.history"))]
+    fn history_file_test(#[case] vars: &[(&str, &str)], #[case] expected: Option<&str>) {
+        let env = Env::new(vars.iter().copied());
+
+        assert_eq!(history_file(&env), expected.map(PathBuf::from));
+    }
+
+    #[test]
+    fn history_file_assigned_in_the_shell() {
+        let env = Env::new([("HISTFILE", "/tmp/hist")]);
+
+        env.set("HISTFILE", "/tmp/other");
+
+        assert_eq!(history_file(&env), Some(PathBuf::from("/tmp/other")));
+    }
 }
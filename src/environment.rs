@@ -0,0 +1,283 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::process;
+use std::rc::Rc;
+use std::time::{Instant, SystemTime};
+
+/// The variables of the shell: the ones it inherited and the ones assigned
+/// since. Assignments are shared by every clone, so e.g. a new PATH is seen
+/// by command lookup right away. RANDOM, SECONDS, LINENO and `?` are
+/// computed when they are looked up.
+#[derive(Clone, Default)]
+pub struct Env {
+    inherited: Inherited,
+    assigned: Rc<RefCell<HashMap<String, Variable>>>,
+    dynamic: Rc<Dynamic>,
+}
+
+#[derive(Clone, Default)]
+enum Inherited {
+    /// The environment of the shell process.
+    #[default]
+    Process,
+    /// Exactly these variables, leaving the process environment alone.
+    Vars(Rc<HashMap<String, String>>),
+}
+
+struct Variable {
+    /// `None` once it was unset, hiding an inherited value, or when it was
+    /// exported before being assigned.
+    value: Option<String>,
+    /// Whether commands the shell runs get it in their environment.
+    exported: bool,
+}
+
+/// State of the computed variables.
+struct Dynamic {
+    /// SECONDS counts up from this value, assigned at this instant.
+    seconds: Cell<(u64, Instant)>,
+    random_seed: Cell<u32>,
+    /// The line of the command being run, in its script.
+    line: Cell<usize>,
+    /// The status of the last command, for `$?`.
+    status: Cell<i32>,
+}
+
+impl Default for Dynamic {
+    fn default() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        Self {
+            seconds: Cell::new((0, Instant::now())),
+            random_seed: Cell::new(nanos ^ process::id()),
+            line: Cell::new(0),
+            status: Cell::new(0),
+        }
+    }
+}
+
+impl Dynamic {
+    fn var(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "RANDOM" => self.random(),
+            "SECONDS" => {
+                let (start, since) = self.seconds.get();
+                start + since.elapsed().as_secs()
+            }
+            "LINENO" => self.line.get() as u64,
+            "?" => return Some(self.status.get().to_string()),
+            _ => return None,
+        };
+
+        Some(value.to_string())
+    }
+
+    /// Assigning RANDOM seeds it, SECONDS counts up from the value, and
+    /// LINENO and `?` can't be assigned.
+    fn set(&self, name: &str, value: &str) -> bool {
+        match name {
+            "RANDOM" => self.random_seed.set(value.parse().unwrap_or_default()),
+            "SECONDS" => self
+                .seconds
+                .set((value.parse().unwrap_or_default(), Instant::now())),
+            "LINENO" | "?" => {}
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// A number from 0 to 32767, from a linear congruential generator.
+    fn random(&self) -> u64 {
+        let seed = self
+            .random_seed
+            .get()
+            .wrapping_mul(1103515245)
+            .wrapping_add(12345);
+        self.random_seed.set(seed);
+        u64::from((seed >> 16) & 0x7fff)
+    }
+}
+
+impl Env {
+    pub fn new<K: Into<String>, V: Into<String>>(vars: impl IntoIterator<Item = (K, V)>) -> Self {
+        let vars = vars
+            .into_iter()
+            .map(|(name, value)| (name.into(), value.into()))
+            .collect();
+        Env {
+            inherited: Inherited::Vars(Rc::new(vars)),
+            ..Env::default()
+        }
+    }
+
+    /// Unset variables and ones that aren't valid UTF-8 are `None`.
+    pub fn var(&self, name: &str) -> Option<String> {
+        if let Some(value) = self.dynamic.var(name) {
+            return Some(value);
+        }
+        if let Some(variable) = self.assigned.borrow().get(name) {
+            return variable.value.clone();
+        }
+
+        match &self.inherited {
+            Inherited::Process => env::var(name).ok(),
+            Inherited::Vars(vars) => vars.get(name).cloned(),
+        }
+    }
+
+    pub fn var_os(&self, name: &str) -> Option<OsString> {
+        if let Some(value) = self.dynamic.var(name) {
+            return Some(OsString::from(value));
+        }
+        if let Some(variable) = self.assigned.borrow().get(name) {
+            return variable.value.as_ref().map(OsString::from);
+        }
+
+        match &self.inherited {
+            Inherited::Process => env::var_os(name),
+            Inherited::Vars(vars) => vars.get(name).map(OsString::from),
+        }
+    }
+
+    /// Assigns `value` to `name`. A variable that was already exported, like
+    /// one inherited from the environment, stays exported; a new one is only
+    /// seen by the shell itself.
+    pub fn set(&self, name: &str, value: &str) {
+        self.assign(name, value, false);
+    }
+
+    /// Assigns `value` to `name` and exports it.
+    pub fn set_exported(&self, name: &str, value: &str) {
+        self.assign(name, value, true);
+    }
+
+    fn assign(&self, name: &str, value: &str, export: bool) {
+        if self.dynamic.set(name, value) {
+            return;
+        }
+
+        let mut assigned = self.assigned.borrow_mut();
+        let exported = export
+            || match assigned.get(name) {
+                Some(variable) => variable.exported,
+                None => self.is_inherited(name),
+            };
+
+        let value = Some(value.to_string());
+        assigned.insert(name.to_string(), Variable { value, exported });
+    }
+
+    /// Exports `name`, with the value it has, or the one it's assigned
+    /// later if it has none yet.
+    pub fn export(&self, name: &str) {
+        let mut assigned = self.assigned.borrow_mut();
+        if let Some(variable) = assigned.get_mut(name) {
+            variable.exported = true;
+        } else if !self.is_inherited(name) {
+            let variable = Variable {
+                value: None,
+                exported: true,
+            };
+            assigned.insert(name.to_string(), variable);
+        }
+    }
+
+    /// Removes `name`, including from the environment of commands. The
+    /// computed variables stay.
+    pub fn unset(&self, name: &str) {
+        let variable = Variable {
+            value: None,
+            exported: false,
+        };
+        self.assigned
+            .borrow_mut()
+            .insert(name.to_string(), variable);
+    }
+
+    fn is_inherited(&self, name: &str) -> bool {
+        match &self.inherited {
+            Inherited::Process => env::var_os(name).is_some(),
+            Inherited::Vars(vars) => vars.contains_key(name),
+        }
+    }
+
+    /// The variables that are set, exported or not, sorted by name. The
+    /// computed ones and ones that aren't valid UTF-8 are left out.
+    pub fn vars(&self) -> Vec<(String, String)> {
+        let mut vars: BTreeMap<String, String> = match &self.inherited {
+            Inherited::Process => env::vars_os()
+                .filter_map(|(name, value)| {
+                    Some((name.into_string().ok()?, value.into_string().ok()?))
+                })
+                .collect(),
+            Inherited::Vars(vars) => vars
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+        };
+        for (name, variable) in self.assigned.borrow().iter() {
+            match &variable.value {
+                Some(value) => vars.insert(name.clone(), value.clone()),
+                None => vars.remove(name),
+            };
+        }
+
+        vars.into_iter().collect()
+    }
+
+    /// The exported variables that are set, sorted by name.
+    pub(crate) fn exported(&self) -> Vec<(OsString, OsString)> {
+        let mut exported: BTreeMap<OsString, OsString> = match &self.inherited {
+            Inherited::Process => env::vars_os().collect(),
+            Inherited::Vars(vars) => vars
+                .iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect(),
+        };
+        for (name, variable) in self.assigned.borrow().iter() {
+            match &variable.value {
+                Some(value) if variable.exported => {
+                    exported.insert(name.into(), value.into());
+                }
+                _ => {
+                    exported.remove(OsStr::new(name));
+                }
+            }
+        }
+
+        exported.into_iter().collect()
+    }
+
+    /// The line of the command being run, for LINENO.
+    pub(crate) fn line(&self) -> usize {
+        self.dynamic.line.get()
+    }
+
+    pub(crate) fn set_line(&self, line: usize) {
+        self.dynamic.line.set(line);
+    }
+
+    /// Sets the status `$?` expands to.
+    pub(crate) fn set_status(&self, status: i32) {
+        self.dynamic.status.set(status);
+    }
+
+    /// Makes `command` run with the exported variables.
+    pub(crate) fn apply(&self, command: &mut process::Command) {
+        if let Inherited::Vars(vars) = &self.inherited {
+            command.env_clear().envs(vars.iter());
+        }
+
+        for (name, variable) in self.assigned.borrow().iter() {
+            match &variable.value {
+                Some(value) if variable.exported => command.env(name, value),
+                _ => command.env_remove(name),
+            };
+        }
+    }
+}
@@ -0,0 +1,201 @@
+pub struct Getopt<'a> {
+    name: &'a str,
+    usage: &'a str,
+    optstring: &'a str,
+    args: &'a [String],
+    index: usize,
+    char_index: usize,
+}
+
+impl<'a> Getopt<'a> {
+    /// `optstring` lists the accepted option characters, a character followed
+    /// by ':' takes an argument (`"d:anrw"`). `args` must not include the
+    /// builtin name itself.
+    pub fn new(name: &'a str, usage: &'a str, optstring: &'a str, args: &'a [String]) -> Self {
+        Self {
+            name,
+            usage,
+            optstring,
+            args,
+            index: 0,
+            char_index: 0,
+        }
+    }
+
+    pub fn next_opt(&mut self) -> Result<Option<(char, Option<&'a str>)>, UsageError> {
+        if self.char_index == 0 {
+            let Some(arg) = self.args.get(self.index) else {
+                return Ok(None);
+            };
+
+            if arg == "--" {
+                self.index += 1;
+                return Ok(None);
+            }
+
+            if !arg.starts_with('-') || arg == "-" {
+                return Ok(None);
+            }
+
+            self.char_index = 1;
+        }
+
+        let arg = &self.args[self.index];
+        let opt = arg[self.char_index..].chars().next().unwrap();
+        self.char_index += opt.len_utf8();
+
+        let rest = &arg[self.char_index..];
+        if rest.is_empty() {
+            self.index += 1;
+            self.char_index = 0;
+        }
+
+        match self.optstring.find(opt) {
+            Some(i) if opt != ':' && self.optstring[i + opt.len_utf8()..].starts_with(':') => {
+                if !rest.is_empty() {
+                    self.index += 1;
+                    self.char_index = 0;
+                    return Ok(Some((opt, Some(rest))));
+                }
+
+                let Some(argument) = self.args.get(self.index) else {
+                    return Err(UsageError::MissingArgument {
+                        name: self.name.to_string(),
+                        opt,
+                        usage: self.usage.to_string(),
+                    });
+                };
+                self.index += 1;
+
+                Ok(Some((opt, Some(argument))))
+            }
+            Some(_) if opt != ':' => Ok(Some((opt, None))),
+            _ => Err(self.invalid_option(opt)),
+        }
+    }
+
+    /// Arguments left after option parsing stopped.
+    pub fn operands(&self) -> &'a [String] {
+        if self.char_index != 0 {
+            return &self.args[self.index + 1..];
+        }
+
+        &self.args[self.index..]
+    }
+
+    pub fn usage_error(&self) -> UsageError {
+        UsageError::Usage {
+            name: self.name.to_string(),
+            usage: self.usage.to_string(),
+        }
+    }
+
+    pub fn too_many_arguments(&self) -> UsageError {
+        UsageError::TooManyArguments {
+            name: self.name.to_string(),
+        }
+    }
+
+    pub fn numeric_argument_required(&self, arg: &str) -> UsageError {
+        UsageError::NumericArgumentRequired {
+            name: self.name.to_string(),
+            arg: arg.to_string(),
+        }
+    }
+
+    pub fn invalid_option(&self, opt: char) -> UsageError {
+        UsageError::InvalidOption {
+            name: self.name.to_string(),
+            opt,
+            usage: self.usage.to_string(),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum UsageError {
+    #[error("{name}: -{opt}: invalid option\n{name}: usage: {usage}")]
+    InvalidOption {
+        name: String,
+        opt: char,
+        usage: String,
+    },
+    #[error("{name}: -{opt}: option requires an argument\n{name}: usage: {usage}")]
+    MissingArgument {
+        name: String,
+        opt: char,
+        usage: String,
+    },
+    #[error("{name}: usage: {usage}")]
+    Usage { name: String, usage: String },
+    #[error("{name}: too many arguments")]
+    TooManyArguments { name: String },
+    #[error("{name}: {arg}: numeric argument required")]
+    NumericArgumentRequired { name: String, arg: String },
+}
+
+impl UsageError {
+    pub fn status(&self) -> i32 {
+        match self {
+            UsageError::InvalidOption { .. }
+            | UsageError::MissingArgument { .. }
+            | UsageError::Usage { .. } => 2,
+            UsageError::TooManyArguments { .. } | UsageError::NumericArgumentRequired { .. } => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    type Opts = Vec<(char, Option<String>)>;
+
+    fn parse(optstring: &str, args: &[&str]) -> Result<(Opts, Vec<String>), UsageError> {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        let mut getopt = Getopt::new("test", "test [-ab] [-c arg]", optstring, &args);
+
+        let mut opts = Vec::new();
+        while let Some((opt, argument)) = getopt.next_opt()? {
+            opts.push((opt, argument.map(String::from)));
+        }
+
+        Ok((opts, getopt.operands().to_vec()))
+    }
+
+    #[rstest]
+    #[case(vec![], vec![], vec![])]
+    #[case(vec!["x", "-a"], vec![], vec!["x", "-a"])]
+    #[case(vec!["-a", "-b", "x"], vec![('a', None), ('b', None)], vec!["x"])]
+    #[case(vec!["-ab", "x"], vec![('a', None), ('b', None)], vec!["x"])]
+    #[case(vec!["-c", "val", "x"], vec![('c', Some("val"))], vec!["x"])]
+    #[case(vec!["-acval"], vec![('a', None), ('c', Some("val"))], vec![])]
+    #[case(vec!["-a", "--", "-b"], vec![('a', None)], vec!["-b"])]
+    #[case(vec!["-", "-a"], vec![], vec!["-", "-a"])]
+    fn getopt_test(
+        #[case] args: Vec<&str>,
+        #[case] expected_opts: Vec<(char, Option<&str>)>,
+        #[case] expected_operands: Vec<&str>,
+    ) {
+        let (opts, operands) = parse("abc:", &args).unwrap();
+        let expected_opts: Opts = expected_opts
+            .into_iter()
+            .map(|(c, a)| (c, a.map(String::from)))
+            .collect();
+
+        assert_eq!(opts, expected_opts);
+        assert_eq!(operands, expected_operands);
+    }
+
+    #[rstest]
+    #[case(vec!["-z"], "test: -z: invalid option\ntest: usage: test [-ab] [-c arg]")]
+    #[case(vec!["-a", "-c"], "test: -c: option requires an argument\ntest: usage: test [-ab] [-c arg]")]
+    #[case(vec!["-:"], "test: -:: invalid option\ntest: usage: test [-ab] [-c arg]")]
+    fn getopt_error_test(#[case] args: Vec<&str>, #[case] expected: &str) {
+        let err = parse("abc:", &args).unwrap_err();
+        assert_eq!(err.to_string(), expected);
+        assert_eq!(err.status(), 2);
+    }
+}
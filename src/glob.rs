@@ -0,0 +1,249 @@
+use std::fs;
+use std::path::Path;
+
+/// Whether `pattern` has an unescaped `*`, `?` or `[`.
+pub fn has_glob(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+
+    false
+}
+
+/// `text` with the characters that are special in patterns escaped, so it
+/// only matches itself.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for char in text.chars() {
+        if matches!(char, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(char);
+    }
+
+    escaped
+}
+
+/// The paths matching `pattern`, sorted. `*` and `?` don't match a `/`, nor
+/// a leading `.` unless `dotglob` is set; `.` and `..` are only matched
+/// literally. A trailing `/` only matches directories.
+pub fn expand(pattern: &str, dotglob: bool) -> Vec<String> {
+    let (root, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => ("/", rest),
+        None => ("", pattern),
+    };
+
+    let mut paths = vec![root.to_string()];
+    let components: Vec<&str> = rest.split('/').collect();
+    for (index, &component) in components.iter().enumerate() {
+        let last = index == components.len() - 1;
+        if component.is_empty() {
+            // `//` or a trailing `/`
+            paths.retain(|path| path.is_empty() || Path::new(path).is_dir());
+            for path in &mut paths {
+                if !path.ends_with('/') {
+                    path.push('/');
+                }
+            }
+            continue;
+        }
+
+        paths = if has_glob(component) {
+            paths
+                .iter()
+                .flat_map(|path| matching_entries(path, component, dotglob))
+                .collect()
+        } else {
+            let name = unescape(component);
+            paths.iter().map(|path| join(path, &name)).collect()
+        };
+        if !last {
+            paths.retain(|path| Path::new(path).is_dir());
+        }
+    }
+
+    paths.retain(|path| !path.is_empty() && fs::symlink_metadata(path).is_ok());
+    paths.sort();
+    paths
+}
+
+fn matching_entries(dir: &str, pattern: &str, dotglob: bool) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| {
+            let hidden_ok = !name.starts_with('.') || pattern.starts_with('.') || dotglob;
+            hidden_ok && matches(pattern, name)
+        })
+        .map(|name| join(dir, &name))
+        .collect()
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() || dir.ends_with('/') {
+        format!("{dir}{name}")
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+fn unescape(pattern: &str) -> String {
+    let mut unescaped = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => unescaped.extend(chars.next()),
+            char => unescaped.push(char),
+        }
+    }
+
+    unescaped
+}
+
+/// Whether the file name `name` matches `pattern`: `*` matches any run of
+/// characters, `?` any one, `[abc]`, `[a-z]` and `[!a]` or `[^a]` one of a
+/// set, and `\` makes the next character match itself.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    if matches!(name, "." | "..") && pattern != name {
+        return false;
+    }
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches_from(&pattern, &name)
+}
+
+fn matches_from(pattern: &[char], name: &[char]) -> bool {
+    let Some((&first, rest)) = pattern.split_first() else {
+        return name.is_empty();
+    };
+
+    match first {
+        '*' => (0..=name.len()).any(|skip| matches_from(rest, &name[skip..])),
+        '?' => !name.is_empty() && matches_from(rest, &name[1..]),
+        '[' => match bracket(rest) {
+            Some((set, negated, after)) => name.first().is_some_and(|&char| {
+                set_contains(set, char) != negated && matches_from(after, &name[1..])
+            }),
+            // an unclosed `[` is an ordinary character
+            None => name.first() == Some(&'[') && matches_from(rest, &name[1..]),
+        },
+        '\\' if !rest.is_empty() => {
+            name.first() == Some(&rest[0]) && matches_from(&rest[1..], &name[1..])
+        }
+        char => name.first() == Some(&char) && matches_from(rest, &name[1..]),
+    }
+}
+
+/// The set of a bracket expression after its `[`, whether it is negated,
+/// and the pattern after its `]`.
+fn bracket(pattern: &[char]) -> Option<(&[char], bool, &[char])> {
+    let (negated, pattern) = match pattern.first() {
+        Some('!' | '^') => (true, &pattern[1..]),
+        _ => (false, pattern),
+    };
+
+    // a `]` right at the start is part of the set
+    let close = pattern
+        .iter()
+        .skip(1)
+        .position(|&char| char == ']')
+        .map(|index| index + 1)?;
+    Some((&pattern[..close], negated, &pattern[close + 1..]))
+}
+
+fn set_contains(set: &[char], char: char) -> bool {
+    let mut index = 0;
+    while index < set.len() {
+        if index + 2 < set.len() && set[index + 1] == '-' {
+            if (set[index]..=set[index + 2]).contains(&char) {
+                return true;
+            }
+            index += 3;
+        } else {
+            if set[index] == char {
+                return true;
+            }
+            index += 1;
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("*.rs", "main.rs", true)]
+    #[case("*.rs", "main.rsx", false)]
+    #[case("?.txt", "a.txt", true)]
+    #[case("?.txt", "ab.txt", false)]
+    #[case("[ab]*", "beta", true)]
+    #[case("[!ab]*", "beta", false)]
+    #[case("[a-c]x", "bx", true)]
+    #[case("[]]", "]", true)]
+    #[case("a[", "a[", true)]
+    #[case("\\*", "*", true)]
+    #[case("\\*", "a", false)]
+    #[case("*", "..", false)]
+    #[case("..", "..", true)]
+    fn matches_test(#[case] pattern: &str, #[case] name: &str, #[case] expected: bool) {
+        assert_eq!(matches(pattern, name), expected);
+    }
+
+    #[rstest]
+    #[case("*.txt", true)]
+    #[case("a\\*", false)]
+    #[case("plain", false)]
+    fn has_glob_test(#[case] pattern: &str, #[case] expected: bool) {
+        assert_eq!(has_glob(pattern), expected);
+    }
+
+    #[test]
+    fn expand_test() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        for file in ["b.txt", "a.txt", ".hidden.txt", "sub/c.txt", "x*y"] {
+            fs::write(dir.path().join(file), "").unwrap();
+        }
+        let root = dir.path().display().to_string();
+        let expand = |pattern: &str, dotglob| -> Vec<String> {
+            expand(&format!("{root}/{pattern}"), dotglob)
+                .into_iter()
+                .map(|path| path[root.len() + 1..].to_string())
+                .collect()
+        };
+
+        let results = (
+            expand("*.txt", false),
+            expand("*.txt", true),
+            expand(".*", false),
+            expand("*/", false),
+            expand("*/*.txt", false),
+            expand("x\\*y", false),
+            expand("*.nope", false),
+        );
+
+        assert_eq!(results.0, vec!["a.txt", "b.txt"]);
+        assert_eq!(results.1, vec![".hidden.txt", "a.txt", "b.txt"]);
+        assert_eq!(results.2, vec![".hidden.txt"]);
+        assert_eq!(results.3, vec!["sub/"]);
+        assert_eq!(results.4, vec!["sub/c.txt"]);
+        assert_eq!(results.5, vec!["x*y"]);
+        assert!(results.6.is_empty());
+    }
+}
@@ -0,0 +1,346 @@
+use crate::completion::Kind;
+use crate::editor::Helper;
+use crate::lexer::{Lexer, Token, TokenKind};
+use crate::parser;
+use crate::BUILTIN_COMMANDS;
+use rustyline::highlight::{CmdKind, Highlighter};
+use rustyline::CompletionType;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::mem;
+
+const RESET: &str = "\x1b[0m";
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+const BOLD_RED: &str = "\x1b[1;31m";
+const BOLD_BLUE: &str = "\x1b[1;34m";
+const REVERSE: &str = "\x1b[7m";
+
+impl Highlighter for Helper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        if line.is_empty() {
+            return Cow::Borrowed(line);
+        }
+        if let Some(highlighted) = self.highlight_search_match(line) {
+            return Cow::Owned(highlighted);
+        }
+
+        let mut bin_path = self.bin_path.borrow_mut();
+        let is_command = |name: &str| BUILTIN_COMMANDS.contains(&name) || bin_path.resolves(name);
+
+        // continuation lines are lexed together with the ones before them
+        let pending = self.pending.borrow();
+        let input = format!("{pending}{line}");
+        let cursor = pending.len() + pos;
+        Cow::Owned(highlight_line(
+            &input,
+            pending.len(),
+            Some(cursor),
+            is_command,
+        ))
+    }
+
+    /// The editor lays out the plain prompt; swap in the styled one for display.
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
+        &'s self,
+        prompt: &'p str,
+        default: bool,
+    ) -> Cow<'b, str> {
+        if default {
+            Cow::Owned(self.styled_prompt.borrow().clone())
+        } else {
+            Cow::Borrowed(prompt)
+        }
+    }
+
+    fn highlight_candidate<'c>(
+        &self,
+        candidate: &'c str,
+        _completion: CompletionType,
+    ) -> Cow<'c, str> {
+        let style = match self.candidate_kinds.borrow().get(candidate) {
+            Some(Kind::Builtin) => CYAN,
+            Some(Kind::Executable) => GREEN,
+            Some(Kind::Directory) => BOLD_BLUE,
+            Some(Kind::File) | None => return Cow::Borrowed(candidate),
+        };
+
+        Cow::Owned(format!("{style}{candidate}{RESET}"))
+    }
+
+    /// Moving the cursor only changes the highlighting when it can move onto
+    /// or off a bracket or quote.
+    fn highlight_char(&self, line: &str, _pos: usize, kind: CmdKind) -> bool {
+        kind != CmdKind::MoveCursor || line.contains(is_paired)
+    }
+}
+
+impl Helper {
+    /// The entry found by the substring search with what was searched for
+    /// in reverse video, while it is the line shown.
+    fn highlight_search_match(&self, line: &str) -> Option<String> {
+        let search = self.substring_search.lock().unwrap();
+        if search.found.as_deref() != Some(line) {
+            return None;
+        }
+
+        let start = line.find(&search.query)?;
+        let end = start + search.query.len();
+        Some(format!(
+            "{}{REVERSE}{}{RESET}{}",
+            &line[..start],
+            &line[start..end],
+            &line[end..]
+        ))
+    }
+}
+
+/// Colors the command words green when `is_command` knows them and red
+/// otherwise, quoted strings yellow, pipes and redirections cyan, and
+/// unbalanced brackets and a quote left open at the end of the line bold
+/// red. The partner of the bracket or quote at or just before the `cursor`
+/// is bold blue. The first `skip` bytes of `line` are only lexed for context
+/// and left out of the result.
+fn highlight_line(
+    line: &str,
+    skip: usize,
+    cursor: Option<usize>,
+    mut is_command: impl FnMut(&str) -> bool,
+) -> String {
+    let tokens = Lexer::new(line).lex();
+    let mut styles: Vec<Option<&str>> = Vec::with_capacity(tokens.len());
+
+    let mut open_quote: Option<(usize, &TokenKind)> = None;
+    let mut command_position = true;
+    let mut in_word = false;
+    // assignments before a command leave the next word in command position
+    let mut in_assignment = false;
+
+    for (index, token) in tokens.iter().enumerate() {
+        let style = match (&token.kind, open_quote) {
+            (TokenKind::SingleQuote | TokenKind::DoubleQuote, Some((_, quote))) => {
+                if *quote == token.kind {
+                    open_quote = None;
+                }
+                Some(YELLOW)
+            }
+            (_, Some(_)) => Some(YELLOW),
+            (TokenKind::SingleQuote | TokenKind::DoubleQuote, None) => {
+                open_quote = Some((index, &token.kind));
+                in_word = true;
+                Some(YELLOW)
+            }
+            (TokenKind::Whitespace | TokenKind::EOF, None) => {
+                if in_word {
+                    command_position = mem::take(&mut in_assignment);
+                    in_word = false;
+                }
+                None
+            }
+            (TokenKind::String, None) if token.lexeme == "|" => {
+                command_position = true;
+                in_word = false;
+                Some(CYAN)
+            }
+            (TokenKind::String, None) if is_redirect(token.lexeme) => Some(CYAN),
+            (TokenKind::String, None)
+                if command_position && !in_word && parser::is_assignment(token.lexeme) =>
+            {
+                in_word = true;
+                in_assignment = true;
+                None
+            }
+            (TokenKind::String, None)
+                if command_position && !in_word && ends_word(&tokens, index) =>
+            {
+                in_word = true;
+                Some(if is_command(token.lexeme) { GREEN } else { RED })
+            }
+            (_, None) => {
+                in_word = true;
+                None
+            }
+        };
+        styles.push(style);
+    }
+
+    if let Some((index, _)) = open_quote {
+        styles[index] = Some(BOLD_RED);
+    }
+
+    // styles of single characters, by byte offset
+    let pairs = pairs(&tokens);
+    let mut marks: HashMap<usize, &str> = pairs
+        .iter()
+        .filter(|(_, partner)| partner.is_none())
+        .map(|&(offset, _)| (offset, BOLD_RED))
+        .collect();
+    let at_cursor = cursor.and_then(|cursor| {
+        let paired = |offset: usize| pairs.iter().find(|(at, _)| *at == offset);
+        paired(cursor).or_else(|| paired(cursor.checked_sub(1)?))
+    });
+    if let Some(&(_, Some(partner))) = at_cursor {
+        marks.insert(partner, BOLD_BLUE);
+    }
+
+    let mut highlighted = String::with_capacity(line.len());
+    let mut current = None;
+    let mut offset = 0;
+    for (Token { lexeme, .. }, style) in tokens.iter().zip(styles) {
+        for (index, char) in lexeme.char_indices() {
+            let at = offset + index;
+            if at < skip {
+                continue;
+            }
+
+            let style = marks.get(&at).copied().or(style);
+            if style != current {
+                if current.is_some() {
+                    highlighted.push_str(RESET);
+                }
+                if let Some(style) = style {
+                    highlighted.push_str(style);
+                }
+                current = style;
+            }
+            highlighted.push(char);
+        }
+        offset += lexeme.len();
+    }
+    if current.is_some() {
+        highlighted.push_str(RESET);
+    }
+
+    highlighted
+}
+
+fn is_paired(char: char) -> bool {
+    "()[]{}'\"".contains(char)
+}
+
+/// The byte offsets of the brackets and quotes of a lexed line, each with
+/// the offset of its partner, or `None` when it is unbalanced. Brackets
+/// inside quotes don't count.
+fn pairs(tokens: &[Token]) -> Vec<(usize, Option<usize>)> {
+    let mut pairs = Vec::new();
+    let mut open_brackets: Vec<(usize, char)> = Vec::new();
+    let mut open_quote: Option<(usize, &TokenKind)> = None;
+
+    let mut offset = 0;
+    for token in tokens {
+        match (&token.kind, open_quote) {
+            (TokenKind::SingleQuote | TokenKind::DoubleQuote, Some((open, quote)))
+                if *quote == token.kind =>
+            {
+                pairs.push((open, Some(offset)));
+                pairs.push((offset, Some(open)));
+                open_quote = None;
+            }
+            (_, Some(_)) => {}
+            (TokenKind::SingleQuote | TokenKind::DoubleQuote, None) => {
+                open_quote = Some((offset, &token.kind));
+            }
+            (TokenKind::String, None) => {
+                for (index, char) in token.lexeme.char_indices() {
+                    let at = offset + index;
+                    match char {
+                        '(' | '[' | '{' => open_brackets.push((at, char)),
+                        ')' | ']' | '}' => match open_brackets.last() {
+                            Some(&(open, opening)) if closing(opening) == char => {
+                                open_brackets.pop();
+                                pairs.push((open, Some(at)));
+                                pairs.push((at, Some(open)));
+                            }
+                            _ => pairs.push((at, None)),
+                        },
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+        offset += token.lexeme.len();
+    }
+
+    if let Some((open, _)) = open_quote {
+        pairs.push((open, None));
+    }
+    pairs.extend(open_brackets.into_iter().map(|(open, _)| (open, None)));
+
+    pairs
+}
+
+fn closing(bracket: char) -> char {
+    match bracket {
+        '(' => ')',
+        '[' => ']',
+        _ => '}',
+    }
+}
+
+fn is_redirect(lexeme: &str) -> bool {
+    let lexeme = lexeme.trim_start_matches(['1', '2']);
+    lexeme.starts_with('>')
+}
+
+/// Whether the word at `index` is a single token, so it can be looked up as a
+/// command name as is.
+fn ends_word(tokens: &[Token], index: usize) -> bool {
+    matches!(
+        tokens.get(index + 1).map(|token| &token.kind),
+        Some(TokenKind::Whitespace | TokenKind::EOF)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("echo hi", "\x1b[32mecho\x1b[0m hi")]
+    #[case("nope hi", "\x1b[31mnope\x1b[0m hi")]
+    #[case(
+        "echo 'a b' | cat",
+        "\x1b[32mecho\x1b[0m \x1b[33m'a b'\x1b[0m \x1b[36m|\x1b[0m \x1b[32mcat\x1b[0m"
+    )]
+    #[case("echo x >out", "\x1b[32mecho\x1b[0m x \x1b[36m>out\x1b[0m")]
+    #[case("echo \"a", "\x1b[32mecho\x1b[0m \x1b[1;31m\"\x1b[0m\x1b[33ma\x1b[0m")]
+    #[case("ec\"ho\" x", "ec\x1b[33m\"ho\"\x1b[0m x")]
+    #[case("a=1 b='x' echo", "a=1 b=\x1b[33m'x'\x1b[0m \x1b[32mecho\x1b[0m")]
+    fn highlight_line_test(#[case] line: &str, #[case] expected: &str) {
+        let is_command = |name: &str| ["echo", "cat"].contains(&name);
+        assert_eq!(highlight_line(line, 0, None, is_command), expected);
+    }
+
+    #[rstest]
+    #[case("x (a) y", Some(3), "\x1b[31mx\x1b[0m (a\x1b[1;34m)\x1b[0m y")]
+    #[case("x (a) y", Some(5), "\x1b[31mx\x1b[0m \x1b[1;34m(\x1b[0ma) y")]
+    #[case("x {[a]} y", Some(4), "\x1b[31mx\x1b[0m {[a\x1b[1;34m]\x1b[0m} y")]
+    #[case("x (a y", None, "\x1b[31mx\x1b[0m \x1b[1;31m(\x1b[0ma y")]
+    #[case("x a] y", None, "\x1b[31mx\x1b[0m a\x1b[1;31m]\x1b[0m y")]
+    #[case(
+        "x '(' y",
+        Some(2),
+        "\x1b[31mx\x1b[0m \x1b[33m'(\x1b[0m\x1b[1;34m'\x1b[0m y"
+    )]
+    fn highlight_brackets_test(
+        #[case] line: &str,
+        #[case] cursor: Option<usize>,
+        #[case] expected: &str,
+    ) {
+        assert_eq!(highlight_line(line, 0, cursor, |_| false), expected);
+    }
+
+    #[test]
+    fn highlight_continuation_line() {
+        let is_command = |name: &str| name == "cat";
+        assert_eq!(
+            highlight_line("echo \"a\nb\" | cat", 8, None, is_command),
+            "\x1b[33mb\"\x1b[0m \x1b[36m|\x1b[0m \x1b[32mcat\x1b[0m"
+        );
+    }
+}
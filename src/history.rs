@@ -0,0 +1,974 @@
+use crate::json;
+use crate::lexer::{Lexer, TokenKind};
+use rustyline::history::{History, SearchDirection, SearchResult};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_MAX_LEN: usize = 1000;
+
+/// A command in the history, with what is known about running it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Entry {
+    pub line: String,
+    /// When it was entered, in seconds since the epoch.
+    pub timestamp: Option<u64>,
+    /// How long it ran.
+    pub duration: Option<Duration>,
+    pub status: Option<i32>,
+}
+
+impl Entry {
+    pub fn new(line: impl Into<String>) -> Self {
+        Self {
+            line: line.into(),
+            ..Self::default()
+        }
+    }
+
+    /// An entry for a command entered just now.
+    fn entered(line: String) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()
+            .map(|since| since.as_secs());
+        Self {
+            timestamp,
+            ..Self::new(line)
+        }
+    }
+}
+
+/// In-memory command history persisted as one entry per line, like bash's
+/// history file. The timestamps, durations and statuses of entries are only
+/// kept in memory.
+pub struct ShellHistory {
+    entries: VecDeque<Entry>,
+    max_len: usize,
+    ignore_space: bool,
+    ignore_dups: bool,
+    /// Entries added since the last `save`/`append`, always the newest ones.
+    new_entries: usize,
+    /// How far `load_new` has read each file.
+    read_offsets: HashMap<PathBuf, u64>,
+    substring_search: Arc<Mutex<SubstringSearch>>,
+}
+
+/// A search through history for the entries containing what was typed,
+/// anywhere in them. Rustyline only searches for prefixes on a key, so
+/// [`History::starts_with`] does this search instead while it is armed.
+#[derive(Debug, Default)]
+pub struct SubstringSearch {
+    /// What is searched for: the line as it was when the search started.
+    pub query: String,
+    /// Whether the next prefix search is this search instead.
+    pub armed: bool,
+    /// The entry last found, while it is shown.
+    pub found: Option<String>,
+    /// Whether the keys are moving through all of history, as nothing was
+    /// typed to search for.
+    pub browsing: bool,
+}
+
+impl Default for ShellHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShellHistory {
+    pub fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            max_len: DEFAULT_MAX_LEN,
+            ignore_space: false,
+            ignore_dups: false,
+            new_entries: 0,
+            read_offsets: HashMap::new(),
+            substring_search: Arc::default(),
+        }
+    }
+
+    /// The state of the substring search, shared with the keys starting it.
+    pub fn substring_search(&self) -> Arc<Mutex<SubstringSearch>> {
+        Arc::clone(&self.substring_search)
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &String> + '_ {
+        self.entries.iter().map(|entry| &entry.line)
+    }
+
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &Entry> + '_ {
+        self.entries.iter()
+    }
+
+    pub fn last(&self) -> Option<&String> {
+        self.entries.back().map(|entry| &entry.line)
+    }
+
+    /// Records how running `line` went, if it is the newest entry.
+    pub fn set_result(&mut self, line: &str, status: i32, duration: Option<Duration>) {
+        if let Some(entry) = self.entries.back_mut()
+            && entry.line == line
+        {
+            entry.status = Some(status);
+            entry.duration = duration;
+        }
+    }
+
+    /// Removes every entry equal to `line`.
+    pub fn erase(&mut self, line: &str) {
+        let first_new = self.entries.len() - self.new_entries;
+        let mut index = 0;
+        self.entries.retain(|entry| {
+            let keep = entry.line != line;
+            if !keep && index >= first_new {
+                self.new_entries -= 1;
+            }
+            index += 1;
+            keep
+        });
+    }
+
+    /// Reads the lines in the `range` of bytes of the file at `path`, e.g.
+    /// the commands other shells appended since it was last read, and
+    /// returns the offset reached. They go before the entries not yet
+    /// written out, so those still get appended.
+    pub fn load_range(&mut self, path: &Path, range: Range<u64>) -> io::Result<u64> {
+        let mut file = fs::File::open(path)?;
+        file.seek(SeekFrom::Start(range.start))?;
+
+        let mut reader = BufReader::new(file.take(range.end - range.start));
+        let mut offset = range.start;
+        let mut lines = String::new();
+        loop {
+            let read = reader.read_line(&mut lines)?;
+            // a command still being written by another shell is read next
+            // time
+            if read == 0 || !lines.ends_with('\n') {
+                break;
+            }
+            if lines.ends_with("\\\n") {
+                continue;
+            }
+            offset += lines.len() as u64;
+
+            if let Some(entry) = parse_plain(&lines).pop() {
+                self.insert_before_new(entry);
+            }
+            lines.clear();
+        }
+
+        Ok(offset)
+    }
+
+    /// Reads the lines appended to the file at `path` since this was last
+    /// called for it, all of them the first time.
+    pub fn load_new(&mut self, path: &Path) -> io::Result<()> {
+        let start = self.read_offsets.get(path).copied().unwrap_or(0);
+        let offset = self.load_range(path, start..u64::MAX)?;
+        self.read_offsets.insert(path.to_path_buf(), offset);
+        Ok(())
+    }
+
+    fn insert_before_new(&mut self, entry: Entry) {
+        if self.max_len == 0 || self.new_entries == self.max_len {
+            return;
+        }
+
+        if self.entries.len() == self.max_len {
+            self.entries.pop_front();
+        }
+        let index = self.entries.len() - self.new_entries;
+        self.entries.insert(index, entry);
+    }
+
+    fn insert(&mut self, entry: Entry) -> bool {
+        if self.max_len == 0
+            || (self.ignore_space && entry.line.starts_with(char::is_whitespace))
+            || (self.ignore_dups && self.last() == Some(&entry.line))
+        {
+            return false;
+        }
+
+        if self.entries.len() == self.max_len {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+        self.new_entries = (self.new_entries + 1).min(self.entries.len());
+
+        true
+    }
+
+    fn write_entries(&self, file: fs::File, skip: usize) -> rustyline::Result<()> {
+        let mut writer = BufWriter::new(file);
+        // the lines of a multi-line command end in `\` but the last
+        for entry in self.entries.iter().skip(skip) {
+            writeln!(writer, "{}", entry.line.replace('\n', "\\\n"))?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// The nearest entry from `start` containing the query of `search`,
+    /// skipping the one shown, with the cursor at its end. Past the newest
+    /// one it is the query itself, as the line being edited below them.
+    fn search_substring(
+        &self,
+        search: &mut SubstringSearch,
+        start: usize,
+        dir: SearchDirection,
+    ) -> Option<SearchResult<'_>> {
+        let query = search.query.as_str();
+        let shown = search.found.as_deref();
+        let result = self.search_match(start, dir, |entry| {
+            (entry.contains(query) && Some(entry) != shown).then_some(entry.len())
+        });
+
+        search.found = match &result {
+            Some(result) => Some(result.entry.to_string()),
+            None if dir == SearchDirection::Forward => None,
+            None => return None,
+        };
+        Some(result.unwrap_or_else(|| SearchResult {
+            entry: Cow::Owned(search.query.clone()),
+            idx: self.entries.len(),
+            pos: search.query.len(),
+        }))
+    }
+
+    fn search_match(
+        &self,
+        start: usize,
+        dir: SearchDirection,
+        matches: impl Fn(&str) -> Option<usize>,
+    ) -> Option<SearchResult<'_>> {
+        let found = |idx: usize| {
+            let entry = &self.entries[idx].line;
+            matches(entry).map(|pos| SearchResult {
+                entry: Cow::Borrowed(entry.as_str()),
+                idx,
+                pos,
+            })
+        };
+
+        match dir {
+            SearchDirection::Reverse => (0..=start.min(self.entries.len().checked_sub(1)?))
+                .rev()
+                .find_map(found),
+            SearchDirection::Forward => (start..self.entries.len()).find_map(found),
+        }
+    }
+}
+
+impl History for ShellHistory {
+    fn get(
+        &self,
+        index: usize,
+        _dir: SearchDirection,
+    ) -> rustyline::Result<Option<SearchResult<'_>>> {
+        Ok(self.entries.get(index).map(|entry| SearchResult {
+            entry: Cow::Borrowed(entry.line.as_str()),
+            idx: index,
+            pos: 0,
+        }))
+    }
+
+    fn add(&mut self, line: &str) -> rustyline::Result<bool> {
+        Ok(self.insert(Entry::entered(line.to_string())))
+    }
+
+    fn add_owned(&mut self, line: String) -> rustyline::Result<bool> {
+        Ok(self.insert(Entry::entered(line)))
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn set_max_len(&mut self, len: usize) -> rustyline::Result<()> {
+        self.max_len = len;
+        while self.entries.len() > len {
+            self.entries.pop_front();
+        }
+        self.new_entries = self.new_entries.min(len);
+
+        Ok(())
+    }
+
+    fn ignore_dups(&mut self, yes: bool) -> rustyline::Result<()> {
+        self.ignore_dups = yes;
+        Ok(())
+    }
+
+    fn ignore_space(&mut self, yes: bool) {
+        self.ignore_space = yes;
+    }
+
+    fn save(&mut self, path: &Path) -> rustyline::Result<()> {
+        self.write_entries(fs::File::create(path)?, 0)?;
+        self.new_entries = 0;
+
+        Ok(())
+    }
+
+    fn append(&mut self, path: &Path) -> rustyline::Result<()> {
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?;
+        self.write_entries(file, self.entries.len() - self.new_entries)?;
+        self.new_entries = 0;
+
+        Ok(())
+    }
+
+    /// Reads the file in any [`HistoryFormat`], so bash and zsh histories
+    /// can be imported.
+    fn load(&mut self, path: &Path) -> rustyline::Result<()> {
+        for entry in read_entries(&fs::read(path)?) {
+            self.insert(entry);
+        }
+        self.new_entries = 0;
+
+        Ok(())
+    }
+
+    fn clear(&mut self) -> rustyline::Result<()> {
+        self.entries.clear();
+        self.new_entries = 0;
+        Ok(())
+    }
+
+    fn search(
+        &self,
+        term: &str,
+        start: usize,
+        dir: SearchDirection,
+    ) -> rustyline::Result<Option<SearchResult<'_>>> {
+        if term.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(self.search_match(start, dir, |entry| entry.find(term)))
+    }
+
+    fn starts_with(
+        &self,
+        term: &str,
+        start: usize,
+        dir: SearchDirection,
+    ) -> rustyline::Result<Option<SearchResult<'_>>> {
+        let mut search = self.substring_search.lock().unwrap();
+        if mem::take(&mut search.armed) {
+            return Ok(self.search_substring(&mut search, start, dir));
+        }
+        if term.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(self.search_match(start, dir, |entry| {
+            entry.starts_with(term).then_some(term.len())
+        }))
+    }
+}
+
+/// What `history --export` writes the history as.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(ExportFormat::Json),
+            "csv" => Some(ExportFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+impl ShellHistory {
+    /// Writes every entry with its number in `history`, its timestamp,
+    /// duration in seconds and exit status, for analysis tools. Unknown
+    /// values are `null` in JSON and empty in CSV.
+    pub fn export(&self, format: ExportFormat, output: &mut impl Write) -> io::Result<()> {
+        match format {
+            ExportFormat::Json => self.export_json(output),
+            ExportFormat::Csv => self.export_csv(output),
+        }
+    }
+
+    fn export_json(&self, output: &mut impl Write) -> io::Result<()> {
+        fn or_null(value: Option<impl ToString>) -> String {
+            value.map_or_else(|| String::from("null"), |value| value.to_string())
+        }
+
+        write!(output, "[")?;
+        for (index, entry) in self.entries.iter().enumerate() {
+            let separator = if index == 0 { "" } else { "," };
+            write!(
+                output,
+                "{separator}\n  {{\"index\":{index},\"command\":{},\"timestamp\":{},\"duration\":{},\"status\":{}}}",
+                json::string(&entry.line),
+                or_null(entry.timestamp),
+                or_null(entry.duration.map(|duration| duration.as_secs_f64())),
+                or_null(entry.status),
+            )?;
+        }
+        let end = if self.entries.is_empty() { "" } else { "\n" };
+        writeln!(output, "{end}]")
+    }
+
+    fn export_csv(&self, output: &mut impl Write) -> io::Result<()> {
+        fn or_empty(value: Option<impl ToString>) -> String {
+            value.map_or_else(String::new, |value| value.to_string())
+        }
+
+        writeln!(output, "index,command,timestamp,duration,status")?;
+        for (index, entry) in self.entries.iter().enumerate() {
+            writeln!(
+                output,
+                "{index},{},{},{},{}",
+                csv_field(&entry.line),
+                or_empty(entry.timestamp),
+                or_empty(entry.duration.map(|duration| duration.as_secs_f64())),
+                or_empty(entry.status),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// `value` quoted when it has a comma, quote or line break in it.
+/// `command` on one line, as bash's `cmdhist` stores it unless `lithist`
+/// is set: a line continued with `\` goes on right after the one before,
+/// one after a `|`, `&&` or `;` after a space, and others after a `; `.
+/// Newlines inside quotes stay, and so do those of a command with a
+/// here-document, whose body needs its lines.
+pub fn single_line(command: &str) -> Cow<'_, str> {
+    if !command.contains('\n') {
+        return Cow::Borrowed(command);
+    }
+
+    let tokens = Lexer::new(command).lex();
+    let mut joined = String::with_capacity(command.len());
+    let mut separator = "";
+    let mut quote: Option<&TokenKind> = None;
+    for token in &tokens {
+        match (&token.kind, quote) {
+            (TokenKind::SingleQuote | TokenKind::DoubleQuote, Some(open))
+                if *open == token.kind =>
+            {
+                quote = None
+            }
+            (_, Some(_)) => {}
+            (TokenKind::SingleQuote | TokenKind::DoubleQuote, None) => quote = Some(&token.kind),
+            (TokenKind::String, None)
+                if token.lexeme.starts_with("<<") && !token.lexeme.starts_with("<<<") =>
+            {
+                return Cow::Borrowed(command);
+            }
+            (TokenKind::EscapeSequence, None) if token.lexeme == "\\\n" => continue,
+            (TokenKind::Whitespace, None) if token.lexeme.contains('\n') => {
+                if !joined.is_empty() {
+                    separator = if joined.ends_with(['|', '&', ';']) {
+                        " "
+                    } else {
+                        "; "
+                    };
+                }
+                continue;
+            }
+            (TokenKind::EOF, _) => break,
+            _ => {}
+        }
+        joined.push_str(mem::take(&mut separator));
+        joined.push_str(token.lexeme);
+    }
+
+    Cow::Owned(joined)
+}
+
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// How a history file stores its entries, told apart by its first line.
+#[derive(Debug, PartialEq)]
+pub enum HistoryFormat {
+    /// One command per line, as this shell and bash write it.
+    Plain,
+    /// bash with $HISTTIMEFORMAT set: a `#<seconds>` line before every
+    /// command.
+    BashTimestamps,
+    /// zsh with EXTENDED_HISTORY: `: <start>:<duration>;<command>`, the
+    /// lines of a multi-line command ending in `\`.
+    ZshExtended,
+}
+
+impl HistoryFormat {
+    pub fn detect(contents: &str) -> Self {
+        let first = contents.lines().find(|line| !line.is_empty());
+        match first {
+            Some(line) if zsh_metadata(line).is_some() => HistoryFormat::ZshExtended,
+            Some(line) if bash_timestamp(line).is_some() => HistoryFormat::BashTimestamps,
+            _ => HistoryFormat::Plain,
+        }
+    }
+}
+
+/// The entries of a history file in whichever [`HistoryFormat`] it is.
+pub fn read_entries(contents: &[u8]) -> Vec<Entry> {
+    let format = HistoryFormat::detect(&String::from_utf8_lossy(contents));
+    if format == HistoryFormat::ZshExtended {
+        return parse_zsh(&String::from_utf8_lossy(&unmetafy(contents)));
+    }
+
+    let contents = String::from_utf8_lossy(contents);
+    match format {
+        HistoryFormat::BashTimestamps => parse_bash_timestamps(&contents),
+        _ => parse_plain(&contents),
+    }
+}
+
+/// One command per line, except that a line ending in `\` goes on on the
+/// next one, as a multi-line command is written.
+fn parse_plain(contents: &str) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut continued = false;
+    for line in contents.lines() {
+        let (text, continues) = match line.strip_suffix('\\') {
+            Some(text) => (text, true),
+            None => (line, false),
+        };
+
+        match entries.last_mut() {
+            Some(entry) if continued => {
+                entry.line.push('\n');
+                entry.line.push_str(text);
+            }
+            _ if !text.is_empty() => entries.push(Entry::new(text)),
+            _ => {}
+        }
+        continued = continues;
+    }
+
+    entries
+}
+
+/// The lines after a timestamp up to the next one are a single command.
+fn parse_bash_timestamps(contents: &str) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut timestamp = None;
+    for line in contents.lines() {
+        if let Some(seconds) = bash_timestamp(line) {
+            timestamp = Some(seconds);
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+
+        match entries.last_mut() {
+            Some(entry) if timestamp.is_none() && entry.timestamp.is_some() => {
+                entry.line.push('\n');
+                entry.line.push_str(line);
+            }
+            _ => entries.push(Entry {
+                timestamp: timestamp.take(),
+                ..Entry::new(line)
+            }),
+        }
+    }
+
+    entries
+}
+
+fn parse_zsh(contents: &str) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut continued = false;
+    for line in contents.lines() {
+        let (text, continues) = match line.strip_suffix('\\') {
+            Some(text) => (text, true),
+            None => (line, false),
+        };
+
+        match entries.last_mut() {
+            Some(entry) if continued => {
+                entry.line.push('\n');
+                entry.line.push_str(text);
+            }
+            _ => match zsh_metadata(text) {
+                Some((timestamp, duration, command)) => entries.push(Entry {
+                    timestamp: Some(timestamp),
+                    duration: Some(Duration::from_secs(duration)),
+                    ..Entry::new(command)
+                }),
+                None if !text.is_empty() => entries.push(Entry::new(text)),
+                None => {}
+            },
+        }
+        continued = continues;
+    }
+
+    entries
+}
+
+/// The seconds of a bash timestamp line like `#1700000000`.
+fn bash_timestamp(line: &str) -> Option<u64> {
+    let digits = line.strip_prefix('#')?;
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    digits.parse().ok()
+}
+
+/// The start, duration and command of a zsh line like
+/// `: 1700000000:5;make`.
+fn zsh_metadata(line: &str) -> Option<(u64, u64, &str)> {
+    let (metadata, command) = line.strip_prefix(": ")?.split_once(';')?;
+    let (start, duration) = metadata.split_once(':')?;
+
+    Some((start.parse().ok()?, duration.parse().ok()?, command))
+}
+
+/// Undoes zsh's escaping of bytes in its history file: a 0x83 byte means
+/// the next one was XORed with 0x20.
+fn unmetafy(bytes: &[u8]) -> Vec<u8> {
+    const META: u8 = 0x83;
+
+    let mut unmetafied = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter();
+    while let Some(&byte) = iter.next() {
+        match byte {
+            META => unmetafied.extend(iter.next().map(|byte| byte ^ 0x20)),
+            byte => unmetafied.push(byte),
+        }
+    }
+
+    unmetafied
+}
+
+/// Parsed $HISTCONTROL, a colon-separated list of `ignorespace`,
+/// `ignoredups`, `ignoreboth` and `erasedups`.
+#[derive(Default, Debug, PartialEq)]
+pub struct HistControl {
+    pub ignore_space: bool,
+    pub ignore_dups: bool,
+    pub erase_dups: bool,
+}
+
+impl HistControl {
+    pub fn parse(value: &str) -> Self {
+        let mut control = Self::default();
+        for option in value.split(':') {
+            match option {
+                "ignorespace" => control.ignore_space = true,
+                "ignoredups" => control.ignore_dups = true,
+                "ignoreboth" => {
+                    control.ignore_space = true;
+                    control.ignore_dups = true;
+                }
+                "erasedups" => control.erase_dups = true,
+                _ => {}
+            }
+        }
+
+        control
+    }
+
+    /// Adds `line` to `history` unless HISTCONTROL says it should be skipped.
+    pub fn add(&self, history: &mut ShellHistory, line: &str) -> bool {
+        if line.trim().is_empty()
+            || (self.ignore_space && line.starts_with(' '))
+            || (self.ignore_dups && history.last().is_some_and(|last| last == line))
+        {
+            return false;
+        }
+
+        if self.erase_dups {
+            history.erase(line);
+        }
+
+        history.insert(Entry::entered(line.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("", vec!["ls", "ls", " pwd", "cd", "ls"], vec!["ls", "ls", " pwd", "cd", "ls"])]
+    #[case("ignorespace", vec!["ls", " pwd", "cd"], vec!["ls", "cd"])]
+    #[case("ignoredups", vec!["ls", "ls", "cd", "ls"], vec!["ls", "cd", "ls"])]
+    #[case("ignoreboth", vec!["ls", "ls", " pwd"], vec!["ls"])]
+    #[case("erasedups", vec!["ls", "cd", "ls", "pwd", "cd"], vec!["ls", "pwd", "cd"])]
+    #[case("ignorespace:erasedups", vec!["ls", " ls", "cd", "ls"], vec!["cd", "ls"])]
+    fn hist_control_test(
+        #[case] histcontrol: &str,
+        #[case] lines: Vec<&str>,
+        #[case] expected: Vec<&str>,
+    ) {
+        let control = HistControl::parse(histcontrol);
+        let mut history = ShellHistory::new();
+        for line in lines {
+            control.add(&mut history, line);
+        }
+
+        assert_eq!(history.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(history.new_entries, expected.len());
+    }
+
+    #[test]
+    fn substring_search_skips_the_entry_shown() {
+        let mut history = ShellHistory::new();
+        for line in [
+            "echo alpha",
+            "ls beta",
+            "echo gamma alpha",
+            "echo alpha",
+            "ls",
+        ] {
+            history.add(line).unwrap();
+        }
+        let search = history.substring_search();
+        search.lock().unwrap().query = String::from("alp");
+        let find = |start, dir| {
+            search.lock().unwrap().armed = true;
+            let result = history.starts_with("ignored", start, dir).unwrap().unwrap();
+            (result.entry.into_owned(), result.idx, result.pos)
+        };
+
+        let up = [
+            find(4, SearchDirection::Reverse),
+            find(2, SearchDirection::Reverse),
+            find(1, SearchDirection::Reverse),
+        ];
+        let down = [
+            find(1, SearchDirection::Forward),
+            find(3, SearchDirection::Forward),
+            find(4, SearchDirection::Forward),
+        ];
+
+        assert_eq!(
+            up,
+            [
+                (String::from("echo alpha"), 3, 10),
+                (String::from("echo gamma alpha"), 2, 16),
+                (String::from("echo alpha"), 0, 10),
+            ]
+        );
+        assert_eq!(
+            down,
+            [
+                (String::from("echo gamma alpha"), 2, 16),
+                (String::from("echo alpha"), 3, 10),
+                (String::from("alp"), 5, 3),
+            ]
+        );
+        assert!(!history.substring_search.lock().unwrap().armed);
+    }
+
+    #[test]
+    fn erase_keeps_new_entries_count() {
+        let mut history = ShellHistory::new();
+        history.add("ls").unwrap();
+        history.add("cd").unwrap();
+        history.new_entries = 0;
+        history.add("pwd").unwrap();
+        history.add("ls").unwrap();
+
+        history.erase("ls");
+
+        assert_eq!(history.iter().collect::<Vec<_>>(), vec!["cd", "pwd"]);
+        assert_eq!(history.new_entries, 1);
+    }
+
+    #[test]
+    fn load_range_reads_only_appended_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        fs::write(&path, "ls\ncd\n").unwrap();
+
+        let mut history = ShellHistory::new();
+        let offset = history.load_range(&path, 0..u64::MAX).unwrap();
+        history.add("pwd").unwrap();
+
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "make\nunfinish").unwrap();
+        let offset = history.load_range(&path, offset..u64::MAX).unwrap();
+
+        assert_eq!(
+            history.iter().collect::<Vec<_>>(),
+            vec!["ls", "cd", "make", "pwd"]
+        );
+        assert_eq!(history.new_entries, 1);
+        assert_eq!(offset, "ls\ncd\nmake\n".len() as u64);
+    }
+
+    #[test]
+    fn load_new_reads_each_line_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        fs::write(&path, "ls\ncd\n").unwrap();
+
+        let mut history = ShellHistory::new();
+        history.load_new(&path).unwrap();
+        history.load_new(&path).unwrap();
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "make").unwrap();
+        history.load_new(&path).unwrap();
+
+        assert_eq!(history.iter().collect::<Vec<_>>(), vec!["ls", "cd", "make"]);
+    }
+
+    #[test]
+    fn multi_line_entries_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let mut history = ShellHistory::new();
+        history.add("for x in a b\ndo echo $x\ndone").unwrap();
+        history.add("ls").unwrap();
+        history.save(&path).unwrap();
+
+        let mut loaded = ShellHistory::new();
+        loaded.load(&path).unwrap();
+        let mut appended = ShellHistory::new();
+        appended.load_range(&path, 0..u64::MAX).unwrap();
+
+        let expected = vec!["for x in a b\ndo echo $x\ndone", "ls"];
+        assert_eq!(loaded.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(appended.iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[rstest]
+    #[case("echo a", "echo a")]
+    #[case("echo a |\ncat", "echo a | cat")]
+    #[case("echo a\necho b", "echo a; echo b")]
+    #[case("true &&\n\nfalse", "true && false")]
+    #[case("echo a \\\nb", "echo a b")]
+    #[case("echo 'x\ny'", "echo 'x\ny'")]
+    #[case("cat <<EOF\nhi\nEOF", "cat <<EOF\nhi\nEOF")]
+    fn single_line_test(#[case] command: &str, #[case] expected: &str) {
+        assert_eq!(single_line(command), expected);
+    }
+
+    fn history_with_metadata() -> ShellHistory {
+        let mut history = ShellHistory::new();
+        history.insert(Entry::new("ls"));
+        history.insert(Entry {
+            timestamp: Some(1700000000),
+            duration: Some(Duration::from_millis(1500)),
+            status: Some(1),
+            ..Entry::new("echo \"a, b\" |\ncat")
+        });
+        history
+    }
+
+    #[rstest]
+    #[case(ExportFormat::Json, concat!(
+        "[\n",
+        r#"  {"index":0,"command":"ls","timestamp":null,"duration":null,"status":null},"#, "\n",
+        r#"  {"index":1,"command":"echo \"a, b\" |\ncat","timestamp":1700000000,"duration":1.5,"status":1}"#, "\n",
+        "]\n",
+    ))]
+    #[case(ExportFormat::Csv, concat!(
+        "index,command,timestamp,duration,status\n",
+        "0,ls,,,\n",
+        "1,\"echo \"\"a, b\"\" |\ncat\",1700000000,1.5,1\n",
+    ))]
+    fn export_test(#[case] format: ExportFormat, #[case] expected: &str) {
+        let mut output = Vec::new();
+        history_with_metadata().export(format, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn export_empty_json() {
+        let mut output = Vec::new();
+        ShellHistory::new()
+            .export(ExportFormat::Json, &mut output)
+            .unwrap();
+        assert_eq!(output, b"[]\n");
+    }
+
+    #[rstest]
+    #[case("ls\n\ncd /tmp\n", HistoryFormat::Plain)]
+    #[case("#1700000000\nls\n", HistoryFormat::BashTimestamps)]
+    #[case(": 1700000000:0;ls\n", HistoryFormat::ZshExtended)]
+    #[case("# not a timestamp\nls\n", HistoryFormat::Plain)]
+    #[case("", HistoryFormat::Plain)]
+    fn detect_format_test(#[case] contents: &str, #[case] expected: HistoryFormat) {
+        assert_eq!(HistoryFormat::detect(contents), expected);
+    }
+
+    fn entry(line: &str, timestamp: Option<u64>, duration: Option<u64>) -> Entry {
+        Entry {
+            timestamp,
+            duration: duration.map(Duration::from_secs),
+            ..Entry::new(line)
+        }
+    }
+
+    #[rstest]
+    #[case(b"ls\n\ncd /tmp\n", vec![entry("ls", None, None), entry("cd /tmp", None, None)])]
+    #[case(
+        b"#1700000000\nls\n#1700000005\necho a |\ncat\n",
+        vec![entry("ls", Some(1700000000), None), entry("echo a |\ncat", Some(1700000005), None)]
+    )]
+    #[case(
+        b": 1700000000:0;ls\n: 1700000003:12;echo a |\\\ncat\n: 1700000020:0;cd\n",
+        vec![
+            entry("ls", Some(1700000000), Some(0)),
+            entry("echo a |\ncat", Some(1700000003), Some(12)),
+            entry("cd", Some(1700000020), Some(0)),
+        ]
+    )]
+    #[case(
+        b"echo a |\\\ncat\nls \\\\\nx\n",
+        vec![entry("echo a |\ncat", None, None), entry("ls \\\nx", None, None)]
+    )]
+    #[case(
+        b": 1700000000:0;echo \xc4\x83\xa3\n",
+        vec![entry("echo \u{103}", Some(1700000000), Some(0))]
+    )]
+    fn read_entries_test(#[case] contents: &[u8], #[case] expected: Vec<Entry>) {
+        assert_eq!(read_entries(contents), expected);
+    }
+
+    #[test]
+    fn load_imports_other_shells_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("zsh_history");
+        fs::write(&path, ": 1700000000:0;ls\n: 1700000001:0;cd /tmp\n").unwrap();
+
+        let mut history = ShellHistory::new();
+        history.load(&path).unwrap();
+
+        assert_eq!(history.iter().collect::<Vec<_>>(), vec!["ls", "cd /tmp"]);
+        assert_eq!(history.new_entries, 0);
+    }
+}
@@ -0,0 +1,37 @@
+use std::fmt::Write;
+
+/// `value` as a JSON string, quoted and escaped.
+pub fn string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for char in value.chars() {
+        match char {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            char if char.is_control() => write!(json, "\\u{:04x}", char as u32).unwrap(),
+            char => json.push(char),
+        }
+    }
+    json.push('"');
+
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("", r#""""#)]
+    #[case("hi ünï", r#""hi ünï""#)]
+    #[case("a \"b\" \\c", r#""a \"b\" \\c""#)]
+    #[case("a\tb\n\x1b[0m", r#""a\tb\n\u001b[0m""#)]
+    fn string_test(#[case] value: &str, #[case] expected: &str) {
+        assert_eq!(string(value), expected);
+    }
+}
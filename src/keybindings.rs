@@ -0,0 +1,757 @@
+use crate::history::SubstringSearch;
+use crate::lexer::{self, Lexer, TokenKind};
+use crate::{eprint, named_dirs, terminal};
+use rustyline::line_buffer::{ChangeListener, LineBuffer};
+use rustyline::{
+    Anchor, At, Cmd, ConditionalEventHandler, Event, EventContext, EventHandler, InputMode,
+    KeyCode, KeyEvent, Modifiers, Movement, RepeatCount, Word,
+};
+use std::collections::hash_map::RandomState;
+use std::ffi::OsString;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{env, fs, io, process};
+
+/// A key sequence bound to a readline function name, from a line of an
+/// inputrc file.
+#[derive(Debug, PartialEq)]
+pub struct KeyBinding {
+    pub keys: Vec<KeyEvent>,
+    pub function: String,
+}
+
+/// State shared between the editor and the key handlers, which only get to
+/// see the line being edited.
+#[derive(Clone, Default)]
+pub struct HandlerState {
+    /// Last word of the previous command line, for Alt-.
+    pub last_argument: Arc<Mutex<String>>,
+    /// Whether Up/Down are moving through all of history rather than
+    /// searching it for a typed prefix.
+    browsing_history: Arc<AtomicBool>,
+    /// The search history-substring-search-up/down keep going.
+    pub substring_search: Arc<Mutex<SubstringSearch>>,
+    /// Whether text was pasted into the line being read.
+    pub pasted: Arc<AtomicBool>,
+    /// The terminal settings before the line was read, for running an
+    /// editor from the line editor's raw mode.
+    pub cooked_mode: Arc<Mutex<Option<terminal::Mode>>>,
+    /// The command saved in the editor run by `edit-and-execute-command`,
+    /// to run in place of the line accepted.
+    pub edited: Arc<Mutex<Option<String>>>,
+    /// The variables of the shell that editor is run with.
+    pub editor_vars: Arc<Mutex<EditorVars>>,
+    /// Whether the completion asked for by `insert-completions` is to
+    /// insert every candidate.
+    pub insert_completions: Arc<AtomicBool>,
+    /// The edit `auto_pairs` asked completion to make.
+    pub pairing: Arc<Mutex<Option<Pairing>>>,
+}
+
+/// What `edit-and-execute-command` needs of the shell's variables, copied
+/// before every line is read, as key handlers can't share the shell's own.
+#[derive(Clone, Debug, Default)]
+pub struct EditorVars {
+    /// $VISUAL, or else $EDITOR, when it isn't empty.
+    pub editor: Option<String>,
+    /// The exported variables.
+    pub exported: Vec<(OsString, OsString)>,
+}
+
+/// $INPUTRC, or `~/.inputrc` like readline.
+pub fn inputrc_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("INPUTRC") {
+        return Some(PathBuf::from(path));
+    }
+
+    named_dirs::home_dir().map(|home| home.join(".inputrc"))
+}
+
+/// Parses the key bindings of an inputrc file: `"\C-l": clear-screen`,
+/// `"\e.": yank-last-arg` or `Control-l: clear-screen`. Variable settings,
+/// conditionals, macros and malformed lines are skipped.
+pub fn parse_inputrc(contents: &str) -> Vec<KeyBinding> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with(['#', '$']))
+        .filter(|line| !line.starts_with("set "))
+        .filter_map(parse_binding)
+        .collect()
+}
+
+fn parse_binding(line: &str) -> Option<KeyBinding> {
+    let (keys, function) = match line.strip_prefix('"') {
+        Some(rest) => {
+            let end = closing_quote(rest)?;
+            let function = rest[end + 1..].trim_start().strip_prefix(':')?;
+            (parse_keyseq(&rest[..end])?, function)
+        }
+        None => {
+            let (name, function) = line.split_once(':')?;
+            (vec![parse_keyname(name.trim())?], function)
+        }
+    };
+
+    let function = function.trim();
+    if function.is_empty() || function.starts_with(['"', '\'']) {
+        return None;
+    }
+
+    Some(KeyBinding {
+        keys,
+        function: function.to_string(),
+    })
+}
+
+fn closing_quote(quoted: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (index, char) in quoted.char_indices() {
+        match char {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Some(index),
+            _ => escaped = false,
+        }
+    }
+
+    None
+}
+
+/// `\C-x` is Ctrl, `\M-x` and `\ex` are Alt (readline's meta prefix).
+fn parse_keyseq(seq: &str) -> Option<Vec<KeyEvent>> {
+    let mut keys = Vec::new();
+    let mut chars = seq.chars();
+    let mut modifiers = Modifiers::NONE;
+
+    while let Some(char) = chars.next() {
+        let char = if char == '\\' {
+            match chars.next()? {
+                'C' if chars.as_str().starts_with('-') => {
+                    chars.next();
+                    modifiers |= Modifiers::CTRL;
+                    continue;
+                }
+                'M' if chars.as_str().starts_with('-') => {
+                    chars.next();
+                    modifiers |= Modifiers::ALT;
+                    continue;
+                }
+                'e' => {
+                    if let Some(arrow) = arrow_key(chars.as_str()) {
+                        chars.nth(1);
+                        keys.push(KeyEvent(arrow, modifiers));
+                        modifiers = Modifiers::NONE;
+                    } else {
+                        modifiers |= Modifiers::ALT;
+                    }
+                    continue;
+                }
+                't' => '\t',
+                'n' => '\n',
+                'r' => '\r',
+                escaped => escaped,
+            }
+        } else {
+            char
+        };
+
+        keys.push(key(char, modifiers));
+        modifiers = Modifiers::NONE;
+    }
+
+    (!keys.is_empty() && modifiers == Modifiers::NONE).then_some(keys)
+}
+
+/// The arrow key sent as `\e[A` or `\eOA` and so on.
+fn arrow_key(seq: &str) -> Option<KeyCode> {
+    let code = seq.strip_prefix(['[', 'O'])?;
+    match code.chars().next()? {
+        'A' => Some(KeyCode::Up),
+        'B' => Some(KeyCode::Down),
+        'C' => Some(KeyCode::Right),
+        'D' => Some(KeyCode::Left),
+        _ => None,
+    }
+}
+
+/// `Control-l`, `C-l`, `Meta-.` or `M-.` key names.
+fn parse_keyname(name: &str) -> Option<KeyEvent> {
+    let mut modifiers = Modifiers::NONE;
+    let mut rest = name;
+    loop {
+        let lower = rest.to_ascii_lowercase();
+        if let Some(prefix) = ["control-", "c-"].iter().find(|p| lower.starts_with(*p)) {
+            modifiers |= Modifiers::CTRL;
+            rest = &rest[prefix.len()..];
+        } else if let Some(prefix) = ["meta-", "m-"].iter().find(|p| lower.starts_with(*p)) {
+            modifiers |= Modifiers::ALT;
+            rest = &rest[prefix.len()..];
+        } else {
+            break;
+        }
+    }
+
+    let mut chars = rest.chars();
+    match (chars.next(), chars.next()) {
+        (Some(char), None) => Some(key(char, modifiers)),
+        _ => None,
+    }
+}
+
+fn key(char: char, modifiers: Modifiers) -> KeyEvent {
+    match (char, modifiers) {
+        ('\t', Modifiers::NONE) => KeyEvent(KeyCode::Tab, modifiers),
+        ('\n' | '\r', Modifiers::NONE) => KeyEvent(KeyCode::Enter, modifiers),
+        _ => KeyEvent::normalize(KeyEvent(KeyCode::Char(char), modifiers)),
+    }
+}
+
+/// The editing command behind a readline function name.
+pub fn handler(function: &str, state: &HandlerState) -> Option<EventHandler> {
+    let cmd = match function {
+        "insert-last-argument" | "yank-last-arg" => {
+            let handler = InsertLastArgument(Arc::clone(&state.last_argument));
+            return Some(EventHandler::Conditional(Box::new(handler)));
+        }
+        "up-line-or-beginning-search" | "down-line-or-beginning-search" => {
+            let handler = BeginningSearch {
+                up: function.starts_with("up"),
+                browsing: Arc::clone(&state.browsing_history),
+            };
+            return Some(EventHandler::Conditional(Box::new(handler)));
+        }
+        "history-substring-search-up" | "history-substring-search-down" => {
+            let handler = SubstringSearchKey {
+                up: function.ends_with("up"),
+                search: Arc::clone(&state.substring_search),
+            };
+            return Some(EventHandler::Conditional(Box::new(handler)));
+        }
+        "edit-and-execute-command" | "edit-command-line" => {
+            let handler = EditCommand {
+                execute: function == "edit-and-execute-command",
+                cooked_mode: Arc::clone(&state.cooked_mode),
+                edited: Arc::clone(&state.edited),
+                vars: Arc::clone(&state.editor_vars),
+            };
+            return Some(EventHandler::Conditional(Box::new(handler)));
+        }
+        "insert-completions" | "glob-expand-word" => {
+            let handler = InsertCompletions(Arc::clone(&state.insert_completions));
+            return Some(EventHandler::Conditional(Box::new(handler)));
+        }
+        "accept-line" => Cmd::AcceptLine,
+        "backward-char" => Cmd::Move(Movement::BackwardChar(1)),
+        "backward-kill-word" => Cmd::Kill(Movement::BackwardWord(1, Word::Emacs)),
+        "backward-word" => Cmd::Move(Movement::BackwardWord(1, Word::Emacs)),
+        "beginning-of-history" => Cmd::BeginningOfHistory,
+        "beginning-of-line" => Cmd::Move(Movement::BeginningOfLine),
+        "capitalize-word" => Cmd::CapitalizeWord,
+        "clear-screen" => Cmd::ClearScreen,
+        "complete" => Cmd::Complete,
+        "delete-char" => Cmd::Kill(Movement::ForwardChar(1)),
+        "downcase-word" => Cmd::DowncaseWord,
+        "end-of-history" => Cmd::EndOfHistory,
+        "end-of-line" => Cmd::Move(Movement::EndOfLine),
+        "forward-char" => Cmd::Move(Movement::ForwardChar(1)),
+        "forward-search-history" => Cmd::ForwardSearchHistory,
+        "forward-word" => Cmd::Move(Movement::ForwardWord(1, At::AfterEnd, Word::Emacs)),
+        "history-search-backward" => Cmd::HistorySearchBackward,
+        "history-search-forward" => Cmd::HistorySearchForward,
+        "kill-line" => Cmd::Kill(Movement::EndOfLine),
+        "kill-whole-line" => Cmd::Kill(Movement::WholeLine),
+        "kill-word" => Cmd::Kill(Movement::ForwardWord(1, At::AfterEnd, Word::Emacs)),
+        "next-history" => Cmd::NextHistory,
+        "previous-history" => Cmd::PreviousHistory,
+        "quoted-insert" => Cmd::QuotedInsert,
+        "redraw-current-line" => Cmd::Repaint,
+        "reverse-search-history" => Cmd::ReverseSearchHistory,
+        "transpose-chars" => Cmd::TransposeChars,
+        "transpose-words" => Cmd::TransposeWords(1),
+        "undo" => Cmd::Undo(1),
+        "unix-line-discard" => Cmd::Kill(Movement::BeginningOfLine),
+        "unix-word-rubout" => Cmd::Kill(Movement::BackwardWord(1, Word::Big)),
+        "upcase-word" => Cmd::UpcaseWord,
+        "yank" => Cmd::Yank(1, Anchor::Before),
+        "yank-pop" => Cmd::YankPop,
+        _ => return None,
+    };
+
+    Some(EventHandler::Simple(cmd))
+}
+
+/// Inserts the last word of the previous command line, like readline's
+/// `yank-last-arg` (Alt-.).
+struct InsertLastArgument(Arc<Mutex<String>>);
+
+impl ConditionalEventHandler for InsertLastArgument {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, _: &EventContext) -> Option<Cmd> {
+        let last_argument = self.0.lock().unwrap();
+        if last_argument.is_empty() {
+            return Some(Cmd::Noop);
+        }
+
+        Some(Cmd::Insert(1, last_argument.clone()))
+    }
+}
+
+/// Completes the word with all its candidates at once, like readline's
+/// `insert-completions` (Alt-*), or with every file a pattern matches,
+/// like `glob-expand-word` (Ctrl-X *).
+struct InsertCompletions(Arc<AtomicBool>);
+
+impl ConditionalEventHandler for InsertCompletions {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, _: &EventContext) -> Option<Cmd> {
+        self.0.store(true, Ordering::Relaxed);
+        Some(Cmd::Complete)
+    }
+}
+
+/// Notes that text is being pasted, leaving it to rustyline to insert it as
+/// it is, newlines included, without running anything.
+pub struct NotePaste(pub Arc<AtomicBool>);
+
+impl ConditionalEventHandler for NotePaste {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, _: &EventContext) -> Option<Cmd> {
+        self.0.store(true, Ordering::Relaxed);
+        None
+    }
+}
+
+/// Opens the line in $VISUAL or $EDITOR, like bash's
+/// `edit-and-execute-command` (Ctrl-X Ctrl-E) which then runs what was
+/// saved, or zsh's `edit-command-line` which puts it back in the line, with
+/// the cursor at its start, to edit further.
+struct EditCommand {
+    execute: bool,
+    cooked_mode: Arc<Mutex<Option<terminal::Mode>>>,
+    edited: Arc<Mutex<Option<String>>>,
+    vars: Arc<Mutex<EditorVars>>,
+}
+
+impl ConditionalEventHandler for EditCommand {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        let raw_mode = terminal::Mode::current();
+        if let Some(mode) = &*self.cooked_mode.lock().unwrap() {
+            mode.apply();
+        }
+        let vars = self.vars.lock().unwrap().clone();
+        let edited = edit_in_editor(ctx.line(), &vars);
+        if let Some(mode) = raw_mode {
+            mode.apply();
+        }
+
+        let edited = match edited {
+            Ok(edited) => edited,
+            Err(err) => {
+                eprint!("\n{err}\n");
+                return Some(Cmd::Repaint);
+            }
+        };
+        if !self.execute {
+            return Some(Cmd::Replace(Movement::WholeBuffer, Some(edited)));
+        }
+
+        *self.edited.lock().unwrap() = Some(edited);
+        Some(Cmd::AcceptLine)
+    }
+}
+
+/// An edit of the `auto_pairs` option around the cursor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pairing {
+    /// Inserts an opening character and the one closing it, with the cursor
+    /// between them.
+    Insert(char, char),
+    /// Moves over the closing character typed, already there.
+    Skip(char),
+    /// Deletes the empty pair around the cursor.
+    Delete,
+}
+
+impl Pairing {
+    /// Makes the edit in `line`, telling `cl` about it.
+    pub fn apply(self, line: &mut LineBuffer, cl: &mut impl ChangeListener) {
+        let pos = line.pos();
+        match self {
+            Pairing::Insert(open, close) => {
+                line.replace(pos..pos, &format!("{open}{close}"), cl);
+                line.set_pos(pos + open.len_utf8());
+            }
+            Pairing::Skip(close) => line.set_pos(pos + close.len_utf8()),
+            Pairing::Delete => {
+                let open = line[..pos].chars().next_back().map_or(0, char::len_utf8);
+                let close = line[pos..].chars().next().map_or(0, char::len_utf8);
+                line.replace(pos - open..pos + close, "", cl);
+            }
+        }
+    }
+}
+
+/// The keys the `auto_pairs` option binds, with the character each types,
+/// or `None` for Backspace.
+pub fn auto_pair_keys() -> Vec<(KeyEvent, Option<char>)> {
+    let typed = ['"', '\'', '(', ')', '{', '}'].map(|char| (KeyEvent::from(char), Some(char)));
+    let backspace = (KeyEvent(KeyCode::Backspace, Modifiers::NONE), None);
+    typed.into_iter().chain([backspace]).collect()
+}
+
+/// Types `key` with the `auto_pairs` option, or Backspace for `None`. Keys
+/// it has nothing to pair do what they always do.
+pub fn auto_pair_handler(key: Option<char>, state: &HandlerState) -> EventHandler {
+    let handler = AutoPair {
+        key,
+        pairing: Arc::clone(&state.pairing),
+    };
+    EventHandler::Conditional(Box::new(handler))
+}
+
+/// No editing command inserts text and leaves the cursor inside it, so the
+/// edits of `auto_pairs` are made by completing: the completer sees the
+/// pairing asked for and makes it with [`Pairing::apply`].
+struct AutoPair {
+    key: Option<char>,
+    pairing: Arc<Mutex<Option<Pairing>>>,
+}
+
+impl ConditionalEventHandler for AutoPair {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        if ctx.input_mode() != InputMode::Insert {
+            return None;
+        }
+
+        match pairing(ctx.line(), ctx.pos(), self.key)? {
+            Pairing::Skip(_) => Some(Cmd::Move(Movement::ForwardChar(1))),
+            pairing => {
+                *self.pairing.lock().unwrap() = Some(pairing);
+                Some(Cmd::Complete)
+            }
+        }
+    }
+}
+
+/// What typing `key`, or Backspace for `None`, does at `pos` in `line` with
+/// `auto_pairs`, if anything other than usual. Inside quotes, as the lexer
+/// sees them, only the quote closing them is paired, and an escaped or
+/// in-word quote like the one of `don't` isn't.
+fn pairing(line: &str, pos: usize, key: Option<char>) -> Option<Pairing> {
+    let before = &line[..pos];
+    let previous = before.chars().next_back();
+    let next = line[pos..].chars().next();
+    let quote = lexer::open_quote(before);
+
+    let Some(key) = key else {
+        let open = previous?;
+        let escaped = before[..pos - open.len_utf8()].ends_with('\\');
+        let opened = match open {
+            '"' | '\'' => quote == Some(open),
+            _ => quote.is_none(),
+        };
+        return (next == closing(open) && opened && !escaped).then_some(Pairing::Delete);
+    };
+
+    let closes = match key {
+        '"' | '\'' => quote == Some(key),
+        _ => quote.is_none(),
+    };
+    if next == Some(key) && closes {
+        return Some(Pairing::Skip(key));
+    }
+
+    let escaped = before.ends_with('\\');
+    let in_word = previous.is_some_and(char::is_alphanumeric);
+    match key {
+        '"' | '\'' if quote.is_none() && !escaped && !in_word => Some(Pairing::Insert(key, key)),
+        '(' | '{' if quote.is_none() && !escaped => Some(Pairing::Insert(key, closing(key)?)),
+        _ => None,
+    }
+}
+
+/// The character closing a pair opened with `open`.
+fn closing(open: char) -> Option<char> {
+    match open {
+        '"' | '\'' => Some(open),
+        '(' => Some(')'),
+        '{' => Some('}'),
+        _ => None,
+    }
+}
+
+/// Has $VISUAL or $EDITOR, or else vi, edit `text` in a temporary file, and
+/// returns what it was saved as, without its final newline. It runs with
+/// the exported variables of `vars` only.
+fn edit_in_editor(text: &str, vars: &EditorVars) -> io::Result<String> {
+    let (path, mut file) = create_temp_file()?;
+    let written = writeln!(file, "{text}");
+    drop(file);
+    if let Err(err) = written {
+        let _ = fs::remove_file(&path);
+        return Err(err);
+    }
+
+    let editor = vars.editor.as_deref().unwrap_or("vi");
+    // through sh, as it may come with arguments, like `code --wait`
+    let status = process::Command::new("sh")
+        .env_clear()
+        .envs(vars.exported.iter().cloned())
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(&path)
+        .status();
+    let edited = fs::read_to_string(&path);
+    let _ = fs::remove_file(&path);
+
+    let status = status?;
+    if !status.success() {
+        return Err(io::Error::other(format!("{editor}: {status}")));
+    }
+    let edited = edited?;
+    Ok(edited.strip_suffix('\n').unwrap_or(&edited).to_string())
+}
+
+/// Creates a file in the temporary directory that only the user can read
+/// and write. Its name is random and it must not exist yet, so another user
+/// can't have put a file or symlink there to see or change what's edited.
+fn create_temp_file() -> io::Result<(PathBuf, fs::File)> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut attempts = 0;
+    loop {
+        let random = RandomState::new().build_hasher().finish();
+        let path = env::temp_dir().join(format!("shell-edit-{random:016x}.sh"));
+        match options.open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists && attempts < 100 => {
+                attempts += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// With text typed before the cursor, moves to the previous or next history
+/// entry starting with it, leaving the cursor after it so pressing the key
+/// again keeps searching for the same prefix. With nothing before the cursor
+/// it moves through all entries, like zsh's `up-line-or-beginning-search`.
+struct BeginningSearch {
+    up: bool,
+    browsing: Arc<AtomicBool>,
+}
+
+impl ConditionalEventHandler for BeginningSearch {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        // a whole entry recalled by the previous press isn't a prefix to
+        // search for
+        let browsing = ctx.pos() == 0
+            || self.browsing.load(Ordering::Relaxed) && ctx.pos() == ctx.line().len();
+        self.browsing.store(browsing, Ordering::Relaxed);
+
+        Some(match (browsing, self.up) {
+            (true, true) => Cmd::LineUpOrPreviousHistory(1),
+            (true, false) => Cmd::LineDownOrNextHistory(1),
+            (false, true) => Cmd::HistorySearchBackward,
+            (false, false) => Cmd::HistorySearchForward,
+        })
+    }
+}
+
+/// Moves to the previous or next history entry containing the line as it
+/// was typed anywhere in it, like zsh's `history-substring-search-up`, so
+/// pressing the key again finds the one after. Going down past the newest
+/// brings back what was typed. With nothing typed it moves through all
+/// entries.
+struct SubstringSearchKey {
+    up: bool,
+    search: Arc<Mutex<SubstringSearch>>,
+}
+
+impl ConditionalEventHandler for SubstringSearchKey {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        let mut search = self.search.lock().unwrap();
+        let continuing = search.found.as_deref() == Some(ctx.line());
+        // an entry recalled by browsing isn't something to search for
+        search.browsing = ctx.line().is_empty()
+            || search.browsing && !continuing && ctx.pos() == ctx.line().len();
+        if search.browsing {
+            search.found = None;
+            return Some(if self.up {
+                Cmd::LineUpOrPreviousHistory(1)
+            } else {
+                Cmd::LineDownOrNextHistory(1)
+            });
+        }
+
+        if !continuing {
+            search.query = ctx.line().to_string();
+            search.found = None;
+        }
+        search.armed = true;
+        Some(if self.up {
+            Cmd::HistorySearchBackward
+        } else {
+            Cmd::HistorySearchForward
+        })
+    }
+}
+
+/// The last word of `line` as typed, quotes included.
+pub fn last_word(line: &str) -> &str {
+    let line = line.trim_end();
+    let mut quote = None;
+    let mut word_start = 0;
+    let mut offset = 0;
+
+    for token in Lexer::new(line).lex() {
+        offset += token.lexeme.len();
+        match (&token.kind, &quote) {
+            (TokenKind::SingleQuote | TokenKind::DoubleQuote, Some(open))
+                if *open == token.kind =>
+            {
+                quote = None
+            }
+            (TokenKind::SingleQuote | TokenKind::DoubleQuote, None) => quote = Some(token.kind),
+            (TokenKind::Whitespace, None) => word_start = offset,
+            _ => {}
+        }
+    }
+
+    &line[word_start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(r#""\C-l": clear-screen"#, Some((vec![KeyEvent::ctrl('L')], "clear-screen")))]
+    #[case(r#""\e.": yank-last-arg"#, Some((vec![KeyEvent::alt('.')], "yank-last-arg")))]
+    #[case(r#""\M-b":backward-word"#, Some((vec![KeyEvent::alt('b')], "backward-word")))]
+    #[case(
+        r#""\C-x\C-r": re-read-init-file"#,
+        Some((vec![KeyEvent::ctrl('X'), KeyEvent::ctrl('R')], "re-read-init-file"))
+    )]
+    #[case("Control-u: unix-line-discard", Some((vec![KeyEvent::ctrl('U')], "unix-line-discard")))]
+    #[case("M-.: insert-last-argument", Some((vec![KeyEvent::alt('.')], "insert-last-argument")))]
+    #[case(r#""\e[A": history-search-backward"#, Some((vec![KeyEvent(KeyCode::Up, Modifiers::NONE)], "history-search-backward")))]
+    #[case(r#""\eOB": next-history"#, Some((vec![KeyEvent(KeyCode::Down, Modifiers::NONE)], "next-history")))]
+    #[case(r#""\C-xd": "date\n""#, None)]
+    #[case(r#""\C-": complete"#, None)]
+    #[case("set editing-mode vi", None)]
+    fn parse_inputrc_test(#[case] line: &str, #[case] expected: Option<(Vec<KeyEvent>, &str)>) {
+        let expected = expected
+            .map(|(keys, function)| KeyBinding {
+                keys,
+                function: function.to_string(),
+            })
+            .into_iter()
+            .collect::<Vec<_>>();
+        assert_eq!(parse_inputrc(line), expected);
+    }
+
+    #[rstest]
+    #[case("ls -l /tmp", "/tmp")]
+    #[case("echo 'a b'  ", "'a b'")]
+    #[case("cat \"x y\"z", "\"x y\"z")]
+    #[case("pwd", "pwd")]
+    #[case("", "")]
+    fn last_word_test(#[case] line: &str, #[case] expected: &str) {
+        assert_eq!(last_word(line), expected);
+    }
+
+    #[rstest]
+    #[case("echo |", Some('"'), Some(Pairing::Insert('"', '"')))]
+    #[case("echo |", Some('('), Some(Pairing::Insert('(', ')')))]
+    #[case("echo $|", Some('{'), Some(Pairing::Insert('{', '}')))]
+    #[case("echo don|", Some('\''), None)]
+    #[case("echo \\|", Some('"'), None)]
+    #[case("echo \"a |", Some('('), None)]
+    #[case("echo \"it|\"", Some('\''), None)]
+    #[case("echo \"a|\"", Some('"'), Some(Pairing::Skip('"')))]
+    #[case("echo $(ls|)", Some(')'), Some(Pairing::Skip(')')))]
+    #[case("echo \"(|)\"", Some(')'), None)]
+    #[case("echo |", Some(')'), None)]
+    #[case("echo (|)", None, Some(Pairing::Delete))]
+    #[case("echo \"|\"", None, Some(Pairing::Delete))]
+    #[case("echo \"a\"|\"", None, None)]
+    #[case("echo \\(|)", None, None)]
+    #[case("echo (a|)", None, None)]
+    #[case("|", None, None)]
+    fn pairing_test(
+        #[case] line: &str,
+        #[case] key: Option<char>,
+        #[case] expected: Option<Pairing>,
+    ) {
+        let pos = line.find('|').unwrap();
+        let line = line.replacen('|', "", 1);
+        assert_eq!(pairing(&line, pos, key), expected);
+    }
+
+    /// Makes no note of changes, for editing a line outside the editor.
+    struct NoChanges;
+
+    impl rustyline::line_buffer::DeleteListener for NoChanges {
+        fn delete(&mut self, _: usize, _: &str, _: rustyline::line_buffer::Direction) {}
+    }
+
+    impl ChangeListener for NoChanges {
+        fn insert_char(&mut self, _: usize, _: char) {}
+
+        fn insert_str(&mut self, _: usize, _: &str) {}
+
+        fn replace(&mut self, _: usize, _: &str, _: &str) {}
+    }
+
+    #[rstest]
+    #[case("echo |x", Pairing::Insert('(', ')'), "echo (|)x")]
+    #[case("echo \"a|\"", Pairing::Skip('"'), "echo \"a\"|")]
+    #[case("echo {|}x", Pairing::Delete, "echo |x")]
+    fn pairing_apply_test(#[case] line: &str, #[case] pairing: Pairing, #[case] expected: &str) {
+        let mut buffer = LineBuffer::with_capacity(64);
+        let pos = line.find('|').unwrap();
+        buffer.update(&line.replacen('|', "", 1), pos, &mut NoChanges);
+
+        pairing.apply(&mut buffer, &mut NoChanges);
+
+        let mut edited = buffer.as_str().to_string();
+        edited.insert(buffer.pos(), '|');
+        assert_eq!(edited, expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn editor_runs_with_the_shell_variables() {
+        let path = env::var_os("PATH").unwrap_or_default();
+        let vars = EditorVars {
+            editor: Some(String::from("f() { echo \"$EDITED\" >> \"$1\"; }; f")),
+            exported: vec![("PATH".into(), path), ("EDITED".into(), "ls".into())],
+        };
+
+        assert_eq!(edit_in_editor("cd /", &vars).unwrap(), "cd /\nls");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn temp_files_are_private_and_new() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (first, _) = create_temp_file().unwrap();
+        let (second, _) = create_temp_file().unwrap();
+        let mode = fs::metadata(&first).unwrap().permissions().mode();
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -1,99 +1,229 @@
-pub struct Lexer {
-    input: Vec<char>,
+/// Splits a line into tokens borrowing from it, without copying any text.
+pub struct Lexer<'a> {
+    input: &'a str,
+    /// Byte offset of the next token.
     position: usize,
 }
 
-impl Lexer {
-    pub fn new(input: &str) -> Self {
-        Self {
-            input: input.chars().collect(),
-            position: 0,
-        }
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self { input, position: 0 }
     }
 
-    pub fn lex(&mut self) -> Vec<Token> {
+    pub fn lex(&mut self) -> Vec<Token<'a>> {
+        let _span = tracing::trace_span!("lex", len = self.input.len()).entered();
         let mut tokens: Vec<Token> = Vec::new();
-        while !self.is_eof() {
-            tokens.push(self.next_token());
+        while let Some(char) = self.rest().chars().next() {
+            tokens.push(self.next_token(char));
         }
         tokens.push(Token::eof());
         tokens
     }
 
-    fn next_token(&mut self) -> Token {
-        match self.input[self.position] {
-            '\'' => self.handle_single_quote(),
-            '"' => self.handle_double_quote(),
+    fn next_token(&mut self, char: char) -> Token<'a> {
+        match char {
+            '\'' => self.take(TokenKind::SingleQuote, 1),
+            '"' => self.take(TokenKind::DoubleQuote, 1),
             '\\' => self.handle_backslash(),
-            char if char::is_whitespace(char) => self.handle_whitespace(),
-            char if is_string_char(char) => self.handle_string(),
-            char => unimplemented!("handling of {:?}", char),
+            '$' => self.take(TokenKind::Dollar, 1),
+            char if char::is_whitespace(char) => {
+                self.take_while(TokenKind::Whitespace, char::is_whitespace)
+            }
+            _ => self.take_while(TokenKind::String, is_string_char),
         }
     }
 
-    fn is_eof(&self) -> bool {
-        self.position >= self.input.len()
+    fn rest(&self) -> &'a str {
+        &self.input[self.position..]
     }
 
-    fn handle_single_quote(&mut self) -> Token {
-        let lexeme = String::from(self.input[self.position]);
-        self.position += 1;
+    /// The next `len` bytes as a token of `kind`.
+    fn take(&mut self, kind: TokenKind, len: usize) -> Token<'a> {
+        let lexeme = &self.rest()[..len];
+        self.position += len;
 
-        Token {
-            kind: TokenKind::SingleQuote,
-            lexeme,
-        }
+        Token { kind, lexeme }
     }
 
-    fn handle_double_quote(&mut self) -> Token {
-        let lexeme = String::from(self.input[self.position]);
-        self.position += 1;
+    fn take_while(&mut self, kind: TokenKind, matches: impl Fn(char) -> bool) -> Token<'a> {
+        let rest = self.rest();
+        let len = rest.find(|char| !matches(char)).unwrap_or(rest.len());
+        self.take(kind, len)
+    }
 
-        Token {
-            kind: TokenKind::DoubleQuote,
-            lexeme,
-        }
+    /// A backslash ending the input (a line continuation) is a lone `\`.
+    fn handle_backslash(&mut self) -> Token<'a> {
+        let escaped = self.rest()[1..].chars().next();
+        self.take(
+            TokenKind::EscapeSequence,
+            1 + escaped.map_or(0, char::len_utf8),
+        )
     }
+}
+
+/// Whether `input` stops in the middle of a command: inside quotes, after a
+/// trailing `\`, `|` or `&&`, or before the end of a here-document's body.
+pub fn is_incomplete(input: &str) -> bool {
+    let tokens = Lexer::new(input).lex();
+    let mut quote: Option<&TokenKind> = None;
+    let mut heredocs = Vec::new();
+    let mut last: Option<&Token> = None;
+    let mut offset = 0;
 
-    fn handle_string(&mut self) -> Token {
-        let mut end_position = self.position;
-        while end_position < self.input.len() && is_string_char(self.input[end_position]) {
-            end_position += 1;
+    for (index, token) in tokens.iter().enumerate() {
+        offset += token.lexeme.len();
+        match (&token.kind, quote) {
+            (TokenKind::SingleQuote | TokenKind::DoubleQuote, Some(open))
+                if *open == token.kind =>
+            {
+                quote = None
+            }
+            (_, Some(_)) => {}
+            (TokenKind::SingleQuote | TokenKind::DoubleQuote, None) => quote = Some(&token.kind),
+            (TokenKind::Whitespace, None) => {
+                if !heredocs.is_empty()
+                    && let Some(newline) = token.lexeme.find('\n')
+                {
+                    let body_start = offset - token.lexeme.len() + newline + 1;
+                    return match skip_heredoc_bodies(&input[body_start..], &heredocs) {
+                        Some(rest) => is_incomplete(rest),
+                        None => true,
+                    };
+                }
+                continue;
+            }
+            (TokenKind::String, None)
+                if token.lexeme.starts_with("<<") && !token.lexeme.starts_with("<<<") =>
+            {
+                heredocs.push(delimiter(&token.lexeme[2..], &tokens[index + 1..]));
+            }
+            _ => {}
         }
-        let lexeme: String = self.input[self.position..end_position].iter().collect();
-        self.position = end_position;
 
-        Token {
-            kind: TokenKind::String,
-            lexeme,
+        if token.kind != TokenKind::EOF {
+            last = Some(token);
         }
     }
 
-    fn handle_whitespace(&mut self) -> Token {
-        let mut end_position = self.position;
-        while end_position < self.input.len() && char::is_whitespace(self.input[end_position]) {
-            end_position += 1;
+    quote.is_some()
+        || !heredocs.is_empty()
+        || last.is_some_and(|token| match token.kind {
+            TokenKind::EscapeSequence => token.lexeme == "\\",
+            TokenKind::String => token.lexeme.ends_with('|') || token.lexeme.ends_with("&&"),
+            _ => false,
+        })
+}
+
+/// The quote `input` ends inside of, if any.
+pub fn open_quote(input: &str) -> Option<char> {
+    let mut quote = None;
+    for token in Lexer::new(input).lex() {
+        match (token.kind, quote) {
+            (TokenKind::SingleQuote, Some('\'')) | (TokenKind::DoubleQuote, Some('"')) => {
+                quote = None
+            }
+            (_, Some(_)) => {}
+            (TokenKind::SingleQuote, None) => quote = Some('\''),
+            (TokenKind::DoubleQuote, None) => quote = Some('"'),
+            _ => {}
         }
-        let lexeme: String = self.input[self.position..end_position].iter().collect();
-        self.position = end_position;
+    }
 
-        Token {
-            kind: TokenKind::Whitespace,
-            lexeme,
+    quote
+}
+
+/// The word ending a here-document, after its `<<`.
+pub(crate) struct Delimiter {
+    /// With any quoting removed.
+    pub(crate) word: String,
+    /// For `<<-`, which strips leading tabs from the lines of the body.
+    pub(crate) strip_tabs: bool,
+    /// Whether any of it was quoted, which leaves the body unexpanded.
+    pub(crate) quoted: bool,
+    /// How many tokens after the one of the operator it takes up.
+    pub(crate) tokens: usize,
+}
+
+/// The delimiter following `<<` (or `<<-`): `operator` is the rest of the
+/// token of the `<<`, and `following` the tokens after it.
+pub(crate) fn delimiter(operator: &str, following: &[Token]) -> Delimiter {
+    let (word, strip_tabs) = match operator.strip_prefix('-') {
+        Some(word) => (word, true),
+        None => (operator, false),
+    };
+
+    let mut delimiter = Delimiter {
+        word: String::from(word),
+        strip_tabs,
+        quoted: false,
+        tokens: 0,
+    };
+    let leading_space = following
+        .iter()
+        .take_while(|token| word.is_empty() && token.kind == TokenKind::Whitespace)
+        .count();
+    delimiter.tokens = leading_space;
+    let mut quote: Option<&TokenKind> = None;
+    for token in &following[leading_space..] {
+        match (&token.kind, quote) {
+            (TokenKind::EOF, _) | (TokenKind::Whitespace, None) => break,
+            (TokenKind::SingleQuote | TokenKind::DoubleQuote, Some(open))
+                if *open == token.kind =>
+            {
+                quote = None
+            }
+            (TokenKind::SingleQuote | TokenKind::DoubleQuote, None) => {
+                delimiter.quoted = true;
+                quote = Some(&token.kind);
+            }
+            (TokenKind::EscapeSequence, None) => {
+                delimiter.quoted = true;
+                delimiter.word.extend(token.lexeme.chars().nth(1));
+            }
+            _ => delimiter.word.push_str(token.lexeme),
         }
+        delimiter.tokens += 1;
     }
 
-    fn handle_backslash(&mut self) -> Token {
-        let lexeme: String = self.input[self.position..self.position + 2]
-            .iter()
-            .collect();
-        self.position += 2;
+    delimiter
+}
 
-        Token {
-            kind: TokenKind::EscapeSequence,
-            lexeme,
+/// The body of a here-document at the start of `input`, up to the line of
+/// its delimiter, and the input left after that line. `None` if the
+/// delimiter doesn't end it yet.
+pub(crate) fn heredoc_body<'a>(
+    mut input: &'a str,
+    delimiter: &Delimiter,
+) -> Option<(String, &'a str)> {
+    let mut body = String::new();
+    loop {
+        let (line, rest) = match input.split_once('\n') {
+            Some((line, rest)) => (line, Some(rest)),
+            None => (input, None),
+        };
+        let line = if delimiter.strip_tabs {
+            line.trim_start_matches('\t')
+        } else {
+            line
+        };
+
+        if line == delimiter.word {
+            return Some((body, rest.unwrap_or_default()));
         }
+        body.push_str(line);
+        body.push('\n');
+        input = rest?;
+    }
+}
+
+/// The input left after the bodies of `heredocs`, `None` if one of them isn't
+/// terminated yet.
+fn skip_heredoc_bodies<'a>(mut input: &'a str, heredocs: &[Delimiter]) -> Option<&'a str> {
+    for delimiter in heredocs {
+        (_, input) = heredoc_body(input, delimiter)?;
     }
+
+    Some(input)
 }
 
 fn is_string_char(char: char) -> bool {
@@ -101,16 +231,16 @@ fn is_string_char(char: char) -> bool {
 }
 
 #[derive(PartialEq, Debug)]
-pub struct Token {
+pub struct Token<'a> {
     pub kind: TokenKind,
-    pub lexeme: String,
+    pub lexeme: &'a str,
 }
 
-impl Token {
+impl Token<'_> {
     fn eof() -> Self {
         Self {
             kind: TokenKind::EOF,
-            lexeme: String::new(),
+            lexeme: "",
         }
     }
 }
@@ -121,6 +251,7 @@ pub enum TokenKind {
     DoubleQuote,
     String,
     EscapeSequence,
+    Dollar,
     Whitespace,
     EOF,
 }
@@ -135,51 +266,83 @@ mod tests {
     #[case(r#"hello    world"#, vec![
         Token {
             kind: TokenKind::String,
-            lexeme: String::from("hello"),
+            lexeme: "hello",
         },
         Token {
             kind: TokenKind::Whitespace,
-            lexeme: String::from("    "),
+            lexeme: "    ",
         },
         Token {
             kind: TokenKind::String,
-            lexeme: String::from("world")
+            lexeme: "world"
         },
         Token {
             kind: TokenKind::EOF,
-            lexeme: String::new(),
+            lexeme: "",
         }
     ])]
     #[case(r#"'hello    world'"#, vec![
         Token {
             kind: TokenKind::SingleQuote,
-            lexeme: String::from("'")
+            lexeme: "'"
         },
         Token {
             kind: TokenKind::String,
-            lexeme: String::from("hello")
+            lexeme: "hello"
         },
         Token {
             kind: TokenKind::Whitespace,
-            lexeme: String::from("    ")
+            lexeme: "    "
         },
         Token {
             kind: TokenKind::String,
-            lexeme: String::from("world")
+            lexeme: "world"
         },
         Token {
             kind: TokenKind::SingleQuote,
-            lexeme: String::from("'")
+            lexeme: "'"
         },
         Token {
             kind: TokenKind::EOF,
-            lexeme: String::new(),
+            lexeme: "",
+        }
+    ])]
+    #[case(r"$HOME\", vec![
+        Token {
+            kind: TokenKind::Dollar,
+            lexeme: "$"
+        },
+        Token {
+            kind: TokenKind::String,
+            lexeme: "HOME"
+        },
+        Token {
+            kind: TokenKind::EscapeSequence,
+            lexeme: "\\"
+        },
+        Token {
+            kind: TokenKind::EOF,
+            lexeme: "",
+        }
+    ])]
+    #[case("héllo\\ü", vec![
+        Token {
+            kind: TokenKind::String,
+            lexeme: "héllo"
+        },
+        Token {
+            kind: TokenKind::EscapeSequence,
+            lexeme: "\\ü"
+        },
+        Token {
+            kind: TokenKind::EOF,
+            lexeme: ""
         }
     ])]
     #[case("", vec![
         Token {
             kind: TokenKind::EOF,
-            lexeme: String::from("")
+            lexeme: ""
         }
     ])]
     fn lexer_test(#[case] input: &str, #[case] expected_tokens: Vec<Token>) {
@@ -187,4 +350,38 @@ mod tests {
         let tokens = lexer.lex();
         assert_eq!(tokens, expected_tokens,);
     }
+
+    #[rstest]
+    #[case("echo hi", false)]
+    #[case("echo 'hi", true)]
+    #[case("echo \"it's", true)]
+    #[case("echo \"it's\"", false)]
+    #[case("echo 'a\nb'", false)]
+    #[case("ls |", true)]
+    #[case("ls |  \n", true)]
+    #[case("ls |\n wc", false)]
+    #[case("ls &&", true)]
+    #[case("echo a \\", true)]
+    #[case("echo a \\\nb", false)]
+    #[case("cat <<EOF", true)]
+    #[case("cat <<EOF\nit's\n", true)]
+    #[case("cat <<EOF\nit's\nEOF", false)]
+    #[case("cat <<-'END'\nbody\n\tEND\n", false)]
+    #[case("cat <<A <<B\na\nA\nb\n", true)]
+    #[case("cat <<A <<B\na\nA\nB\necho 'x", true)]
+    #[case("cat <<\"E F\"\nE\n", true)]
+    #[case("cat <<\"E F\"\nE F\n", false)]
+    fn is_incomplete_test(#[case] input: &str, #[case] expected: bool) {
+        assert_eq!(is_incomplete(input), expected);
+    }
+
+    #[rstest]
+    #[case("echo a", None)]
+    #[case("echo 'a", Some('\''))]
+    #[case("echo \"it's", Some('"'))]
+    #[case("echo 'a' \"b\"", None)]
+    #[case("echo \\\"", None)]
+    fn open_quote_test(#[case] input: &str, #[case] expected: Option<char>) {
+        assert_eq!(open_quote(input), expected);
+    }
 }
@@ -1,19 +1,130 @@
+pub mod audit;
 pub mod bin_path;
 pub mod completion;
+#[cfg(unix)]
+pub mod control;
+pub mod dir_history;
 pub mod editor;
+pub mod environment;
+pub mod getopt;
+pub mod glob;
+pub mod highlight;
+pub mod history;
+pub mod json;
+pub mod keybindings;
 pub mod lexer;
+pub mod line_editor;
+pub mod logging;
 pub mod macros;
+pub mod named_dirs;
+pub mod options;
 pub mod parser;
 pub mod pipeline;
+pub mod plugins;
+pub mod prompt;
+mod reactor;
+pub mod restricted;
 pub mod shell;
+pub mod spell;
+pub mod streams;
+pub mod terminal;
+pub mod tool_completion;
+pub mod transcript;
+pub mod traps;
+pub mod usage;
 
-pub static BUILTIN_COMMANDS: &[&str] = &["exit", "echo", "type", "pwd", "cd", "history"];
+pub static BUILTIN_COMMANDS: &[&str] = &[
+    "exit", "logout", "echo", "type", "pwd", "cd", "history", "which", "hash", "shopt", "set",
+    "export", "unset", "source", ".", "enable", "spawn", "trap", "exec", "j", "record",
+];
 
+/// Why running commands failed or stopped, for the REPL and embedders to
+/// tell apart without parsing messages.
 #[derive(thiserror::Error, Debug)]
-pub struct ExitError {}
+pub enum ShellError {
+    /// The input ended in the middle of a command, e.g. inside a quote.
+    #[error("syntax error: {message}")]
+    ParseError { message: String },
+    /// With the names of up to three commands it might be a typo of.
+    #[error("{name}: command not found{}", spell::did_you_mean(suggestions))]
+    CommandNotFound {
+        name: String,
+        suggestions: Vec<String>,
+    },
+    /// A variable that isn't set was expanded with `nounset` set.
+    #[error("{name}: unbound variable")]
+    Unbound { name: String },
+    /// A pattern matched no files with `failglob` set.
+    #[error("no match: {pattern}")]
+    NoMatch { pattern: String },
+    /// Something a shell started with `-r` doesn't allow, like `cd`.
+    #[error("{message}")]
+    Restricted { message: String },
+    /// A file named as a command exists but couldn't be run, e.g. for
+    /// lacking permission, or a script given to the shell couldn't be read.
+    #[error("{name}: {}", io_message(io))]
+    CannotExecute {
+        name: String,
+        #[source]
+        io: std::io::Error,
+    },
+    /// A file to redirect to or from couldn't be opened.
+    #[error("{path}: {}", io_message(io))]
+    RedirectError {
+        path: String,
+        #[source]
+        io: std::io::Error,
+    },
+    /// `exit` was run, with the status to exit with, or `None` for the one
+    /// of the last command.
+    #[error("exit")]
+    ExitRequested { code: Option<i32> },
+    #[error(transparent)]
+    Read(#[from] line_editor::ReadError),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl ShellError {
+    /// The exit status of a command that failed with this error.
+    pub fn status(&self) -> i32 {
+        match self {
+            ShellError::ParseError { .. } => 2,
+            ShellError::CommandNotFound { .. } => 127,
+            ShellError::CannotExecute { io, .. } if io.kind() == std::io::ErrorKind::NotFound => {
+                127
+            }
+            ShellError::CannotExecute { .. } => 126,
+            ShellError::ExitRequested { code } => code.unwrap_or(0),
+            _ => 1,
+        }
+    }
+}
+
+/// The message of `err` without the "(os error 2)" the standard library
+/// appends to the description of an OS error, like the shell's other
+/// messages.
+fn io_message(err: &std::io::Error) -> String {
+    let message = err.to_string();
+    match err.raw_os_error() {
+        Some(code) => message
+            .strip_suffix(&format!(" (os error {code})"))
+            .unwrap_or(&message)
+            .to_string(),
+        None => message,
+    }
+}
+
+impl From<anyhow::Error> for ShellError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<ShellError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
 
-impl std::fmt::Display for ExitError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("ExitError"))
+        match err.downcast::<line_editor::ReadError>() {
+            Ok(err) => ShellError::Read(err),
+            Err(err) => ShellError::Other(err),
+        }
     }
 }
@@ -0,0 +1,126 @@
+use crate::completion::CompletionProvider;
+use crate::history::{HistControl, ShellHistory};
+use crate::lexer;
+use crate::options::Options;
+use crate::prompt::Prompt;
+use std::error::Error;
+use std::io;
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Why no command could be read.
+#[derive(thiserror::Error, Debug)]
+pub enum ReadError {
+    /// The input ended, e.g. with Ctrl-D.
+    #[error("end of input")]
+    Eof,
+    /// Reading was cancelled with Ctrl-C.
+    #[error("interrupted")]
+    Interrupted,
+    /// Nothing was entered within $TMOUT seconds.
+    #[error("timed out waiting for input")]
+    TimedOut,
+    #[error(transparent)]
+    Backend(Box<dyn Error + Send + Sync>),
+}
+
+/// Where the shell reads its commands from, and keeps their history.
+/// [`crate::editor::Editor`] edits lines on a terminal, [`PlainReader`]
+/// reads them from a pipe or file.
+pub trait LineEditor {
+    /// Reads a whole command, prompting with `continuation_prompt` for every
+    /// line after the first while it stays incomplete.
+    fn readline(
+        &mut self,
+        prompt: &Prompt,
+        continuation_prompt: &Prompt,
+    ) -> Result<String, ReadError>;
+
+    /// Gives up reading a command with [`ReadError::TimedOut`] when it isn't
+    /// entered within `timeout`, if this editor can.
+    fn set_timeout(&mut self, _timeout: Option<Duration>) {}
+
+    /// Applies the options that affect line editing.
+    fn reconfigure(&mut self, _options: &Options) {}
+
+    /// Adds a provider completing the arguments of `command`, if this editor
+    /// completes at all.
+    fn register_completion(&mut self, _command: &str, _provider: Box<dyn CompletionProvider>) {}
+
+    fn history(&self) -> &ShellHistory;
+
+    fn history_mut(&mut self) -> &mut ShellHistory;
+
+    /// Records an entered line, unless `control` says to skip it.
+    fn add_history_entry(&mut self, line: &str, control: &HistControl) -> bool {
+        control.add(self.history_mut(), line)
+    }
+
+    /// The file $HISTFILE names now, if history is saved to one.
+    fn history_file(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Writes out the history of this session.
+    fn save_history(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Reads the entries other shells appended to the history file since it
+    /// was last read or written.
+    fn read_new_history(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads commands from stdin without prompting or line editing, for when
+/// it isn't a terminal. The history is kept only in memory.
+#[derive(Default)]
+pub struct PlainReader {
+    /// Where commands are read from instead of stdin.
+    input: Option<Box<dyn BufRead>>,
+    history: ShellHistory,
+}
+
+impl PlainReader {
+    pub fn new(input: Box<dyn BufRead>) -> Self {
+        Self {
+            input: Some(input),
+            history: ShellHistory::default(),
+        }
+    }
+}
+
+impl LineEditor for PlainReader {
+    fn readline(&mut self, _: &Prompt, _: &Prompt) -> Result<String, ReadError> {
+        let mut input = String::new();
+        loop {
+            let read = match &mut self.input {
+                Some(reader) => reader.read_line(&mut input),
+                None => io::stdin().read_line(&mut input),
+            };
+            let read = read.map_err(|err| ReadError::Backend(Box::new(err)))?;
+            if read == 0 {
+                if input.is_empty() {
+                    return Err(ReadError::Eof);
+                }
+                return Ok(input);
+            }
+
+            let command = input.strip_suffix('\n').unwrap_or(&input);
+            if !lexer::is_incomplete(command) {
+                input.truncate(command.len());
+                return Ok(input);
+            }
+        }
+    }
+
+    fn history(&self) -> &ShellHistory {
+        &self.history
+    }
+
+    fn history_mut(&mut self) -> &mut ShellHistory {
+        &mut self.history
+    }
+}
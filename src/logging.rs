@@ -0,0 +1,36 @@
+use std::fs::OpenOptions;
+use std::sync::Mutex;
+use std::{env, io};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+/// Logs what the shell does to stderr, or appended to `$SHELL_LOG_FILE`.
+/// `$SHELL_LOG` selects what is logged, as a filter like `debug` or
+/// `codecrafters_shell::bin_path=trace`; `debug` makes it default to `debug`.
+/// Without either, nothing is logged.
+///
+/// Lexing, parsing, PATH lookups, spawning and waiting for commands are
+/// spans, logged with how long they took when they close.
+pub fn init(debug: bool) -> io::Result<()> {
+    let filter = match env::var("SHELL_LOG") {
+        Ok(filter) => EnvFilter::new(filter),
+        Err(_) if debug => EnvFilter::new("debug"),
+        Err(_) => return Ok(()),
+    };
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+    match env::var_os("SHELL_LOG_FILE") {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            subscriber
+                .with_ansi(false)
+                .with_writer(Mutex::new(file))
+                .init();
+        }
+        None => subscriber.with_writer(io::stderr).init(),
+    }
+
+    Ok(())
+}
@@ -1,11 +1,13 @@
 #[macro_export]
 macro_rules! print_to {
     ($out:expr, $fmt:expr) => {{
+        #[allow(unused_imports)]
         use std::io::Write;
 
         $out.write_fmt(format_args!($fmt)).unwrap();
     }};
     ($out:expr, $fmt:expr, $($args:tt)*) => {{
+        #[allow(unused_imports)]
         use std::io::Write;
 
         $out.write_fmt(format_args!($fmt, $($args)*)).unwrap();
@@ -15,11 +17,23 @@ macro_rules! print_to {
 #[macro_export]
 macro_rules! print {
     ($fmt:expr) => {{
-        use std::io::Write;
+        let mut stdout = std::io::stdout();
+        $crate::print_to!(stdout, $fmt);
+    }};
+    ($fmt:expr, $($args:tt)*) => {{
+        let mut stdout = std::io::stdout();
+        $crate::print_to!(stdout, $fmt, $($args)*);
+    }};
+}
 
-        crate::print_to!(std::io::stdout(), $fmt);
+#[macro_export]
+macro_rules! eprint {
+    ($fmt:expr) => {{
+        let mut stderr = std::io::stderr();
+        $crate::print_to!(stderr, $fmt);
     }};
     ($fmt:expr, $($args:tt)*) => {{
-        crate::print_to!(std::io::stdout(), $fmt, $($args)*);
+        let mut stderr = std::io::stderr();
+        $crate::print_to!(stderr, $fmt, $($args)*);
     }};
 }
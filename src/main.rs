@@ -1,11 +1,162 @@
-use codecrafters_shell::shell::{contain, Shell};
-use codecrafters_shell::ExitError;
+use codecrafters_shell::audit::AuditLog;
+use codecrafters_shell::line_editor::ReadError;
+use codecrafters_shell::logging;
+use codecrafters_shell::shell::{self, Shell};
+use codecrafters_shell::{eprint, ShellError};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::{env, fs, io};
 
-fn main() -> anyhow::Result<()> {
-    let mut shell = Shell::new()?;
-    match shell.repl() {
-        Ok(_) => Ok(()),
-        Err(err) if contain::<ExitError>(err.chain()) => Ok(()),
-        Err(err) => Err(err.into()),
+/// Command line of the shell:
+/// `[-l] [-r] [-n] [-v] [--norc] [--debug] [-s] [-c command] [--control-socket path] [script]`.
+#[derive(Default)]
+struct Args {
+    /// Started as a login shell, with `-l` or a name starting with `-`
+    /// like `login` runs it with.
+    login: bool,
+    /// Restricted like rbash once the startup files ran.
+    restricted: bool,
+    /// Only check the syntax of the commands, without running them.
+    noexec: bool,
+    /// Print the input as it is read.
+    verbose: bool,
+    norc: bool,
+    /// Log at debug level unless $SHELL_LOG says otherwise.
+    debug: bool,
+    /// Read commands from stdin without prompting or line editing, even
+    /// when it is a terminal.
+    stdin: bool,
+    /// The commands to run instead of reading them from stdin.
+    command: Option<String>,
+    /// Where to serve clients' commands instead of reading them from stdin.
+    control_socket: Option<PathBuf>,
+    /// A file to run the commands of instead of reading them from stdin.
+    script: Option<PathBuf>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+        let mut parsed = Args {
+            login: args.next().is_some_and(|name| name.starts_with('-')),
+            ..Args::default()
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-l" | "--login" => parsed.login = true,
+                "-r" | "--restricted" => parsed.restricted = true,
+                "-n" => parsed.noexec = true,
+                "-v" => parsed.verbose = true,
+                "--norc" => parsed.norc = true,
+                "--debug" => parsed.debug = true,
+                "-s" => parsed.stdin = true,
+                "-c" => {
+                    let command = args.next().ok_or("-c: option requires an argument")?;
+                    parsed.command = Some(command);
+                }
+                "--control-socket" => {
+                    let path = args
+                        .next()
+                        .ok_or("--control-socket: option requires an argument")?;
+                    parsed.control_socket = Some(PathBuf::from(path));
+                }
+                _ if arg.starts_with('-') => return Err(format!("{arg}: invalid option")),
+                _ if parsed.script.is_some() => return Err(format!("{arg}: too many arguments")),
+                _ => parsed.script = Some(PathBuf::from(arg)),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    let args = match Args::parse(env::args()) {
+        Ok(args) => args,
+        Err(err) => {
+            eprint!("{err}\nusage: shell [-l] [-r] [-n] [-v] [--norc] [--debug] [-s] [-c command] [--control-socket path] [script]\n");
+            return Ok(ExitCode::from(2));
+        }
+    };
+    if let Err(err) = logging::init(args.debug) {
+        eprint!("SHELL_LOG_FILE: {err}\n");
+    }
+    if args.noexec {
+        return Ok(ExitCode::from(check_syntax(&args)));
+    }
+
+    let mut builder = Shell::builder().login(args.login).verbose(args.verbose);
+    if let Some(audit_log) = AuditLog::from_env() {
+        builder = builder.hook(audit_log);
+    }
+    let script = args.command.is_some() || args.script.is_some();
+    if script || args.stdin || args.control_socket.is_some() {
+        builder = builder.interactive(false);
     }
+    let mut shell = builder.build()?;
+
+    let result = shell.source_profile().and_then(|()| {
+        if !script && !args.norc {
+            shell.source_rc()?;
+        }
+        if args.restricted {
+            shell.restrict();
+        }
+        match (&args.command, &args.script, &args.control_socket) {
+            (Some(command), _, _) => shell.run_script(command),
+            (None, Some(path), _) => shell.run_file(path),
+            (None, None, Some(path)) => serve(&mut shell, path),
+            (None, None, None) => shell.repl(),
+        }
+    });
+    // end of input exits with the status of the last command, like bash
+    let status = match result {
+        Ok(()) | Err(ShellError::Read(ReadError::Eof | ReadError::TimedOut)) => shell.last_status(),
+        Err(ShellError::ExitRequested { code }) => code.unwrap_or(shell.last_status()),
+        Err(err) => {
+            eprint!("{err}\n");
+            err.status()
+        }
+    };
+    Ok(ExitCode::from(status as u8))
+}
+
+/// Reports the syntax errors of the commands `args` would run, with their
+/// line numbers, and returns the status to exit with: 2 if there were any.
+fn check_syntax(args: &Args) -> u8 {
+    let (name, script) = match (&args.command, &args.script) {
+        (Some(command), _) => (String::from("-c"), Ok(command.clone())),
+        (None, Some(path)) => (path.display().to_string(), fs::read_to_string(path)),
+        (None, None) => (String::from("stdin"), io::read_to_string(io::stdin())),
+    };
+    let script = match script {
+        Ok(script) => script,
+        Err(io) => {
+            let err = ShellError::CannotExecute { name, io };
+            eprint!("{err}\n");
+            return err.status() as u8;
+        }
+    };
+
+    let errors = shell::syntax_errors(&script);
+    for (line, err) in &errors {
+        eprint!("{name}: line {line}: {err}\n");
+    }
+    if errors.is_empty() {
+        0
+    } else {
+        2
+    }
+}
+
+#[cfg(unix)]
+fn serve(shell: &mut Shell, path: &Path) -> Result<(), ShellError> {
+    codecrafters_shell::control::serve(shell, path)
+        .map_err(|err| ShellError::Other(anyhow::anyhow!("{}: {err}", path.display())))
+}
+
+#[cfg(not(unix))]
+fn serve(_shell: &mut Shell, _path: &Path) -> Result<(), ShellError> {
+    Err(ShellError::Other(anyhow::anyhow!(
+        "--control-socket: unix sockets are not supported"
+    )))
 }
@@ -0,0 +1,217 @@
+use crate::dir_history::DirHistory;
+use crate::parser::Expander;
+use indexmap::IndexMap;
+use std::path::{Path, PathBuf};
+use std::{env, mem};
+
+/// zsh-style named directories, defined with `hash -d name=dir` and
+/// referenced as `~name`, and the directories visited with `cd`.
+#[derive(Default)]
+pub struct NamedDirs {
+    dirs: IndexMap<String, PathBuf>,
+    visited: DirHistory,
+    /// Whether $CHPWD_COMMAND is running.
+    in_chpwd: bool,
+}
+
+impl NamedDirs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, name: &str, dir: impl Into<PathBuf>) {
+        self.dirs.insert(name.to_string(), dir.into());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Path> {
+        self.dirs.get(name).map(PathBuf::as_path)
+    }
+
+    pub fn visited(&self) -> &DirHistory {
+        &self.visited
+    }
+
+    pub fn visited_mut(&mut self) -> &mut DirHistory {
+        &mut self.visited
+    }
+
+    /// Marks $CHPWD_COMMAND as running until [`NamedDirs::finish_chpwd`].
+    /// False while it runs already, for a `cd` in it not to run it again.
+    pub(crate) fn start_chpwd(&mut self) -> bool {
+        !mem::replace(&mut self.in_chpwd, true)
+    }
+
+    pub(crate) fn finish_chpwd(&mut self) {
+        self.in_chpwd = false;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Path)> {
+        self.dirs
+            .iter()
+            .map(|(name, dir)| (name.as_str(), dir.as_path()))
+    }
+
+    /// Replaces the longest matching directory prefix of `path` with `~name`,
+    /// or `~` for the home directory.
+    pub fn abbreviate(&self, path: &Path) -> String {
+        let home = home_dir();
+        let candidates = self
+            .iter()
+            .map(|(name, dir)| (name, dir.to_path_buf()))
+            .chain(home.map(|home| ("", home)));
+
+        let best = candidates
+            .filter(|(_, dir)| path.starts_with(dir))
+            .max_by_key(|(_, dir)| dir.components().count());
+
+        match best {
+            Some((name, dir)) => {
+                let rest = path.strip_prefix(&dir).unwrap();
+                if rest.as_os_str().is_empty() {
+                    format!("~{name}")
+                } else {
+                    format!("~{name}/{}", rest.display())
+                }
+            }
+            None => path.display().to_string(),
+        }
+    }
+}
+
+/// Directories listed in $CDPATH, searched by `cd` for relative names. An
+/// empty entry stands for the current directory.
+pub fn cdpath() -> Vec<PathBuf> {
+    let Some(cdpath) = env::var_os("CDPATH") else {
+        return Vec::new();
+    };
+
+    env::split_paths(&cdpath)
+        .map(|dir| {
+            if dir.as_os_str().is_empty() {
+                PathBuf::from(".")
+            } else {
+                dir
+            }
+        })
+        .collect()
+}
+
+impl Expander for NamedDirs {
+    fn expand_tilde(&self, prefix: &str) -> Option<String> {
+        if prefix.is_empty() {
+            return home_dir().map(|dir| dir.display().to_string());
+        }
+
+        match self.get(prefix) {
+            Some(dir) => Some(dir.display().to_string()),
+            None => user_home(prefix).map(|dir| dir.display().to_string()),
+        }
+    }
+}
+
+/// $HOME, or when it isn't set, the home directory of the user running the
+/// shell in the password database, like bash expands `~`.
+pub fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME").map(PathBuf::from).or_else(own_home)
+}
+
+/// The home directory of the user running the shell, from the password
+/// database.
+#[cfg(unix)]
+pub(crate) fn own_home() -> Option<PathBuf> {
+    use std::ffi::{CStr, OsStr};
+    use std::os::unix::ffi::OsStrExt;
+
+    // SAFETY: getpwuid returns null or a pointer to a static passwd entry,
+    // read before any other call could overwrite it.
+    unsafe {
+        let passwd = libc::getpwuid(libc::getuid());
+        if passwd.is_null() {
+            return None;
+        }
+        let dir = CStr::from_ptr((*passwd).pw_dir);
+        Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())))
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn own_home() -> Option<PathBuf> {
+    None
+}
+
+/// The home directory of the user `name`, for `~name`.
+#[cfg(unix)]
+pub(crate) fn user_home(name: &str) -> Option<PathBuf> {
+    use std::ffi::{CStr, CString, OsStr};
+    use std::os::unix::ffi::OsStrExt;
+
+    let name = CString::new(name).ok()?;
+    // SAFETY: getpwnam returns null or a pointer to a static passwd entry,
+    // read before any other call could overwrite it.
+    unsafe {
+        let passwd = libc::getpwnam(name.as_ptr());
+        if passwd.is_null() {
+            return None;
+        }
+        let dir = CStr::from_ptr((*passwd).pw_dir);
+        Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())))
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn user_home(_name: &str) -> Option<PathBuf> {
+    None
+}
+
+/// The names of all users in the password database.
+#[cfg(unix)]
+pub fn user_names() -> Vec<String> {
+    use std::ffi::CStr;
+
+    let mut names = Vec::new();
+    // SAFETY: each entry is copied out before getpwent is called again, and
+    // the enumeration is closed before returning.
+    unsafe {
+        libc::setpwent();
+        loop {
+            let passwd = libc::getpwent();
+            if passwd.is_null() {
+                break;
+            }
+            names.push(
+                CStr::from_ptr((*passwd).pw_name)
+                    .to_string_lossy()
+                    .into_owned(),
+            );
+        }
+        libc::endpwent();
+    }
+
+    names
+}
+
+#[cfg(not(unix))]
+pub fn user_names() -> Vec<String> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("/srv/project", "~proj")]
+    #[case("/srv/project/src/lexer", "~proj/src/lexer")]
+    #[case("/srv/project/src/ui", "~ui")]
+    #[case("/srv/projects", "/srv/projects")]
+    #[case("/etc", "/etc")]
+    fn abbreviate_test(#[case] path: &str, #[case] expected: &str) {
+        let mut named_dirs = NamedDirs::new();
+        named_dirs.insert("proj", "/srv/project");
+        named_dirs.insert("ui", "/srv/project/src/ui");
+
+        assert_eq!(named_dirs.abbreviate(Path::new(path)), expected);
+    }
+}
@@ -0,0 +1,309 @@
+use indexmap::IndexMap;
+
+static SHOPT_OPTIONS: &[&str] = &[
+    "auto_pairs",
+    "autocd",
+    "autols",
+    "cdspell",
+    "color_stderr",
+    "confirm_paste",
+    "dotglob",
+    "failglob",
+    "fuzzy_completion",
+    "history_substring_search",
+    "inc_append_history",
+    "lithist",
+    "login_shell",
+    "menu_complete",
+    "nocase_completion",
+    "nullglob",
+    "posix_spawn",
+    "restricted_shell",
+    "term_integration",
+];
+
+static SET_OPTIONS: &[&str] = &[
+    "allexport",
+    "emacs",
+    "errexit",
+    "ignoreeof",
+    "noclobber",
+    "noglob",
+    "nounset",
+    "pipefail",
+    "posix",
+    "verbose",
+    "vi",
+    "xtrace",
+];
+
+/// The `set` options that also have a flag of their own, like `set -e`.
+static SET_FLAGS: &[(char, &str)] = &[
+    ('a', "allexport"),
+    ('C', "noclobber"),
+    ('e', "errexit"),
+    ('f', "noglob"),
+    ('u', "nounset"),
+    ('v', "verbose"),
+    ('x', "xtrace"),
+];
+
+/// Shell options toggled with `shopt -s` / `shopt -u` and `set -o` / `set +o`.
+pub struct Options {
+    shopt: IndexMap<&'static str, bool>,
+    set: IndexMap<&'static str, bool>,
+    /// Whether the shell prompts for commands, which no option changes.
+    interactive: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self {
+            shopt: SHOPT_OPTIONS.iter().map(|&name| (name, false)).collect(),
+            set: SET_OPTIONS
+                .iter()
+                .map(|&name| (name, name == "emacs"))
+                .collect(),
+            interactive: false,
+        }
+    }
+
+    /// The shell prompts for commands rather than running a script.
+    pub fn interactive(&self) -> bool {
+        self.interactive
+    }
+
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+
+    pub fn shopt(&self, name: &str) -> Option<bool> {
+        self.shopt.get(name).copied()
+    }
+
+    /// Options describing how the shell was started, which `shopt` can only
+    /// show.
+    pub fn is_read_only(name: &str) -> bool {
+        matches!(name, "login_shell" | "restricted_shell")
+    }
+
+    /// Returns `false` when `name` is not a known option.
+    pub fn set_shopt(&mut self, name: &str, value: bool) -> bool {
+        match self.shopt.get_mut(name) {
+            Some(option) => {
+                *option = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn shopts(&self) -> impl Iterator<Item = (&'static str, bool)> + '_ {
+        self.shopt.iter().map(|(&name, &value)| (name, value))
+    }
+
+    pub fn option(&self, name: &str) -> Option<bool> {
+        self.set.get(name).copied()
+    }
+
+    /// Returns `false` when `name` is not a known option. The `emacs` and
+    /// `vi` editing modes exclude each other, and leaving vi mode goes back
+    /// to emacs.
+    pub fn set_option(&mut self, name: &str, value: bool) -> bool {
+        let Some(option) = self.set.get_mut(name) else {
+            return false;
+        };
+        *option = value;
+
+        match (name, value) {
+            ("emacs", true) => self.set["vi"] = false,
+            ("vi", true) => self.set["emacs"] = false,
+            ("vi", false) => self.set["emacs"] = true,
+            _ => {}
+        }
+
+        true
+    }
+
+    pub fn options(&self) -> impl Iterator<Item = (&'static str, bool)> + '_ {
+        self.set.iter().map(|(&name, &value)| (name, value))
+    }
+
+    /// The option set by `set -flag`, e.g. `errexit` for `e`.
+    pub fn flag_option(flag: char) -> Option<&'static str> {
+        SET_FLAGS
+            .iter()
+            .find(|&&(option_flag, _)| option_flag == flag)
+            .map(|&(_, name)| name)
+    }
+
+    /// Variables assigned are exported.
+    pub fn allexport(&self) -> bool {
+        self.option("allexport").unwrap()
+    }
+
+    /// The shell exits as soon as a command fails.
+    pub fn errexit(&self) -> bool {
+        self.option("errexit").unwrap()
+    }
+
+    /// `>` doesn't overwrite an existing file, only `>|` does.
+    pub fn noclobber(&self) -> bool {
+        self.option("noclobber").unwrap()
+    }
+
+    /// Patterns aren't expanded to the files they match.
+    pub fn noglob(&self) -> bool {
+        self.option("noglob").unwrap()
+    }
+
+    /// Expanding a variable that isn't set is an error, which ends a shell
+    /// that isn't interactive.
+    pub fn nounset(&self) -> bool {
+        self.option("nounset").unwrap()
+    }
+
+    /// A pipeline fails with the status of its last command that failed,
+    /// rather than with the status of its last command.
+    pub fn pipefail(&self) -> bool {
+        self.option("pipefail").unwrap()
+    }
+
+    /// Input is printed to stderr as it is read, before anything in it is
+    /// expanded.
+    pub fn verbose(&self) -> bool {
+        self.option("verbose").unwrap()
+    }
+
+    /// Commands are printed to stderr, after $PS4, before they run.
+    pub fn xtrace(&self) -> bool {
+        self.option("xtrace").unwrap()
+    }
+
+    pub fn vi(&self) -> bool {
+        self.option("vi").unwrap()
+    }
+
+    /// Ctrl-D doesn't exit an interactive shell, only `exit` does.
+    pub fn ignoreeof(&self) -> bool {
+        self.option("ignoreeof").unwrap()
+    }
+
+    /// Stick to POSIX where bash-isms would behave differently, on from the
+    /// start when $POSIXLY_CORRECT is set. Overrides `autocd` and `cdspell`,
+    /// and changes tilde expansion, `.` and special builtin assignments.
+    pub fn posix(&self) -> bool {
+        self.option("posix").unwrap()
+    }
+
+    /// Typing a quote, `(` or `{` also inserts the one closing it, typing
+    /// that then moves over it, and Backspace deletes an empty pair. Off
+    /// with `menu_complete`, as the pairs are made through completion.
+    pub fn auto_pairs(&self) -> bool {
+        self.shopt("auto_pairs").unwrap() && !self.menu_complete()
+    }
+
+    /// Off in posix mode, and in restricted shells as it would change
+    /// directories.
+    pub fn autocd(&self) -> bool {
+        self.shopt("autocd").unwrap() && !self.posix() && !self.restricted_shell()
+    }
+
+    /// List the directory `cd` went to with `ls`, unless $CHPWD_COMMAND
+    /// says what to run instead.
+    pub fn autols(&self) -> bool {
+        self.shopt("autols").unwrap()
+    }
+
+    /// Off in posix mode.
+    pub fn cdspell(&self) -> bool {
+        self.shopt("cdspell").unwrap() && !self.posix()
+    }
+
+    /// What commands print on stderr is shown in red, when that is the
+    /// terminal.
+    pub fn color_stderr(&self) -> bool {
+        self.shopt("color_stderr").unwrap()
+    }
+
+    /// Ask before running pasted text holding control characters, which
+    /// could make it do something else than it seems to.
+    pub fn confirm_paste(&self) -> bool {
+        self.shopt("confirm_paste").unwrap()
+    }
+
+    /// Patterns match files starting with `.` without spelling out the dot.
+    pub fn dotglob(&self) -> bool {
+        self.shopt("dotglob").unwrap()
+    }
+
+    /// A pattern matching no files fails the command, before `nullglob`.
+    pub fn failglob(&self) -> bool {
+        self.shopt("failglob").unwrap()
+    }
+
+    pub fn fuzzy_completion(&self) -> bool {
+        self.shopt("fuzzy_completion").unwrap()
+    }
+
+    /// Up and Down search history for the entries containing what was
+    /// typed, rather than starting with it.
+    pub fn history_substring_search(&self) -> bool {
+        self.shopt("history_substring_search").unwrap()
+    }
+
+    /// Append every command to the history file as soon as it is entered,
+    /// instead of on exit.
+    pub fn inc_append_history(&self) -> bool {
+        self.shopt("inc_append_history").unwrap()
+    }
+
+    /// Keep the newlines of a multi-line command in its history entry,
+    /// rather than joining its lines with `;` or spaces.
+    pub fn lithist(&self) -> bool {
+        self.shopt("lithist").unwrap()
+    }
+
+    /// Started as a login shell, so `logout` works.
+    pub fn login_shell(&self) -> bool {
+        self.shopt("login_shell").unwrap()
+    }
+
+    /// Started with `-r`, which rules out `cd`, assigning PATH and more.
+    pub fn restricted_shell(&self) -> bool {
+        self.shopt("restricted_shell").unwrap()
+    }
+
+    /// Tab cycles through ambiguous completions in place, rather than
+    /// completing their common prefix and listing them on the second Tab.
+    pub fn menu_complete(&self) -> bool {
+        self.shopt("menu_complete").unwrap()
+    }
+
+    pub fn nocase_completion(&self) -> bool {
+        self.shopt("nocase_completion").unwrap()
+    }
+
+    /// A pattern matching no files is removed, rather than kept as it is.
+    pub fn nullglob(&self) -> bool {
+        self.shopt("nullglob").unwrap()
+    }
+
+    /// Start external commands with posix_spawn(3) itself rather than
+    /// through std::process, which may fork. Only on unix.
+    pub fn posix_spawn(&self) -> bool {
+        self.shopt("posix_spawn").unwrap()
+    }
+
+    /// Show the running command in the terminal title and report the
+    /// working directory to the terminal.
+    pub fn term_integration(&self) -> bool {
+        self.shopt("term_integration").unwrap()
+    }
+}
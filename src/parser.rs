@@ -1,20 +1,64 @@
-use crate::lexer::{Lexer, Token, TokenKind};
-use std::io::Write;
+use crate::lexer::{self, Lexer, Token, TokenKind};
+use crate::streams::{Input, Output, Redirections, Streams, Target};
+use crate::{glob, ShellError};
+use std::borrow::Cow;
 use std::{fs, io, mem};
 
-pub struct Parser {
-    input: Vec<Token>,
+/// Supplies the shell state needed for word expansions.
+pub trait Expander {
+    /// Expands `~prefix` where `prefix` is empty for a bare `~`.
+    fn expand_tilde(&self, _prefix: &str) -> Option<String> {
+        None
+    }
+
+    /// The value of the variable `name`, `None` if it is unset.
+    fn variable(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// Whether expanding an unset variable is an error, as with `set -u`.
+    fn nounset(&self) -> bool {
+        false
+    }
+
+    /// The words an unquoted `pattern` like `*.rs` expands to: `None` keeps
+    /// it as it is, and an empty list removes it. Quoted characters are
+    /// escaped with `\` in it.
+    fn glob(&self, _pattern: &str) -> Result<Option<Vec<String>>, ShellError> {
+        Ok(None)
+    }
+}
+
+pub struct Parser<'a> {
+    expander: Option<&'a dyn Expander>,
+    source: &'a str,
+    input: Vec<Token<'a>>,
     argument_buffer: String,
     position: usize,
     quotes: Vec<TokenKind>,
     args: Vec<String>,
     redirects: Vec<Redirect>,
+    assignments: Vec<(String, String)>,
+    /// Whether the word being read is a `name=value` assignment.
+    in_assignment: bool,
+    /// Whether the word being read is the target of a redirection.
+    in_redirect: bool,
+    /// The offsets in the word being read of unquoted `*`, `?` and `[`.
+    globs: Vec<usize>,
+    /// Where the body of the next here-document on the line being read
+    /// starts, after the ones before it, once there is one.
+    heredoc_start: Option<usize>,
+    /// The first error found.
+    error: Option<ShellError>,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Default, PartialEq, Debug)]
 pub struct Command {
     pub(crate) args: Vec<String>,
     pub(crate) redirects: Vec<Redirect>,
+    /// The `name=value` words before the command name. Without a command
+    /// they set shell variables, otherwise only the command's environment.
+    pub(crate) assignments: Vec<(String, String)>,
 }
 
 impl Command {
@@ -22,64 +66,144 @@ impl Command {
         Self {
             args: args.into_iter().map(String::from).collect(),
             redirects,
+            assignments: Vec::new(),
         }
     }
 
-    pub(crate) fn output(&self) -> Option<&Redirect> {
-        self.redirects
-            .iter()
-            .find(|r| r.from == OutputStream::Stdout)
-    }
-
-    pub(crate) fn get_output(&self) -> io::Result<Box<dyn Write + Send>> {
-        let Some(redirect) = self.output() else {
-            return Ok(Box::new(io::stdout()));
-        };
-
-        let file = redirect.open_output()?;
-        Ok(Box::new(file))
+    /// Empties the command, keeping the storage for parsing another one into.
+    pub(crate) fn clear(&mut self) {
+        self.args.clear();
+        self.redirects.clear();
+        self.assignments.clear();
     }
 
-    pub(crate) fn errors(&self) -> Option<&Redirect> {
+    /// The command its output is piped into.
+    pub(crate) fn pipe(&self) -> Option<&Command> {
         self.redirects
             .iter()
-            .find(|r| r.from == OutputStream::Stderr)
+            .find_map(|redirect| match &redirect.to {
+                OutputStream::Pipe(command) => Some(command),
+                _ => None,
+            })
     }
 
-    pub(crate) fn get_error_output(&self) -> io::Result<Box<dyn Write + Send>> {
-        let Some(redirect) = self.errors() else {
-            return Ok(Box::new(io::stderr()));
+    /// Applies its redirections from left to right, like bash does before
+    /// it even looks the command up: every file is opened, and created or
+    /// truncated, a later redirection of a stream replaces an earlier one,
+    /// and `2>&1` sends stderr where stdout goes at that point. Without
+    /// any, its output goes into its pipe or to `streams`. With `noclobber`,
+    /// `>` refuses to truncate an existing file.
+    pub(crate) fn open_redirects(
+        &self,
+        streams: &Streams,
+        noclobber: bool,
+    ) -> Result<Redirections, ShellError> {
+        let stdout = match self.pipe() {
+            Some(_) => Target::Pipe,
+            None => Target::Output(Output::Sink(streams.stdout.clone())),
         };
+        let mut redirections = Redirections {
+            stdin: None,
+            stdout,
+            stderr: Target::Output(Output::Sink(streams.stderr.clone())),
+            shared: false,
+        };
+
+        for redirect in &self.redirects {
+            let target = match (&redirect.from, &redirect.to) {
+                (_, OutputStream::Pipe(_)) => continue,
+                (OutputStream::Stdin, OutputStream::File(_)) => {
+                    redirections.stdin = Some(Input::File(redirect.open(noclobber)?));
+                    continue;
+                }
+                (_, OutputStream::HereDoc(heredoc)) => {
+                    redirections.stdin = Some(Input::Text(heredoc.body.clone()));
+                    continue;
+                }
+                (_, OutputStream::File(_)) => {
+                    Target::Output(Output::File(redirect.open(noclobber)?))
+                }
+                (_, OutputStream::Stdout) => redirections.stdout.try_clone().map_err(|io| {
+                    let path = String::from("1");
+                    ShellError::RedirectError { path, io }
+                })?,
+                (_, OutputStream::Stderr) => redirections.stderr.try_clone().map_err(|io| {
+                    let path = String::from("2");
+                    ShellError::RedirectError { path, io }
+                })?,
+                (_, OutputStream::Stdin) => unreachable!("input is never duplicated"),
+            };
 
-        let file = redirect.open_output()?;
-        Ok(Box::new(file))
+            redirections.shared =
+                matches!(redirect.to, OutputStream::Stdout | OutputStream::Stderr);
+            match redirect.from {
+                OutputStream::Stderr => redirections.stderr = target,
+                _ => redirections.stdout = target,
+            }
+        }
+
+        Ok(redirections)
     }
 }
 
-impl Parser {
-    pub fn new(input: &str) -> Self {
+impl<'a> Parser<'a> {
+    pub fn new(input: &'a str) -> Self {
         Self {
+            expander: None,
+            source: input,
             input: Lexer::new(input).lex(),
             argument_buffer: String::new(),
             position: 0,
             quotes: Vec::with_capacity(1),
             args: Vec::new(),
             redirects: Vec::new(),
+            assignments: Vec::new(),
+            in_assignment: false,
+            in_redirect: false,
+            globs: Vec::new(),
+            heredoc_start: None,
+            error: None,
         }
     }
 
-    pub fn parse(&mut self) -> Command {
+    pub fn with_expander(mut self, expander: &'a dyn Expander) -> Self {
+        self.expander = Some(expander);
+        self
+    }
+
+    pub fn parse(&mut self) -> Result<Command, ShellError> {
+        let mut command = Command::default();
+        self.parse_into(&mut command)?;
+        Ok(command)
+    }
+
+    /// Parses into `command`, reusing the storage of its arguments and
+    /// redirections instead of allocating new ones.
+    pub fn parse_into(&mut self, command: &mut Command) -> Result<(), ShellError> {
+        let _span = tracing::debug_span!("parse", tokens = self.input.len()).entered();
+        command.clear();
+        mem::swap(&mut self.args, &mut command.args);
+        mem::swap(&mut self.redirects, &mut command.redirects);
+        mem::swap(&mut self.assignments, &mut command.assignments);
+
         while !self.is_eof() {
             self.process_next_lexeme();
         }
 
-        self.current_command()
+        *command = self.current_command();
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        tracing::debug!(?command, "parsed");
+        Ok(())
     }
 
     fn current_command(&mut self) -> Command {
         Command {
             args: mem::take(&mut self.args),
             redirects: mem::take(&mut self.redirects),
+            assignments: mem::take(&mut self.assignments),
         }
     }
 
@@ -101,12 +225,13 @@ impl Parser {
             TokenKind::DoubleQuote => self.handle_double_quote(),
             TokenKind::String => self.handle_string(),
             TokenKind::EscapeSequence => self.handle_escape_sequence(),
+            TokenKind::Dollar => self.handle_dollar(),
             TokenKind::Whitespace => self.handle_whitespace(),
             TokenKind::EOF => self.handle_eof(),
         }
     }
 
-    fn current_token(&self) -> &Token {
+    fn current_token(&self) -> &Token<'a> {
         &self.input[self.position]
     }
 
@@ -143,21 +268,74 @@ impl Parser {
     }
 
     fn handle_string(&mut self) -> Option<String> {
-        let lexeme = self.current_token().lexeme.clone();
-        if lexeme == "|" {
+        let lexeme = self.current_token().lexeme;
+        if lexeme == "|" && self.quotes.is_empty() {
             self.handle_pipe()
-        } else if lexeme.contains('>') {
-            self.handle_redirect()
+        } else if lexeme.starts_with("<<") && !lexeme.starts_with("<<<") && self.quotes.is_empty() {
+            self.handle_heredoc()
+        } else if let Some(operator) = redirect_operator(lexeme)
+            && self.quotes.is_empty()
+        {
+            self.handle_redirect(operator)
+        } else if self.args.is_empty() && self.at_word_start() && is_assignment(lexeme) {
+            self.in_assignment = true;
+            self.argument_buffer.push_str(lexeme)
+        } else if lexeme.starts_with('~') && self.at_word_start() {
+            let expanded = self.expand_tilde(lexeme);
+            // only what follows an expanded directory is a pattern
+            let literal = match expanded {
+                Cow::Owned(_) => {
+                    expanded.len() - (lexeme.len() - lexeme.find('/').unwrap_or(lexeme.len()))
+                }
+                Cow::Borrowed(_) => 0,
+            };
+            self.argument_buffer.push_str(&expanded[..literal]);
+            self.push_unquoted(&expanded[literal..]);
+        } else if self.quotes.is_empty() && !self.in_assignment {
+            self.push_unquoted(lexeme)
         } else {
-            self.argument_buffer.push_str(&lexeme)
+            self.argument_buffer.push_str(lexeme)
         }
 
         None
     }
 
+    fn at_word_start(&self) -> bool {
+        self.argument_buffer.is_empty()
+            && self.quotes.is_empty()
+            && (self.position == 0 || self.input[self.position - 1].kind == TokenKind::Whitespace)
+    }
+
+    fn expand_tilde(&self, lexeme: &'a str) -> Cow<'a, str> {
+        let (prefix, rest) = match lexeme.find('/') {
+            Some(slash) => lexeme.split_at(slash),
+            None => {
+                // `~"name"`: a quoted tilde-prefix is not expanded
+                let next = &self.input[self.position + 1];
+                if !matches!(next.kind, TokenKind::Whitespace | TokenKind::EOF) {
+                    return Cow::Borrowed(lexeme);
+                }
+
+                (lexeme, "")
+            }
+        };
+
+        match self.expander.and_then(|e| e.expand_tilde(&prefix[1..])) {
+            Some(dir) => Cow::Owned(dir + rest),
+            None => Cow::Borrowed(lexeme),
+        }
+    }
+
     fn handle_pipe(&mut self) {
+        if self.args.is_empty() && self.redirects.is_empty() && self.assignments.is_empty() {
+            self.error.get_or_insert_with(|| ShellError::ParseError {
+                message: String::from("unexpected token `|'"),
+            });
+        }
+
         let args = mem::take(&mut self.args);
         let mut redirects = mem::take(&mut self.redirects);
+        let assignments = mem::take(&mut self.assignments);
 
         self.position += 1;
         while !self.is_eof() {
@@ -169,56 +347,212 @@ impl Parser {
 
         self.args = args;
         self.redirects = redirects;
+        self.assignments = assignments;
     }
 
-    //TODO: return errors
-    fn handle_redirect(&mut self) {
-        let token = self.current_token();
-        let mut chars = token.lexeme.chars().peekable();
+    /// `[n]>file`, `[n]>>file`, `[n]<file`, `[n]>&m` and `&>file`, with the
+    /// `<` or `>` at `operator` in the current lexeme. What comes before it
+    /// is the number of the stream redirected, or else the end of a word.
+    fn handle_redirect(&mut self, operator: usize) {
+        let lexeme = self.current_token().lexeme;
+        let (prefix, operator) = lexeme.split_at(operator);
+        let is_fd =
+            prefix == "&" || !prefix.is_empty() && prefix.bytes().all(|b| b.is_ascii_digit());
+        let fd = if is_fd && self.argument_buffer.is_empty() {
+            prefix
+        } else {
+            self.push_unquoted(prefix);
+            ""
+        };
+        if let Some(word) = self.flush_buf() {
+            self.args.push(word);
+        }
 
-        let mut from = OutputStream::default();
-        match chars.peek().unwrap() {
-            '1' => {
-                from = OutputStream::Stdout;
-                chars.next();
+        let (redirect_type, remaining) = if let Some(rest) = operator.strip_prefix(">>") {
+            (RedirectType::Append, rest)
+        } else if let Some(rest) = operator.strip_prefix(">|") {
+            (RedirectType::Clobber, rest)
+        } else if let Some(rest) = operator.strip_prefix('>') {
+            (RedirectType::Overwrite, rest)
+        } else {
+            (RedirectType::Read, &operator[1..])
+        };
+        let from = match (fd, &redirect_type) {
+            ("" | "0", RedirectType::Read) => OutputStream::Stdin,
+            (
+                "" | "1" | "&",
+                RedirectType::Overwrite | RedirectType::Append | RedirectType::Clobber,
+            ) => OutputStream::Stdout,
+            ("2", RedirectType::Overwrite | RedirectType::Append | RedirectType::Clobber) => {
+                OutputStream::Stderr
             }
-            '2' => {
-                from = OutputStream::Stderr;
-                chars.next();
+            _ => return self.bad_file_descriptor(fd),
+        };
+
+        let to = match remaining.strip_prefix('&') {
+            Some("1") if redirect_type == RedirectType::Overwrite => OutputStream::Stdout,
+            Some("2") if redirect_type == RedirectType::Overwrite => OutputStream::Stderr,
+            Some(fd) => return self.bad_file_descriptor(fd),
+            None => {
+                self.argument_buffer.push_str(remaining);
+                self.position += 1;
+                self.in_redirect = true;
+                let path = self.next_string();
+                self.in_redirect = false;
+                OutputStream::File(path)
             }
-            _ => {}
+        };
+
+        self.redirects.push(Redirect {
+            from,
+            redirect_type,
+            to,
+        });
+        if fd == "&" {
+            self.redirects.push(Redirect {
+                from: OutputStream::Stderr,
+                redirect_type: RedirectType::Overwrite,
+                to: OutputStream::Stdout,
+            });
         }
+    }
 
-        let next = chars.next().unwrap();
-        let redirect_type: RedirectType;
-        if next == '>' {
-            redirect_type = if chars.peek() != None && chars.peek().unwrap() == &'>' {
-                chars.next();
-                RedirectType::Append
-            } else {
-                RedirectType::Overwrite
-            }
-        } else {
-            panic!("unexpected char '{next:?}' while waiting for '>'")
+    /// `<<word` reads the lines after the one of the command, up to a line
+    /// of just `word`, as its input. `<<-word` strips leading tabs from them,
+    /// and quoting any of `word` leaves them unexpanded.
+    fn handle_heredoc(&mut self) {
+        let operator = &self.current_token().lexeme[2..];
+        let delimiter = lexer::delimiter(operator, &self.input[self.position + 1..]);
+        if let Some(word) = self.flush_buf() {
+            self.args.push(word);
         }
+        self.position += delimiter.tokens;
 
-        let remaining = chars.collect::<String>();
-        let to = OutputStream::File(if remaining.len() == 0 {
-            self.position += 1;
-            self.next_string()
+        let start = match self.heredoc_start {
+            Some(start) => start,
+            None => self.line_end(),
+        };
+        let Some((body, rest)) = lexer::heredoc_body(&self.source[start..], &delimiter) else {
+            self.error.get_or_insert_with(|| ShellError::ParseError {
+                message: format!("here-document not ended by `{}'", delimiter.word),
+            });
+            return;
+        };
+        self.heredoc_start = Some(self.source.len() - rest.len());
+
+        let body = if delimiter.quoted {
+            body
         } else {
-            self.argument_buffer.push_str(&remaining);
-            self.position += 1;
-            self.next_string()
+            self.expand_heredoc(&body)
+        };
+        self.redirects.push(Redirect {
+            from: OutputStream::Stdin,
+            redirect_type: RedirectType::Read,
+            to: OutputStream::HereDoc(HereDoc {
+                body,
+                strip_tabs: delimiter.strip_tabs,
+                quoted: delimiter.quoted,
+            }),
         });
+    }
 
-        self.redirects.push(Redirect {
-            from,
-            redirect_type,
-            to,
-        })
+    /// The offset of the start of the next line, after the unquoted newline
+    /// ending the current one.
+    fn line_end(&self) -> usize {
+        let mut offset = self.offset(self.position + 1);
+        let mut quote = None;
+        for token in &self.input[self.position + 1..] {
+            match (&token.kind, quote) {
+                (TokenKind::SingleQuote | TokenKind::DoubleQuote, Some(open))
+                    if open == &token.kind =>
+                {
+                    quote = None
+                }
+                (TokenKind::SingleQuote | TokenKind::DoubleQuote, None) => {
+                    quote = Some(&token.kind)
+                }
+                (TokenKind::Whitespace, None) => {
+                    if let Some(newline) = token.lexeme.find('\n') {
+                        return offset + newline + 1;
+                    }
+                }
+                _ => {}
+            }
+            offset += token.lexeme.len();
+        }
+
+        self.source.len()
+    }
+
+    /// The offset in the source of the token at `position`.
+    fn offset(&self, position: usize) -> usize {
+        self.input[..position]
+            .iter()
+            .map(|token| token.lexeme.len())
+            .sum()
+    }
+
+    /// Skips the here-document bodies following the newline being read.
+    fn skip_heredoc_bodies(&mut self) {
+        let Some(end) = self.heredoc_start.take() else {
+            return;
+        };
+
+        let mut next = self.offset(self.position + 1);
+        while next < end && self.position + 2 < self.input.len() {
+            self.position += 1;
+            next += self.current_token().lexeme.len();
+        }
+    }
+
+    /// The body of a here-document with an unquoted delimiter: variables
+    /// are expanded, and `\` only escapes `$`, `` ` ``, `\` and newlines.
+    fn expand_heredoc(&mut self, body: &str) -> String {
+        let mut expanded = String::with_capacity(body.len());
+        let mut rest = body;
+        while let Some(index) = rest.find(['$', '\\']) {
+            expanded.push_str(&rest[..index]);
+            let after = &rest[index + 1..];
+            rest = if rest[index..].starts_with('\\') {
+                match after.chars().next() {
+                    Some(char @ ('$' | '`' | '\\')) => {
+                        expanded.push(char);
+                        &after[1..]
+                    }
+                    Some('\n') => &after[1..],
+                    _ => {
+                        expanded.push('\\');
+                        after
+                    }
+                }
+            } else {
+                match variable_reference(after) {
+                    Some((name, tail)) => {
+                        expanded.push_str(&self.variable(name));
+                        tail
+                    }
+                    None => {
+                        expanded.push('$');
+                        after
+                    }
+                }
+            };
+        }
+        expanded.push_str(rest);
+
+        expanded
+    }
+
+    /// A redirection of a stream other than stdin, stdout and stderr, or one
+    /// that can't be read or written.
+    fn bad_file_descriptor(&mut self, fd: &str) {
+        self.error.get_or_insert_with(|| ShellError::RedirectError {
+            path: fd.to_string(),
+            io: io::Error::other("Bad file descriptor"),
+        });
     }
 
+    /// The word a redirection writes to or reads from.
     fn next_string(&mut self) -> String {
         while !self.is_eof() {
             if let Some(str) = self.match_current_token() {
@@ -228,12 +562,20 @@ impl Parser {
             self.position += 1;
         }
 
-        panic!("unexpected EOF")
+        self.error.get_or_insert_with(|| ShellError::ParseError {
+            message: String::from("unexpected token `newline'"),
+        });
+        String::new()
     }
 
     fn handle_escape_sequence(&mut self) -> Option<String> {
-        let lexeme = self.current_token().lexeme.clone();
-        let escape_char = lexeme.chars().nth(1).unwrap();
+        let lexeme = self.current_token().lexeme;
+        let escape_char = lexeme.chars().nth(1)?;
+
+        if escape_char == '\n' && self.quotes.last() != Some(&TokenKind::SingleQuote) {
+            // line continuation
+            return None;
+        }
 
         if self.quotes.is_empty() {
             self.argument_buffer.push(escape_char);
@@ -258,13 +600,86 @@ impl Parser {
         None
     }
 
+    /// `$name` and `${name}` expand to the value of the variable, or to
+    /// nothing when it is unset. Unquoted, the value is split into words at
+    /// whitespace. A `$` not followed by a name, or in single quotes, is kept
+    /// literally.
+    fn handle_dollar(&mut self) -> Option<String> {
+        let next = &self.input[self.position + 1];
+        let reference = match (self.quotes.last(), &next.kind) {
+            (Some(TokenKind::SingleQuote), _) => None,
+            (_, TokenKind::String) => variable_reference(next.lexeme),
+            _ => None,
+        };
+        let Some((name, rest)) = reference else {
+            self.argument_buffer.push('$');
+            return None;
+        };
+
+        self.position += 1;
+        let value = self.variable(name);
+        if self.quotes.is_empty() && !self.in_assignment {
+            self.push_fields(&value);
+            self.push_unquoted(rest);
+        } else {
+            self.argument_buffer.push_str(&value);
+            self.argument_buffer.push_str(rest);
+        }
+
+        None
+    }
+
+    /// The value of the variable `name`, empty when it is unset, which is
+    /// an error with `nounset`.
+    fn variable(&mut self, name: &str) -> String {
+        let Some(expander) = self.expander else {
+            return String::new();
+        };
+
+        expander.variable(name).unwrap_or_else(|| {
+            if expander.nounset() {
+                self.error.get_or_insert_with(|| ShellError::Unbound {
+                    name: name.to_string(),
+                });
+            }
+            String::new()
+        })
+    }
+
+    /// Appends `value` to the word being read, starting a new word at each
+    /// run of whitespace in it.
+    fn push_fields(&mut self, value: &str) {
+        let mut fields = value.split(char::is_whitespace);
+        self.push_unquoted(fields.next().unwrap_or_default());
+        for field in fields {
+            if let Some(word) = self.flush_buf() {
+                self.args.push(word);
+            }
+            self.push_unquoted(field);
+        }
+    }
+
+    /// Appends unquoted text to the word being read, where `*`, `?` and `[`
+    /// are pattern characters.
+    fn push_unquoted(&mut self, text: &str) {
+        let start = self.argument_buffer.len();
+        let globs = text
+            .match_indices(['*', '?', '['])
+            .map(|(offset, _)| start + offset);
+        self.globs.extend(globs);
+        self.argument_buffer.push_str(text);
+    }
+
     fn handle_whitespace(&mut self) -> Option<String> {
         if !self.quotes.is_empty() {
-            self.argument_buffer
-                .push_str(&self.current_token().lexeme.clone());
+            let lexeme = self.current_token().lexeme;
+            self.argument_buffer.push_str(lexeme);
 
             None
         } else {
+            if self.current_token().lexeme.contains('\n') {
+                self.skip_heredoc_bodies();
+            }
             self.flush_buf()
         }
     }
@@ -278,26 +693,125 @@ impl Parser {
             return None;
         }
 
-        Some(mem::take(&mut self.argument_buffer))
+        let word = mem::take(&mut self.argument_buffer);
+        let globs = mem::take(&mut self.globs);
+        if mem::take(&mut self.in_assignment) {
+            let (name, value) = word.split_once('=').expect("checked at the word start");
+            self.assignments.push((name.to_string(), value.to_string()));
+            return None;
+        }
+        if globs.is_empty() || self.in_redirect {
+            return Some(word);
+        }
+
+        self.expand_pattern(word, &globs)
+    }
+
+    /// Pathname expansion of a word with unquoted pattern characters at the
+    /// offsets `globs`: the matching files become words of their own.
+    fn expand_pattern(&mut self, word: String, globs: &[usize]) -> Option<String> {
+        let mut pattern = String::with_capacity(word.len());
+        for (offset, char) in word.char_indices() {
+            if globs.contains(&offset) {
+                pattern.push(char);
+            } else {
+                pattern.push_str(&glob::escape(char.encode_utf8(&mut [0; 4])));
+            }
+        }
+
+        let Some(expander) = self.expander else {
+            return Some(word);
+        };
+        match expander.glob(&pattern) {
+            Ok(None) => Some(word),
+            Ok(Some(mut words)) => {
+                let last = words.pop();
+                self.args.extend(words);
+                last
+            }
+            Err(err) => {
+                self.error.get_or_insert(err);
+                None
+            }
+        }
+    }
+}
+
+/// The offset of the `<` or `>` in `lexeme` starting a redirection, if it
+/// has one. `<<` starts a here-document instead.
+fn redirect_operator(lexeme: &str) -> Option<usize> {
+    let operator = lexeme.find(['<', '>'])?;
+    (!lexeme[operator..].starts_with("<<")).then_some(operator)
+}
+
+/// Whether `name` can name a variable: letters, digits and `_`, not
+/// starting with a digit.
+pub(crate) fn is_name(name: &str) -> bool {
+    name.starts_with(|char: char| char.is_ascii_alphabetic() || char == '_')
+        && name
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || char == '_')
+}
+
+/// Whether a word starting with `lexeme` assigns a variable, like `a=1`.
+pub(crate) fn is_assignment(lexeme: &str) -> bool {
+    lexeme
+        .split_once('=')
+        .is_some_and(|(name, _)| is_name(name))
+}
+
+/// The name referenced after a `$` at the start of `lexeme`, `name` or
+/// `{name}`, and the text following it. `?` names the status of the last
+/// command.
+fn variable_reference(lexeme: &str) -> Option<(&str, &str)> {
+    if let Some(braced) = lexeme.strip_prefix('{') {
+        let (name, rest) = braced.split_once('}')?;
+        return (is_name(name) || name == "?").then_some((name, rest));
+    }
+    if let Some(rest) = lexeme.strip_prefix('?') {
+        return Some(("?", rest));
     }
+
+    let end = lexeme
+        .find(|char: char| !(char.is_ascii_alphanumeric() || char == '_'))
+        .unwrap_or(lexeme.len());
+    let name = &lexeme[..end];
+    is_name(name).then_some((name, &lexeme[end..]))
 }
 
-#[derive(Default, PartialEq, Debug)]
+#[derive(Clone, Default, PartialEq, Debug)]
 pub enum OutputStream {
     #[default]
     Stdout,
     Stderr,
+    /// Only redirected from a file, with `<`, or a here-document.
+    Stdin,
     File(String),
     Pipe(Command),
+    HereDoc(HereDoc),
+}
+
+/// A here-document, `<<word` and the lines after the command up to `word`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct HereDoc {
+    /// Its lines, expanded unless `quoted`.
+    pub body: String,
+    /// For `<<-`, with the leading tabs stripped from its lines.
+    pub strip_tabs: bool,
+    /// Whether the delimiter was quoted, leaving the body as it is.
+    pub quoted: bool,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub enum RedirectType {
     Overwrite,
     Append,
+    Read,
+    /// `>|`, which overwrites a file even with `noclobber` set.
+    Clobber,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct Redirect {
     pub from: OutputStream,
     pub redirect_type: RedirectType,
@@ -313,18 +827,32 @@ impl Redirect {
         }
     }
 
-    pub fn open_output(&self) -> io::Result<fs::File> {
+    pub fn open(&self, noclobber: bool) -> Result<fs::File, ShellError> {
         let filename = match &self.to {
             OutputStream::File(filename) => filename,
             output => unimplemented!("open output for {:?}", output),
         };
 
-        Ok(match self.redirect_type {
-            RedirectType::Overwrite => fs::File::create(filename)?,
+        let file = match self.redirect_type {
+            // only regular files are protected, so e.g. `> /dev/null` works
+            RedirectType::Overwrite
+                if noclobber && fs::metadata(filename).is_ok_and(|file| file.is_file()) =>
+            {
+                Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "cannot overwrite existing file",
+                ))
+            }
+            RedirectType::Overwrite | RedirectType::Clobber => fs::File::create(filename),
             RedirectType::Append => fs::OpenOptions::new()
                 .append(true)
                 .create(true)
-                .open(filename)?,
+                .open(filename),
+            RedirectType::Read => fs::File::open(filename),
+        };
+        file.map_err(|io| ShellError::RedirectError {
+            path: filename.clone(),
+            io,
         })
     }
 }
@@ -392,9 +920,251 @@ mod tests {
             ],
         ))
     ]))]
+    #[case("cmd > a 2>&1 >b <in", Command::new(vec!["cmd"], vec![
+        Redirect{
+            from: OutputStream::Stdout,
+            redirect_type: RedirectType::Overwrite,
+            to: OutputStream::File(String::from("a")),
+        },
+        Redirect{
+            from: OutputStream::Stderr,
+            redirect_type: RedirectType::Overwrite,
+            to: OutputStream::Stdout,
+        },
+        Redirect{
+            from: OutputStream::Stdout,
+            redirect_type: RedirectType::Overwrite,
+            to: OutputStream::File(String::from("b")),
+        },
+        Redirect{
+            from: OutputStream::Stdin,
+            redirect_type: RedirectType::Read,
+            to: OutputStream::File(String::from("in")),
+        },
+    ]))]
+    #[case("echo hi>out >&2", Command::new(vec!["echo", "hi"], vec![
+        Redirect{
+            from: OutputStream::Stdout,
+            redirect_type: RedirectType::Overwrite,
+            to: OutputStream::File(String::from("out")),
+        },
+        Redirect{
+            from: OutputStream::Stdout,
+            redirect_type: RedirectType::Overwrite,
+            to: OutputStream::Stderr,
+        },
+    ]))]
+    #[case("make &>> log", Command::new(vec!["make"], vec![
+        Redirect{
+            from: OutputStream::Stdout,
+            redirect_type: RedirectType::Append,
+            to: OutputStream::File(String::from("log")),
+        },
+        Redirect{
+            from: OutputStream::Stderr,
+            redirect_type: RedirectType::Overwrite,
+            to: OutputStream::Stdout,
+        },
+    ]))]
+    #[case("echo 'a > b' \"c | d\"", Command::new(vec!["echo", "a > b", "c | d"], vec![]))]
     fn parser_test(#[case] input: &str, #[case] expected: Command) {
         let mut parser = Parser::new(input);
-        let command = parser.parse();
+        let command = parser.parse().unwrap();
         assert_eq!(command, expected);
     }
+
+    #[rstest]
+    #[case("echo x 3> f", "3: Bad file descriptor")]
+    #[case("echo x >&5", "5: Bad file descriptor")]
+    #[case("cat 1< f", "1: Bad file descriptor")]
+    fn bad_file_descriptor_test(#[case] input: &str, #[case] expected: &str) {
+        let error = Parser::new(input).parse().unwrap_err();
+        assert_eq!(error.to_string(), expected);
+    }
+
+    #[test]
+    fn parse_into_replaces_the_previous_command() {
+        let mut command = Parser::new("cat a b > out | wc").parse().unwrap();
+        let capacity = command.args.capacity();
+
+        Parser::new("ls").parse_into(&mut command).unwrap();
+        assert_eq!(command, Command::new(vec!["ls"], vec![]));
+        assert_eq!(command.args.capacity(), capacity);
+    }
+
+    struct TestExpander;
+
+    impl Expander for TestExpander {
+        fn expand_tilde(&self, prefix: &str) -> Option<String> {
+            match prefix {
+                "" => Some(String::from("/home/me")),
+                "proj" => Some(String::from("/srv/project")),
+                _ => None,
+            }
+        }
+
+        fn variable(&self, name: &str) -> Option<String> {
+            match name {
+                "x" => Some(String::from("a  b")),
+                "dir" => Some(String::from("/home/me")),
+                _ => None,
+            }
+        }
+
+        fn glob(&self, pattern: &str) -> Result<Option<Vec<String>>, ShellError> {
+            match pattern {
+                "none*" => Ok(Some(vec![])),
+                "fail*" => Err(ShellError::NoMatch {
+                    pattern: pattern.to_string(),
+                }),
+                "two*" => Ok(Some(vec![String::from("two1"), String::from("two2")])),
+                _ => Ok(Some(vec![format!("<{pattern}>")])),
+            }
+        }
+    }
+
+    #[rstest]
+    #[case("cd ~", Command::new(vec!["cd", "/home/me"], vec![]))]
+    #[case("cd ~/src", Command::new(vec!["cd", "/home/me/src"], vec![]))]
+    #[case("cd ~proj", Command::new(vec!["cd", "/srv/project"], vec![]))]
+    #[case("cd ~proj/src/lexer", Command::new(vec!["cd", "/srv/project/src/lexer"], vec![]))]
+    #[case("cd ~nobody/src", Command::new(vec!["cd", "~nobody/src"], vec![]))]
+    #[case("echo '~proj' \\~ a~", Command::new(vec!["echo", "~proj", "~", "a~"], vec![]))]
+    #[case("echo ~'proj'", Command::new(vec!["echo", "~proj"], vec![]))]
+    fn tilde_expansion_test(#[case] input: &str, #[case] expected: Command) {
+        let mut parser = Parser::new(input).with_expander(&TestExpander);
+        let command = parser.parse().unwrap();
+        assert_eq!(command, expected);
+    }
+
+    #[rstest]
+    #[case("echo $x", Command::new(vec!["echo", "a", "b"], vec![]))]
+    #[case("echo \"$x\"", Command::new(vec!["echo", "a  b"], vec![]))]
+    #[case("echo '$x'", Command::new(vec!["echo", "$x"], vec![]))]
+    #[case("echo ${dir}/src $nope.", Command::new(vec!["echo", "/home/me/src", "."], vec![]))]
+    #[case("echo $ a$1 $nope", Command::new(vec!["echo", "$", "a$1"], vec![]))]
+    fn variable_expansion_test(#[case] input: &str, #[case] expected: Command) {
+        let mut parser = Parser::new(input).with_expander(&TestExpander);
+        assert_eq!(parser.parse().unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case(
+        "cat <<EOF\n$x in ${dir}, \\$x \\n 'q'\nEOF",
+        "a  b in /home/me, $x \\n 'q'\n",
+        false,
+        false
+    )]
+    #[case("cat <<'EOF'\n$x \\$x\nEOF\n", "$x \\$x\n", false, true)]
+    #[case("cat << \\EOF\n$x\nEOF", "$x\n", false, true)]
+    #[case(
+        "cat <<-END\n\t\tone\n\ttwo $dir\n\tEND",
+        "one\ntwo /home/me\n",
+        true,
+        false
+    )]
+    #[case("cat <<-\"E F\"\n\t$x\n\tE F", "$x\n", true, true)]
+    fn heredoc_test(
+        #[case] input: &str,
+        #[case] body: &str,
+        #[case] strip_tabs: bool,
+        #[case] quoted: bool,
+    ) {
+        let mut parser = Parser::new(input).with_expander(&TestExpander);
+        let heredoc = HereDoc {
+            body: body.to_string(),
+            strip_tabs,
+            quoted,
+        };
+        assert_eq!(
+            parser.parse().unwrap(),
+            Command::new(
+                vec!["cat"],
+                vec![Redirect {
+                    from: OutputStream::Stdin,
+                    redirect_type: RedirectType::Read,
+                    to: OutputStream::HereDoc(heredoc),
+                }]
+            )
+        );
+    }
+
+    #[test]
+    fn heredocs_follow_the_line_in_order() {
+        let command = Parser::new("cat <<A | cat - <<B\na\nA\nb\nB")
+            .parse()
+            .unwrap();
+        let bodies: Vec<_> = command
+            .redirects
+            .iter()
+            .chain(&command.pipe().unwrap().redirects)
+            .filter_map(|redirect| match &redirect.to {
+                OutputStream::HereDoc(heredoc) => Some(heredoc.body.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(command.args, vec!["cat"]);
+        assert_eq!(command.pipe().unwrap().args, vec!["cat", "-"]);
+        assert_eq!(bodies, vec!["a\n", "b\n"]);
+    }
+
+    #[rstest]
+    #[case("a=1", vec![], vec![("a", "1")])]
+    #[case("a=1 b='x y' env", vec!["env"], vec![("a", "1"), ("b", "x y")])]
+    #[case("y=$x z=", vec![], vec![("y", "a  b"), ("z", "")])]
+    #[case("echo a=1", vec!["echo", "a=1"], vec![])]
+    #[case("'a=1' 1a=2", vec!["a=1", "1a=2"], vec![])]
+    fn assignment_test(
+        #[case] input: &str,
+        #[case] args: Vec<&str>,
+        #[case] assignments: Vec<(&str, &str)>,
+    ) {
+        let mut parser = Parser::new(input).with_expander(&TestExpander);
+        let expected = Command {
+            assignments: assignments
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ..Command::new(args, vec![])
+        };
+        assert_eq!(parser.parse().unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case("echo *.rs", vec!["echo", "<*.rs>"])]
+    #[case("echo '*'.rs \\*.rs \"a?\"", vec!["echo", "*.rs", "*.rs", "a?"])]
+    #[case("echo \"a\"[bc]\\?", vec!["echo", "<a[bc]\\?>"])]
+    #[case("echo ~/*.txt $dir/?", vec!["echo", "</home/me/*.txt>", "</home/me/?>"])]
+    #[case("echo none* two* x", vec!["echo", "two1", "two2", "x"])]
+    #[case("a=* echo", vec!["echo"])]
+    fn glob_test(#[case] input: &str, #[case] args: Vec<&str>) {
+        let mut parser = Parser::new(input).with_expander(&TestExpander);
+        assert_eq!(parser.parse().unwrap().args, args);
+    }
+
+    #[test]
+    fn glob_error_fails_the_command() {
+        let mut parser = Parser::new("echo fail*").with_expander(&TestExpander);
+        assert!(
+            matches!(parser.parse(), Err(ShellError::NoMatch { pattern }) if pattern == "fail*")
+        );
+    }
+
+    #[rstest]
+    #[case("| cat")]
+    #[case("echo a | | wc")]
+    fn empty_pipeline_command_test(#[case] input: &str) {
+        let error = Parser::new(input).parse().unwrap_err();
+        assert_eq!(error.to_string(), "syntax error: unexpected token `|'");
+    }
+
+    #[test]
+    fn redirect_targets_are_not_globbed() {
+        let mut parser = Parser::new("echo a > *.txt").with_expander(&TestExpander);
+        let command = parser.parse().unwrap();
+        assert_eq!(
+            command.redirects[0].to,
+            OutputStream::File(String::from("*.txt"))
+        );
+    }
 }
@@ -1,236 +1,1578 @@
 use crate::bin_path::BinPath;
-use crate::editor::Editor;
-use crate::parser::{Command, OutputStream};
-use crate::{print_to, ExitError, BUILTIN_COMMANDS};
-use anyhow::{bail, Context};
+use crate::environment::Env;
+use crate::getopt::{Getopt, UsageError};
+use crate::history::ExportFormat;
+use crate::line_editor::LineEditor;
+use crate::named_dirs::{self, NamedDirs};
+use crate::options::Options;
+use crate::parser::{self, Command, OutputStream, Redirect, RedirectType};
+use crate::plugins::Plugins;
+use crate::reactor::{Child, Reactor, Reader};
+use crate::restricted;
+use crate::shell::{self, Hooks};
+use crate::spell;
+use crate::streams::{Input, Output, Redirections, Sink, Streams, Target};
+use crate::transcript::Transcript;
+use crate::traps::{Trap, Traps};
+use crate::{print_to, ShellError, BUILTIN_COMMANDS};
 use rustyline::history::History;
+use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::io::Write;
+use std::path::{Component, Path, PathBuf};
 use std::rc::Rc;
-use std::{env, fs, io, mem, process, thread};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{env, fs, io, iter, mem, process};
+
+/// Builtins whose prefix assignments stay set after they ran in posix
+/// mode, like for an assignment-only command.
+static SPECIAL_BUILTINS: &[&str] = &[".", "exit", "set"];
+
+/// What commands print on stderr is shown in, with `color_stderr`.
+const STDERR_COLOR: &str = "\x1b[31m";
+
+/// How long `timeout` waits after TERM before it sends KILL, without `-k`.
+const DEFAULT_KILL_AFTER: Duration = Duration::from_secs(5);
 
 pub struct Pipeline<'a> {
     cmd: &'a Command,
     bin_path: Rc<RefCell<BinPath>>,
-    editor: Rc<RefCell<Editor>>,
-    threads: Vec<thread::JoinHandle<()>>,
+    editor: Rc<RefCell<dyn LineEditor>>,
+    named_dirs: Rc<RefCell<NamedDirs>>,
+    options: Rc<RefCell<Options>>,
+    plugins: Rc<RefCell<Plugins>>,
+    traps: Rc<RefCell<Traps>>,
+    hooks: Rc<RefCell<Hooks>>,
+    streams: Streams,
+    env: Env,
+    reactor: Rc<RefCell<Reactor>>,
 }
 
 impl<'a> Pipeline<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         cmd: &'a Command,
         bin_path: Rc<RefCell<BinPath>>,
-        editor: Rc<RefCell<Editor>>,
+        editor: Rc<RefCell<dyn LineEditor>>,
+        named_dirs: Rc<RefCell<NamedDirs>>,
+        options: Rc<RefCell<Options>>,
+        plugins: Rc<RefCell<Plugins>>,
+        traps: Rc<RefCell<Traps>>,
+        hooks: Rc<RefCell<Hooks>>,
+        streams: Streams,
+        env: Env,
     ) -> Self {
         Self {
             cmd,
             bin_path,
             editor,
-            threads: Vec::with_capacity(4),
+            named_dirs,
+            options,
+            plugins,
+            traps,
+            hooks,
+            streams,
+            env,
+            reactor: Rc::default(),
         }
     }
 
-    pub fn run(&mut self) -> anyhow::Result<()> {
-        let mut command = self.cmd;
-        let mut process = self.call(&self.cmd.args, None)?;
+    /// Runs its I/O on `reactor`, to reuse its storage.
+    pub(crate) fn with_reactor(mut self, reactor: Rc<RefCell<Reactor>>) -> Self {
+        self.reactor = reactor;
+        self
+    }
 
-        while let Some(output) = command.output() {
-            let OutputStream::Pipe(pipe) = &output.to else {
-                break;
-            };
+    /// Runs the pipeline and returns the exit status of its last command, or
+    /// with `pipefail` that of the last one failing.
+    pub fn run(&mut self) -> anyhow::Result<i32> {
+        match self.cmd.args.first().map(String::as_str) {
+            Some("time") => return self.run_timed(),
+            Some("timeout") => return self.run_with_timeout(),
+            _ => {}
+        }
 
-            let next_process = self.call(&pipe.args, Some(process.stdout()))?;
-            process.wait(&mut self.threads)?;
+        let _span = tracing::debug_span!("pipeline", args = ?self.cmd.args).entered();
+        // before anything runs, so a redirect that fails runs nothing
+        let commands: Vec<&'a Command> = self.commands().collect();
+        let noclobber = self.options.borrow().noclobber();
+        let redirections = commands
+            .iter()
+            .map(|command| command.open_redirects(&self.streams, noclobber))
+            .collect::<Result<Vec<_>, _>>()?;
 
-            command = pipe;
-            process = next_process;
+        let mut processes: Vec<Box<dyn Process + 'a>> = Vec::with_capacity(commands.len());
+        let mut stdin = None;
+        for (command, mut redirections) in commands.into_iter().zip(redirections) {
+            let mut process = self.call(command, stdin.take(), &mut redirections)?;
+            // nothing, when its output is all redirected elsewhere
+            let piped = self.route(&mut *process, redirections)?;
+            stdin = Some(piped.unwrap_or(ProcessOutput::Buffer(Vec::new())));
+            if let Some(previous) = processes.last_mut() {
+                previous.wait(&mut self.reactor.borrow_mut())?;
+            }
+            processes.push(process);
         }
 
-        self.copy_stdout(process.stdout(), command.get_output()?);
-        self.copy_stderr(process.stderr(), command.get_error_output()?);
-        process.wait(&mut self.threads)?;
+        let mut reactor = self.reactor.borrow_mut();
+        let last = processes.last_mut().expect("a pipeline has a command");
+        last.wait(&mut reactor)?;
+        reactor.run()?;
 
-        for thread in self.threads.drain(..) {
-            thread.join().unwrap();
+        let mut statuses = processes.iter().map(|process| process.exit_status());
+        if self.options.borrow().pipefail() {
+            return Ok(statuses.rfind(|&status| status != 0).unwrap_or(0));
         }
+        Ok(statuses.next_back().unwrap_or(0))
+    }
 
-        Ok(())
+    /// Its commands, from the first to the last.
+    fn commands(&self) -> impl Iterator<Item = &'a Command> {
+        iter::successors(Some(self.cmd), |command| command.pipe())
+    }
+
+    /// Prints `command` as it is run for `xtrace`, after $PS4, with its
+    /// words quoted where they need it.
+    fn trace(&mut self, command: &Command) {
+        let words = quoted_words(command);
+        if words.is_empty() {
+            return;
+        }
+
+        let ps4 = self.env.var("PS4").unwrap_or_else(|| String::from("+ "));
+        print_to!(self.streams.stderr, "{ps4}{}\n", words.join(" "));
+    }
+
+    /// Sends what `process` prints where `redirections` have it go, and
+    /// returns what goes into the next command of the pipeline.
+    fn route(
+        &self,
+        process: &mut dyn Process,
+        redirections: Redirections,
+    ) -> io::Result<Option<ProcessOutput>> {
+        let mut reactor = self.reactor.borrow_mut();
+        let mut piped = None;
+        let stderr = process.stderr();
+        // only what external commands print, not the builtins' errors
+        let stderr_target = match (&stderr, redirections.stderr) {
+            (ProcessOutput::Pipe(_), Target::Output(Output::Sink(sink)))
+                if self.options.borrow().color_stderr() && sink.is_terminal_stderr() =>
+            {
+                Target::Output(Output::Sink(sink.colored(STDERR_COLOR)))
+            }
+            (_, target) => target,
+        };
+        let outputs = [
+            (process.stdout(), redirections.stdout),
+            (stderr, stderr_target),
+        ];
+        for (output, target) in outputs {
+            match (output, target) {
+                (output, Target::Pipe) => {
+                    piped = Some(match (piped, output) {
+                        (None, output) => output,
+                        (Some(ProcessOutput::Buffer(mut buf)), ProcessOutput::Buffer(more)) => {
+                            buf.extend(more);
+                            ProcessOutput::Buffer(buf)
+                        }
+                        // an external command writes both into one pipe
+                        (Some(piped), _) => piped,
+                    })
+                }
+                (ProcessOutput::Pipe(pipe), Target::Output(output)) => reactor.copy(pipe, output),
+                (ProcessOutput::Buffer(buf), Target::Output(output)) => write_all(output, &buf)?,
+                (ProcessOutput::File(_), _) => unreachable!("only read"),
+            }
+        }
+
+        Ok(piped)
+    }
+
+    /// `time [-p | -v] pipeline` runs the pipeline after it, then reports
+    /// how long it took and what its commands used on stderr.
+    fn run_timed(&mut self) -> anyhow::Result<i32> {
+        let mut getopt = getopt(&self.cmd.args, "time [-p | -v] pipeline", "pv");
+        let mut format = None;
+        loop {
+            match getopt.next_opt() {
+                Ok(Some((opt, _))) => format = Some(opt),
+                Ok(None) => break,
+                Err(usage_error) => {
+                    print_to!(self.streams.stderr, "{usage_error}\n");
+                    return Ok(usage_error.status());
+                }
+            }
+        }
+        let command = Command {
+            args: getopt.operands().to_vec(),
+            ..self.cmd.clone()
+        };
+
+        self.reactor.borrow_mut().take_usage();
+        let start = Instant::now();
+        let status = if command.args.is_empty() {
+            Ok(0)
+        } else {
+            self.nested(&command).run()
+        };
+        let real = start.elapsed();
+        let usage = self.reactor.borrow_mut().take_usage();
+
+        let report = match format {
+            Some('p') => usage.portable_report(real),
+            Some('v') => {
+                let code = match &status {
+                    Ok(code) => *code,
+                    Err(err) => err.downcast_ref().map_or(1, ShellError::status),
+                };
+                usage.verbose_report(&command.args.join(" "), real, code)
+            }
+            _ => usage.report(real),
+        };
+        print_to!(self.streams.stderr, "{report}");
+        status
+    }
+
+    /// `timeout [-k duration] duration pipeline` runs the pipeline after it,
+    /// sending its commands TERM if it is still running after `duration`
+    /// and KILL if they are still running `-k duration` later. Returns 124
+    /// if it timed out, and 125 for a bad duration, like GNU timeout.
+    fn run_with_timeout(&mut self) -> anyhow::Result<i32> {
+        let mut getopt = getopt(
+            &self.cmd.args,
+            "timeout [-k duration] duration pipeline",
+            "k:",
+        );
+        let mut kill_after = DEFAULT_KILL_AFTER;
+        loop {
+            match getopt.next_opt() {
+                Ok(Some((_, Some(arg)))) => match parse_duration(arg) {
+                    Some(duration) => kill_after = duration,
+                    None => {
+                        print_to!(
+                            self.streams.stderr,
+                            "timeout: {arg}: invalid time interval\n"
+                        );
+                        return Ok(125);
+                    }
+                },
+                Ok(Some(_)) => unreachable!("-k takes an argument"),
+                Ok(None) => break,
+                Err(usage_error) => {
+                    print_to!(self.streams.stderr, "{usage_error}\n");
+                    return Ok(125);
+                }
+            }
+        }
+        let (duration, args) = match getopt.operands() {
+            [duration, args @ ..] if !args.is_empty() => (duration, args),
+            _ => {
+                print_to!(self.streams.stderr, "{}\n", getopt.usage_error());
+                return Ok(125);
+            }
+        };
+        let Some(duration) = parse_duration(duration) else {
+            print_to!(
+                self.streams.stderr,
+                "timeout: {duration}: invalid time interval\n"
+            );
+            return Ok(125);
+        };
+        let command = Command {
+            args: args.to_vec(),
+            ..self.cmd.clone()
+        };
+
+        // a zero duration never times out
+        if !duration.is_zero() {
+            self.reactor.borrow_mut().set_timeout(duration, kill_after);
+        }
+        let status = self.nested(&command).run();
+        if self.reactor.borrow_mut().clear_timeout() {
+            return Ok(124);
+        }
+        status
     }
 
+    /// A pipeline of `command` run by this one, like by `time`, sharing its
+    /// streams and reactor.
+    fn nested<'b>(&self, command: &'b Command) -> Pipeline<'b> {
+        Pipeline::new(
+            command,
+            Rc::clone(&self.bin_path),
+            Rc::clone(&self.editor),
+            Rc::clone(&self.named_dirs),
+            Rc::clone(&self.options),
+            Rc::clone(&self.plugins),
+            Rc::clone(&self.traps),
+            Rc::clone(&self.hooks),
+            self.streams.clone(),
+            self.env.clone(),
+        )
+        .with_reactor(Rc::clone(&self.reactor))
+    }
+
+    /// Starts `command`, reading `stdin` unless its input is redirected.
     fn call(
         &mut self,
-        args: &'a Vec<String>,
-        stdin: Option<ProcessStdout>,
+        command: &'a Command,
+        stdin: Option<ProcessOutput>,
+        redirections: &mut Redirections,
     ) -> anyhow::Result<Box<dyn Process + 'a>> {
+        let stdin = match redirections.stdin.take() {
+            Some(Input::File(file)) => Some(ProcessOutput::File(file)),
+            Some(Input::Text(text)) => Some(ProcessOutput::Buffer(text.into_bytes())),
+            None => stdin,
+        };
+        if self.options.borrow().restricted_shell() {
+            restricted::check(command)?;
+        }
+        if self.options.borrow().xtrace() {
+            self.trace(command);
+        }
+
+        let args = &command.args;
+        // `exec` without a command keeps its redirections for the shell
+        if args.len() == 1 && args[0] == "exec" {
+            self.redirect_shell(redirections)?;
+        }
+        // only redirections or assignments, like in `echo a | > f`
+        if args.is_empty() {
+            return Ok(Box::new(self.builtin(args, stdin)));
+        }
         if BUILTIN_COMMANDS.contains(&&*args[0]) {
-            return Ok(Box::new(BuiltinProcess::new(
-                args,
-                Rc::clone(&self.bin_path),
-                Rc::clone(&self.editor),
-            )));
+            let options = self.options.borrow();
+            if options.posix() && SPECIAL_BUILTINS.contains(&&*args[0]) {
+                for (name, value) in &command.assignments {
+                    if options.allexport() {
+                        self.env.set_exported(name, value);
+                    } else {
+                        self.env.set(name, value);
+                    }
+                }
+            }
+            drop(options);
+            return Ok(Box::new(self.builtin(args, stdin)));
+        }
+        if self.plugins.borrow().contains(&args[0]) {
+            return Ok(Box::new(self.builtin(args, stdin)));
+        }
+
+        let lookup = self.bin_path.borrow_mut().lookup(&args[0])?;
+        if let Some(path) = lookup {
+            let process = ExternalProcess::new(
+                &path,
+                command,
+                stdin,
+                redirections.shared,
+                &self.env,
+                &mut self.reactor.borrow_mut(),
+                self.options.borrow().posix_spawn(),
+            )
+            .map_err(|io| ShellError::CannotExecute {
+                name: args[0].clone(),
+                io,
+            })?;
+            return Ok(Box::new(process));
         }
 
-        if let Some(_) = self.bin_path.borrow_mut().lookup(&args[0])? {
-            return Ok(Box::new(ExternalProcess::new(args, stdin)));
+        if self.options.borrow().autocd() && args.len() == 1 && Path::new(&args[0]).is_dir() {
+            let cd = vec![String::from("cd"), String::from("--"), args[0].clone()];
+            return Ok(Box::new(self.builtin(&cd, stdin)));
+        }
+        // a path to a file that isn't executable, or to a directory
+        if args[0].contains(std::path::is_separator)
+            && let Ok(attr) = fs::metadata(&args[0])
+        {
+            let io = if attr.is_dir() {
+                io::Error::new(io::ErrorKind::IsADirectory, "Is a directory")
+            } else {
+                io::Error::new(io::ErrorKind::PermissionDenied, "Permission denied")
+            };
+            let name = args[0].clone();
+            return Err(ShellError::CannotExecute { name, io }.into());
         }
 
-        bail!("{}: command not found", args[0]);
+        let name = args[0].clone();
+        let suggestions = self.suggestions(&name);
+        Err(ShellError::CommandNotFound { name, suggestions }.into())
     }
 
-    fn copy_stdout<T: io::Write + Send + 'static>(&mut self, stdout: ProcessStdout, mut output: T) {
-        let mut stdout: Box<dyn io::Read + Send + 'static> = match stdout {
-            ProcessStdout::ChildStdout(stdout) => Box::new(stdout),
-            ProcessStdout::Buffer(buf) => Box::new(io::Cursor::new(buf)),
+    /// Has the shell's own stdout and stderr go where `redirections` send
+    /// them, for everything run after it.
+    fn redirect_shell(&self, redirections: &mut Redirections) -> io::Result<()> {
+        let take = |target: &mut Target, sink: &Sink| match mem::replace(
+            target,
+            Target::Output(Output::Sink(sink.clone())),
+        ) {
+            Target::Output(output) => output,
+            Target::Pipe => Output::Sink(sink.clone()),
         };
+        let stdout = take(&mut redirections.stdout, &self.streams.stdout);
+        let stderr = take(&mut redirections.stderr, &self.streams.stderr);
+        self.streams.redirect(stdout, stderr)
+    }
 
-        let stdout_thread = thread::spawn(move || {
-            io::copy(&mut stdout, &mut output).unwrap();
-        });
-        self.threads.push(stdout_thread);
+    /// Builtins, plugins and executables in PATH that `name` might be a typo
+    /// of.
+    fn suggestions(&self, name: &str) -> Vec<String> {
+        let plugins = self.plugins.borrow();
+        let mut bin_path = self.bin_path.borrow_mut();
+        let candidates = BUILTIN_COMMANDS
+            .iter()
+            .copied()
+            .chain(plugins.iter().map(|(name, _)| name))
+            .chain(bin_path.all_executable_names());
+        spell::suggestions(name, candidates)
     }
 
-    fn copy_stderr<T: io::Write + Send + 'static>(&mut self, stderr: ProcessStderr, mut output: T) {
-        let mut stderr: Box<dyn io::Read + Send + 'static> = match stderr {
-            ProcessStderr::ChildStderr(stderr) => Box::new(stderr),
-            ProcessStderr::Buffer(buf) => Box::new(io::Cursor::new(buf)),
+    fn builtin(&self, args: &[String], stdin: Option<ProcessOutput>) -> BuiltinProcess {
+        let stdin = match stdin {
+            None => ProcessStdin::Inherit,
+            Some(ProcessOutput::Pipe(pipe)) => {
+                ProcessStdin::Pipe(Reader::new(Rc::clone(&self.reactor), pipe))
+            }
+            Some(ProcessOutput::Buffer(buf)) => ProcessStdin::Buffer(io::Cursor::new(buf)),
+            Some(ProcessOutput::File(file)) => ProcessStdin::File(file),
         };
-
-        let stderr_thread = thread::spawn(move || {
-            io::copy(&mut stderr, &mut output).unwrap();
-        });
-        self.threads.push(stderr_thread);
+        BuiltinProcess::new(
+            args,
+            stdin,
+            Rc::clone(&self.bin_path),
+            Rc::clone(&self.editor),
+            Rc::clone(&self.named_dirs),
+            Rc::clone(&self.options),
+            Rc::clone(&self.plugins),
+            Rc::clone(&self.traps),
+            Rc::clone(&self.hooks),
+            self.streams.clone(),
+            self.env.clone(),
+        )
     }
 }
 
+/// Writes a builtin's output where its command's output goes.
+fn write_all(mut output: Output, buf: &[u8]) -> io::Result<()> {
+    output.write_all(buf)?;
+    output.flush()
+}
+
 trait Process {
-    fn stdout(&mut self) -> ProcessStdout;
+    fn stdout(&mut self) -> ProcessOutput;
+
+    fn stderr(&mut self) -> ProcessOutput;
 
-    fn stderr(&mut self) -> ProcessStderr;
+    /// Has `reactor` store the exit status once the command exits.
+    fn wait(&mut self, reactor: &mut Reactor) -> anyhow::Result<()>;
 
-    fn wait(&mut self, threads: &mut Vec<thread::JoinHandle<()>>) -> anyhow::Result<()>;
+    /// Only meaningful once the reactor passed to `wait` has run.
+    fn exit_status(&self) -> i32;
 }
 
-enum ProcessStdout {
-    ChildStdout(process::ChildStdout),
+/// What a command prints on one of its streams, for another one to read.
+enum ProcessOutput {
+    Pipe(io::PipeReader),
     Buffer(Vec<u8>),
+    /// A file the input of the other one is redirected from instead.
+    File(fs::File),
 }
 
-enum ProcessStderr {
-    ChildStderr(process::ChildStderr),
-    Buffer(Vec<u8>),
+enum ProcessStdin {
+    Inherit,
+    Pipe(Reader),
+    Buffer(io::Cursor<Vec<u8>>),
+    File(fs::File),
 }
 
-struct BuiltinProcess<'a> {
-    args: &'a Vec<String>,
+impl io::Read for ProcessStdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ProcessStdin::Inherit => io::stdin().read(buf),
+            ProcessStdin::Pipe(reader) => reader.read(buf),
+            ProcessStdin::Buffer(cursor) => cursor.read(buf),
+            ProcessStdin::File(file) => file.read(buf),
+        }
+    }
+}
+
+struct BuiltinProcess {
+    stdin: ProcessStdin,
     bin_path: Rc<RefCell<BinPath>>,
-    editor: Rc<RefCell<Editor>>,
+    editor: Rc<RefCell<dyn LineEditor>>,
+    named_dirs: Rc<RefCell<NamedDirs>>,
+    options: Rc<RefCell<Options>>,
+    plugins: Rc<RefCell<Plugins>>,
+    traps: Rc<RefCell<Traps>>,
+    hooks: Rc<RefCell<Hooks>>,
+    /// Where the commands of a sourced script write.
+    streams: Streams,
+    env: Env,
     output: Vec<u8>,
+    error_output: Vec<u8>,
+    status: i32,
     result: anyhow::Result<()>,
 }
 
-impl<'a> BuiltinProcess<'a> {
+impl BuiltinProcess {
+    #[allow(clippy::too_many_arguments)]
     fn new(
-        args: &'a Vec<String>,
+        args: &[String],
+        stdin: ProcessStdin,
         bin_path: Rc<RefCell<BinPath>>,
-        editor: Rc<RefCell<Editor>>,
+        editor: Rc<RefCell<dyn LineEditor>>,
+        named_dirs: Rc<RefCell<NamedDirs>>,
+        options: Rc<RefCell<Options>>,
+        plugins: Rc<RefCell<Plugins>>,
+        traps: Rc<RefCell<Traps>>,
+        hooks: Rc<RefCell<Hooks>>,
+        streams: Streams,
+        env: Env,
     ) -> Self {
         let mut p = Self {
-            args,
+            stdin,
             bin_path,
             editor,
+            named_dirs,
+            options,
+            plugins,
+            traps,
+            hooks,
+            streams,
+            env,
             output: Vec::new(),
+            error_output: Vec::new(),
+            status: 0,
             result: Ok(()),
         };
 
-        p.result = match p.args[0].as_ref() {
-            "exit" => Err(ExitError {}.into()),
-            "echo" => p.echo_builtin(),
-            "type" => p.type_builtin(),
-            "pwd" => Ok(print_to!(
-                p.output,
-                "{}\n",
-                env::current_dir().unwrap().display()
-            )),
-            "cd" => p.cd_builtin(),
-            "history" => p.history_builtin(),
-            _ => unimplemented!("builtin command {}", p.args[0]),
+        let result = match args.first().map_or("", String::as_str) {
+            "" => Ok(()),
+            "exit" => p.exit_builtin(args),
+            "logout" => p.logout_builtin(args),
+            "echo" => p.echo_builtin(args),
+            "type" => p.type_builtin(args),
+            "pwd" => p.pwd_builtin(args),
+            "cd" => p.cd_builtin(args),
+            "j" => p.j_builtin(args),
+            "history" => p.history_builtin(args),
+            "which" => p.which_builtin(args),
+            "hash" => p.hash_builtin(args),
+            "shopt" => p.shopt_builtin(args),
+            "set" => p.set_builtin(args),
+            "export" => p.export_builtin(args),
+            "unset" => p.unset_builtin(args),
+            "source" | "." => p.source_builtin(args),
+            "enable" => p.enable_builtin(args),
+            "spawn" => p.spawn_builtin(args),
+            "trap" => p.trap_builtin(args),
+            "exec" => p.exec_builtin(args),
+            "record" => p.record_builtin(args),
+            _ => p.plugin_builtin(args),
+        };
+
+        p.result = match result {
+            Err(err) => match err.downcast::<UsageError>() {
+                Ok(usage_error) => {
+                    print_to!(p.error_output, "{usage_error}\n");
+                    p.status = usage_error.status();
+                    Ok(())
+                }
+                Err(err) => Err(err),
+            },
+            Ok(()) => Ok(()),
         };
 
         p
     }
 
-    fn type_builtin(&mut self) -> anyhow::Result<()> {
-        let _ = self.args.clone()[1..]
-            .iter()
-            .try_for_each(|arg| -> anyhow::Result<()> {
-                if BUILTIN_COMMANDS.contains(&arg.as_str()) {
-                    print_to!(self.output, "{} is a shell builtin\n", arg);
-                    return Ok(());
+    fn exit_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut getopt = getopt(args, "exit [n]", "");
+        while getopt.next_opt()?.is_some() {}
+
+        let code = match getopt.operands() {
+            [] => None,
+            [code] => match code.parse::<i32>() {
+                Ok(code) => Some(code),
+                Err(_) => return Err(getopt.numeric_argument_required(code).into()),
+            },
+            _ => return Err(getopt.too_many_arguments().into()),
+        };
+
+        Err(ShellError::ExitRequested { code }.into())
+    }
+
+    /// `exit` for login shells.
+    fn logout_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        if !self.options.borrow().login_shell() {
+            print_to!(self.error_output, "logout: not login shell: use `exit'\n");
+            self.status = 1;
+            return Ok(());
+        }
+
+        self.exit_builtin(args)
+    }
+
+    fn type_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut getopt = getopt(args, "type name [name ...]", "");
+        while getopt.next_opt()?.is_some() {}
+
+        for arg in getopt.operands() {
+            if BUILTIN_COMMANDS.contains(&arg.as_str()) || self.plugins.borrow().contains(arg) {
+                print_to!(self.output, "{} is a shell builtin\n", arg);
+                continue;
+            }
+
+            if let Some(path) = self.bin_path.borrow_mut().lookup(arg)? {
+                print_to!(self.output, "{} is {}\n", arg, path.display());
+                continue;
+            }
+
+            print_to!(self.output, "{}: not found\n", arg);
+            self.status = 1;
+        }
+
+        Ok(())
+    }
+
+    fn which_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut getopt = getopt(args, "which [-a] name [name ...]", "a");
+        let mut all = false;
+        while getopt.next_opt()?.is_some() {
+            all = true;
+        }
+
+        let names = getopt.operands();
+        if names.is_empty() {
+            return Err(getopt.usage_error().into());
+        }
+
+        for name in names {
+            let mut found = false;
+            if BUILTIN_COMMANDS.contains(&name.as_str()) || self.plugins.borrow().contains(name) {
+                print_to!(self.output, "{name}: shell built-in command\n");
+                found = true;
+            }
+
+            if !found || all {
+                for path in self.bin_path.borrow_mut().lookup_all(name)? {
+                    print_to!(self.output, "{}\n", path.display());
+                    found = true;
+                    if !all {
+                        break;
+                    }
                 }
+            }
+
+            if !found {
+                print_to!(self.error_output, "{name} not found\n");
+                self.status = 1;
+            }
+        }
+
+        Ok(())
+    }
 
-                if let Some(path) = self.bin_path.borrow_mut().lookup(&arg)? {
-                    print_to!(self.output, "{} is {}\n", arg, path.display());
+    fn hash_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut getopt = getopt(args, "hash -d [name[=dir] ...]", "d");
+        let mut named = false;
+        while getopt.next_opt()?.is_some() {
+            named = true;
+        }
+
+        if !named {
+            return Err(getopt.usage_error().into());
+        }
+
+        let mut named_dirs = self.named_dirs.borrow_mut();
+        if getopt.operands().is_empty() {
+            for (name, dir) in named_dirs.iter() {
+                print_to!(self.output, "{name}={}\n", dir.display());
+            }
+        }
+
+        for operand in getopt.operands() {
+            match operand.split_once('=') {
+                Some((name, dir)) => named_dirs.insert(name, dir),
+                None => match named_dirs.get(operand) {
+                    Some(dir) => print_to!(self.output, "{operand}={}\n", dir.display()),
+                    None => {
+                        print_to!(self.error_output, "hash: {operand}: not found\n");
+                        self.status = 1;
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn shopt_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut getopt = getopt(args, "shopt [-squ] [optname ...]", "squ");
+        let (mut set, mut quiet) = (None, false);
+        while let Some((opt, _)) = getopt.next_opt()? {
+            match opt {
+                's' => set = Some(true),
+                'u' => set = Some(false),
+                _ => quiet = true,
+            }
+        }
+
+        let mut options = self.options.borrow_mut();
+        let operands = getopt.operands();
+        if operands.is_empty() {
+            for (name, value) in options.shopts() {
+                if !quiet && set.is_none_or(|set| set == value) {
+                    print_shopt(&mut self.output, name, value);
+                }
+            }
+
+            return Ok(());
+        }
+
+        for name in operands {
+            let Some(value) = options.shopt(name) else {
+                print_to!(
+                    self.error_output,
+                    "shopt: {name}: invalid shell option name\n"
+                );
+                self.status = 1;
+                continue;
+            };
+            if set.is_some() && Options::is_read_only(name) {
+                print_to!(self.error_output, "shopt: {name}: cannot set option\n");
+                self.status = 1;
+                continue;
+            }
+
+            match set {
+                Some(set) => {
+                    options.set_shopt(name, set);
+                    self.editor.borrow_mut().reconfigure(&options);
+                }
+                None => {
+                    if !value {
+                        self.status = 1;
+                    }
+                    if !quiet {
+                        print_shopt(&mut self.output, name, value);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `set -o name` and `set +o name` turn an option on and off, and so do
+    /// `set -x` and `set +x` for the ones with a flag. `-o` alone lists the
+    /// options, and `+o` alone prints the commands setting them as they are.
+    fn set_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let getopt = getopt(
+            args,
+            "set [-aCefuvx] [-o option-name] [+o option-name]",
+            "aCefuvxo:",
+        );
+
+        let mut args = args[1..].iter();
+        while let Some(arg) = args.next() {
+            let (value, flags) = match arg.split_at_checked(1) {
+                Some(("-", flags)) if !flags.is_empty() => (true, flags),
+                Some(("+", flags)) if !flags.is_empty() => (false, flags),
+                _ => return Err(getopt.usage_error().into()),
+            };
+
+            let mut options = self.options.borrow_mut();
+            let names = if flags == "o" {
+                let Some(name) = args.next() else {
+                    for (name, state) in options.options() {
+                        if value {
+                            print_shopt(&mut self.output, name, state);
+                        } else {
+                            let flag = if state { '-' } else { '+' };
+                            print_to!(self.output, "set {flag}o {name}\n");
+                        }
+                    }
+                    continue;
+                };
+                vec![name.as_str()]
+            } else {
+                flags
+                    .chars()
+                    .map(|flag| Options::flag_option(flag).ok_or(getopt.invalid_option(flag)))
+                    .collect::<Result<_, _>>()?
+            };
+
+            for name in names {
+                if !options.set_option(name, value) {
+                    print_to!(self.error_output, "set: {name}: invalid option name\n");
+                    self.status = 1;
                     return Ok(());
                 }
+            }
+            self.editor.borrow_mut().reconfigure(&options);
+        }
+
+        Ok(())
+    }
 
-                print_to!(self.output, "{}: not found\n", arg);
+    /// `export name=value` assigns and exports, `export name` exports the
+    /// variable as it is, and without names the exported variables are
+    /// listed as commands that export them again.
+    fn export_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut getopt = getopt(args, "export [-p] [name[=value] ...]", "p");
+        while getopt.next_opt()?.is_some() {}
 
-                Ok(())
-            })?;
+        let operands = getopt.operands();
+        if operands.is_empty() {
+            for (name, value) in self.env.exported() {
+                let name = name.to_string_lossy();
+                let value = trace_quote(&value.to_string_lossy()).into_owned();
+                print_to!(self.output, "export {name}={value}\n");
+            }
+            return Ok(());
+        }
+
+        for operand in operands {
+            let (name, value) = match operand.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (operand.as_str(), None),
+            };
+            if !parser::is_name(name) {
+                print_to!(
+                    self.error_output,
+                    "export: `{operand}': not a valid identifier\n"
+                );
+                self.status = 1;
+                continue;
+            }
+
+            match value {
+                Some(value) => self.env.set_exported(name, value),
+                None => self.env.export(name),
+            }
+        }
 
         Ok(())
     }
 
-    fn cd_builtin(&mut self) -> anyhow::Result<()> {
-        let path = if self.args.len() == 1 || self.args[1] == "~" {
-            env::var("HOME").unwrap()
-        } else {
-            self.args[1].clone()
+    /// `unset name ...`, removing the variables from the shell and the
+    /// environment of commands.
+    fn unset_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut getopt = getopt(args, "unset [-v] [name ...]", "v");
+        while getopt.next_opt()?.is_some() {}
+
+        for name in getopt.operands() {
+            if !parser::is_name(name) {
+                print_to!(
+                    self.error_output,
+                    "unset: `{name}': not a valid identifier\n"
+                );
+                self.status = 1;
+                continue;
+            }
+            self.env.unset(name);
+        }
+
+        Ok(())
+    }
+
+    fn pwd_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut getopt = getopt(args, "pwd [-LP]", "LP");
+        let mut physical = false;
+        while let Some((opt, _)) = getopt.next_opt()? {
+            physical = opt == 'P';
+        }
+
+        let mut cwd = env::current_dir()?;
+        if physical {
+            cwd = cwd.canonicalize()?;
+        }
+        print_to!(self.output, "{}\n", cwd.display());
+
+        Ok(())
+    }
+
+    fn cd_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        // not options, so before getopt sees them
+        if let [_, back] = args
+            && let Some(n) = back.strip_prefix('-')
+        {
+            if n.is_empty() {
+                return self.cd_previous();
+            }
+            if n.bytes().all(|byte| byte.is_ascii_digit()) {
+                return self.cd_back(n);
+            }
+        }
+
+        let mut getopt = getopt(args, "cd [dir]", "");
+        while getopt.next_opt()?.is_some() {}
+
+        let mut path = match getopt.operands() {
+            [] => match self.env.var("HOME") {
+                Some(home) => home,
+                None => {
+                    print_to!(self.error_output, "cd: HOME not set\n");
+                    self.status = 1;
+                    return Ok(());
+                }
+            },
+            [path] => path.clone(),
+            _ => return Err(getopt.too_many_arguments().into()),
         };
-        let attr = fs::metadata(&path);
+
+        let relative = matches!(
+            Path::new(&path).components().next(),
+            Some(Component::Normal(_))
+        );
+        if relative
+            && let Some(dir) = named_dirs::cdpath()
+                .into_iter()
+                .map(|dir| dir.join(&path))
+                .find(|dir| dir.is_dir())
+        {
+            path = dir.display().to_string();
+            if !path.starts_with("./") {
+                print_to!(self.output, "{path}\n");
+            }
+        }
+
+        let mut attr = fs::metadata(&path);
+        if matches!(attr, Err(ref err) if err.kind() == io::ErrorKind::NotFound)
+            && self.options.borrow().cdspell()
+            && let Some(corrected) = spell::correct_dir(&path)
+        {
+            path = corrected.display().to_string();
+            print_to!(self.output, "{path}\n");
+            attr = fs::metadata(&path);
+        }
+
         if matches!(attr, Err(ref err) if err.kind() == io::ErrorKind::NotFound) {
             print_to!(self.output, "cd: {path}: No such file or directory\n");
+            self.status = 1;
             return Ok(());
         }
 
-        env::set_current_dir(&path)?;
+        self.change_dir(Path::new(&path))
+    }
 
+    /// `cd -` goes back to OLDPWD, printing it like bash.
+    fn cd_previous(&mut self) -> anyhow::Result<()> {
+        let Some(dir) = self.env.var("OLDPWD") else {
+            print_to!(self.error_output, "cd: OLDPWD not set\n");
+            self.status = 1;
+            return Ok(());
+        };
+
+        print_to!(self.output, "{dir}\n");
+        self.change_dir(Path::new(&dir))
+    }
+
+    /// `cd -N` goes to the Nth most recently visited directory other than
+    /// the current one.
+    fn cd_back(&mut self, n: &str) -> anyhow::Result<()> {
+        let dir = n.parse::<usize>().ok().and_then(|n| {
+            let named_dirs = self.named_dirs.borrow();
+            let back = named_dirs.visited().back();
+            Some(back.get(n.checked_sub(1)?)?.to_path_buf())
+        });
+        let Some(dir) = dir else {
+            print_to!(
+                self.error_output,
+                "cd: -{n}: no such entry in the directory history\n"
+            );
+            self.status = 1;
+            return Ok(());
+        };
+
+        print_to!(self.output, "{}\n", dir.display());
+        self.change_dir(&dir)
+    }
+
+    /// Goes to `dir`, updating OLDPWD and PWD and recording the visit for
+    /// `cd -N` and `j`.
+    fn change_dir(&mut self, dir: &Path) -> anyhow::Result<()> {
+        let old_dir = env::current_dir();
+        env::set_current_dir(dir)?;
+        if let Ok(old_dir) = old_dir {
+            self.env
+                .set_exported("OLDPWD", &old_dir.display().to_string());
+        }
+        if let Ok(dir) = env::current_dir() {
+            self.env.set_exported("PWD", &dir.display().to_string());
+            if let Err(err) = self.named_dirs.borrow_mut().visited_mut().visit(&dir) {
+                tracing::warn!(%err, "saving the directory history");
+            }
+        }
+
+        self.run_chpwd()
+    }
+
+    /// Runs $CHPWD_COMMAND after the directory changed, or lists the new one
+    /// with `ls` when it is unset and `autols` is on. Its own `cd`s don't
+    /// run it again, and its status isn't the one of the `cd`.
+    fn run_chpwd(&mut self) -> anyhow::Result<()> {
+        let commands = match self.env.var("CHPWD_COMMAND") {
+            Some(commands) => commands,
+            None if self.options.borrow().autols() => String::from("ls"),
+            None => return Ok(()),
+        };
+        if !self.named_dirs.borrow_mut().start_chpwd() {
+            return Ok(());
+        }
+
+        let result = self.source_script(&commands, None);
+        self.named_dirs.borrow_mut().finish_chpwd();
+        result?;
         Ok(())
     }
 
-    fn echo_builtin(&mut self) -> anyhow::Result<()> {
-        let str = self.args[1..].join(" ");
+    /// `j keyword...` jumps to the visited directory with the highest
+    /// frecency whose path has the keywords in it, in order. Without any it
+    /// lists the directories with their frecency.
+    fn j_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut getopt = getopt(args, "j [keyword ...]", "");
+        while getopt.next_opt()?.is_some() {}
+
+        let keywords = getopt.operands();
+        if keywords.is_empty() {
+            for (frecency, dir) in self.named_dirs.borrow().visited().ranked() {
+                print_to!(self.output, "{frecency:<10.1} {}\n", dir.display());
+            }
+            return Ok(());
+        }
+
+        let dir = self
+            .named_dirs
+            .borrow()
+            .visited()
+            .best_match(keywords)
+            .map(Path::to_path_buf);
+        let Some(dir) = dir else {
+            print_to!(
+                self.error_output,
+                "j: {}: no matching directory\n",
+                keywords.join(" ")
+            );
+            self.status = 1;
+            return Ok(());
+        };
+
+        self.change_dir(&dir)
+    }
+
+    fn echo_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let str = args[1..].join(" ");
         print_to!(self.output, "{str}\n");
 
         Ok(())
     }
 
-    fn history_builtin(&mut self) -> anyhow::Result<()> {
+    fn history_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        if args.get(1).is_some_and(|arg| arg == "--export") {
+            return self.export_history(&args[2..]);
+        }
+
+        let mut getopt = getopt(args, "history [n] or history -anrw [filename]", "anrw");
+        let mut action = None;
+        while let Some((opt, _)) = getopt.next_opt()? {
+            action = Some(opt);
+        }
+        let operands = getopt.operands();
+
         let mut editor = self.editor.borrow_mut();
+        if let Some(action) = action {
+            // on the history file, -a and -n keep track of what other shells
+            // appended in between
+            match (action, operands) {
+                ('a', []) if editor.history_file().is_some() => return editor.save_history(),
+                ('n', []) if editor.history_file().is_some() => return editor.read_new_history(),
+                _ => {}
+            }
+
+            let filename = match operands {
+                [] => match editor.history_file() {
+                    Some(path) => path.display().to_string(),
+                    None => return Err(getopt.usage_error().into()),
+                },
+                [filename] => filename.clone(),
+                _ => return Err(getopt.too_many_arguments().into()),
+            };
+
+            match action {
+                'r' => editor.history_mut().load(filename.as_ref())?,
+                'w' => editor.history_mut().save(filename.as_ref())?,
+                'a' => editor.history_mut().append(filename.as_ref())?,
+                'n' => editor.history_mut().load_new(filename.as_ref())?,
+                _ => unreachable!("history action {action}"),
+            }
+
+            return Ok(());
+        }
 
-        if self.args.len() >= 3 && self.args[1] == "-r" {
-            editor.history_mut().load((self.args[2]).as_ref())?
-        } else if self.args.len() >= 3 && self.args[1] == "-w" {
-            editor.history_mut().save((self.args[2]).as_ref())?
-        } else if self.args.len() >= 3 && self.args[1] == "-a" {
-            editor.history_mut().append((self.args[2]).as_ref())?
-        } else if self.args.len() >= 2 {
-            let num: usize = self.args[1].parse().context("failed to parse number")?;
-            let iter = editor.history().iter().enumerate();
-
-            last_n(iter, num)
+        let num = match operands {
+            [] => None,
+            [num] => Some(
+                num.parse::<usize>()
+                    .map_err(|_| getopt.numeric_argument_required(num))?,
+            ),
+            _ => return Err(getopt.too_many_arguments().into()),
+        };
+
+        let iter = editor.history().iter().enumerate();
+        match num {
+            Some(num) => last_n(iter, num)
                 .into_iter()
-                .for_each(|(num, line)| print_to!(self.output, "\t{num}  {line}\n"));
+                .for_each(|(num, line)| print_to!(self.output, "\t{num}  {line}\n")),
+            None => iter.for_each(|(num, line)| print_to!(self.output, "\t{num}  {line}\n")),
+        }
+
+        Ok(())
+    }
+
+    /// `history --export json|csv`
+    fn export_history(&mut self, operands: &[String]) -> anyhow::Result<()> {
+        let [format] = operands else {
+            return Err(UsageError::Usage {
+                name: String::from("history"),
+                usage: String::from("history --export json|csv"),
+            }
+            .into());
+        };
+        let Some(format) = ExportFormat::parse(format) else {
+            print_to!(
+                self.error_output,
+                "history: {format}: invalid export format\n"
+            );
+            self.status = 1;
+            return Ok(());
+        };
+
+        let editor = self.editor.borrow();
+        editor.history().export(format, &mut self.output)?;
+        Ok(())
+    }
+
+    fn source_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut getopt = getopt(args, "source filename", "");
+        while getopt.next_opt()?.is_some() {}
+
+        let [path] = getopt.operands() else {
+            return Err(getopt.usage_error().into());
+        };
+
+        let file = if self.options.borrow().posix() && !path.contains('/') {
+            match find_in_path(&self.env, path) {
+                Some(file) => file,
+                None => {
+                    print_to!(self.error_output, "{}: {path}: file not found\n", args[0]);
+                    self.status = 1;
+                    return Ok(());
+                }
+            }
         } else {
-            let iter = editor.history().iter().enumerate();
-            iter.for_each(|(num, line)| print_to!(self.output, "\t{num}  {line}\n"))
+            PathBuf::from(path)
+        };
+
+        let script = match fs::read_to_string(file) {
+            Ok(script) => script,
+            Err(err) => {
+                if err.kind() == io::ErrorKind::NotFound {
+                    print_to!(
+                        self.error_output,
+                        "{}: {path}: No such file or directory\n",
+                        args[0]
+                    );
+                } else {
+                    print_to!(self.error_output, "{}: {path}: {err}\n", args[0]);
+                }
+                self.status = 1;
+                return Ok(());
+            }
+        };
+
+        self.status = self.source_script(&script, Some(path))?;
+        Ok(())
+    }
+
+    /// Runs the commands of `script`, named `name` in its errors, in this
+    /// shell.
+    fn source_script(&mut self, script: &str, name: Option<&str>) -> Result<i32, ShellError> {
+        let errors = &mut self.error_output;
+        shell::source(
+            script,
+            name,
+            &self.named_dirs,
+            &self.options,
+            &self.env,
+            &self.traps,
+            errors,
+            |command| {
+                shell::run_hooked(&self.hooks, command, |command| {
+                    Pipeline::new(
+                        command,
+                        Rc::clone(&self.bin_path),
+                        Rc::clone(&self.editor),
+                        Rc::clone(&self.named_dirs),
+                        Rc::clone(&self.options),
+                        Rc::clone(&self.plugins),
+                        Rc::clone(&self.traps),
+                        Rc::clone(&self.hooks),
+                        self.streams.clone(),
+                        self.env.clone(),
+                    )
+                    .run()
+                })
+            },
+        )
+    }
+
+    /// Loads builtins from shared libraries with `-f`, and removes them with
+    /// `-d`. Without names it lists the loaded ones, as the commands loading
+    /// them.
+    fn enable_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut getopt = getopt(args, "enable [-d] [-f filename] [name ...]", "df:");
+        let (mut delete, mut file) = (false, None);
+        while let Some((opt, arg)) = getopt.next_opt()? {
+            match opt {
+                'd' => delete = true,
+                _ => file = arg,
+            }
+        }
+
+        let names = getopt.operands();
+        let changes = delete || file.is_some();
+        if (delete && file.is_some()) || (changes && names.is_empty()) {
+            return Err(getopt.usage_error().into());
+        }
+        if changes && self.options.borrow().restricted_shell() {
+            print_to!(self.error_output, "enable: restricted\n");
+            self.status = 1;
+            return Ok(());
+        }
+
+        let mut plugins = self.plugins.borrow_mut();
+        if names.is_empty() {
+            for (name, path) in plugins.iter() {
+                print_to!(self.output, "enable -f {} {name}\n", path.display());
+            }
+            return Ok(());
+        }
+
+        for name in names {
+            let builtin = BUILTIN_COMMANDS.contains(&name.as_str());
+            let error = match file {
+                Some(_) if builtin => Some(format!("{name}: cannot replace a shell builtin")),
+                Some(file) => plugins
+                    .load(Path::new(file), name)
+                    .err()
+                    .map(|err| err.to_string()),
+                None if delete => {
+                    (!plugins.remove(name)).then(|| format!("{name}: not dynamically loaded"))
+                }
+                None => (!builtin && !plugins.contains(name))
+                    .then(|| format!("{name}: not a shell builtin")),
+            };
+            if let Some(error) = error {
+                print_to!(self.error_output, "enable: {error}\n");
+                self.status = 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Starts a command detached from the shell, for GUI apps and the like:
+    /// it isn't waited for and keeps running after the shell exits or its
+    /// terminal closes. Its input is /dev/null, and its output goes to
+    /// /dev/null or is appended to the file given with `-o`.
+    fn spawn_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut getopt = getopt(args, "spawn [-o file] command [arg ...]", "o:");
+        let mut log = None;
+        while let Some((_, file)) = getopt.next_opt()? {
+            log = file;
+        }
+
+        let operands = getopt.operands();
+        let Some(name) = operands.first() else {
+            return Err(getopt.usage_error().into());
+        };
+        if self.options.borrow().restricted_shell() {
+            let redirects = log.map(|path| Redirect {
+                from: OutputStream::Stdout,
+                redirect_type: RedirectType::Append,
+                to: OutputStream::File(path.to_string()),
+            });
+            restricted::check(&Command {
+                args: operands.to_vec(),
+                redirects: redirects.into_iter().collect(),
+                ..Command::default()
+            })?;
+        }
+        let Some(path) = self.bin_path.borrow_mut().lookup(name)? else {
+            let name = name.clone();
+            let suggestions = Vec::new();
+            return Err(ShellError::CommandNotFound { name, suggestions }.into());
+        };
+
+        let (stdout, stderr) = match log {
+            Some(path) => {
+                let file = fs::OpenOptions::new().create(true).append(true).open(path);
+                match file.and_then(|file| Ok((file.try_clone()?, file))) {
+                    Ok((stdout, stderr)) => (stdout.into(), stderr.into()),
+                    Err(err) => {
+                        print_to!(self.error_output, "spawn: {path}: {err}\n");
+                        self.status = 1;
+                        return Ok(());
+                    }
+                }
+            }
+            None => (process::Stdio::null(), process::Stdio::null()),
+        };
+        let mut command = platform::command(&path, name);
+        self.env.apply(&mut command);
+        command
+            .args(&operands[1..])
+            .stdin(process::Stdio::null())
+            .stdout(stdout)
+            .stderr(stderr);
+        platform::spawn_detached(command).map_err(|io| ShellError::CannotExecute {
+            name: name.clone(),
+            io,
+        })?;
+        Ok(())
+    }
+
+    /// `trap action name ...` sets the commands to run on the traps named,
+    /// and `trap - name ...` or `trap name` resets them. Without names, or
+    /// with `-p`, it prints the commands setting them as they are.
+    fn trap_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut getopt = getopt(args, "trap [-p] [[action] name ...]", "p");
+        let mut print = false;
+        while getopt.next_opt()?.is_some() {
+            print = true;
+        }
+
+        let operands = getopt.operands();
+        let (action, names) = match operands {
+            [] => (None, operands),
+            _ if print => (None, operands),
+            [_] => (Some("-"), operands),
+            [action, names @ ..] => (Some(action.as_str()), names),
+        };
+
+        let mut traps = self.traps.borrow_mut();
+        let mut named = Vec::with_capacity(names.len());
+        for name in names {
+            match Trap::parse(name) {
+                Some(trap) => named.push(trap),
+                None => {
+                    print_to!(
+                        self.error_output,
+                        "trap: {name}: invalid signal specification\n"
+                    );
+                    self.status = 1;
+                }
+            }
+        }
+
+        let Some(action) = action else {
+            let shown = if operands.is_empty() {
+                &Trap::ALL[..]
+            } else {
+                &named
+            };
+            for &trap in shown {
+                if let Some(action) = traps.get(trap) {
+                    let action = trace_quote(action);
+                    print_to!(self.output, "trap -- {action} {}\n", trap.name());
+                }
+            }
+            return Ok(());
+        };
+        let action = (action != "-").then(|| action.to_string());
+        for trap in named {
+            traps.set(trap, action.clone());
+        }
+
+        Ok(())
+    }
+
+    /// `exec` with only redirections makes them apply to the shell itself,
+    /// which the pipeline did before calling it. Replacing the shell with a
+    /// command isn't supported.
+    fn exec_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut getopt = getopt(args, "exec [redirection ...]", "");
+        while getopt.next_opt()?.is_some() {}
+
+        if let Some(name) = getopt.operands().first() {
+            print_to!(
+                self.error_output,
+                "exec: {name}: cannot replace the shell with a command\n"
+            );
+            self.status = 1;
+        }
+
+        Ok(())
+    }
+
+    /// `record start [-a] file` copies the prompts, what is entered at them
+    /// and everything printed into `file` until `record stop`, like
+    /// script(1). Without arguments it tells where it records to.
+    fn record_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let usage = "record [start [-a] file | stop]";
+        match args.get(1).map(String::as_str) {
+            None => match self.streams.transcript() {
+                Some(transcript) => {
+                    print_to!(
+                        self.output,
+                        "recording to {}\n",
+                        transcript.path().display()
+                    );
+                }
+                None => print_to!(self.output, "not recording\n"),
+            },
+            Some("start") => {
+                let mut getopt = Getopt::new("record", usage, "a", &args[2..]);
+                let mut append = false;
+                while getopt.next_opt()?.is_some() {
+                    append = true;
+                }
+                let [path] = getopt.operands() else {
+                    return Err(getopt.usage_error().into());
+                };
+
+                if self.options.borrow().restricted_shell() {
+                    print_to!(self.error_output, "record: restricted\n");
+                    self.status = 1;
+                    return Ok(());
+                }
+                if let Some(transcript) = self.streams.transcript() {
+                    print_to!(
+                        self.error_output,
+                        "record: already recording to {}\n",
+                        transcript.path().display()
+                    );
+                    self.status = 1;
+                    return Ok(());
+                }
+
+                match Transcript::start(Path::new(path), append) {
+                    Ok(transcript) => self.streams.record(transcript),
+                    Err(err) => {
+                        print_to!(self.error_output, "record: {path}: {err}\n");
+                        self.status = 1;
+                    }
+                }
+            }
+            Some("stop") if args.len() == 2 => match self.streams.stop_recording() {
+                Some(mut transcript) => {
+                    if let Err(err) = transcript.finish() {
+                        let path = transcript.path().display();
+                        print_to!(self.error_output, "record: {path}: {err}\n");
+                        self.status = 1;
+                    }
+                }
+                None => {
+                    print_to!(self.error_output, "record: not recording\n");
+                    self.status = 1;
+                }
+            },
+            _ => return Err(getopt(args, usage, "").usage_error().into()),
+        }
+
+        Ok(())
+    }
+
+    /// Runs a builtin loaded with `enable -f`.
+    fn plugin_builtin(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let mut plugins = self.plugins.borrow_mut();
+        // commands only run as builtins when they are loaded, and builtins
+        // run as their process is created, so nothing removed it since
+        let Some(builtin) = plugins.get_mut(&args[0]) else {
+            unreachable!("builtin command {} not loaded", args[0]);
         };
 
+        self.status = builtin.run(
+            args,
+            &mut self.stdin,
+            &mut self.output,
+            &mut self.error_output,
+        );
         Ok(())
     }
 }
 
+/// The first file named `name` in a $PATH directory, for `.` in posix
+/// mode. Unlike commands, it doesn't have to be executable.
+fn find_in_path(env: &Env, name: &str) -> Option<PathBuf> {
+    let path = env.var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|file| file.is_file())
+}
+
+/// The commands of the pipeline `command` starts as they are run, with
+/// their words expanded and quoted where they need it.
+pub(crate) fn expanded_text(command: &Command) -> String {
+    let commands = iter::successors(Some(command), |command| command.pipe());
+    let texts: Vec<String> = commands
+        .map(|command| quoted_words(command).join(" "))
+        .collect();
+    texts.join(" | ")
+}
+
+/// The assignments and arguments of `command`, quoted with `trace_quote`.
+fn quoted_words(command: &Command) -> Vec<String> {
+    let assignments = command
+        .assignments
+        .iter()
+        .map(|(name, value)| format!("{name}={}", trace_quote(value)));
+    let args = command.args.iter().map(|arg| trace_quote(arg).into_owned());
+    assignments.chain(args).collect()
+}
+
+/// `word` in single quotes if the shell would otherwise interpret any of it.
+fn trace_quote(word: &str) -> Cow<'_, str> {
+    let special = |char: char| char.is_whitespace() || "'\"\\$`|&;<>()*?[]{}!#~".contains(char);
+    if !word.is_empty() && !word.contains(special) {
+        return Cow::Borrowed(word);
+    }
+
+    Cow::Owned(format!("'{}'", word.replace('\'', "'\\''")))
+}
+
+fn print_shopt(output: &mut Vec<u8>, name: &str, value: bool) {
+    let state = if value { "on" } else { "off" };
+    print_to!(output, "{name:<15}\t{state}\n");
+}
+
+fn getopt<'b>(args: &'b [String], usage: &'b str, optstring: &'b str) -> Getopt<'b> {
+    Getopt::new(&args[0], usage, optstring, &args[1..])
+}
+
+/// A duration like `timeout` takes: a number of seconds, possibly with a
+/// fraction, or of minutes, hours or days with an `m`, `h` or `d` suffix.
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let (number, unit) = match duration.char_indices().last()? {
+        (index, 's') => (&duration[..index], 1.0),
+        (index, 'm') => (&duration[..index], 60.0),
+        (index, 'h') => (&duration[..index], 3600.0),
+        (index, 'd') => (&duration[..index], 86400.0),
+        _ => (duration, 1.0),
+    };
+    // no signs, `inf` or `nan`
+    if !number.starts_with(|char: char| char.is_ascii_digit() || char == '.') {
+        return None;
+    }
+
+    let seconds: f64 = number.parse().ok()?;
+    Duration::try_from_secs_f64(seconds * unit).ok()
+}
+
 fn last_n<T>(iter: impl Iterator<Item = T>, n: usize) -> VecDeque<T> {
     let mut buffer = VecDeque::with_capacity(n);
 
@@ -245,28 +1587,93 @@ fn last_n<T>(iter: impl Iterator<Item = T>, n: usize) -> VecDeque<T> {
     buffer
 }
 
-impl<'a> Process for BuiltinProcess<'a> {
-    fn stdout(&mut self) -> ProcessStdout {
-        ProcessStdout::Buffer(mem::take(&mut self.output))
+impl Process for BuiltinProcess {
+    fn stdout(&mut self) -> ProcessOutput {
+        ProcessOutput::Buffer(mem::take(&mut self.output))
     }
 
-    fn stderr(&mut self) -> ProcessStderr {
-        ProcessStderr::Buffer(Vec::new())
+    fn stderr(&mut self) -> ProcessOutput {
+        ProcessOutput::Buffer(mem::take(&mut self.error_output))
     }
 
-    fn wait(&mut self, _threads: &mut Vec<thread::JoinHandle<()>>) -> anyhow::Result<()> {
+    fn wait(&mut self, _reactor: &mut Reactor) -> anyhow::Result<()> {
         mem::replace(&mut self.result, Ok(()))
     }
+
+    fn exit_status(&self) -> i32 {
+        self.status
+    }
 }
 
 struct ExternalProcess {
-    stdin_buf: Option<Vec<u8>>,
-    child: Option<process::Child>,
+    child: Option<Child>,
+    stdout: Option<io::PipeReader>,
+    /// `None` once taken, or when it shares the pipe of stdout.
+    stderr: Option<io::PipeReader>,
+    status: Arc<AtomicI32>,
 }
 
-impl<'a> ExternalProcess {
-    fn new(args: &'a Vec<String>, stdin: Option<ProcessStdout>) -> Self {
-        let mut cmd = process::Command::new(&args[0]);
+impl ExternalProcess {
+    /// Runs the executable at `path`, found in PATH for the name of
+    /// `command`, with its assignments added to the environment. Output of
+    /// a builtin before it is fed to it by `reactor`. With `shared_output`,
+    /// it writes both stdout and stderr into one pipe. With `posix_spawn`,
+    /// it is started with posix_spawn(3) on unix.
+    fn new(
+        path: &Path,
+        command: &Command,
+        stdin: Option<ProcessOutput>,
+        shared_output: bool,
+        env: &Env,
+        reactor: &mut Reactor,
+        posix_spawn: bool,
+    ) -> io::Result<Self> {
+        let _span = tracing::debug_span!("spawn", path = %path.display()).entered();
+        let (stdout, stdout_writer) = io::pipe()?;
+        let (stderr, stderr_writer) = if shared_output {
+            (None, stdout_writer.try_clone()?)
+        } else {
+            let (stderr, stderr_writer) = io::pipe()?;
+            (Some(stderr), stderr_writer)
+        };
+        let outputs = (stdout_writer, stderr_writer);
+
+        let child = match posix_spawn {
+            #[cfg(unix)]
+            true => Self::posix_spawn(path, command, stdin, outputs, env, reactor)?,
+            _ => Self::spawn(path, command, stdin, outputs, env, reactor)?,
+        };
+        tracing::debug!(pid = child.id(), "spawned");
+
+        Ok(Self {
+            child: Some(child),
+            stdout: Some(stdout),
+            stderr,
+            status: Arc::new(AtomicI32::new(0)),
+        })
+    }
+
+    /// Starts the command with std::process, writing to `outputs`.
+    fn spawn(
+        path: &Path,
+        command: &Command,
+        stdin: Option<ProcessOutput>,
+        (stdout, stderr): (io::PipeWriter, io::PipeWriter),
+        env: &Env,
+        reactor: &mut Reactor,
+    ) -> io::Result<Child> {
+        let args = &command.args;
+        let mut cmd = platform::command(path, &args[0]);
+        if reactor.has_timeout() {
+            platform::own_process_group(&mut cmd);
+        }
+        env.apply(&mut cmd);
+        cmd.envs(
+            command
+                .assignments
+                .iter()
+                .map(|(name, value)| (name, value)),
+        );
 
         args[1..].iter().for_each(|arg| {
             cmd.arg(arg);
@@ -275,69 +1682,341 @@ impl<'a> ExternalProcess {
         let mut stdin_buf = None;
         let stdin = stdin
             .and_then(|stdin| match stdin {
-                ProcessStdout::ChildStdout(child) => Some(process::Stdio::from(child)),
-                ProcessStdout::Buffer(buf) => {
+                ProcessOutput::Pipe(pipe) => Some(process::Stdio::from(pipe)),
+                ProcessOutput::Buffer(buf) => {
                     stdin_buf = Some(buf);
                     None
                 }
+                ProcessOutput::File(file) => Some(process::Stdio::from(file)),
             })
             .unwrap_or(process::Stdio::piped());
 
-        let child = cmd
-            .stdin(stdin)
-            .stdout(process::Stdio::piped())
-            .stderr(process::Stdio::piped())
-            .spawn()
-            .unwrap();
+        // dropped with `cmd` once spawned, for the readers to see the end
+        let mut child = cmd.stdin(stdin).stdout(stdout).stderr(stderr).spawn()?;
 
-        Self {
-            stdin_buf,
-            child: Some(child),
+        // Fed as the pipeline runs, so a downstream builtin draining our
+        // stdout can't deadlock against us waiting for input.
+        if let Some(buf) = stdin_buf {
+            reactor.feed(child.stdin.take().expect("handle present"), buf);
+        }
+
+        Ok(child.into())
+    }
+
+    /// Starts the command with posix_spawn(3), writing to `outputs`. Its
+    /// stdin is a pipe like with std unless it reads another command or a
+    /// file.
+    #[cfg(unix)]
+    fn posix_spawn(
+        path: &Path,
+        command: &Command,
+        stdin: Option<ProcessOutput>,
+        (stdout, stderr): (io::PipeWriter, io::PipeWriter),
+        env: &Env,
+        reactor: &mut Reactor,
+    ) -> io::Result<Child> {
+        use std::os::fd::{AsFd, OwnedFd};
+
+        let mut vars: BTreeMap<_, _> = env.exported().into_iter().collect();
+        vars.extend(
+            command
+                .assignments
+                .iter()
+                .map(|(name, value)| (name.into(), value.into())),
+        );
+
+        let mut fed = None;
+        let mut open_stdin = None;
+        let stdin: OwnedFd = match stdin {
+            Some(ProcessOutput::Pipe(pipe)) => pipe.into(),
+            Some(ProcessOutput::File(file)) => file.into(),
+            Some(ProcessOutput::Buffer(buf)) => {
+                let (reader, writer) = io::pipe()?;
+                fed = Some((writer, buf));
+                reader.into()
+            }
+            None => {
+                let (reader, writer) = io::pipe()?;
+                open_stdin = Some(writer);
+                reader.into()
+            }
+        };
+
+        let pid = platform::posix_spawn(
+            path,
+            &command.args,
+            &vars,
+            [stdin.as_fd(), stdout.as_fd(), stderr.as_fd()],
+            reactor.has_timeout(),
+        )?;
+        // only the command has them open now, for the readers to see the end
+        drop((stdin, stdout, stderr));
+
+        // fed as the pipeline runs, like with std
+        if let Some((writer, buf)) = fed {
+            reactor.feed(writer, buf);
         }
+
+        Ok(Child::Spawned {
+            pid,
+            _stdin: open_stdin,
+        })
     }
 }
 
 impl Process for ExternalProcess {
-    fn stdout(&mut self) -> ProcessStdout {
-        ProcessStdout::ChildStdout(
-            self.child
-                .as_mut()
-                .unwrap()
-                .stdout
-                .take()
-                .expect("handle present"),
-        )
+    fn stdout(&mut self) -> ProcessOutput {
+        ProcessOutput::Pipe(self.stdout.take().expect("handle present"))
     }
 
-    fn stderr(&mut self) -> ProcessStderr {
-        ProcessStderr::ChildStderr(
-            self.child
-                .as_mut()
-                .unwrap()
-                .stderr
-                .take()
-                .expect("handle present"),
-        )
+    fn stderr(&mut self) -> ProcessOutput {
+        match self.stderr.take() {
+            Some(stderr) => ProcessOutput::Pipe(stderr),
+            None => ProcessOutput::Buffer(Vec::new()),
+        }
     }
 
-    fn wait(&mut self, threads: &mut Vec<thread::JoinHandle<()>>) -> anyhow::Result<()> {
-        let mut child = mem::take(&mut self.child).unwrap();
+    fn wait(&mut self, reactor: &mut Reactor) -> anyhow::Result<()> {
+        let child = mem::take(&mut self.child).unwrap();
+        reactor.wait(child, Arc::clone(&self.status));
+        Ok(())
+    }
+
+    fn exit_status(&self) -> i32 {
+        self.status.load(Ordering::SeqCst)
+    }
+}
+
+pub(crate) use platform::exit_code;
+
+/// How external commands are started and report how they ended.
+#[cfg(unix)]
+mod platform {
+    use std::collections::BTreeMap;
+    use std::ffi::{CString, OsString};
+    use std::os::fd::{AsRawFd, BorrowedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    use std::path::Path;
+    use std::{io, iter, mem, process, ptr};
 
-        match self.stdin_buf {
-            Some(ref mut buf) => child
-                .stdin
-                .take()
-                .expect("handle present")
-                .write_all(buf)
-                .unwrap(),
-            None => {}
+    /// Runs `path` with `name`, as typed, for its `argv[0]`. std may fork
+    /// to start it; with the `posix_spawn` shell option, [`posix_spawn`] is
+    /// used instead.
+    pub fn command(path: &Path, name: &str) -> process::Command {
+        let mut command = process::Command::new(path);
+        command.arg0(name);
+        command
+    }
+
+    /// Starts `command` as the leader of a new process group, so signalling
+    /// the group reaches whatever it starts too.
+    pub fn own_process_group(command: &mut process::Command) {
+        command.process_group(0);
+    }
+
+    /// Starts the executable at `path` with posix_spawn(3), with `args` for
+    /// its `argv` and `vars` for its environment. Its stdin, stdout and
+    /// stderr are duplicated from `stdio`; the shell's other files are all
+    /// closed on exec. Like [`own_process_group`] with `process_group`.
+    pub fn posix_spawn(
+        path: &Path,
+        args: &[String],
+        vars: &BTreeMap<OsString, OsString>,
+        stdio: [BorrowedFd<'_>; 3],
+        process_group: bool,
+    ) -> io::Result<u32> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        let args = args
+            .iter()
+            .map(|arg| c_string(arg.as_bytes()))
+            .collect::<io::Result<Vec<_>>>()?;
+        let vars = vars
+            .iter()
+            .map(|(name, value)| c_string(&[name.as_bytes(), b"=", value.as_bytes()].concat()))
+            .collect::<io::Result<Vec<_>>>()?;
+        let argv = null_terminated(&args);
+        let envp = null_terminated(&vars);
+
+        let mut actions = FileActions::new()?;
+        for (to, from) in stdio.iter().enumerate() {
+            // SAFETY: `actions` was initialized, and both are open fds.
+            check(unsafe {
+                libc::posix_spawn_file_actions_adddup2(
+                    &mut actions.0,
+                    from.as_raw_fd(),
+                    to as libc::c_int,
+                )
+            })?;
         }
+        let attr = SpawnAttr::new(process_group)?;
 
-        let process = thread::spawn(move || {
-            child.wait().unwrap();
-        });
+        let mut pid = 0;
+        // SAFETY: `path` and the strings `argv` and `envp` point to live
+        // until it returns are NUL-terminated, and both arrays end in a null
+        // pointer. `actions` and `attr` were initialized.
+        check(unsafe {
+            libc::posix_spawn(
+                &mut pid,
+                path.as_ptr(),
+                &actions.0,
+                &attr.0,
+                argv.as_ptr(),
+                envp.as_ptr(),
+            )
+        })?;
+        Ok(pid as u32)
+    }
+
+    fn c_string(bytes: &[u8]) -> io::Result<CString> {
+        CString::new(bytes).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "argument contains a NUL byte")
+        })
+    }
+
+    /// Pointers to `strings`, followed by a null pointer, as exec takes
+    /// them.
+    fn null_terminated(strings: &[CString]) -> Vec<*mut libc::c_char> {
+        strings
+            .iter()
+            .map(|string| string.as_ptr().cast_mut())
+            .chain(iter::once(ptr::null_mut()))
+            .collect()
+    }
+
+    /// posix_spawn functions return the error number instead of setting
+    /// errno.
+    fn check(result: libc::c_int) -> io::Result<()> {
+        match result {
+            0 => Ok(()),
+            err => Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+
+    /// What posix_spawn does in the child before exec, destroyed when
+    /// dropped.
+    struct FileActions(libc::posix_spawn_file_actions_t);
+
+    impl FileActions {
+        fn new() -> io::Result<Self> {
+            // SAFETY: init fills in the zeroed value.
+            let mut actions = unsafe { mem::zeroed() };
+            check(unsafe { libc::posix_spawn_file_actions_init(&mut actions) })?;
+            Ok(Self(actions))
+        }
+    }
+
+    impl Drop for FileActions {
+        fn drop(&mut self) {
+            // SAFETY: it was initialized, and isn't used after this.
+            unsafe { libc::posix_spawn_file_actions_destroy(&mut self.0) };
+        }
+    }
+
+    /// The attributes a command is spawned with: no signals blocked,
+    /// SIGPIPE back to its default as Rust ignores it, and optionally a new
+    /// process group. Destroyed when dropped.
+    struct SpawnAttr(libc::posix_spawnattr_t);
 
-        threads.push(process);
+    impl SpawnAttr {
+        fn new(process_group: bool) -> io::Result<Self> {
+            // SAFETY: init fills in the zeroed value, which is dropped, and
+            // so destroyed, once initialized.
+            let mut attr = Self(unsafe { mem::zeroed() });
+            check(unsafe { libc::posix_spawnattr_init(&mut attr.0) })?;
+
+            let mut flags = libc::POSIX_SPAWN_SETSIGMASK | libc::POSIX_SPAWN_SETSIGDEF;
+            if process_group {
+                flags |= libc::POSIX_SPAWN_SETPGROUP;
+            }
+            // SAFETY: the sets are initialized by sigemptyset before they
+            // are used, and `attr` was initialized.
+            unsafe {
+                let mut mask: libc::sigset_t = mem::zeroed();
+                libc::sigemptyset(&mut mask);
+                check(libc::posix_spawnattr_setsigmask(&mut attr.0, &mask))?;
+                let mut default: libc::sigset_t = mem::zeroed();
+                libc::sigemptyset(&mut default);
+                libc::sigaddset(&mut default, libc::SIGPIPE);
+                check(libc::posix_spawnattr_setsigdefault(&mut attr.0, &default))?;
+                check(libc::posix_spawnattr_setpgroup(&mut attr.0, 0))?;
+                check(libc::posix_spawnattr_setflags(
+                    &mut attr.0,
+                    flags as libc::c_short,
+                ))?;
+            }
+            Ok(attr)
+        }
+    }
+
+    impl Drop for SpawnAttr {
+        fn drop(&mut self) {
+            // SAFETY: it was initialized, and isn't used after this.
+            unsafe { libc::posix_spawnattr_destroy(&mut self.0) };
+        }
+    }
+
+    /// Starts `command` like `nohup command & disown`, only more so: in a
+    /// session of its own, without a controlling terminal to be signalled
+    /// by, ignoring SIGHUP, and as the child of a process that exits right
+    /// away, so init reaps it instead of the shell.
+    pub fn spawn_detached(mut command: process::Command) -> io::Result<()> {
+        // SAFETY: setsid, fork, signal and _exit are async-signal-safe.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                match libc::fork() {
+                    -1 => return Err(io::Error::last_os_error()),
+                    0 => {}
+                    _ => libc::_exit(0),
+                }
+                libc::signal(libc::SIGHUP, libc::SIG_IGN);
+                Ok(())
+            });
+        }
+
+        // the grandchild reports failing to exec before the child is reaped
+        command.spawn()?.wait()?;
         Ok(())
     }
+
+    /// Killed by a signal is 128 plus its number, like in sh.
+    pub fn exit_code(status: process::ExitStatus) -> i32 {
+        match status.code() {
+            Some(code) => code,
+            None => 128 + status.signal().unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::io;
+    use std::os::windows::process::CommandExt;
+    use std::path::Path;
+    use std::process;
+
+    /// Runs the file found in PATH itself: Windows only searches for `.exe`
+    /// files, and sets no separate program name.
+    pub fn command(path: &Path, _name: &str) -> process::Command {
+        process::Command::new(path)
+    }
+
+    /// Without process groups, only the command itself is killed.
+    pub fn own_process_group(_command: &mut process::Command) {}
+
+    /// Starts `command` without a console, in a process group of its own.
+    pub fn spawn_detached(mut command: process::Command) -> io::Result<()> {
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x200;
+        const DETACHED_PROCESS: u32 = 0x8;
+        command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+        command.spawn()?;
+        Ok(())
+    }
+
+    pub fn exit_code(status: process::ExitStatus) -> i32 {
+        status.code().unwrap_or(1)
+    }
 }
@@ -0,0 +1,128 @@
+use indexmap::IndexMap;
+use libloading::Library;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A builtin command loaded from a shared library with `enable -f`.
+///
+/// A plugin is a `cdylib` built with the same compiler as the shell,
+/// exporting a [`BuiltinInit`] named `shell_builtin_<name>` for every
+/// builtin it provides:
+///
+/// ```ignore
+/// #[unsafe(no_mangle)]
+/// pub fn shell_builtin_hello() -> Box<dyn Builtin> {
+///     Box::new(Hello)
+/// }
+/// ```
+pub trait Builtin {
+    /// Runs the builtin with `args`, the first being its name, and returns
+    /// its exit status. What it writes is redirected and piped like the
+    /// output of the other builtins.
+    fn run(
+        &mut self,
+        args: &[String],
+        stdin: &mut dyn io::Read,
+        stdout: &mut dyn io::Write,
+        stderr: &mut dyn io::Write,
+    ) -> i32;
+}
+
+/// Creates the builtin a plugin provides.
+pub type BuiltinInit = fn() -> Box<dyn Builtin>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PluginError {
+    #[error("cannot open shared object {path}: {source}")]
+    Open {
+        path: String,
+        #[source]
+        source: libloading::Error,
+    },
+    #[error("cannot find {symbol} in shared object {path}: {source}")]
+    Symbol {
+        symbol: String,
+        path: String,
+        #[source]
+        source: libloading::Error,
+    },
+}
+
+struct Plugin {
+    builtin: Box<dyn Builtin>,
+    path: PathBuf,
+    /// Keeps the code of `builtin` loaded; dropped after it. The tests add
+    /// builtins of their own without one.
+    _library: Option<Library>,
+}
+
+/// The builtins loaded with `enable -f`, by name, in the order they were
+/// loaded.
+#[derive(Default)]
+pub struct Plugins {
+    plugins: IndexMap<String, Plugin>,
+}
+
+impl Plugins {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the builtin `name` from the shared library at `path`, replacing
+    /// one loaded before with the same name.
+    pub fn load(&mut self, path: &Path, name: &str) -> Result<(), PluginError> {
+        // SAFETY: plugins are trusted like the commands the shell runs, and
+        // their initializers are expected to have the `BuiltinInit` type.
+        let library = unsafe { Library::new(path) }.map_err(|source| PluginError::Open {
+            path: path.display().to_string(),
+            source,
+        })?;
+        let symbol = format!("shell_builtin_{name}");
+        let init = unsafe { library.get::<BuiltinInit>(symbol.as_bytes()) }
+            .map(|init| *init)
+            .map_err(|source| PluginError::Symbol {
+                symbol,
+                path: path.display().to_string(),
+                source,
+            })?;
+
+        let plugin = Plugin {
+            builtin: init(),
+            path: path.to_path_buf(),
+            _library: Some(library),
+        };
+        self.plugins.insert(name.to_string(), plugin);
+        Ok(())
+    }
+
+    /// Adds `builtin` as if it was loaded from `path`.
+    #[cfg(test)]
+    pub(crate) fn insert(&mut self, name: &str, path: &Path, builtin: Box<dyn Builtin>) {
+        let plugin = Plugin {
+            builtin,
+            path: path.to_path_buf(),
+            _library: None,
+        };
+        self.plugins.insert(name.to_string(), plugin);
+    }
+
+    /// Returns `false` when no builtin named `name` was loaded.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.plugins.shift_remove(name).is_some()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.plugins.contains_key(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut dyn Builtin> {
+        Some(self.plugins.get_mut(name)?.builtin.as_mut())
+    }
+
+    /// The loaded builtins with the libraries they came from.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Path)> {
+        self.plugins
+            .iter()
+            .map(|(name, plugin)| (name.as_str(), plugin.path.as_path()))
+    }
+}
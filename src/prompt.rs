@@ -0,0 +1,607 @@
+use crate::environment::Env;
+use crate::named_dirs::NamedDirs;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::sync::mpsc;
+use std::time::Duration;
+use std::{env, fs, thread};
+
+pub const DEFAULT_PS1: &str = "$ ";
+pub const DEFAULT_PS2: &str = "> ";
+
+/// A rendered prompt. `plain` leaves out everything PS1 marked as
+/// non-printing with `\[ \]`, so the editor measures its width from it and
+/// shows `styled` in its place.
+#[derive(Debug, PartialEq)]
+pub struct Prompt {
+    pub plain: String,
+    pub styled: String,
+}
+
+/// What prompt segments are rendered from.
+pub struct PromptState<'a> {
+    pub named_dirs: &'a NamedDirs,
+    /// Exit status of the last command.
+    pub last_status: i32,
+    /// How long the last command took, `None` before the first one.
+    pub duration: Option<Duration>,
+    pub cwd_style: CwdStyle,
+}
+
+/// How `\w` shortens the working directory, set with $PROMPT_DIRTRIM and
+/// $PROMPT_DIRLENGTH.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CwdStyle {
+    /// How many trailing directories to keep, the ones before them shown as
+    /// `...` like bash does. 0 keeps them all.
+    pub trim: usize,
+    /// How many characters to keep of the directories above the current
+    /// one, like fish's `~/s/p/shell`. 0 keeps them whole.
+    pub length: usize,
+}
+
+impl CwdStyle {
+    /// The style the variables set, anything but a number leaving it off.
+    pub fn from_env(env: &Env) -> Self {
+        let number = |name| {
+            env.var(name)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or_default()
+        };
+        Self {
+            trim: number("PROMPT_DIRTRIM"),
+            length: number("PROMPT_DIRLENGTH"),
+        }
+    }
+
+    /// Shortens `cwd`, as abbreviated with a leading `~` or `~name`, which
+    /// stays as it is.
+    fn apply(self, cwd: &str) -> String {
+        let (head, rest) = match cwd.split_once('/') {
+            Some((head, rest)) if head.starts_with('~') || head.is_empty() => (head, rest),
+            _ => return cwd.to_string(),
+        };
+        let mut dirs: Vec<String> = rest.split('/').map(String::from).collect();
+
+        if self.length > 0
+            && let Some((_, parents)) = dirs.split_last_mut()
+        {
+            for dir in parents {
+                let dot = usize::from(dir.starts_with('.'));
+                if let Some((end, _)) = dir.char_indices().nth(dot + self.length) {
+                    dir.truncate(end);
+                }
+            }
+        }
+        if self.trim > 0 && dirs.len() > self.trim {
+            dirs.drain(..dirs.len() - self.trim);
+            dirs.insert(0, String::from("..."));
+        }
+
+        format!("{head}/{}", dirs.join("/"))
+    }
+}
+
+/// Supplies the text a prompt escape expands to.
+pub trait Segment {
+    fn render(&self, state: &PromptState) -> String;
+}
+
+impl<F: Fn(&PromptState) -> String> Segment for F {
+    fn render(&self, state: &PromptState) -> String {
+        self(state)
+    }
+}
+
+/// Prompt segments by the escape character that shows them, `\u` for `u`.
+pub struct Segments {
+    segments: HashMap<char, Box<dyn Segment>>,
+}
+
+impl Default for Segments {
+    /// The bash escapes `\u`, `\h`, `\H`, `\w`, `\W`, `\$` and `\t`, plus `\?`
+    /// for a non-zero exit status, `\c` for the last command's duration and
+    /// `\g` for the git branch.
+    fn default() -> Self {
+        let mut segments = Self {
+            segments: HashMap::new(),
+        };
+        segments.register('u', |_: &PromptState| user());
+        segments.register('h', |_: &PromptState| {
+            hostname().split('.').next().unwrap_or_default().to_string()
+        });
+        segments.register('H', |_: &PromptState| hostname());
+        segments.register('w', |state: &PromptState| {
+            state.cwd_style.apply(&cwd(state.named_dirs))
+        });
+        segments.register('W', |state: &PromptState| cwd_basename(state.named_dirs));
+        segments.register('$', |_: &PromptState| {
+            if is_root() { "#" } else { "$" }.to_string()
+        });
+        segments.register('t', |_: &PromptState| time());
+        segments.register('?', |state: &PromptState| match state.last_status {
+            0 => String::new(),
+            status => status.to_string(),
+        });
+        segments.register('c', |state: &PromptState| {
+            state.duration.map(format_duration).unwrap_or_default()
+        });
+        segments.register('g', |_: &PromptState| git_branch().unwrap_or_default());
+
+        segments
+    }
+}
+
+impl Segments {
+    pub fn register(&mut self, escape: char, segment: impl Segment + 'static) {
+        self.segments.insert(escape, Box::new(segment));
+    }
+
+    /// Expands the escapes of the registered segments in `ps1`, along with
+    /// `\n`, `\e`, `\a`, `\\`, `\nnn` octal codes and the `\[ \]` non-printing
+    /// markers.
+    pub fn render(&self, ps1: &str, state: &PromptState) -> Prompt {
+        expand(ps1, |escape| {
+            let segment = self.segments.get(&escape)?;
+            Some(segment.render(state))
+        })
+    }
+}
+
+/// Gets PS1 from a program like `starship prompt`, set with its arguments in
+/// $PROMPT_PROGRAM. It is told about the last command with starship's flags,
+/// and in $PROMPT_STATUS, $PROMPT_DURATION (in milliseconds) and
+/// $PROMPT_JOBS.
+#[derive(Default)]
+pub struct PromptProgram {
+    /// What it printed last, used again when it fails or takes too long.
+    last: Option<String>,
+}
+
+impl PromptProgram {
+    /// What `program` prints, without its final newline. After `timeout` it
+    /// is killed and what it printed the time before is used instead, so a
+    /// slow prompt never holds up input.
+    pub fn render(
+        &mut self,
+        program: &str,
+        state: &PromptState,
+        env: &Env,
+        timeout: Duration,
+    ) -> Option<String> {
+        match run_prompt_program(program, state, env, timeout) {
+            Ok(ps1) => {
+                self.last = Some(ps1.clone());
+                Some(ps1)
+            }
+            Err(err) => {
+                tracing::debug!(%err, program, "prompt program failed");
+                self.last.clone()
+            }
+        }
+    }
+}
+
+fn run_prompt_program(
+    program: &str,
+    state: &PromptState,
+    env: &Env,
+    timeout: Duration,
+) -> io::Result<String> {
+    let status = state.last_status.to_string();
+    let duration = state.duration.unwrap_or_default().as_millis().to_string();
+    // there are no background jobs to count
+    let jobs = "0";
+
+    let mut command = process::Command::new("sh");
+    env.apply(&mut command);
+    // through sh, as it comes with arguments
+    command
+        .arg("-c")
+        .arg(format!("{program} \"$@\""))
+        .arg("sh")
+        .arg(format!("--status={status}"))
+        .arg(format!("--cmd-duration={duration}"))
+        .arg(format!("--jobs={jobs}"))
+        .env("PROMPT_STATUS", &status)
+        .env("PROMPT_DURATION", &duration)
+        .env("PROMPT_JOBS", jobs)
+        // for starship to mark its escapes with `\[ \]` like bash needs
+        .env("STARSHIP_SHELL", "bash")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    if let Some(columns) = env.var("COLUMNS") {
+        command.arg(format!("--terminal-width={columns}"));
+    }
+
+    let mut child = command.spawn()?;
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = String::new();
+        let _ = sender.send(stdout.read_to_string(&mut output).map(|_| output));
+    });
+
+    let Ok(output) = receiver.recv_timeout(timeout) else {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+    };
+    let exit = child.wait()?;
+    if !exit.success() {
+        return Err(io::Error::other(exit.to_string()));
+    }
+
+    let output = output?;
+    Ok(output.strip_suffix('\n').unwrap_or(&output).to_string())
+}
+
+fn expand(ps1: &str, lookup: impl Fn(char) -> Option<String>) -> Prompt {
+    let mut prompt = Prompt {
+        plain: String::new(),
+        styled: String::new(),
+    };
+    let mut non_printing = false;
+
+    let mut chars = ps1.chars().peekable();
+    while let Some(char) = chars.next() {
+        let expanded = match (char, chars.peek()) {
+            ('\\', Some('[')) => {
+                chars.next();
+                non_printing = true;
+                continue;
+            }
+            ('\\', Some(']')) => {
+                chars.next();
+                non_printing = false;
+                continue;
+            }
+            ('\\', Some('0'..='7')) => {
+                let mut code = 0;
+                for _ in 0..3 {
+                    match chars.peek().and_then(|digit| digit.to_digit(8)) {
+                        Some(digit) => code = code * 8 + digit,
+                        None => break,
+                    }
+                    chars.next();
+                }
+                char::from_u32(code).map(String::from).unwrap_or_default()
+            }
+            ('\\', Some(&escape)) => {
+                chars.next();
+                match escape {
+                    'e' => String::from('\x1b'),
+                    'a' => String::from('\x07'),
+                    'n' => String::from('\n'),
+                    '\\' => String::from('\\'),
+                    _ => lookup(escape).unwrap_or_else(|| format!("\\{escape}")),
+                }
+            }
+            (char, _) => String::from(char),
+        };
+
+        prompt.styled.push_str(&expanded);
+        if !non_printing {
+            prompt.plain.push_str(&expanded);
+        }
+    }
+
+    prompt
+}
+
+pub(crate) fn cwd(named_dirs: &NamedDirs) -> String {
+    match env::current_dir() {
+        Ok(cwd) => named_dirs.abbreviate(&cwd),
+        Err(_) => String::from("."),
+    }
+}
+
+fn cwd_basename(named_dirs: &NamedDirs) -> String {
+    let cwd = cwd(named_dirs);
+    match cwd.rsplit_once('/') {
+        Some((_, "")) | None => cwd,
+        Some((_, basename)) => basename.to_string(),
+    }
+}
+
+#[cfg(unix)]
+fn user() -> String {
+    use std::ffi::CStr;
+
+    if let Ok(user) = env::var("USER") {
+        return user;
+    }
+
+    // SAFETY: getpwuid returns null or a pointer to a static passwd entry,
+    // read before any other call could overwrite it.
+    unsafe {
+        let passwd = libc::getpwuid(libc::getuid());
+        if passwd.is_null() {
+            return libc::getuid().to_string();
+        }
+        CStr::from_ptr((*passwd).pw_name)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+#[cfg(not(unix))]
+fn user() -> String {
+    env::var("USERNAME").unwrap_or_default()
+}
+
+#[cfg(unix)]
+pub(crate) fn hostname() -> String {
+    use std::ffi::CStr;
+
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is writable for its whole length, and the last byte
+    // stays NUL even if the name gets truncated.
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len() - 1) };
+    if result != 0 {
+        return String::new();
+    }
+
+    CStr::from_bytes_until_nul(&buf)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn hostname() -> String {
+    env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions.
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+/// The local time as HH:MM:SS.
+fn time() -> String {
+    let now = LocalTime::now();
+    format!("{:02}:{:02}:{:02}", now.hour, now.minute, now.second)
+}
+
+/// The local date and time as `YYYY-MM-DD HH:MM:SS`.
+pub(crate) fn date_time() -> String {
+    let now = LocalTime::now();
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+        now.year, now.month, now.day, now.hour, now.minute, now.second
+    )
+}
+
+struct LocalTime {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+impl LocalTime {
+    #[cfg(unix)]
+    fn now() -> Self {
+        use std::{mem, ptr};
+
+        // SAFETY: localtime_r only writes to the tm we own.
+        let tm = unsafe {
+            let now = libc::time(ptr::null_mut());
+            let mut tm: libc::tm = mem::zeroed();
+            libc::localtime_r(&now, &mut tm);
+            tm
+        };
+
+        Self {
+            year: i64::from(tm.tm_year) + 1900,
+            month: tm.tm_mon as u32 + 1,
+            day: tm.tm_mday as u32,
+            hour: tm.tm_hour as u32,
+            minute: tm.tm_min as u32,
+            second: tm.tm_sec as u32,
+        }
+    }
+
+    /// Without a portable way to the local time zone, the time in UTC.
+    #[cfg(not(unix))]
+    fn now() -> Self {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        let day_secs = secs % 86400;
+        let (year, month, day) = civil_from_days((secs / 86400) as i64);
+        Self {
+            year,
+            month,
+            day,
+            hour: (day_secs / 3600) as u32,
+            minute: (day_secs / 60 % 60) as u32,
+            second: (day_secs % 60) as u32,
+        }
+    }
+}
+
+/// The date `days` after 1970-01-01, by Howard Hinnant's algorithm.
+#[cfg(not(unix))]
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day)
+}
+
+/// `350ms`, `12s` or `2m5s`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0 => format!("{}ms", duration.as_millis()),
+        1..60 => format!("{secs}s"),
+        _ => format!("{}m{}s", secs / 60, secs % 60),
+    }
+}
+
+/// The checked out branch of the repository containing the working
+/// directory, or the abbreviated commit when HEAD is detached. Reads
+/// `.git/HEAD` directly rather than running git, as this runs for every
+/// prompt.
+fn git_branch() -> Option<String> {
+    let git_dir = git_dir(&env::current_dir().ok()?)?;
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    branch_name(&head)
+}
+
+/// The nearest `.git` directory at or above `dir`. In worktrees and
+/// submodules `.git` is a file pointing to the real one.
+fn git_dir(dir: &Path) -> Option<PathBuf> {
+    for dir in dir.ancestors() {
+        let git = dir.join(".git");
+        if git.is_dir() {
+            return Some(git);
+        }
+        if let Ok(contents) = fs::read_to_string(&git)
+            && let Some(path) = contents.trim_end().strip_prefix("gitdir: ")
+        {
+            return Some(dir.join(path));
+        }
+    }
+
+    None
+}
+
+fn branch_name(head: &str) -> Option<String> {
+    let head = head.trim_end();
+    if let Some(reference) = head.strip_prefix("ref: ") {
+        let name = reference.strip_prefix("refs/heads/").unwrap_or(reference);
+        return Some(name.to_string());
+    }
+
+    head.get(..7).map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("$ ", "$ ", "$ ")]
+    #[case(r"\u@\h:\w\$ ", "me@box:~/src$ ", "me@box:~/src$ ")]
+    #[case(r"\[\e[32m\]\w\[\e[0m\] ", "~/src ", "\x1b[32m~/src\x1b[0m ")]
+    #[case(r"\[\033[1m\]>\[\033[0m\]", ">", "\x1b[1m>\x1b[0m")]
+    #[case(r"a\nb\\ \q", "a\nb\\ \\q", "a\nb\\ \\q")]
+    fn expand_test(#[case] ps1: &str, #[case] plain: &str, #[case] styled: &str) {
+        let lookup = |escape| match escape {
+            'u' => Some(String::from("me")),
+            'h' => Some(String::from("box")),
+            'w' => Some(String::from("~/src")),
+            '$' => Some(String::from("$")),
+            _ => None,
+        };
+
+        let expected = Prompt {
+            plain: plain.to_string(),
+            styled: styled.to_string(),
+        };
+        assert_eq!(expand(ps1, lookup), expected);
+    }
+
+    #[rstest]
+    #[case("~/src/project/shell", 0, 0, "~/src/project/shell")]
+    #[case("~/src/project/shell", 0, 1, "~/s/p/shell")]
+    #[case("~proj/.config/nvim", 0, 2, "~proj/.co/nvim")]
+    #[case("/usr/local/share/doc", 2, 0, "/.../share/doc")]
+    #[case("~/src/project/shell", 2, 1, "~/.../p/shell")]
+    #[case("~/src", 2, 1, "~/src")]
+    #[case("~", 1, 1, "~")]
+    #[case("/", 1, 1, "/")]
+    #[case(".", 1, 1, ".")]
+    fn cwd_style_test(
+        #[case] cwd: &str,
+        #[case] trim: usize,
+        #[case] length: usize,
+        #[case] expected: &str,
+    ) {
+        assert_eq!(CwdStyle { trim, length }.apply(cwd), expected);
+    }
+
+    #[test]
+    fn prompt_program_falls_back_to_its_last_prompt() {
+        let env = Env::new([("PATH", "/usr/bin:/bin"), ("COLUMNS", "80")]);
+        let named_dirs = NamedDirs::new();
+        let state = PromptState {
+            named_dirs: &named_dirs,
+            last_status: 1,
+            duration: Some(Duration::from_millis(1500)),
+            cwd_style: CwdStyle::default(),
+        };
+        let timeout = Duration::from_secs(5);
+        let mut program = PromptProgram::default();
+
+        assert_eq!(program.render("exit 1", &state, &env, timeout), None);
+        assert_eq!(
+            program
+                .render("printf '%s,'", &state, &env, timeout)
+                .as_deref(),
+            Some("--status=1,--cmd-duration=1500,--jobs=0,--terminal-width=80,")
+        );
+        assert_eq!(
+            program
+                .render(
+                    "sh -c 'echo \"$PROMPT_STATUS $PROMPT_DURATION\"'",
+                    &state,
+                    &env,
+                    timeout
+                )
+                .as_deref(),
+            Some("1 1500")
+        );
+        assert_eq!(
+            program
+                .render("sleep 5", &state, &env, Duration::from_millis(50))
+                .as_deref(),
+            Some("1 1500")
+        );
+    }
+
+    #[rstest]
+    #[case(Duration::from_millis(350), "350ms")]
+    #[case(Duration::from_millis(12_900), "12s")]
+    #[case(Duration::from_secs(125), "2m5s")]
+    fn format_duration_test(#[case] duration: Duration, #[case] expected: &str) {
+        assert_eq!(format_duration(duration), expected);
+    }
+
+    #[rstest]
+    #[case("ref: refs/heads/main\n", Some("main"))]
+    #[case("ref: refs/heads/feature/x\n", Some("feature/x"))]
+    #[case("3aa48bcf1e2d0c4b9a7e6f5d4c3b2a1908f7e6d5\n", Some("3aa48bc"))]
+    #[case("", None)]
+    fn branch_name_test(#[case] head: &str, #[case] expected: Option<&str>) {
+        assert_eq!(branch_name(head).as_deref(), expected);
+    }
+}
@@ -0,0 +1,797 @@
+use crate::pipeline;
+use crate::streams::Output;
+use std::cell::RefCell;
+use std::io;
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+
+pub(crate) use imp::{Child, Reactor};
+
+/// Reads the output of a command for a builtin further down the pipeline,
+/// running the rest of the pipeline's I/O while it waits for it.
+pub(crate) struct Reader {
+    reactor: Rc<RefCell<Reactor>>,
+    from: imp::Source,
+}
+
+impl Reader {
+    pub(crate) fn new(reactor: Rc<RefCell<Reactor>>, from: io::PipeReader) -> Self {
+        Self {
+            reactor,
+            from: imp::Source::new(from),
+        }
+    }
+}
+
+impl io::Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        imp::read(&self.reactor, &mut self.from, buf)
+    }
+}
+
+/// A command's output going where it is redirected.
+struct Copying {
+    from: imp::Source,
+    to: Output,
+    /// Whether to try moving it with splice(2), until that turns out not to
+    /// work for the output.
+    #[cfg(target_os = "linux")]
+    splice: bool,
+}
+
+/// A builtin's output going into the command after it.
+struct Feeding {
+    to: imp::Sink,
+    data: Vec<u8>,
+    written: usize,
+}
+
+/// A command that hasn't exited yet, with where to store its exit status.
+struct Waiting {
+    child: Child,
+    status: Arc<AtomicI32>,
+}
+
+impl Waiting {
+    fn exited(&self, exit_status: process::ExitStatus) {
+        tracing::debug!(pid = self.child.id(), %exit_status, "exited");
+        let code = pipeline::exit_code(exit_status);
+        self.status.store(code, Ordering::SeqCst);
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::{Copying, Feeding, Waiting};
+    use crate::streams::Output;
+    use crate::usage::Usage;
+    use std::cell::RefCell;
+    use std::fs::File;
+    use std::io::{self, Read, Write};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::os::unix::process::ExitStatusExt;
+    use std::process;
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::sync::{Arc, OnceLock};
+    use std::time::{Duration, Instant};
+
+    /// A command started by the shell. Either way it is reaped with wait4,
+    /// by its id.
+    pub(crate) enum Child {
+        Std(process::Child),
+        /// Started with posix_spawn(3).
+        Spawned {
+            pid: u32,
+            /// Keeps its stdin open while it runs when nothing is fed to it,
+            /// like std keeps a piped stdin.
+            _stdin: Option<io::PipeWriter>,
+        },
+    }
+
+    impl Child {
+        pub(crate) fn id(&self) -> u32 {
+            match self {
+                Child::Std(child) => child.id(),
+                Child::Spawned { pid, .. } => *pid,
+            }
+        }
+    }
+
+    impl From<process::Child> for Child {
+        fn from(child: process::Child) -> Self {
+            Child::Std(child)
+        }
+    }
+
+    /// How long to wait for SIGCHLD before checking on the commands anyway:
+    /// another thread running commands, as in tests, may have taken it.
+    const REAP_INTERVAL: Duration = Duration::from_millis(50);
+
+    /// Runs the I/O of pipelines and waits for their commands on the
+    /// shell's own thread, multiplexing every pipe and SIGCHLD with poll(2)
+    /// instead of a thread for each.
+    #[derive(Default)]
+    pub(crate) struct Reactor {
+        copies: Vec<Copying>,
+        feeds: Vec<Feeding>,
+        children: Vec<Waiting>,
+        /// What the commands reaped since the last `take_usage` used.
+        usage: Usage,
+        /// The first error writing output, reported once everything ran.
+        error: Option<io::Error>,
+        timeout: Option<Timeout>,
+    }
+
+    /// When to signal the commands run under `timeout`.
+    struct Timeout {
+        /// The next signal to send and when, TERM and then KILL.
+        next: Option<(Instant, libc::c_int)>,
+        kill_after: Duration,
+        expired: bool,
+    }
+
+    /// The read end of a pipe, non-blocking.
+    pub(super) struct Source(File);
+
+    impl Source {
+        pub(super) fn new(pipe: impl Into<OwnedFd>) -> Self {
+            Self(non_blocking(pipe.into()))
+        }
+    }
+
+    /// The write end of a pipe, non-blocking.
+    pub(super) struct Sink(File);
+
+    impl Reactor {
+        /// Copies everything read from `from` to `to`.
+        pub(crate) fn copy(&mut self, from: impl Into<OwnedFd>, to: Output) {
+            self.copies.push(Copying {
+                from: Source::new(from),
+                to,
+                #[cfg(target_os = "linux")]
+                splice: true,
+            });
+        }
+
+        /// Writes `data` to `to`, then closes it.
+        pub(crate) fn feed(&mut self, to: impl Into<OwnedFd>, data: Vec<u8>) {
+            let to = Sink(non_blocking(to.into()));
+            self.feeds.push(Feeding {
+                to,
+                data,
+                written: 0,
+            });
+        }
+
+        /// Stores the exit status of `child` in `status` once it exits.
+        pub(crate) fn wait(&mut self, child: impl Into<Child>, status: Arc<AtomicI32>) {
+            sigchld_pipe();
+            self.children.push(Waiting {
+                child: child.into(),
+                status,
+            });
+        }
+
+        /// Sends TERM to the commands waited for once `duration` passed, and
+        /// KILL if they still run `kill_after` later. Until it is cleared,
+        /// commands are started in process groups of their own, so whatever
+        /// they started is signalled with them.
+        pub(crate) fn set_timeout(&mut self, duration: Duration, kill_after: Duration) {
+            self.timeout = Some(Timeout {
+                next: Some((Instant::now() + duration, libc::SIGTERM)),
+                kill_after,
+                expired: false,
+            });
+        }
+
+        pub(crate) fn has_timeout(&self) -> bool {
+            self.timeout.is_some()
+        }
+
+        /// Stops the timeout. Returns whether it expired.
+        pub(crate) fn clear_timeout(&mut self) -> bool {
+            self.timeout.take().is_some_and(|timeout| timeout.expired)
+        }
+
+        /// Runs until all output is copied and every command exited.
+        pub(crate) fn run(&mut self) -> io::Result<()> {
+            self.reap();
+            while !(self.copies.is_empty() && self.feeds.is_empty() && self.children.is_empty()) {
+                self.turn(None)?;
+            }
+
+            self.error.take().map_or(Ok(()), Err)
+        }
+
+        /// Waits until there is something to do, or `extra` can be read, and
+        /// does it.
+        fn turn(&mut self, extra: Option<RawFd>) -> io::Result<()> {
+            let sigchld = sigchld_pipe();
+            let mut fds = vec![poll_fd(sigchld.as_raw_fd(), libc::POLLIN)];
+            fds.extend(
+                self.copies
+                    .iter()
+                    .map(|copy| poll_fd(copy.from.0.as_raw_fd(), libc::POLLIN)),
+            );
+            fds.extend(
+                self.feeds
+                    .iter()
+                    .map(|feed| poll_fd(feed.to.0.as_raw_fd(), libc::POLLOUT)),
+            );
+            fds.extend(extra.map(|fd| poll_fd(fd, libc::POLLIN)));
+
+            let timeout = if self.children.is_empty() {
+                -1
+            } else {
+                let mut wait = REAP_INTERVAL;
+                if let Some(Timeout {
+                    next: Some((at, _)),
+                    ..
+                }) = &self.timeout
+                {
+                    wait = wait.min(at.saturating_duration_since(Instant::now()));
+                }
+                wait.as_micros().div_ceil(1000) as libc::c_int
+            };
+            // SAFETY: `fds` is a valid array of `fds.len()` pollfd structs.
+            let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+            if ready < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+
+            if fds[0].revents != 0 {
+                let _ = io::copy(&mut &*sigchld, &mut io::sink());
+            }
+            let mut revents = fds[1..].iter().map(|fd| fd.revents);
+            let copies: Vec<_> = revents.by_ref().take(self.copies.len()).collect();
+            let feeds: Vec<_> = revents.by_ref().take(self.feeds.len()).collect();
+
+            let mut ready = copies.into_iter();
+            let error = &mut self.error;
+            self.copies
+                .retain_mut(|copy| ready.next() == Some(0) || copy.step(error));
+            let mut ready = feeds.into_iter();
+            self.feeds
+                .retain_mut(|feed| ready.next() == Some(0) || feed.step());
+            self.reap();
+            self.enforce_timeout();
+
+            Ok(())
+        }
+
+        /// Signals the commands still running if the timeout passed.
+        fn enforce_timeout(&mut self) {
+            let Some(timeout) = &mut self.timeout else {
+                return;
+            };
+            let Some((at, signal)) = timeout.next else {
+                return;
+            };
+            let now = Instant::now();
+            if self.children.is_empty() || now < at {
+                return;
+            }
+
+            tracing::debug!(signal, "timed out");
+            for waiting in &self.children {
+                waiting.signal(signal);
+            }
+            timeout.expired = true;
+            timeout.next =
+                (signal == libc::SIGTERM).then(|| (now + timeout.kill_after, libc::SIGKILL));
+        }
+
+        fn reap(&mut self) {
+            let usage = &mut self.usage;
+            self.children.retain(|waiting| match waiting.reap() {
+                Some(used) => {
+                    usage.add(&used);
+                    false
+                }
+                None => true,
+            });
+        }
+
+        /// What the commands that exited since the last call used.
+        pub(crate) fn take_usage(&mut self) -> Usage {
+            std::mem::take(&mut self.usage)
+        }
+    }
+
+    impl Waiting {
+        /// Sends `signal` to its process group, or to it alone if it isn't
+        /// the leader of one.
+        fn signal(&self, signal: libc::c_int) {
+            let pid = self.child.id() as libc::pid_t;
+            // SAFETY: only sends a signal. Until it is reaped, no other
+            // process or group can have its id.
+            unsafe {
+                if libc::killpg(pid, signal) < 0 {
+                    libc::kill(pid, signal);
+                }
+            }
+        }
+
+        /// What it used if it exited, storing its exit status.
+        fn reap(&self) -> Option<Usage> {
+            let mut status = 0;
+            // SAFETY: rusage is plain data, filled in by wait4.
+            let mut rusage = unsafe { std::mem::zeroed() };
+            let pid = self.child.id() as libc::pid_t;
+            // SAFETY: `status` and `rusage` are valid to write to.
+            match unsafe { libc::wait4(pid, &mut status, libc::WNOHANG, &mut rusage) } {
+                0 => None,
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() == io::ErrorKind::Interrupted {
+                        return None;
+                    }
+                    tracing::warn!(pid, %err, "wait failed");
+                    Some(Usage::default())
+                }
+                _ => {
+                    self.exited(process::ExitStatus::from_raw(status));
+                    Some(Usage::from_rusage(&rusage))
+                }
+            }
+        }
+    }
+
+    impl Copying {
+        /// Copies what can be read without blocking. Returns whether there
+        /// is more to come.
+        fn step(&mut self, error: &mut Option<io::Error>) -> bool {
+            #[cfg(target_os = "linux")]
+            if self.splice
+                && let Some(more) = self.splice(error)
+            {
+                return more;
+            }
+
+            let mut buf = [0; 8192];
+            loop {
+                let read = match self.from.0.read(&mut buf) {
+                    Ok(0) => return false,
+                    Ok(read) => read,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => return true,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => return false,
+                };
+                // closing the pipe stops the command with SIGPIPE
+                if let Err(err) = self
+                    .to
+                    .write_all(&buf[..read])
+                    .and_then(|()| self.to.flush())
+                {
+                    error.get_or_insert(err);
+                    return false;
+                }
+            }
+        }
+
+        /// Moves what can be read without blocking straight from the pipe
+        /// to the output, like `step`. `None` when the output doesn't
+        /// support it, like a terminal or a file opened for appending.
+        #[cfg(target_os = "linux")]
+        fn splice(&mut self, error: &mut Option<io::Error>) -> Option<bool> {
+            let Some(to) = self.to.as_raw_fd() else {
+                self.splice = false;
+                return None;
+            };
+            // what the shell wrote to it has to come first
+            if let Err(err) = self.to.flush() {
+                error.get_or_insert(err);
+                return Some(false);
+            }
+
+            let from = self.from.0.as_raw_fd();
+            loop {
+                // SAFETY: both descriptors are open, and null offsets make
+                // it use and update their file positions.
+                let moved = unsafe {
+                    libc::splice(
+                        from,
+                        std::ptr::null_mut(),
+                        to,
+                        std::ptr::null_mut(),
+                        1 << 16,
+                        libc::SPLICE_F_MOVE,
+                    )
+                };
+                if moved > 0 {
+                    continue;
+                } else if moved == 0 {
+                    return Some(false);
+                }
+
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::Interrupted => {}
+                    // the pipe is empty, or the output is a full pipe
+                    io::ErrorKind::WouldBlock if !wait_writable(to) => return Some(true),
+                    io::ErrorKind::WouldBlock => {}
+                    _ if err.raw_os_error() == Some(libc::EINVAL) => {
+                        self.splice = false;
+                        return None;
+                    }
+                    _ => {
+                        error.get_or_insert(err);
+                        return Some(false);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Blocks until `fd` can be written to, like a blocking write would.
+    /// Returns whether it had to.
+    #[cfg(target_os = "linux")]
+    fn wait_writable(fd: RawFd) -> bool {
+        let mut fds = [poll_fd(fd, libc::POLLOUT)];
+        // SAFETY: `fds` is a valid array of one pollfd.
+        if unsafe { libc::poll(fds.as_mut_ptr(), 1, 0) } != 0 {
+            return false;
+        }
+
+        // SAFETY: as above.
+        unsafe { libc::poll(fds.as_mut_ptr(), 1, -1) };
+        true
+    }
+
+    impl Feeding {
+        /// Writes what can be written without blocking. Returns whether
+        /// there is more to write.
+        fn step(&mut self) -> bool {
+            while self.written < self.data.len() {
+                match self.to.0.write(&self.data[self.written..]) {
+                    Ok(written) => self.written += written,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => return true,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    // the command exited without reading it all
+                    Err(_) => return false,
+                }
+            }
+
+            false
+        }
+    }
+
+    pub(super) fn read(
+        reactor: &RefCell<Reactor>,
+        from: &mut Source,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        loop {
+            match from.0.read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    reactor.borrow_mut().turn(Some(from.0.as_raw_fd()))?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn poll_fd(fd: RawFd, events: libc::c_short) -> libc::pollfd {
+        libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        }
+    }
+
+    fn non_blocking(fd: OwnedFd) -> File {
+        set_flags(
+            fd.as_raw_fd(),
+            libc::F_GETFL,
+            libc::F_SETFL,
+            libc::O_NONBLOCK,
+        );
+        File::from(fd)
+    }
+
+    fn set_flags(fd: RawFd, get: libc::c_int, set: libc::c_int, flags: libc::c_int) {
+        // SAFETY: `fd` is open, and these only change its flags.
+        unsafe {
+            let old = libc::fcntl(fd, get);
+            libc::fcntl(fd, set, old | flags);
+        }
+    }
+
+    /// The write end of the pipe the SIGCHLD handler writes to.
+    static SIGCHLD_WRITER: AtomicI32 = AtomicI32::new(-1);
+
+    /// The read end of a pipe that becomes readable when a child exits, set
+    /// up with the SIGCHLD handler on first use.
+    fn sigchld_pipe() -> &'static File {
+        static READER: OnceLock<File> = OnceLock::new();
+        READER.get_or_init(|| {
+            let mut fds = [0; 2];
+            // SAFETY: `fds` has room for the two descriptors.
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+                panic!("pipe: {}", io::Error::last_os_error());
+            }
+            for fd in fds {
+                set_flags(fd, libc::F_GETFL, libc::F_SETFL, libc::O_NONBLOCK);
+                set_flags(fd, libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+            SIGCHLD_WRITER.store(fds[1], Ordering::SeqCst);
+
+            // SAFETY: the handler only calls write(2), which is
+            // async-signal-safe.
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_sigchld as *const () as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART | libc::SA_NOCLDSTOP;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(libc::SIGCHLD, &action, std::ptr::null_mut());
+                File::from_raw_fd(fds[0])
+            }
+        })
+    }
+
+    extern "C" fn on_sigchld(_signal: libc::c_int) {
+        let fd = SIGCHLD_WRITER.load(Ordering::SeqCst);
+        // a full pipe already wakes up the reactor
+        // SAFETY: writes one byte from a valid buffer.
+        unsafe { libc::write(fd, [0u8].as_ptr().cast(), 1) };
+    }
+}
+
+/// Without poll(2), every copy and wait gets a thread of its own.
+#[cfg(not(unix))]
+mod imp {
+    use super::{Copying, Feeding, Waiting};
+    use crate::streams::Output;
+    use crate::usage::Usage;
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::process;
+    use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// How often a command under `timeout` is checked on.
+    const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+    /// Commands are only started by std here.
+    pub(crate) type Child = process::Child;
+
+    #[derive(Default)]
+    pub(crate) struct Reactor {
+        threads: Vec<thread::JoinHandle<()>>,
+        /// When to kill the commands run under `timeout`, and whether it
+        /// had to.
+        timeout: Option<(Instant, Arc<AtomicBool>)>,
+    }
+
+    pub(super) struct Source(Box<dyn Read + Send>);
+
+    impl Source {
+        pub(super) fn new(pipe: impl Read + Send + 'static) -> Self {
+            Self(Box::new(pipe))
+        }
+    }
+
+    pub(super) struct Sink(process::ChildStdin);
+
+    impl Reactor {
+        pub(crate) fn copy(&mut self, from: impl Read + Send + 'static, to: Output) {
+            let mut copy = Copying {
+                from: Source::new(from),
+                to,
+            };
+            self.threads.push(thread::spawn(move || {
+                let _ = io::copy(&mut copy.from.0, &mut copy.to);
+            }));
+        }
+
+        pub(crate) fn feed(&mut self, to: process::ChildStdin, data: Vec<u8>) {
+            let mut feed = Feeding {
+                to: Sink(to),
+                data,
+                written: 0,
+            };
+            self.threads.push(thread::spawn(move || {
+                let _ = feed.to.0.write_all(&feed.data[feed.written..]);
+            }));
+        }
+
+        pub(crate) fn wait(&mut self, child: process::Child, status: Arc<AtomicI32>) {
+            let mut waiting = Waiting { child, status };
+            let timeout = self.timeout.clone();
+            self.threads.push(thread::spawn(move || {
+                if let Some((at, expired)) = timeout {
+                    let running = |child: &mut process::Child| matches!(child.try_wait(), Ok(None));
+                    while running(&mut waiting.child) && Instant::now() < at {
+                        thread::sleep(POLL_INTERVAL);
+                    }
+                    if running(&mut waiting.child) {
+                        expired.store(true, Ordering::SeqCst);
+                        let _ = waiting.child.kill();
+                    }
+                }
+                if let Ok(exit_status) = waiting.child.wait() {
+                    waiting.exited(exit_status);
+                }
+            }));
+        }
+
+        /// Kills the commands waited for once `duration` passed. There is
+        /// no TERM to send first, so `kill_after` is ignored.
+        pub(crate) fn set_timeout(&mut self, duration: Duration, _kill_after: Duration) {
+            self.timeout = Some((Instant::now() + duration, Arc::default()));
+        }
+
+        pub(crate) fn has_timeout(&self) -> bool {
+            self.timeout.is_some()
+        }
+
+        pub(crate) fn clear_timeout(&mut self) -> bool {
+            self.timeout
+                .take()
+                .is_some_and(|(_, expired)| expired.load(Ordering::SeqCst))
+        }
+
+        pub(crate) fn run(&mut self) -> io::Result<()> {
+            for thread in self.threads.drain(..) {
+                thread.join().unwrap();
+            }
+
+            Ok(())
+        }
+
+        /// Nothing is known about what commands use here.
+        pub(crate) fn take_usage(&mut self) -> Usage {
+            Usage::default()
+        }
+    }
+
+    pub(super) fn read(
+        _reactor: &RefCell<Reactor>,
+        from: &mut Source,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        from.0.read(buf)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::streams::{Capture, Sink};
+    use pretty_assertions::assert_eq;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::Read;
+    use std::os::fd::OwnedFd;
+    use std::process::{Command, Stdio};
+    use std::time::{Duration, Instant};
+
+    fn spawn(program: &str, args: &[&str]) -> process::Child {
+        Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap()
+    }
+
+    #[test]
+    fn runs_copies_feeds_and_waits() {
+        let mut reactor = Reactor::default();
+        let mut cat = spawn("cat", &[]);
+        let mut fail = spawn("sh", &["-c", "echo oops >&2; exit 3"]);
+        let output = Capture::default();
+        let errors = Capture::default();
+        let cat_status = Arc::new(AtomicI32::new(-1));
+        let fail_status = Arc::new(AtomicI32::new(-1));
+
+        // more than fits in a pipe, so feeding and copying must interleave
+        let data = vec![b'x'; 1 << 20];
+        reactor.feed(cat.stdin.take().unwrap(), data.clone());
+        reactor.copy(cat.stdout.take().unwrap(), sink(&output));
+        reactor.copy(fail.stderr.take().unwrap(), sink(&errors));
+        reactor.wait(cat, Arc::clone(&cat_status));
+        reactor.wait(fail, Arc::clone(&fail_status));
+        reactor.run().unwrap();
+
+        assert_eq!(output.take().len(), data.len());
+        assert_eq!(errors.take(), b"oops\n");
+        assert_eq!(cat_status.load(Ordering::SeqCst), 0);
+        assert_eq!(fail_status.load(Ordering::SeqCst), 3);
+        assert!(reactor.take_usage().max_rss > 0);
+        assert_eq!(reactor.take_usage(), Default::default());
+    }
+
+    fn sink(capture: &Capture) -> Output {
+        Output::Sink(Sink::new(capture.clone()))
+    }
+
+    #[test]
+    fn copies_into_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("copied");
+        let mut reactor = Reactor::default();
+        let mut seq = spawn("seq", &["100000"]);
+        reactor.copy(
+            seq.stdout.take().unwrap(),
+            Output::File(File::create(&path).unwrap()),
+        );
+        reactor.wait(seq, Arc::new(AtomicI32::new(-1)));
+        reactor.run().unwrap();
+
+        // appending, which splice(2) refuses to do
+        let mut seq = spawn("seq", &["3"]);
+        let file = OpenOptions::new().append(true).open(&path).unwrap();
+        reactor.copy(seq.stdout.take().unwrap(), Output::File(file));
+        reactor.wait(seq, Arc::new(AtomicI32::new(-1)));
+        reactor.run().unwrap();
+
+        let copied = fs::read_to_string(&path).unwrap();
+        let expected: String = (1..=100000)
+            .chain(1..=3)
+            .map(|n| format!("{n}\n"))
+            .collect();
+        assert!(copied == expected, "copied {} bytes", copied.len());
+    }
+
+    #[test]
+    fn copies_into_colored_sinks() {
+        let mut reactor = Reactor::default();
+        let mut fail = spawn("sh", &["-c", "echo oops >&2"]);
+        let errors = Capture::default();
+        let colored = Sink::new(errors.clone()).colored("\x1b[31m");
+        reactor.copy(fail.stderr.take().unwrap(), Output::Sink(colored));
+        reactor.wait(fail, Arc::new(AtomicI32::new(-1)));
+        reactor.run().unwrap();
+
+        assert_eq!(errors.take(), b"\x1b[31moops\n\x1b[0m");
+    }
+
+    #[test]
+    fn timeout_signals_process_groups() {
+        let mut reactor = Reactor::default();
+        reactor.set_timeout(Duration::from_millis(100), Duration::from_millis(100));
+        let mut command = Command::new("sh");
+        // the shell ignores TERM, and its `sleep` only goes with the group
+        command.args(["-c", "trap '' TERM; sleep 5; exit 3"]);
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut sh = command.stdout(Stdio::piped()).spawn().unwrap();
+        let status = Arc::new(AtomicI32::new(-1));
+
+        let start = Instant::now();
+        reactor.copy(sh.stdout.take().unwrap(), sink(&Capture::default()));
+        reactor.wait(sh, Arc::clone(&status));
+        reactor.run().unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(status.load(Ordering::SeqCst), 128 + libc::SIGKILL);
+        assert!(reactor.clear_timeout());
+        assert!(!reactor.has_timeout());
+    }
+
+    #[test]
+    fn reader_runs_the_reactor_while_waiting() {
+        let reactor = Rc::new(RefCell::new(Reactor::default()));
+        let mut cat = spawn("cat", &[]);
+        let data = vec![b'y'; 1 << 20];
+        reactor
+            .borrow_mut()
+            .feed(cat.stdin.take().unwrap(), data.clone());
+        let stdout = OwnedFd::from(cat.stdout.take().unwrap());
+        let mut reader = Reader::new(Rc::clone(&reactor), io::PipeReader::from(stdout));
+
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        reactor.borrow_mut().wait(cat, Arc::new(AtomicI32::new(-1)));
+        reactor.borrow_mut().run().unwrap();
+
+        assert_eq!(read, data);
+    }
+}
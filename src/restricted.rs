@@ -0,0 +1,115 @@
+use crate::parser::{Command, OutputStream};
+use crate::ShellError;
+
+/// Variables a restricted shell can't assign, as they would let commands
+/// escape it.
+static READ_ONLY_VARIABLES: &[&str] = &["BASH_ENV", "ENV", "HISTFILE", "PATH", "SHELL"];
+
+/// Refuses what a shell started with `-r` doesn't allow, like rbash:
+/// changing directories, assigning or unsetting PATH, running commands by path,
+/// redirecting output to files, naming a history file and sourcing a file
+/// by path.
+pub(crate) fn check(command: &Command) -> Result<(), ShellError> {
+    let refuse = |message: String| Err(ShellError::Restricted { message });
+
+    for (name, _) in &command.assignments {
+        if READ_ONLY_VARIABLES.contains(&name.as_str()) {
+            return refuse(format!("{name}: readonly variable"));
+        }
+    }
+
+    match command.args.first() {
+        Some(name) if name == "export" || name == "unset" => {
+            for arg in &command.args[1..] {
+                let variable = match arg.split_once('=') {
+                    Some((variable, _)) => variable,
+                    None if name == "unset" => arg,
+                    None => continue,
+                };
+                if READ_ONLY_VARIABLES.contains(&variable) {
+                    return refuse(format!("{variable}: readonly variable"));
+                }
+            }
+        }
+        Some(name) if name == "cd" || name == "j" => {
+            return refuse(format!("{name}: restricted"));
+        }
+        Some(name) if name == "history" => {
+            if let Some(path) = history_file(&command.args[1..]) {
+                return refuse(format!("{name}: {path}: restricted"));
+            }
+        }
+        Some(name) if name == "source" || name == "." => {
+            if let Some(path) = command.args[1..].iter().find(|arg| arg.contains('/')) {
+                return refuse(format!("{name}: {path}: restricted"));
+            }
+        }
+        Some(name) if name.contains('/') => {
+            return refuse(format!(
+                "{name}: restricted: cannot specify `/' in command names"
+            ));
+        }
+        _ => {}
+    }
+
+    for redirect in &command.redirects {
+        if let OutputStream::File(path) = &redirect.to
+            && redirect.from != OutputStream::Stdin
+        {
+            return refuse(format!("{path}: restricted: cannot redirect output"));
+        }
+    }
+
+    Ok(())
+}
+
+/// The file named to `history -a`, `-n`, `-r` or `-w`, if one is.
+fn history_file(args: &[String]) -> Option<&String> {
+    let action = args
+        .iter()
+        .take_while(|arg| arg.starts_with('-') && !arg.starts_with("--"))
+        .any(|options| options.contains(['a', 'n', 'r', 'w']));
+    let file = args.iter().find(|arg| !arg.starts_with('-'));
+    file.filter(|_| action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("echo hi | cat", None)]
+    #[case("a=1 ls", None)]
+    #[case("cd /tmp", Some("cd: restricted"))]
+    #[case("j proj", Some("j: restricted"))]
+    #[case("PATH=/tmp ls", Some("PATH: readonly variable"))]
+    #[case("SHELL=/bin/sh", Some("SHELL: readonly variable"))]
+    #[case("export a=1 PATH=/tmp", Some("PATH: readonly variable"))]
+    #[case("export PATH", None)]
+    #[case("unset a ENV", Some("ENV: readonly variable"))]
+    #[case(
+        "/bin/ls",
+        Some("/bin/ls: restricted: cannot specify `/' in command names")
+    )]
+    #[case("echo hi > f", Some("f: restricted: cannot redirect output"))]
+    #[case("ls 2>>log", Some("log: restricted: cannot redirect output"))]
+    #[case("cat < notes 2>&1", None)]
+    #[case("history 5", None)]
+    #[case("history -a", None)]
+    #[case("history --export json", None)]
+    #[case("history -w /tmp/h", Some("history: /tmp/h: restricted"))]
+    #[case("history -a h", Some("history: h: restricted"))]
+    #[case("history -r -- h", Some("history: h: restricted"))]
+    #[case("source notes", None)]
+    #[case("source /tmp/notes", Some("source: /tmp/notes: restricted"))]
+    #[case(". ./notes", Some(".: ./notes: restricted"))]
+    #[case("source notes ../x", Some("source: ../x: restricted"))]
+    fn check_test(#[case] input: &str, #[case] expected: Option<&str>) {
+        let command = Parser::new(input).parse().unwrap();
+        let message = check(&command).err().map(|err| err.to_string());
+        assert_eq!(message.as_deref(), expected);
+    }
+}
@@ -1,122 +1,2295 @@
 use crate::bin_path::BinPath;
+use crate::completion::CompletionProvider;
+use crate::dir_history::DirHistory;
 use crate::editor::Editor;
-use crate::parser::{Command, Parser};
-use crate::pipeline::Pipeline;
-use crate::{print, ExitError};
+use crate::environment::Env;
+use crate::history::{self, HistControl};
+use crate::line_editor::{LineEditor, PlainReader, ReadError};
+use crate::named_dirs::{self, NamedDirs};
+use crate::options::Options;
+use crate::parser::{Command, Expander, Parser};
+use crate::pipeline::{self, Pipeline};
+use crate::plugins::Plugins;
+use crate::prompt::{self, CwdStyle, PromptProgram, PromptState, Segments};
+use crate::reactor::Reactor;
+use crate::streams::{Capture, Sink, Streams};
+use crate::terminal;
+use crate::tool_completion;
+use crate::traps::{Trap, Traps};
+use crate::{eprint, glob, lexer, print_to, restricted, ShellError};
+use std::borrow::Cow;
 use std::cell::RefCell;
-use std::env;
-use std::env::VarError;
+use std::collections::VecDeque;
+use std::io::IsTerminal;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
+use std::{env, fs, io, mem};
+
+/// What evaluating a string with [`Shell::eval_str`] printed, and its exit
+/// status.
+#[derive(Debug, PartialEq)]
+pub struct EvalResult {
+    pub stdout: String,
+    pub stderr: String,
+    pub status: i32,
+}
+
+/// Code run around every command the shell reads, at the prompt, from `-c`,
+/// scripts and sourced files, e.g. to title the terminal with it or time
+/// it. At the prompt, the commands in $PREEXEC_COMMAND and
+/// $POSTEXEC_COMMAND run after the hooks, and see the command in
+/// $HOOK_COMMAND and its exit status in $HOOK_STATUS.
+pub trait CommandHook {
+    /// Called right before `command` runs, with its words expanded.
+    fn preexec(&mut self, _command: &str) {}
+
+    /// Called right after `command` ran and exited with `status`. Commands
+    /// of a file sourced by `command` ran in between.
+    fn postexec(&mut self, _command: &str, _status: i32) {}
+}
+
+/// The hooks of a shell, shared with the pipelines sourcing files.
+#[derive(Default)]
+pub struct Hooks(Vec<Box<dyn CommandHook>>);
+
+impl Hooks {
+    fn preexec(&mut self, command: &str) {
+        for hook in &mut self.0 {
+            hook.preexec(command);
+        }
+    }
+
+    fn postexec(&mut self, command: &str, status: i32) {
+        for hook in &mut self.0 {
+            hook.postexec(command, status);
+        }
+    }
+}
+
+/// Runs `command` of a script with `run`, telling `hooks` about it. They
+/// aren't borrowed while it runs, as it may source another.
+pub(crate) fn run_hooked(
+    hooks: &RefCell<Hooks>,
+    command: &Command,
+    run: impl FnOnce(&Command) -> anyhow::Result<i32>,
+) -> anyhow::Result<i32> {
+    let text = pipeline::expanded_text(command);
+    hooks.borrow_mut().preexec(&text);
+    let result = run(command);
+    let status = match &result {
+        Ok(status) => *status,
+        Err(err) => err
+            .downcast_ref::<ShellError>()
+            .map_or(1, ShellError::status),
+    };
+    hooks.borrow_mut().postexec(&text, status);
+    result
+}
 
 pub struct Shell {
-    editor: Rc<RefCell<Editor>>,
+    editor: Rc<RefCell<dyn LineEditor>>,
     bin_path: Rc<RefCell<BinPath>>,
+    named_dirs: Rc<RefCell<NamedDirs>>,
+    options: Rc<RefCell<Options>>,
+    plugins: Rc<RefCell<Plugins>>,
+    traps: Rc<RefCell<Traps>>,
+    streams: Streams,
+    env: Env,
+    /// What was entered at the prompt, which may be several commands when
+    /// a block of lines was pasted.
     input_buffer: String,
+    /// The commands of `input_buffer` not run yet, with their line numbers.
+    queued: VecDeque<(usize, String)>,
+    /// The command being run, from `input_buffer`.
+    command_line: String,
+    /// How many lines were read, to number the next one for LINENO.
+    lines_read: usize,
+    /// Whether the command read was added to the history, to record how
+    /// running it went.
+    in_history: bool,
     command: Command,
+    /// Runs the I/O of every pipeline, keeping its storage for the next.
+    reactor: Rc<RefCell<Reactor>>,
+    last_status: i32,
+    /// How long the last command took to run.
+    last_duration: Option<Duration>,
+    segments: Segments,
+    prompt_program: PromptProgram,
+    /// The working directory last reported to the terminal.
+    reported_cwd: Option<PathBuf>,
+    /// Whether commands are read from a terminal, with prompts and line
+    /// editing, rather than from a pipe or file.
+    interactive: bool,
+    hooks: Rc<RefCell<Hooks>>,
 }
 
-impl Shell {
-    pub fn new() -> anyhow::Result<Shell> {
-        let bin_path = Rc::new(RefCell::new(BinPath::new()));
+/// Configures a [`Shell`] bound to other streams or variables than the ones
+/// of the process.
+#[derive(Default)]
+pub struct ShellBuilder {
+    stdin: Option<Box<dyn io::BufRead>>,
+    streams: Streams,
+    env: Env,
+    interactive: Option<bool>,
+    login: bool,
+    verbose: bool,
+    hooks: Hooks,
+    editor: Option<Rc<RefCell<dyn LineEditor>>>,
+    completions: Vec<(String, Box<dyn CompletionProvider>)>,
+}
+
+impl ShellBuilder {
+    /// Reads commands from `reader` instead of stdin. Such a shell is not
+    /// interactive unless asked to be, and an interactive one still edits
+    /// lines on the terminal.
+    pub fn stdin(mut self, reader: impl io::Read + 'static) -> Self {
+        self.stdin = Some(Box::new(io::BufReader::new(reader)));
+        self
+    }
+
+    pub fn stdout(mut self, writer: impl io::Write + Send + 'static) -> Self {
+        self.streams.stdout = Sink::new(writer);
+        self
+    }
+
+    pub fn stderr(mut self, writer: impl io::Write + Send + 'static) -> Self {
+        self.streams.stderr = Sink::new(writer);
+        self
+    }
+
+    /// Looks up PATH, HOME, the prompts and so on in `vars`, and runs
+    /// commands with only these variables set.
+    pub fn env<K: Into<String>, V: Into<String>>(
+        mut self,
+        vars: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.env = Env::new(vars);
+        self
+    }
 
-        let shell = Shell {
-            editor: Rc::new(RefCell::new(Editor::new(bin_path.clone())?)),
+    /// Whether to prompt and edit lines; by default when commands are read
+    /// from a terminal.
+    pub fn interactive(mut self, interactive: bool) -> Self {
+        self.interactive = Some(interactive);
+        self
+    }
+
+    /// Makes it a login shell, which reads its profile with
+    /// [`Shell::source_profile`] and can be left with `logout`.
+    pub fn login(mut self, login: bool) -> Self {
+        self.login = login;
+        self
+    }
+
+    /// Prints what it reads as it reads it, like `set -v`.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Has `hook` told about every command read before and after it runs.
+    pub fn hook(mut self, hook: impl CommandHook + 'static) -> Self {
+        self.hooks.0.push(Box::new(hook));
+        self
+    }
+
+    /// Completes the arguments of `command` with `provider`, in place of
+    /// the shell's own completion for it, if any.
+    pub fn completion(
+        mut self,
+        command: &str,
+        provider: impl CompletionProvider + 'static,
+    ) -> Self {
+        self.completions
+            .push((command.to_string(), Box::new(provider)));
+        self
+    }
+
+    /// Reads commands with `editor` instead of the editor or reader chosen
+    /// for stdin.
+    pub fn editor(mut self, editor: impl LineEditor + 'static) -> Self {
+        self.editor = Some(Rc::new(RefCell::new(editor)));
+        self
+    }
+
+    pub fn build(self) -> Result<Shell, ShellError> {
+        let interactive = self
+            .interactive
+            .unwrap_or_else(|| self.stdin.is_none() && io::stdin().is_terminal());
+        init_vars(&self.env, self.login);
+
+        let bin_path = Rc::new(RefCell::new(BinPath::with_env(self.env.clone())));
+        let named_dirs = Rc::new(RefCell::new(NamedDirs::new()));
+        if interactive && let Some(path) = dir_history_file(&self.env) {
+            match DirHistory::load(&path) {
+                Ok(visited) => *named_dirs.borrow_mut().visited_mut() = visited,
+                Err(err) => eprint!("{}: {err}\n", path.display()),
+            }
+        }
+        let options = Rc::new(RefCell::new(Options::new()));
+        options.borrow_mut().set_interactive(interactive);
+        options.borrow_mut().set_shopt("login_shell", self.login);
+        options.borrow_mut().set_option("verbose", self.verbose);
+        if self.env.var_os("POSIXLY_CORRECT").is_some() {
+            options.borrow_mut().set_option("posix", true);
+        }
+
+        let editor: Rc<RefCell<dyn LineEditor>> = match self.stdin {
+            _ if let Some(editor) = self.editor => editor,
+            _ if interactive => Rc::new(RefCell::new(Editor::new(
+                bin_path.clone(),
+                named_dirs.clone(),
+                options.clone(),
+                self.env.clone(),
+            )?)),
+            Some(stdin) => Rc::new(RefCell::new(PlainReader::new(stdin))),
+            None => Rc::new(RefCell::new(PlainReader::default())),
+        };
+        let completions = tool_completion::providers()
+            .into_iter()
+            .chain(self.completions);
+        for (command, provider) in completions {
+            editor.borrow_mut().register_completion(&command, provider);
+        }
+
+        Ok(Shell {
+            editor,
             bin_path,
+            named_dirs,
+            options,
+            plugins: Rc::default(),
+            traps: Rc::default(),
+            streams: self.streams,
+            env: self.env,
             input_buffer: String::new(),
-            command: Command {
-                args: Vec::new(),
-                redirects: Vec::new(),
-            },
+            queued: VecDeque::new(),
+            command_line: String::new(),
+            lines_read: 0,
+            in_history: false,
+            command: Command::default(),
+            reactor: Rc::default(),
+            last_status: 0,
+            last_duration: None,
+            segments: Segments::default(),
+            prompt_program: PromptProgram::default(),
+            reported_cwd: None,
+            interactive,
+            hooks: Rc::new(RefCell::new(self.hooks)),
+        })
+    }
+}
+
+impl Shell {
+    /// A shell reading commands from stdin, interactively when it is a
+    /// terminal.
+    pub fn new() -> Result<Shell, ShellError> {
+        Self::builder().build()
+    }
+
+    /// A shell without prompts or line editing, for running `-c` commands or
+    /// reading a script from stdin.
+    pub fn non_interactive() -> Result<Shell, ShellError> {
+        Self::builder().interactive(false).build()
+    }
+
+    pub fn builder() -> ShellBuilder {
+        ShellBuilder::default()
+    }
+
+    /// Parses the next command entered, prompting for more input once the
+    /// commands of the last input have all run.
+    fn read(&mut self) -> Result<(), ShellError> {
+        if self.queued.is_empty() {
+            self.read_input()?;
+        }
+
+        self.command.clear();
+        let Some((line, input)) = self.queued.pop_front() else {
+            // only blank lines or comments
+            self.command_line.clear();
+            return Ok(());
         };
+        self.env.set_line(line);
+        self.env.set_status(self.last_status);
+        self.command_line = input;
+        if lexer::is_incomplete(&self.command_line) {
+            return Err(unexpected_end());
+        }
 
-        shell.read_history()?;
-        Ok(shell)
+        let named_dirs = self.named_dirs.borrow();
+        let expansions = Expansions {
+            named_dirs: &named_dirs,
+            env: &self.env,
+            options: &self.options.borrow(),
+        };
+        Parser::new(&self.command_line)
+            .with_expander(&expansions)
+            .parse_into(&mut self.command)
     }
 
-    fn read(&mut self) -> anyhow::Result<()> {
-        self.input_buffer = self.editor.borrow_mut().readline("$ ")?;
+    /// Reads input at the prompt, and splits it into commands to run one
+    /// after the other like the lines of a script.
+    fn read_input(&mut self) -> Result<(), ShellError> {
+        let named_dirs = self.named_dirs.borrow();
+        let state = PromptState {
+            named_dirs: &named_dirs,
+            last_status: self.last_status,
+            duration: self.last_duration,
+            cwd_style: CwdStyle::from_env(&self.env),
+        };
+        let ps1 = self
+            .env
+            .var("PROMPT_PROGRAM")
+            .filter(|program| !program.is_empty())
+            .and_then(|program| {
+                let timeout = prompt_timeout(&self.env);
+                self.prompt_program
+                    .render(&program, &state, &self.env, timeout)
+            })
+            .or_else(|| self.env.var("PS1"))
+            .unwrap_or_else(|| String::from(prompt::DEFAULT_PS1));
+        let ps2 = self
+            .env
+            .var("PS2")
+            .unwrap_or_else(|| String::from(prompt::DEFAULT_PS2));
+        let prompt = self.segments.render(&ps1, &state);
+        let continuation_prompt = self.segments.render(&ps2, &state);
+
+        let mut editor = self.editor.borrow_mut();
+        editor.set_timeout(timeout(&self.env));
+        self.input_buffer = editor.readline(&prompt, &continuation_prompt)?;
+        if let Some(mut transcript) = self.streams.transcript() {
+            let recorded = transcript.input(
+                &prompt.plain,
+                &continuation_prompt.plain,
+                &self.input_buffer,
+            );
+            if let Err(err) = recorded {
+                tracing::warn!(%err, "recording the input");
+            }
+        }
+        let control = HistControl::parse(&self.env.var("HISTCONTROL").unwrap_or_default());
+        self.in_history =
+            self.interactive && editor.add_history_entry(&self.history_line(), &control);
+        drop(editor);
+        // the editor handles SIGWINCH itself while the line is edited
+        if self.interactive {
+            self.update_size();
+        }
+        if self.options.borrow().verbose() {
+            echo_input(&mut self.streams.stderr, &self.input_buffer);
+        }
+        let lines_before = self.lines_read;
+        self.lines_read += self.input_buffer.lines().count().max(1);
+        self.queued = script_commands(&self.input_buffer)
+            .into_iter()
+            .map(|(line, command)| (lines_before + line, command))
+            .collect();
 
-        self.command = Parser::new(&self.input_buffer).parse();
         Ok(())
     }
 
+    /// The history entry for `input_buffer`.
+    fn history_line(&self) -> Cow<'_, str> {
+        if self.options.borrow().lithist() {
+            Cow::Borrowed(&self.input_buffer)
+        } else {
+            history::single_line(&self.input_buffer)
+        }
+    }
+
     fn eval(&mut self) -> anyhow::Result<()> {
         if self.command.args.is_empty() {
+            if !self.command.assignments.is_empty() {
+                assign(&self.env, &self.options, &self.command)?;
+                self.last_status = 0;
+            }
             return Ok(());
         }
 
-        self.new_pipeline(&self.command).run()?;
+        let term_integration = self.interactive && self.options.borrow().term_integration();
+        if term_integration {
+            let cwd = prompt::cwd(&self.named_dirs.borrow());
+            terminal::set_title(&format!("{} — {cwd}", self.command_line.trim()));
+        }
+
+        self.reactor.borrow_mut().take_usage();
+        let start = Instant::now();
+        let status = self.run_pipeline(&self.command);
+        let elapsed = start.elapsed();
+        self.last_duration = Some(elapsed);
+        if report_time(&self.env).is_some_and(|threshold| elapsed >= threshold) {
+            let usage = self.reactor.borrow_mut().take_usage();
+            let summary = usage.summary(self.command_line.trim(), elapsed);
+            print_to!(self.streams.stderr, "{summary}");
+        }
+
+        if term_integration {
+            self.update_terminal();
+        }
+        self.last_status = status?;
         Ok(())
     }
 
+    /// Titles the terminal with the working directory, and reports it when
+    /// it changed, e.g. after `cd`.
+    fn update_terminal(&mut self) {
+        terminal::set_title(&prompt::cwd(&self.named_dirs.borrow()));
+
+        let Ok(cwd) = env::current_dir() else {
+            return;
+        };
+        if self.reported_cwd.as_ref() != Some(&cwd) {
+            terminal::report_cwd(&cwd);
+            self.reported_cwd = Some(cwd);
+        }
+    }
+
+    /// Exit status of the most recently evaluated command.
+    pub fn last_status(&self) -> i32 {
+        self.last_status
+    }
+
+    fn run_pipeline(&self, command: &Command) -> anyhow::Result<i32> {
+        self.new_pipeline(command)
+            .with_reactor(Rc::clone(&self.reactor))
+            .run()
+    }
+
     fn new_pipeline<'a>(&'a self, command: &'a Command) -> Pipeline<'a> {
-        Pipeline::new(command, Rc::clone(&self.bin_path), Rc::clone(&self.editor))
+        Pipeline::new(
+            command,
+            Rc::clone(&self.bin_path),
+            Rc::clone(&self.editor),
+            Rc::clone(&self.named_dirs),
+            Rc::clone(&self.options),
+            Rc::clone(&self.plugins),
+            Rc::clone(&self.traps),
+            Rc::clone(&self.hooks),
+            self.streams.clone(),
+            self.env.clone(),
+        )
     }
 
-    pub fn repl(&mut self) -> anyhow::Result<()> {
-        loop {
-            handle_err(self.read())?;
-            handle_err(self.eval())?;
+    /// Applies the restrictions of `-r` from now on. Like rbash, they
+    /// are meant to apply after the startup files ran.
+    pub fn restrict(&mut self) {
+        self.options
+            .borrow_mut()
+            .set_shopt("restricted_shell", true);
+    }
+
+    /// Runs the commands in ~/.myshell_profile, before the rc file. Only
+    /// login shells read it. /etc/profile and ~/.profile are written for
+    /// sh and bash, in syntax this shell doesn't understand.
+    pub fn source_profile(&mut self) -> Result<(), ShellError> {
+        if !self.options.borrow().login_shell() {
+            return Ok(());
+        }
+
+        match profile_file(&self.env) {
+            Some(path) => self.source_file(&path),
+            None => Ok(()),
         }
     }
 
-    fn read_history(&self) -> anyhow::Result<()> {
-        let history_file = env::var("HISTFILE");
-        match history_file {
-            Err(VarError::NotPresent) => return Ok(()),
-            _ => {}
+    /// Runs the commands in the rc file, before the first prompt. Only
+    /// interactive shells read it.
+    pub fn source_rc(&mut self) -> Result<(), ShellError> {
+        if !self.interactive {
+            return Ok(());
+        }
+
+        match rc_file(&self.env) {
+            Some(path) => self.source_file(&path),
+            None => Ok(()),
         }
+    }
 
-        let command = Command {
-            args: vec![String::from("history"), String::from("-r"), history_file?],
-            redirects: vec![],
+    /// Runs the commands in a startup file. A missing one is skipped, and
+    /// one that can't be read is reported but doesn't stop the shell.
+    fn source_file(&mut self, path: &Path) -> Result<(), ShellError> {
+        let script = match fs::read_to_string(path) {
+            Ok(script) => script,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                eprint!("{}: {err}\n", path.display());
+                return Ok(());
+            }
         };
-        self.new_pipeline(&command).run()?;
 
+        self.run_named_script(&script, Some(&path.display().to_string()))
+    }
+
+    /// Runs the commands in the script file at `path`. Its errors say where
+    /// they happened in it.
+    pub fn run_file(&mut self, path: &Path) -> Result<(), ShellError> {
+        let script = fs::read_to_string(path).map_err(|io| ShellError::CannotExecute {
+            name: path.display().to_string(),
+            io,
+        })?;
+        self.run_named_script(&script, Some(&path.display().to_string()))
+    }
+
+    /// Runs the commands in `script`, as given to `-c`.
+    pub fn run_script(&mut self, script: &str) -> Result<(), ShellError> {
+        self.run_named_script(script, None)
+    }
+
+    fn run_named_script(&mut self, script: &str, name: Option<&str>) -> Result<(), ShellError> {
+        let mut errors = self.streams.stderr.clone();
+        self.last_status = source(
+            script,
+            name,
+            &self.named_dirs,
+            &self.options,
+            &self.env,
+            &self.traps,
+            &mut errors,
+            |command| run_hooked(&self.hooks, command, |command| self.run_pipeline(command)),
+        )?;
         Ok(())
     }
 
-    fn append_history(&mut self) -> anyhow::Result<()> {
-        let history_file = env::var("HISTFILE");
-        match history_file {
-            Err(VarError::NotPresent) => return Ok(()),
-            _ => {}
+    /// Runs the commands in `input` like [`Shell::run_script`], capturing
+    /// what they print instead of writing it to the shell's output. An `exit`
+    /// only ends the evaluation, with its status.
+    pub fn eval_str(&mut self, input: &str) -> EvalResult {
+        let stdout = Capture::default();
+        let stderr = Capture::default();
+        let captured = Streams {
+            stdout: Sink::new(stdout.clone()),
+            stderr: Sink::new(stderr.clone()),
+        };
+
+        let streams = mem::replace(&mut self.streams, captured);
+        if let Err(ShellError::ExitRequested { code: Some(code) }) = self.run_script(input) {
+            self.last_status = code;
+        }
+        self.streams = streams;
+
+        EvalResult {
+            stdout: String::from_utf8_lossy(&stdout.take()).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr.take()).into_owned(),
+            status: self.last_status,
         }
+    }
 
-        let command = Command {
-            args: vec![String::from("history"), String::from("-a"), history_file?],
-            redirects: vec![],
+    /// Runs the commands in $PROMPT_COMMAND, e.g. to set the terminal title.
+    fn run_prompt_command(&mut self) -> Result<(), ShellError> {
+        self.run_commands_in("PROMPT_COMMAND")
+    }
+
+    /// Tells the hooks `command` is about to run.
+    fn run_preexec(&mut self, command: &str) -> Result<(), ShellError> {
+        self.hooks.borrow_mut().preexec(command);
+        self.env.set("HOOK_COMMAND", command);
+        self.run_commands_in("PREEXEC_COMMAND")
+    }
+
+    /// Tells the hooks `command` ran, with the status it exited with.
+    fn run_postexec(&mut self, command: &str) -> Result<(), ShellError> {
+        self.hooks.borrow_mut().postexec(command, self.last_status);
+        self.env.set("HOOK_COMMAND", command);
+        self.env.set("HOOK_STATUS", &self.last_status.to_string());
+        self.run_commands_in("POSTEXEC_COMMAND")
+    }
+
+    /// Runs the commands in the variable `name`. Their status doesn't
+    /// replace the one of the last command entered.
+    fn run_commands_in(&mut self, name: &str) -> Result<(), ShellError> {
+        let Some(commands) = self.env.var(name) else {
+            return Ok(());
         };
-        self.new_pipeline(&command).run()?;
 
+        let mut errors = self.streams.stderr.clone();
+        self.env.set_status(self.last_status);
+        source(
+            &commands,
+            None,
+            &self.named_dirs,
+            &self.options,
+            &self.env,
+            &self.traps,
+            &mut errors,
+            |command| self.run_pipeline(command),
+        )?;
         Ok(())
     }
+
+    /// Runs the commands set with `trap` for `trap`, about the command being
+    /// run.
+    fn run_trap(&mut self, trap: Trap) -> Result<(), ShellError> {
+        let mut errors = self.streams.stderr.clone();
+        self.env.set_status(self.last_status);
+        run_trap(
+            trap,
+            &self.command_line,
+            &self.named_dirs,
+            &self.options,
+            &self.env,
+            &self.traps,
+            &mut errors,
+            &mut |command| self.run_pipeline(command),
+        )
+    }
+
+    /// Exports the size of the terminal as COLUMNS and LINES, for commands
+    /// that lay out their output to fit it.
+    fn update_size(&self) {
+        if let Some((columns, lines)) = terminal::size() {
+            self.env.set_exported("COLUMNS", &columns.to_string());
+            self.env.set_exported("LINES", &lines.to_string());
+        }
+    }
+
+    /// Reads and runs commands until the input ends or `exit` is run, which
+    /// are returned as [`ShellError::Read`] and
+    /// [`ShellError::ExitRequested`].
+    pub fn repl(&mut self) -> Result<(), ShellError> {
+        if self.interactive {
+            terminal::watch_resize();
+            self.update_size();
+        }
+        loop {
+            // not between the commands of a pasted block
+            if self.interactive && self.queued.is_empty() {
+                // resized while a command ran
+                if terminal::take_resized() {
+                    self.update_size();
+                }
+                handle_err(self.run_prompt_command(), &mut self.streams.stderr)?;
+            }
+            match catch_panic(|| self.read()) {
+                Err(err @ ShellError::Read(ReadError::Eof)) if self.interactive => {
+                    if self.options.borrow().ignoreeof() {
+                        print_to!(self.streams.stderr, "Use \"exit\" to leave the shell.\n");
+                        continue;
+                    }
+                    print_to!(self.streams.stderr, "exit\n");
+                    return Err(err);
+                }
+                Err(err @ ShellError::Read(ReadError::TimedOut)) => {
+                    print_to!(self.streams.stderr, "\n{err}\n");
+                    return Err(err);
+                }
+                // Ctrl-C abandons the line being edited, like bash
+                Err(ShellError::Read(ReadError::Interrupted)) => {
+                    print_to!(self.streams.stderr, "^C\n");
+                    self.last_status = 130;
+                    continue;
+                }
+                // the line is skipped, but the input goes on
+                Err(ShellError::Read(ReadError::Backend(err))) if is_invalid_data(&*err) => {
+                    print_to!(self.streams.stderr, "{err}\n");
+                    self.last_status = 1;
+                    continue;
+                }
+                result => {
+                    // e.g. a syntax error or `failglob`: the command isn't run
+                    if let Some(status) = handle_err(result, &mut self.streams.stderr)? {
+                        self.last_status = status;
+                        continue;
+                    }
+                }
+            }
+            // not for assignments alone, nor blank lines
+            let hooked =
+                (!self.command.args.is_empty()).then(|| pipeline::expanded_text(&self.command));
+            if let Some(command) = &hooked {
+                handle_err(self.run_preexec(command), &mut self.streams.stderr)?;
+                handle_err(self.run_trap(Trap::Debug), &mut self.streams.stderr)?;
+            }
+            let evaluated = catch_panic(|| self.eval());
+            if let Some(status) = handle_err(evaluated, &mut self.streams.stderr)? {
+                self.last_status = status;
+            }
+            if let Some(command) = &hooked {
+                if self.last_status != 0 {
+                    handle_err(self.run_trap(Trap::Err), &mut self.streams.stderr)?;
+                }
+                handle_err(self.run_postexec(command), &mut self.streams.stderr)?;
+            }
+            if self.in_history {
+                let line = self.history_line().into_owned();
+                self.editor.borrow_mut().history_mut().set_result(
+                    &line,
+                    self.last_status,
+                    self.last_duration,
+                );
+            }
+            check_errexit(&self.options, self.last_status)?;
+        }
+    }
 }
 
 impl Drop for Shell {
     fn drop(&mut self) {
-        self.append_history().unwrap();
+        if let Some(mut transcript) = self.streams.stop_recording()
+            && let Err(err) = transcript.finish()
+        {
+            eprint!("record: {}: {err}\n", transcript.path().display());
+        }
+
+        let mut editor = self.editor.borrow_mut();
+        if let Err(err) = editor.save_history() {
+            let path = editor.history_file().unwrap();
+            eprint!("history: {}: {err}\n", path.display());
+        }
+    }
+}
+
+/// How long to wait for a command to be entered before exiting, from
+/// $TMOUT seconds. Unset, zero or invalid means forever.
+fn timeout(env: &Env) -> Option<Duration> {
+    let seconds: u64 = env.var("TMOUT")?.parse().ok()?;
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+/// How long $PROMPT_PROGRAM gets to print the prompt, from $PROMPT_TIMEOUT
+/// milliseconds. Unset or invalid means half a second, like starship's own
+/// limit on its commands.
+fn prompt_timeout(env: &Env) -> Duration {
+    let millis = env
+        .var("PROMPT_TIMEOUT")
+        .and_then(|millis| millis.parse().ok())
+        .unwrap_or(500);
+    Duration::from_millis(millis)
+}
+
+/// How long a command has to run for what it used to be reported after it,
+/// from $REPORTTIME seconds. Unset or invalid means never.
+fn report_time(env: &Env) -> Option<Duration> {
+    let seconds: f64 = env.var("REPORTTIME")?.parse().ok()?;
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// One more SHLVL than the shell that started this one, and a PWD naming
+/// the working directory, like bash sets them at startup. A login shell
+/// also sets SHELL to itself.
+fn init_vars(env: &Env, login: bool) {
+    let level: u32 = env
+        .var("SHLVL")
+        .and_then(|level| level.parse().ok())
+        .unwrap_or_default();
+    env.set_exported("SHLVL", &(level + 1).to_string());
+
+    if login && let Ok(exe) = env::current_exe() {
+        env.set_exported("SHELL", &exe.display().to_string());
+    }
+
+    let Ok(cwd) = env::current_dir() else {
+        return;
+    };
+    let pwd = env.var_os("PWD").and_then(|pwd| fs::canonicalize(pwd).ok());
+    if pwd.is_none_or(|pwd| pwd != cwd) {
+        env.set_exported("PWD", &cwd.display().to_string());
+    }
+}
+
+/// `~/.myshell_profile`, sourced on startup by login shells.
+fn profile_file(env: &Env) -> Option<PathBuf> {
+    home_dir(env).map(|home| home.join(".myshell_profile"))
+}
+
+/// `~/.myshellrc`, sourced on startup unless `--norc` is given.
+fn rc_file(env: &Env) -> Option<PathBuf> {
+    home_dir(env).map(|home| home.join(".myshellrc"))
+}
+
+/// $DIRHISTFILE, where interactive shells keep the directories visited,
+/// defaulting to ~/.dir_history. Setting it to an empty value disables it.
+fn dir_history_file(env: &Env) -> Option<PathBuf> {
+    match env.var_os("DIRHISTFILE") {
+        Some(path) if path.is_empty() => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None => home_dir(env).map(|home| home.join(".dir_history")),
+    }
+}
+
+/// $HOME, or the home directory in the password database when it isn't
+/// set, like for `~`.
+fn home_dir(env: &Env) -> Option<PathBuf> {
+    env.var_os("HOME")
+        .map(PathBuf::from)
+        .or_else(named_dirs::own_home)
+}
+
+/// Runs every command of `script` with `run`, reporting errors to `errors`
+/// like the REPL does, and returns the status of the last one. LINENO is
+/// the line of the command being run while it runs, and the errors of a
+/// script read from the file `name` say where they happened in it.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn source(
+    script: &str,
+    name: Option<&str>,
+    named_dirs: &RefCell<NamedDirs>,
+    options: &RefCell<Options>,
+    env: &Env,
+    traps: &RefCell<Traps>,
+    errors: &mut impl io::Write,
+    run: impl FnMut(&Command) -> anyhow::Result<i32>,
+) -> Result<i32, ShellError> {
+    let outer_line = env.line();
+    let status = source_commands(script, name, named_dirs, options, env, traps, errors, run);
+    env.set_line(outer_line);
+    status
+}
+
+#[allow(clippy::too_many_arguments)]
+fn source_commands(
+    script: &str,
+    name: Option<&str>,
+    named_dirs: &RefCell<NamedDirs>,
+    options: &RefCell<Options>,
+    env: &Env,
+    traps: &RefCell<Traps>,
+    errors: &mut impl io::Write,
+    mut run: impl FnMut(&Command) -> anyhow::Result<i32>,
+) -> Result<i32, ShellError> {
+    let mut status = 0;
+    let mut command = Command::default();
+    // with the blank and comment lines before each command, for `verbose`
+    let mut lines = script.lines();
+    let mut lines_read = 0;
+    for (index, (line, input)) in script_commands(script).into_iter().enumerate() {
+        check_errexit(options, status)?;
+        env.set_line(line);
+        // the first command sees the status from before the script
+        if index > 0 {
+            env.set_status(status);
+        }
+        let end = line - 1 + input.lines().count();
+        let verbose = options.borrow().verbose();
+        for text in lines.by_ref().take(end - lines_read) {
+            if verbose {
+                echo_input(errors, text);
+            }
+        }
+        lines_read = end;
+        let location = name.map(|name| (name, line));
+        if lexer::is_incomplete(&input) {
+            handle_err_at(Err::<(), _>(unexpected_end()), location, errors)?;
+            return Ok(2);
+        }
+
+        // not borrowed while the command runs, as `hash -d` and `shopt`
+        // change them
+        let parsed = {
+            let expansions = Expansions {
+                named_dirs: &named_dirs.borrow(),
+                env,
+                options: &options.borrow(),
+            };
+            Parser::new(&input)
+                .with_expander(&expansions)
+                .parse_into(&mut command)
+        };
+        let unbound = matches!(parsed, Err(ShellError::Unbound { .. }));
+        if let Some(error_status) = handle_err_at(parsed, location, errors)? {
+            // like bash, only the prompt carries on after it
+            if unbound && !options.borrow().interactive() {
+                return Err(ShellError::ExitRequested {
+                    code: Some(error_status),
+                });
+            }
+            status = error_status;
+            continue;
+        }
+        if command.args.is_empty() {
+            if !command.assignments.is_empty() {
+                let result = assign(env, options, &command).map(|()| status = 0);
+                if let Some(error_status) = handle_err_at(result, location, errors)? {
+                    status = error_status;
+                }
+            }
+            continue;
+        }
+
+        run_trap(
+            Trap::Debug,
+            &input,
+            named_dirs,
+            options,
+            env,
+            traps,
+            errors,
+            &mut run,
+        )?;
+        let result = run(&command).map(|command_status| status = command_status);
+        if let Some(error_status) = handle_err_at(result, location, errors)? {
+            status = error_status;
+        }
+        if status != 0 {
+            env.set_status(status);
+            run_trap(
+                Trap::Err,
+                &input,
+                named_dirs,
+                options,
+                env,
+                traps,
+                errors,
+                &mut run,
+            )?;
+        }
     }
+
+    if options.borrow().verbose() {
+        for text in lines {
+            echo_input(errors, text);
+        }
+    }
+    check_errexit(options, status)?;
+    Ok(status)
+}
+
+/// Prints `input` as it was read, for `verbose`.
+fn echo_input(errors: &mut impl io::Write, input: &str) {
+    let newline = if input.ends_with('\n') { "" } else { "\n" };
+    print_to!(errors, "{input}{newline}");
+}
+
+/// Runs the commands set with `trap` for `trap`, with BASH_COMMAND set to
+/// the `command` it is about. Not while a trap runs, so their own commands
+/// don't set one off, and their status isn't the one of `command`.
+#[allow(clippy::too_many_arguments)]
+fn run_trap(
+    trap: Trap,
+    command: &str,
+    named_dirs: &RefCell<NamedDirs>,
+    options: &RefCell<Options>,
+    env: &Env,
+    traps: &RefCell<Traps>,
+    errors: &mut impl io::Write,
+    run: &mut dyn FnMut(&Command) -> anyhow::Result<i32>,
+) -> Result<(), ShellError> {
+    let Some(action) = traps.borrow_mut().start(trap) else {
+        return Ok(());
+    };
+
+    env.set("BASH_COMMAND", command.trim());
+    let status = source(&action, None, named_dirs, options, env, traps, errors, run);
+    traps.borrow_mut().finish();
+    status.map(|_| ())
+}
+
+/// The syntax errors in `script`, each with the line its command starts
+/// on, found without expanding or running anything, as for `-n`.
+pub fn syntax_errors(script: &str) -> Vec<(usize, ShellError)> {
+    let mut command = Command::default();
+    script_commands(script)
+        .into_iter()
+        .filter_map(|(line, input)| {
+            if lexer::is_incomplete(&input) {
+                return Some((line, unexpected_end()));
+            }
+            let parsed = Parser::new(&input).parse_into(&mut command);
+            parsed.err().map(|err| (line, err))
+        })
+        .collect()
+}
+
+/// With `errexit` set, a command failing exits the shell with its status.
+fn check_errexit(options: &RefCell<Options>, status: i32) -> Result<(), ShellError> {
+    if status != 0 && options.borrow().errexit() {
+        return Err(ShellError::ExitRequested { code: Some(status) });
+    }
+
+    Ok(())
+}
+
+/// Expands words with the named directories and variables of a shell.
+struct Expansions<'a> {
+    named_dirs: &'a NamedDirs,
+    env: &'a Env,
+    /// Posix mode only expands `~user`, not named directories, and the glob
+    /// options decide what a pattern matching no files expands to.
+    options: &'a Options,
+}
+
+impl Expander for Expansions<'_> {
+    fn expand_tilde(&self, prefix: &str) -> Option<String> {
+        if prefix.is_empty() {
+            return home_dir(self.env).map(|dir| dir.display().to_string());
+        }
+        if self.options.posix() {
+            return named_dirs::user_home(prefix).map(|dir| dir.display().to_string());
+        }
+
+        self.named_dirs.expand_tilde(prefix)
+    }
+
+    fn variable(&self, name: &str) -> Option<String> {
+        self.env.var(name)
+    }
+
+    fn nounset(&self) -> bool {
+        self.options.nounset()
+    }
+
+    fn glob(&self, pattern: &str) -> Result<Option<Vec<String>>, ShellError> {
+        if self.options.noglob() {
+            return Ok(None);
+        }
+
+        let paths = glob::expand(pattern, self.options.dotglob());
+        if !paths.is_empty() {
+            Ok(Some(paths))
+        } else if self.options.failglob() {
+            Err(ShellError::NoMatch {
+                pattern: pattern.to_string(),
+            })
+        } else if self.options.nullglob() {
+            Ok(Some(Vec::new()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Sets the variables of an assignment-only command like `a=1 b=2`.
+fn assign(env: &Env, options: &RefCell<Options>, command: &Command) -> Result<(), ShellError> {
+    if options.borrow().restricted_shell() {
+        restricted::check(command)?;
+    }
+
+    let export = options.borrow().allexport();
+    for (name, value) in &command.assignments {
+        if export {
+            env.set_exported(name, value);
+        } else {
+            env.set(name, value);
+        }
+    }
+
+    Ok(())
 }
 
-fn handle_err<T>(result: anyhow::Result<T>) -> anyhow::Result<()> {
-    match result {
-        Ok(_) => Ok(()),
-        Err(err) if contain::<rustyline::error::ReadlineError>(err.chain()) => Err(err),
-        Err(err) if contain::<ExitError>(err.chain()) => Err(err),
+/// Splits `script` into commands, joining the lines of one that continues
+/// past the end of a line, each with the number of its first line. Blank
+/// lines and comment lines are dropped.
+fn script_commands(script: &str) -> Vec<(usize, String)> {
+    let mut commands = Vec::new();
+    let mut pending = String::new();
+    let mut first_line = 0;
+
+    for (index, line) in script.lines().enumerate() {
+        if pending.is_empty() {
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            first_line = index + 1;
+        }
+
+        pending.push_str(line);
+        if lexer::is_incomplete(&pending) {
+            pending.push('\n');
+        } else {
+            commands.push((first_line, mem::take(&mut pending)));
+        }
+    }
+    if !pending.is_empty() {
+        commands.push((first_line, pending));
+    }
+
+    commands
+}
+
+/// Prints an error that only fails the command being run, returning the
+/// status it failed with, and passes on the ones that end the shell's input.
+fn handle_err<T>(
+    result: Result<T, impl Into<ShellError>>,
+    errors: &mut impl io::Write,
+) -> Result<Option<i32>, ShellError> {
+    handle_err_at(result, None, errors)
+}
+
+/// [`handle_err`] for a command on a line of a script file, printing the
+/// error after its `location` like `script.sh: line 42: `.
+fn handle_err_at<T>(
+    result: Result<T, impl Into<ShellError>>,
+    location: Option<(&str, usize)>,
+    errors: &mut impl io::Write,
+) -> Result<Option<i32>, ShellError> {
+    match result.map_err(Into::into) {
+        Ok(_) => Ok(None),
+        Err(err @ (ShellError::Read(_) | ShellError::ExitRequested { .. })) => Err(err),
         Err(err) => {
-            print!("{}\n", err);
-            Ok(())
+            match location {
+                Some((name, line)) => print_to!(errors, "{name}: line {line}: {err}\n"),
+                None => print_to!(errors, "{}\n", err),
+            }
+            Ok(Some(err.status()))
         }
     }
 }
 
-pub fn contain<T: std::error::Error + 'static>(chain: anyhow::Chain) -> bool {
-    for cause in chain {
-        if cause.downcast_ref::<T>().is_some() {
-            return true;
+/// Turns a panic in `run` into an error, so a bug hit by one command
+/// doesn't take the whole shell down. The panic hook already reported
+/// where it happened.
+fn catch_panic<T, E: Into<ShellError>>(
+    run: impl FnOnce() -> Result<T, E>,
+) -> Result<T, ShellError> {
+    match panic::catch_unwind(AssertUnwindSafe(run)) {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(ShellError::Other(anyhow::anyhow!(
+            "internal error, command abandoned"
+        ))),
+    }
+}
+
+/// Whether reading failed on input that isn't UTF-8.
+fn is_invalid_data(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(|err| err.kind() == io::ErrorKind::InvalidData)
+}
+
+fn unexpected_end() -> ShellError {
+    ShellError::ParseError {
+        message: String::from("unexpected end of file"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditLog;
+    use crate::history::ShellHistory;
+    use crate::json;
+    use crate::plugins::Builtin;
+    use crate::prompt::Prompt;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+    use std::cell::Cell;
+    use std::iter;
+
+    /// Enters the lines it is given, or fails reading them, like a user at
+    /// the prompt would. Once they ran out, the input ends.
+    #[derive(Default)]
+    struct ScriptedEditor {
+        input: VecDeque<Result<String, ReadError>>,
+        history: ShellHistory,
+        /// Whether the options last applied cycle through completions.
+        menu_complete: Rc<Cell<bool>>,
+        /// The commands completion providers were registered for.
+        completed: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl ScriptedEditor {
+        fn new(input: impl IntoIterator<Item = Result<&'static str, ReadError>>) -> Self {
+            Self {
+                input: input
+                    .into_iter()
+                    .map(|line| line.map(String::from))
+                    .collect(),
+                ..Self::default()
+            }
+        }
+    }
+
+    impl LineEditor for ScriptedEditor {
+        fn readline(&mut self, _: &Prompt, _: &Prompt) -> Result<String, ReadError> {
+            self.input.pop_front().unwrap_or(Err(ReadError::Eof))
+        }
+
+        fn reconfigure(&mut self, options: &Options) {
+            self.menu_complete.set(options.menu_complete());
+        }
+
+        fn register_completion(&mut self, command: &str, _: Box<dyn CompletionProvider>) {
+            self.completed.borrow_mut().push(command.to_string());
+        }
+
+        fn history(&self) -> &ShellHistory {
+            &self.history
+        }
+
+        fn history_mut(&mut self) -> &mut ShellHistory {
+            &mut self.history
+        }
+    }
+
+    /// An interactive shell reading with `editor`, and what it printed once
+    /// the input ended.
+    fn run_interactive(editor: ScriptedEditor, vars: &[(&str, &str)]) -> (Shell, EvalResult) {
+        let stdout = Capture::default();
+        let stderr = Capture::default();
+        let mut vars = vars.to_vec();
+        vars.push(("PATH", "/usr/bin:/bin"));
+        let mut shell = Shell::builder()
+            .stdout(stdout.clone())
+            .stderr(stderr.clone())
+            .env(vars)
+            .editor(editor)
+            .interactive(true)
+            .build()
+            .unwrap();
+
+        let status = match shell.repl() {
+            Err(ShellError::ExitRequested { code }) => code.unwrap_or(shell.last_status),
+            _ => shell.last_status,
+        };
+        let result = EvalResult {
+            stdout: String::from_utf8(stdout.take()).unwrap(),
+            stderr: String::from_utf8(stderr.take()).unwrap(),
+            status,
+        };
+        (shell, result)
+    }
+
+    #[rstest]
+    #[case("set -o vi\nshopt -s autocd\n", vec![(1, "set -o vi"), (2, "shopt -s autocd")])]
+    #[case("# comment\n\n  \necho a\n", vec![(4, "echo a")])]
+    #[case("echo 'a\nb'\necho c |\ncat\n", vec![(1, "echo 'a\nb'"), (3, "echo c |\ncat")])]
+    #[case("echo \"open", vec![(1, "echo \"open\n")])]
+    fn script_commands_test(#[case] script: &str, #[case] expected: Vec<(usize, &str)>) {
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(line, command)| (line, command.to_string()))
+            .collect();
+        assert_eq!(script_commands(script), expected);
+    }
+
+    #[rstest]
+    #[case("echo $nope *.none\ncd /nowhere", vec![])]
+    #[case("echo a\necho | | wc\necho b |", vec![
+        (2, "syntax error: unexpected token `|'"),
+        (3, "syntax error: unexpected end of file"),
+    ])]
+    fn syntax_errors_test(#[case] script: &str, #[case] expected: Vec<(usize, &str)>) {
+        let errors: Vec<_> = syntax_errors(script)
+            .into_iter()
+            .map(|(line, err)| (line, err.to_string()))
+            .collect();
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(line, message)| (line, message.to_string()))
+            .collect();
+        assert_eq!(errors, expected);
+    }
+
+    #[rstest]
+    #[case("echo hi | cat", "hi\n", "", 0)]
+    #[case("echo a\necho b", "a\nb\n", "", 0)]
+    #[case("nosuch", "", "nosuch: command not found\n", 127)]
+    #[case("echo a\nexit 3\necho b", "a\n", "", 3)]
+    #[case("echo \"open", "", "syntax error: unexpected end of file\n", 2)]
+    #[case(
+        "echo a >\necho b",
+        "b\n",
+        "syntax error: unexpected token `newline'\n",
+        0
+    )]
+    #[case(
+        "echo a >/nonexistent/f",
+        "",
+        "/nonexistent/f: No such file or directory\n",
+        1
+    )]
+    #[case(
+        "type -x",
+        "",
+        "type: -x: invalid option\ntype: usage: type name [name ...]\n",
+        2
+    )]
+    #[case("ls /nonexistent 2>/dev/null", "", "", 2)]
+    #[case("false", "", "", 1)]
+    #[case("a=hi\necho $a", "hi\n", "", 0)]
+    #[case("a=hi\nsh -c 'echo x$a'", "x\n", "", 0)]
+    #[case("a=1 sh -c 'echo $a'\necho x$a", "1\nx\n", "", 0)]
+    #[case("false\na=1", "", "", 0)]
+    #[case("a=1\nexport a b=2\nsh -c 'echo $a$b'", "12\n", "", 0)]
+    #[case("export c\nc=3\nsh -c 'echo x$c'", "x3\n", "", 0)]
+    #[case(
+        "export d='it'\\''s'\nexport | grep '^export d='",
+        "export d='it'\\''s'\n",
+        "",
+        0
+    )]
+    #[case(
+        "export 1a=1 e=5\necho $e\nexport -p 2b",
+        "5\n",
+        "export: `1a=1': not a valid identifier\nexport: `2b': not a valid identifier\n",
+        1
+    )]
+    #[case(
+        "export -z",
+        "",
+        "export: -z: invalid option\nexport: usage: export [-p] [name[=value] ...]\n",
+        2
+    )]
+    #[case("a=1\nunset a\necho x$a", "x\n", "", 0)]
+    #[case("unset HOME\nsh -c 'echo x$HOME'", "x\n", "", 0)]
+    #[case("export f=6\nunset f\nf=7\nsh -c 'echo x$f'", "x\n", "", 0)]
+    #[case("unset 'a b'", "", "unset: `a b': not a valid identifier\n", 1)]
+    #[case("logout", "", "logout: not login shell: use `exit'\n", 1)]
+    #[case(
+        "shopt -s login_shell",
+        "",
+        "shopt: login_shell: cannot set option\n",
+        1
+    )]
+    #[case("echo $LINENO\n\n# comment\necho a |\necho $LINENO", "1\n4\n", "", 0)]
+    #[case(
+        "hash -d proj=/srv\necho ~proj\nset -o posix\necho ~proj",
+        "/srv\n~proj\n",
+        "",
+        0
+    )]
+    #[case("enable -d hello", "", "enable: hello: not dynamically loaded\n", 1)]
+    #[case(
+        "enable -f ./cd.so cd",
+        "",
+        "enable: cd: cannot replace a shell builtin\n",
+        1
+    )]
+    #[case("enable echo\nenable", "", "", 0)]
+    #[case("a=1 set -o emacs\necho x$a", "x\n", "", 0)]
+    #[case(
+        "time -x true",
+        "",
+        "time: -x: invalid option\ntime: usage: time [-p | -v] pipeline\n",
+        2
+    )]
+    #[case("set -o posix\na=1 set -o emacs\necho x$a", "x1\n", "", 0)]
+    #[case("timeout 5 echo hi", "hi\n", "", 0)]
+    #[case("timeout 0.4s sleep 5", "", "", 124)]
+    #[case("timeout -k 0.1 0.1 sh -c 'trap \"\" TERM; sleep 5'", "", "", 124)]
+    #[case("timeout 1m sh -c 'exit 3'", "", "", 3)]
+    #[case("timeout 5x true", "", "timeout: 5x: invalid time interval\n", 125)]
+    #[case(
+        "timeout 5",
+        "",
+        "timeout: usage: timeout [-k duration] duration pipeline\n",
+        125
+    )]
+    #[case(
+        "set -eu\nset +o | grep -e errexit -e nounset",
+        "set -o errexit\nset -o nounset\n",
+        "",
+        0
+    )]
+    #[case(
+        "set -z",
+        "",
+        "set: -z: invalid option\nset: usage: set [-aCefuvx] [-o option-name] [+o option-name]\n",
+        2
+    )]
+    #[case("set -o bogus", "", "set: bogus: invalid option name\n", 1)]
+    #[case("set -e\necho one\nfalse\necho two", "one\n", "", 1)]
+    #[case("set -u\necho $nope\necho after", "", "nope: unbound variable\n", 1)]
+    #[case("false | true", "", "", 0)]
+    #[case("set -o pipefail\nsh -c 'exit 3' | false | true", "", "", 1)]
+    #[case("set -f\necho /*", "/*\n", "", 0)]
+    #[case("set -x\na=1 echo 'a b'", "a b\n", "+ a=1 echo 'a b'\n", 0)]
+    #[case("set -a\nv=1\nprintenv v\nset +a\nw=2\nprintenv w", "1\n", "", 1)]
+    #[case(
+        "echo a\nset -v\n\n# $v\necho $v |\ncat\nset +v\necho b",
+        "a\n\nb\n",
+        "\n# $v\necho $v |\ncat\nset +v\n",
+        0
+    )]
+    #[case(
+        "trap 'echo \"+ $BASH_COMMAND\"' DEBUG\necho a\ntrap - DEBUG\necho b",
+        "+ echo a\na\n+ trap - DEBUG\nb\n",
+        "",
+        0
+    )]
+    #[case(
+        "trap 'echo failed: $BASH_COMMAND' ERR\ntrue\nfalse\nnosuch_xyz",
+        "failed: false\nfailed: nosuch_xyz\n",
+        "nosuch_xyz: command not found\n",
+        127
+    )]
+    #[case(
+        "trap 'echo status $?' ERR\nfalse\nsh -c 'exit 3'",
+        "status 1\nstatus 3\n",
+        "",
+        3
+    )]
+    #[case(
+        "set -e\ntrap 'echo failed\nfalse' err\nfalse\necho after",
+        "failed\n",
+        "",
+        1
+    )]
+    #[case(
+        "trap 'echo x' ERR\ntrap true DEBUG\ntrap - DEBUG\ntrap\ntrap -p ERR",
+        "trap -- 'echo x' ERR\ntrap -- 'echo x' ERR\n",
+        "",
+        0
+    )]
+    #[case(
+        "trap 'echo x' INT ERR\ntrap ERR\ntrap",
+        "x\n",
+        "trap: INT: invalid signal specification\n",
+        0
+    )]
+    #[case(
+        "j\ncd -2\nj nowhere_xyz",
+        "",
+        "cd: -2: no such entry in the directory history\nj: nowhere_xyz: no matching directory\n",
+        1
+    )]
+    #[case("CHPWD_COMMAND='echo changed\ncd .\nfalse'\ncd .", "changed\n", "", 0)]
+    fn eval_str_test(
+        #[case] input: &str,
+        #[case] stdout: &str,
+        #[case] stderr: &str,
+        #[case] status: i32,
+    ) {
+        let mut shell = Shell::non_interactive().unwrap();
+        let expected = EvalResult {
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+            status,
+        };
+        assert_eq!(shell.eval_str(input), expected);
+    }
+
+    #[test]
+    fn builder_binds_streams_and_env() {
+        let stdout = Capture::default();
+        let stderr = Capture::default();
+        let mut shell = Shell::builder()
+            .stdin(&b"echo a\nprintenv FOO HOME\ncd\nnosuch\n"[..])
+            .stdout(stdout.clone())
+            .stderr(stderr.clone())
+            .env([("PATH", "/usr/bin:/bin"), ("FOO", "bar")])
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            shell.repl(),
+            Err(ShellError::Read(ReadError::Eof))
+        ));
+        assert_eq!(String::from_utf8(stdout.take()).unwrap(), "a\nbar\n");
+        assert_eq!(
+            String::from_utf8(stderr.take()).unwrap(),
+            "cd: HOME not set\nnosuch: command not found\n"
+        );
+    }
+
+    #[test]
+    fn assignments_keep_variables_exported() {
+        let stdout = Capture::default();
+        let mut shell = Shell::builder()
+            .stdout(stdout.clone())
+            .env([("PATH", "/usr/bin:/bin"), ("FOO", "bar")])
+            .interactive(false)
+            .build()
+            .unwrap();
+
+        shell
+            .run_script("FOO=baz\nBAR=local\nprintenv FOO BAR\necho $BAR")
+            .unwrap();
+        assert_eq!(String::from_utf8(stdout.take()).unwrap(), "baz\nlocal\n");
+        assert_eq!(shell.last_status(), 0);
+    }
+
+    #[test]
+    fn vars_lists_assigned_and_inherited_variables() {
+        let mut shell = Shell::builder()
+            .env([("PATH", "/usr/bin:/bin"), ("FOO", "bar"), ("GONE", "x")])
+            .interactive(false)
+            .build()
+            .unwrap();
+
+        shell
+            .run_script("FOO=baz\nlocal=1\nexport LATER\nunset GONE")
+            .unwrap();
+        // the shell sets SHLVL and PWD itself
+        let vars: Vec<_> = shell
+            .env
+            .vars()
+            .into_iter()
+            .filter(|(name, _)| name != "SHLVL" && name != "PWD")
+            .collect();
+        let expected = [("FOO", "baz"), ("PATH", "/usr/bin:/bin"), ("local", "1")]
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        assert_eq!(vars, expected);
+    }
+
+    #[test]
+    fn noclobber() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("noclobber").display().to_string();
+        let mut shell = Shell::non_interactive().unwrap();
+
+        let refused = shell.eval_str(&format!("echo a > {file}\nset -C\necho b > {file}"));
+        let kept = fs::read_to_string(&file).unwrap();
+        let clobbered = shell.eval_str(&format!("echo c >| {file}\necho d >> {file}"));
+        let contents = fs::read_to_string(&file).unwrap();
+
+        assert_eq!(
+            refused.stderr,
+            format!("{file}: cannot overwrite existing file\n")
+        );
+        assert_eq!(refused.status, 1);
+        assert_eq!(kept, "a\n");
+        assert_eq!(clobbered.status, 0);
+        assert_eq!(contents, "c\nd\n");
+    }
+
+    #[test]
+    fn exec_redirects_the_shell() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("exec").display().to_string();
+        let mut shell = Shell::non_interactive().unwrap();
+
+        let logged = shell.eval_str(&format!(
+            "echo a\nexec > {file} 2>&1\necho b\nnosuch_xyz\necho c | cat"
+        ));
+        let log = fs::read_to_string(&file).unwrap();
+        let swapped = shell.eval_str("exec 2>&1 >/dev/null\nnosuch_xyz\necho d\nexec ls");
+
+        assert_eq!(logged.stdout, "a\n");
+        assert_eq!(log, "b\nnosuch_xyz: command not found\nc\n");
+        assert_eq!(
+            swapped.stdout,
+            "nosuch_xyz: command not found\nexec: ls: cannot replace the shell with a command\n"
+        );
+        assert_eq!(swapped.status, 1);
+    }
+
+    #[test]
+    fn record_copies_the_output_into_a_transcript() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("record").display().to_string();
+        let mut shell = Shell::non_interactive().unwrap();
+
+        let result = shell.eval_str(&format!(
+            "echo a\nrecord start {file}\nrecord start {file}\nrecord\nprintf 'b\\n' | cat\nnosuch_xyz\nrecord stop\necho c\nrecord stop"
+        ));
+        let transcript = fs::read_to_string(&file).unwrap();
+
+        assert_eq!(result.stdout, format!("a\nrecording to {file}\nb\nc\n"));
+        assert_eq!(
+            result.stderr,
+            format!(
+                "record: already recording to {file}\nnosuch_xyz: command not found\nrecord: not recording\n"
+            )
+        );
+        let lines: Vec<&str> = transcript.lines().collect();
+        assert!(lines[0].starts_with("Script started on "));
+        assert_eq!(
+            lines[1..5],
+            [
+                format!("record: already recording to {file}").as_str(),
+                format!("recording to {file}").as_str(),
+                "b",
+                "nosuch_xyz: command not found",
+            ]
+        );
+        assert!(lines[6].starts_with("Script done on "));
+    }
+
+    #[test]
+    fn special_vars() {
+        let stdout = Capture::default();
+        let mut shell = Shell::builder()
+            .stdout(stdout.clone())
+            .env([("PATH", "/usr/bin:/bin"), ("SHLVL", "2")])
+            .interactive(false)
+            .build()
+            .unwrap();
+
+        shell
+            .run_script("printenv SHLVL\nSECONDS=100\necho $SECONDS\nRANDOM=7\necho $RANDOM")
+            .unwrap();
+        let first_random = String::from_utf8(stdout.take()).unwrap();
+        shell.run_script("RANDOM=7\necho $RANDOM").unwrap();
+        let random = String::from_utf8(stdout.take()).unwrap();
+
+        assert!(first_random.starts_with("3\n100\n"));
+        assert!(first_random.ends_with(&random));
+        assert!(random.trim().parse::<u32>().unwrap() < 32768);
+    }
+
+    #[rstest]
+    #[case("", "D/a.txt D/b.txt D/*.none\n", "", 0)]
+    #[case("dotglob", "D/.h.txt D/a.txt D/b.txt D/*.none\n", "", 0)]
+    #[case("nullglob", "D/a.txt D/b.txt\n", "", 0)]
+    #[case("nullglob dotglob", "D/.h.txt D/a.txt D/b.txt\n", "", 0)]
+    #[case("failglob", "", "no match: D/*.none\n", 1)]
+    #[case("failglob nullglob", "", "no match: D/*.none\n", 1)]
+    #[case("failglob dotglob", "", "no match: D/*.none\n", 1)]
+    fn glob_options(
+        #[case] shopts: &str,
+        #[case] stdout: &str,
+        #[case] stderr: &str,
+        #[case] status: i32,
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["a.txt", "b.txt", ".h.txt"] {
+            fs::write(dir.path().join(file), "").unwrap();
+        }
+        let dir = dir.path().display().to_string();
+        let mut shell = Shell::non_interactive().unwrap();
+
+        if !shopts.is_empty() {
+            shell.eval_str(&format!("shopt -s {shopts}"));
+        }
+        let result = shell.eval_str(&format!("echo {dir}/*.txt {dir}/*.none"));
+
+        let expected = EvalResult {
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+            status,
+        };
+        let result = EvalResult {
+            stdout: result.stdout.replace(&dir, "D"),
+            stderr: result.stderr.replace(&dir, "D"),
+            ..result
+        };
+        assert_eq!(result, expected);
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    #[case("echo a | tr a b", "b\n", "", 0)]
+    #[case("sh -c 'echo out; echo err >&2; exit 3'", "out\n", "err\n", 3)]
+    #[case("sh -c 'echo err >&2' 2>&1 | tr e E", "Err\n", "", 0)]
+    #[case("printf 'b\\na\\n' | sort | head -n 1", "a\n", "", 0)]
+    #[case("a=1 sh -c 'echo $a'", "1\n", "", 0)]
+    #[case("export v=2\nprintenv v\nunset v\nprintenv v", "2\n", "", 1)]
+    #[case("echo x > D/f\ncat < D/f\ncat D/f | wc -l", "x\n1\n", "", 0)]
+    #[case("timeout 0.4s sleep 5", "", "", 124)]
+    #[case("true", "", "", 0)]
+    fn posix_spawn_option(
+        #[case] input: &str,
+        #[case] stdout: &str,
+        #[case] stderr: &str,
+        #[case] status: i32,
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let input = input.replace("D/", &format!("{}/", dir.path().display()));
+        let mut shell = Shell::non_interactive().unwrap();
+        let std = shell.eval_str(&input);
+
+        shell.eval_str("shopt -s posix_spawn");
+        let spawned = shell.eval_str(&input);
+
+        let expected = EvalResult {
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+            status,
+        };
+        assert_eq!(spawned, expected);
+        assert_eq!(std, expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_not_found_suggests_commands() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let cargo = dir.join("cargo");
+        fs::write(&cargo, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&cargo, fs::Permissions::from_mode(0o755)).unwrap();
+        let stderr = Capture::default();
+        let mut shell = Shell::builder()
+            .stderr(stderr.clone())
+            .env([("PATH", dir.to_str().unwrap())])
+            .interactive(false)
+            .build()
+            .unwrap();
+
+        shell.run_script("crago build\nech hi\nzzzz").unwrap();
+
+        assert_eq!(
+            String::from_utf8(stderr.take()).unwrap(),
+            "crago: command not found\ndid you mean 'cargo'?\n\
+             ech: command not found\ndid you mean 'echo'?\n\
+             zzzz: command not found\n"
+        );
+        assert_eq!(shell.last_status(), 127);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn files_that_cant_run_fail_with_126() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let file = dir.join("notes");
+        fs::write(&file, "").unwrap();
+        let stderr = Capture::default();
+        let mut shell = Shell::builder()
+            .stderr(stderr.clone())
+            .interactive(false)
+            .build()
+            .unwrap();
+
+        let mut statuses = Vec::new();
+        for path in [file.as_path(), dir, &dir.join("missing")] {
+            shell.run_script(path.to_str().unwrap()).unwrap();
+            statuses.push(shell.last_status());
+        }
+
+        let dir = dir.display();
+        assert_eq!(
+            String::from_utf8(stderr.take()).unwrap(),
+            format!(
+                "{dir}/notes: Permission denied\n\
+                 {dir}: Is a directory\n\
+                 {dir}/missing: command not found\n"
+            )
+        );
+        assert_eq!(statuses, vec![126, 126, 127]);
+    }
+
+    /// Prints its arguments and then its input, upper-cased.
+    struct Shout;
+
+    impl Builtin for Shout {
+        fn run(
+            &mut self,
+            args: &[String],
+            stdin: &mut dyn io::Read,
+            stdout: &mut dyn io::Write,
+            _stderr: &mut dyn io::Write,
+        ) -> i32 {
+            let mut input = String::new();
+            stdin.read_to_string(&mut input).unwrap();
+            write!(stdout, "{}\n{}", args[1..].join(" "), input.to_uppercase()).unwrap();
+            3
         }
     }
 
-    false
+    /// A shell with [`Shout`] loaded, as if from `./shout.so`.
+    fn shell_with_shout() -> Shell {
+        let shell = Shell::non_interactive().unwrap();
+        shell
+            .plugins
+            .borrow_mut()
+            .insert("shout", Path::new("./shout.so"), Box::new(Shout));
+        shell
+    }
+
+    #[test]
+    fn loaded_builtins_run_until_removed() {
+        let mut shell = shell_with_shout();
+
+        let listed = shell.eval_str("enable");
+        let piped = shell.eval_str("echo hi | shout a b");
+        let removed = shell.eval_str("enable -d shout\nshout");
+
+        assert_eq!(listed.stdout, "enable -f ./shout.so shout\n");
+        assert_eq!(piped.stdout, "a b\nHI\n");
+        assert_eq!(piped.status, 3);
+        assert!(removed.stderr.starts_with("shout: command not found\n"));
+        assert_eq!(removed.status, 127);
+    }
+
+    #[rstest]
+    // after a builtin, its output is buffered
+    #[case("echo hi | shout")]
+    // after a command, it is read from a pipe
+    #[case("printf 'hi\\n' | shout")]
+    #[case("shout < $dir/in")]
+    fn builtins_read_their_input(#[case] input: &str) {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("in"), "hi\n").unwrap();
+        let mut shell = shell_with_shout();
+        shell.env.set("dir", tmp.path().to_str().unwrap());
+
+        let result = shell.eval_str(input);
+
+        assert_eq!(result.stdout, "\nHI\n");
+    }
+
+    #[test]
+    fn builtins_read_all_of_a_pipe() {
+        let mut shell = shell_with_shout();
+
+        let result = shell.eval_str("seq 20000 | shout");
+
+        let expected: String = (1..=20000).map(|n| format!("{n}\n")).collect();
+        assert_eq!(result.stdout, format!("\n{expected}"));
+    }
+
+    #[test]
+    fn redirects_are_opened_before_commands_run() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut shell = Shell::non_interactive().unwrap();
+
+        let missing = shell.eval_str(&format!("nosuch_command_xyz > {}/out", dir.display()));
+        let unopenable = shell.eval_str(&format!(
+            "touch {0}/ran | cat > {0}/no/such/dir",
+            dir.display()
+        ));
+        let created = dir.join("out").exists();
+        let ran = dir.join("ran").exists();
+
+        assert_eq!(missing.status, 127);
+        assert!(created);
+        assert_eq!(unopenable.status, 1);
+        assert!(!ran);
+    }
+
+    #[test]
+    fn redirects_apply_from_left_to_right() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("in"), "from a file\n").unwrap();
+        let mut shell = Shell::non_interactive().unwrap();
+        let (both, d) = ("sh -c 'echo out; echo err >&2'", dir.display());
+        let results = [
+            shell.eval_str(&format!("{both} > {d}/both 2>&1")),
+            shell.eval_str(&format!("{both} 2>&1 > {d}/out")),
+            shell.eval_str(&format!("echo replaced > {d}/first > {d}/second")),
+            shell.eval_str(&format!("{both} 2>&1 >/dev/null | cat > {d}/piped")),
+            shell.eval_str(&format!("echo ignored | cat < {d}/in")),
+        ];
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        let files = [
+            read("both"),
+            read("out"),
+            read("first"),
+            read("second"),
+            read("piped"),
+        ];
+
+        assert_eq!(files, ["out\nerr\n", "out\n", "", "replaced\n", "err\n"]);
+        assert_eq!(results[1].stdout, "err\n");
+        assert_eq!(results[4].stdout, "from a file\n");
+    }
+
+    #[test]
+    fn heredocs_are_the_input_of_commands() {
+        let mut shell = Shell::non_interactive().unwrap();
+        let result = shell
+            .eval_str("name=doc\ncat <<EOF | cat\nhi $name\nEOF\ncat <<-'EOF'\n\t$name\n\tEOF\n");
+        assert_eq!(result.stdout, "hi doc\n$name\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spawn_detaches_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let mut shell = Shell::non_interactive().unwrap();
+
+        let start = Instant::now();
+        let result = shell.eval_str(&format!(
+            "spawn -o {} sh -c 'sleep 0.2; echo out; kill -HUP $$; ps -o tty= -p $$'",
+            log.display()
+        ));
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert_eq!(result.status, 0);
+
+        let mut written = String::new();
+        while start.elapsed() < Duration::from_secs(5) && written.lines().count() < 2 {
+            std::thread::sleep(Duration::from_millis(50));
+            written = fs::read_to_string(&log).unwrap_or_default();
+        }
+        // surviving SIGHUP, without a controlling terminal
+        assert_eq!(written, "out\n?\n");
+    }
+
+    #[test]
+    fn hooks_run_around_commands() {
+        #[derive(Clone, Default)]
+        struct Recorder(Rc<RefCell<Vec<String>>>);
+
+        impl CommandHook for Recorder {
+            fn preexec(&mut self, command: &str) {
+                self.0.borrow_mut().push(format!("pre {command}"));
+            }
+
+            fn postexec(&mut self, command: &str, status: i32) {
+                self.0.borrow_mut().push(format!("post {command} {status}"));
+            }
+        }
+
+        let recorder = Recorder::default();
+        let stdout = Capture::default();
+        let mut shell = Shell::builder()
+            .stdin(&b"v='a b'\necho $v | wc -w\nnosuch\n"[..])
+            .stdout(stdout.clone())
+            .stderr(io::sink())
+            .env([
+                ("PATH", "/usr/bin:/bin"),
+                ("PREEXEC_COMMAND", "echo \"> $HOOK_COMMAND\""),
+                ("POSTEXEC_COMMAND", "echo \"< $HOOK_STATUS\""),
+            ])
+            .hook(recorder.clone())
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            shell.repl(),
+            Err(ShellError::Read(ReadError::Eof))
+        ));
+        assert_eq!(
+            *recorder.0.borrow(),
+            vec![
+                "pre echo a b | wc -w",
+                "post echo a b | wc -w 0",
+                "pre nosuch",
+                "post nosuch 127",
+            ]
+        );
+        assert_eq!(
+            String::from_utf8(stdout.take()).unwrap(),
+            "> echo a b | wc -w\n2\n< 0\n> nosuch\n< 127\n"
+        );
+    }
+
+    #[test]
+    fn audit_log_records_scripts() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("audit.log");
+        let sourced = dir.path().join("sourced");
+        fs::write(&sourced, "false").unwrap();
+        let mut shell = Shell::builder()
+            .stdout(io::sink())
+            .env([("PATH", "/usr/bin:/bin")])
+            .hook(AuditLog::file(log.clone(), u64::MAX))
+            .interactive(false)
+            .build()
+            .unwrap();
+
+        let script = format!("v=hi\necho $v\n. {}", sourced.display());
+        shell.run_script(&script).unwrap();
+        let commands: Vec<_> = fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let (_, command) = line.split_once(",\"command\":").unwrap();
+                let (command, _) = command.split_once(",\"duration_ms\"").unwrap();
+                command.to_string()
+            })
+            .collect();
+
+        let sourced = json::string(&format!(". {}", sourced.display()));
+        assert_eq!(
+            commands,
+            vec![
+                "\"echo hi\",\"status\":0".to_string(),
+                "\"false\",\"status\":1".to_string(),
+                format!("{sourced},\"status\":1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn repl_survives_bad_input() {
+        let stdout = Capture::default();
+        let stderr = Capture::default();
+        let mut shell = Shell::builder()
+            .stdin(&b"echo \xff\necho a | | wc\necho b | > /dev/null\necho c\n"[..])
+            .stdout(stdout.clone())
+            .stderr(stderr.clone())
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            shell.repl(),
+            Err(ShellError::Read(ReadError::Eof))
+        ));
+        assert_eq!(String::from_utf8(stdout.take()).unwrap(), "c\n");
+        assert_eq!(
+            String::from_utf8(stderr.take()).unwrap(),
+            "stream did not contain valid UTF-8\nsyntax error: unexpected token `|'\n"
+        );
+    }
+
+    #[test]
+    fn catch_panic_test() {
+        let result = catch_panic(|| -> anyhow::Result<()> { panic!("a bug") });
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "internal error, command abandoned"
+        );
+        assert_eq!(catch_panic(|| Ok::<_, ShellError>(1)).unwrap(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tilde_without_home_is_the_users_home() {
+        let mut shell = Shell::builder()
+            .env([("PATH", "")])
+            .interactive(false)
+            .build()
+            .unwrap();
+
+        let home = named_dirs::own_home().unwrap();
+        assert_eq!(
+            shell.eval_str("echo ~/x").stdout,
+            format!("{}/x\n", home.display())
+        );
+        let cd = shell.eval_str("cd");
+        assert_eq!((cd.stderr.as_str(), cd.status), ("cd: HOME not set\n", 1));
+    }
+
+    #[test]
+    fn time_reports_usage() {
+        let mut shell = Shell::non_interactive().unwrap();
+
+        let result = shell.eval_str("time -p echo hi | cat");
+        assert_eq!(result.stdout, "hi\n");
+        assert!(result.stderr.starts_with("real 0.0"), "{}", result.stderr);
+        assert!(result.stderr.contains("\nuser 0.0"));
+
+        let result = shell.eval_str("time -v sh -c 'exit 4'");
+        assert_eq!(result.status, 4);
+        assert!(result
+            .stderr
+            .contains("\tCommand being timed: \"sh -c exit 4\"\n"));
+        assert!(result.stderr.ends_with("\tExit status: 4\n"));
+    }
+
+    #[test]
+    fn posix_mode_sources_from_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("lib.sh"), "echo sourced\n").unwrap();
+        let stdout = Capture::default();
+        let stderr = Capture::default();
+        let mut shell = Shell::builder()
+            .stdout(stdout.clone())
+            .stderr(stderr.clone())
+            .env([("PATH", dir.to_str().unwrap()), ("POSIXLY_CORRECT", "y")])
+            .interactive(false)
+            .build()
+            .unwrap();
+
+        shell.run_script(". lib.sh\n. nosuch.sh").unwrap();
+
+        assert_eq!(String::from_utf8(stdout.take()).unwrap(), "sourced\n");
+        assert_eq!(
+            String::from_utf8(stderr.take()).unwrap(),
+            ".: nosuch.sh: file not found\n"
+        );
+    }
+
+    #[test]
+    fn script_errors_say_where() {
+        let dir = tempfile::tempdir().unwrap();
+        let (outer, inner) = (dir.path().join("outer.sh"), dir.path().join("inner.sh"));
+        let sourcing = format!("nosuch_a\n\nsource {}\necho $LINENO\n", inner.display());
+        fs::write(&outer, sourcing).unwrap();
+        fs::write(&inner, "# inner\nnosuch_b \\\n  arg\necho \"open\n").unwrap();
+        let stdout = Capture::default();
+        let stderr = Capture::default();
+        let mut shell = Shell::builder()
+            .stdout(stdout.clone())
+            .stderr(stderr.clone())
+            .env([("PATH", "/usr/bin:/bin")])
+            .interactive(false)
+            .build()
+            .unwrap();
+
+        let result = shell.run_file(&outer);
+
+        result.unwrap();
+        assert_eq!(String::from_utf8(stdout.take()).unwrap(), "4\n");
+        assert_eq!(
+            String::from_utf8(stderr.take()).unwrap(),
+            format!(
+                "{}: line 1: nosuch_a: command not found\n\
+                 {}: line 2: nosuch_b: command not found\n\
+                 {}: line 4: syntax error: unexpected end of file\n",
+                outer.display(),
+                inner.display(),
+                inner.display()
+            )
+        );
+    }
+
+    #[test]
+    fn restricted_shell() {
+        let mut shell = Shell::non_interactive().unwrap();
+        shell.restrict();
+
+        let result = shell.eval_str("cd /\nPATH=/tmp\n/bin/echo a\necho b >/tmp/f\necho c");
+        assert_eq!(result.stdout, "c\n");
+        assert_eq!(
+            result.stderr,
+            "cd: restricted\n\
+             PATH: readonly variable\n\
+             /bin/echo: restricted: cannot specify `/' in command names\n\
+             /tmp/f: restricted: cannot redirect output\n"
+        );
+        assert_eq!(
+            shell.eval_str("shopt -u restricted_shell").stderr,
+            "shopt: restricted_shell: cannot set option\n"
+        );
+    }
+
+    #[test]
+    fn logout_leaves_login_shell() {
+        let mut shell = Shell::builder()
+            .login(true)
+            .interactive(false)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            shell.eval_str("shopt login_shell").stdout,
+            "login_shell    \ton\n"
+        );
+        assert!(matches!(
+            shell.run_script("logout 4"),
+            Err(ShellError::ExitRequested { code: Some(4) })
+        ));
+    }
+
+    #[rstest]
+    #[case(true, "profile\n")]
+    #[case(false, "")]
+    fn login_shell_sources_its_profile(#[case] login: bool, #[case] expected: &str) {
+        let home = tempfile::tempdir().unwrap();
+        fs::write(home.path().join(".myshell_profile"), "echo profile").unwrap();
+        fs::write(home.path().join(".profile"), "echo bash").unwrap();
+        let stdout = Capture::default();
+        let mut shell = Shell::builder()
+            .stdout(stdout.clone())
+            .env([("HOME", home.path().display().to_string())])
+            .login(login)
+            .interactive(false)
+            .build()
+            .unwrap();
+
+        shell.source_profile().unwrap();
+        assert_eq!(String::from_utf8(stdout.take()).unwrap(), expected);
+    }
+
+    #[test]
+    fn prompt_command_runs_before_every_prompt() {
+        let vars = [("PROMPT_COMMAND", "echo prompt\nfalse")];
+        let editor = ScriptedEditor::new([Ok("echo a"), Ok("true\ntrue"), Ok("false")]);
+        let (_, result) = run_interactive(editor, &vars);
+
+        assert_eq!(
+            result.stdout, "prompt\na\nprompt\nprompt\nprompt\n",
+            "once for the commands entered together"
+        );
+        assert_eq!(result.status, 1, "of the last command entered");
+    }
+
+    #[rstest]
+    #[case("shopt -s menu_complete", true)]
+    #[case("shopt -s menu_complete\nshopt -u menu_complete", false)]
+    fn shopt_switches_to_cycling_completions(#[case] input: &'static str, #[case] expected: bool) {
+        let editor = ScriptedEditor::new([Ok(input)]);
+        let menu_complete = Rc::clone(&editor.menu_complete);
+        run_interactive(editor, &[]);
+
+        assert_eq!(menu_complete.get(), expected);
+    }
+
+    #[rstest]
+    #[case(vec![Ok("false")], "exit\n", 1)]
+    #[case(vec![Ok("set -o ignoreeof"), Err(ReadError::Eof), Ok("exit 3")], "Use \"exit\" to leave the shell.\n", 3)]
+    #[case(vec![Err(ReadError::Interrupted)], "^C\nexit\n", 130)]
+    fn end_of_input_exits_unless_ignored(
+        #[case] input: Vec<Result<&'static str, ReadError>>,
+        #[case] stderr: &str,
+        #[case] status: i32,
+    ) {
+        let (_, result) = run_interactive(ScriptedEditor::new(input), &[]);
+        assert_eq!((result.stderr.as_str(), result.status), (stderr, status));
+    }
+
+    #[rstest]
+    #[case(vec![Ok("false"), Ok("echo $?")], "1\n")]
+    #[case(vec![Err(ReadError::Interrupted), Ok("echo $?")], "130\n")]
+    #[case(vec![Ok("false\necho ${?} $?"), Ok("echo \"[$?]\"")], "1 1\n[0]\n")]
+    fn status_expands_at_the_prompt(
+        #[case] input: Vec<Result<&'static str, ReadError>>,
+        #[case] expected: &str,
+    ) {
+        let (_, result) = run_interactive(ScriptedEditor::new(input), &[]);
+        assert_eq!(result.stdout, expected);
+    }
+
+    #[rstest]
+    #[case("HISTCONTROL=ignoredups", vec!["echo a", "echo a"], vec!["echo a"])]
+    #[case("export HISTCONTROL=ignorespace", vec![" echo a", "echo b"], vec!["echo b"])]
+    fn histcontrol_assigned_in_the_shell(
+        #[case] assignment: &'static str,
+        #[case] input: Vec<&'static str>,
+        #[case] expected: Vec<&str>,
+    ) {
+        let input = iter::once(assignment).chain(input).map(Ok);
+        let (shell, _) = run_interactive(ScriptedEditor::new(input), &[]);
+
+        let editor = shell.editor.borrow();
+        let history: Vec<_> = editor.history().iter().skip(1).collect();
+        assert_eq!(history, expected);
+    }
+
+    #[test]
+    fn history_n_reads_each_line_of_a_file_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        fs::write(&path, "ls\n").unwrap();
+        let (read, append) = ("history -n $file", "echo cd >> $file");
+        let input = [read, read, append, read].map(Ok);
+
+        let vars = [("file", path.to_str().unwrap())];
+        let (shell, _) = run_interactive(ScriptedEditor::new(input), &vars);
+
+        let editor = shell.editor.borrow();
+        let history: Vec<_> = editor.history().iter().collect();
+        assert_eq!(history, vec!["ls", "cd", read, read, append, read]);
+    }
+
+    #[test]
+    fn unbound_variables_in_sourced_files_end_the_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("unbound.sh");
+        fs::write(&path, "echo before\necho $nope\necho in").unwrap();
+        let mut shell = Shell::non_interactive().unwrap();
+
+        let result = shell.eval_str(&format!("set -u\nsource {}\necho after", path.display()));
+
+        let expected = EvalResult {
+            stdout: String::from("before\n"),
+            stderr: format!("{}: line 2: nope: unbound variable\n", path.display()),
+            status: 1,
+        };
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn unbound_variables_are_not_fatal_at_the_prompt() {
+        let input = [Ok("set -u"), Ok("echo $nope"), Ok("echo after")];
+        let (_, result) = run_interactive(ScriptedEditor::new(input), &[]);
+
+        assert_eq!(result.stdout, "after\n");
+        assert_eq!(result.stderr, "nope: unbound variable\nexit\n");
+    }
+
+    #[test]
+    fn completions_are_registered_with_the_editor() {
+        struct Deploy;
+
+        impl CompletionProvider for Deploy {
+            fn candidates(&self, _: &[&str], _: &str) -> Option<Vec<crate::completion::Pair>> {
+                None
+            }
+        }
+
+        let editor = ScriptedEditor::default();
+        let completed = Rc::clone(&editor.completed);
+        Shell::builder()
+            .editor(editor)
+            .completion("deploy", Deploy)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            *completed.borrow(),
+            vec!["git", "cargo", "ssh", "kill", "deploy"]
+        );
+    }
 }
@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Optimal string alignment distance: insertions, deletions, substitutions and
+/// transpositions of adjacent characters each cost one edit.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    let mut prev_prev = vec![0; b.len() + 1];
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for i in 1..=a.len() {
+        current[0] = i;
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            current[j] = (prev[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(prev[j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(prev_prev[j - 2] + 1);
+            }
+        }
+
+        prev_prev.clone_from(&prev);
+        prev.clone_from(&current);
+    }
+
+    prev[b.len()]
+}
+
+/// Up to three of `candidates` close enough to `name` to be what was meant
+/// by it, closest first: one edit away, or two for names of eight or more
+/// characters.
+pub fn suggestions<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let max_distance = if name.chars().count() < 8 { 1 } else { 2 };
+    let mut close: Vec<(usize, &str)> = candidates
+        .into_iter()
+        .filter(|&candidate| candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .collect();
+    close.sort();
+    close.dedup();
+
+    close
+        .into_iter()
+        .take(3)
+        .map(|(_, candidate)| candidate.to_string())
+        .collect()
+}
+
+/// A line after "command not found" offering `suggestions`, or nothing
+/// without any.
+pub fn did_you_mean(suggestions: &[String]) -> String {
+    let quoted: Vec<String> = suggestions.iter().map(|s| format!("'{s}'")).collect();
+    match quoted.as_slice() {
+        [] => String::new(),
+        [only] => format!("\ndid you mean {only}?"),
+        [rest @ .., last] => format!("\ndid you mean {} or {last}?", rest.join(", ")),
+    }
+}
+
+/// Corrects minor typos in each component of a directory path the way bash's
+/// `cdspell` does: every component that doesn't exist is replaced with the
+/// closest entry of its parent directory at most one edit away.
+pub fn correct_dir(path: &str) -> Option<PathBuf> {
+    let mut corrected = PathBuf::new();
+    for component in Path::new(path).components() {
+        let Component::Normal(name) = component else {
+            corrected.push(component);
+            continue;
+        };
+
+        let candidate = corrected.join(name);
+        if candidate.is_dir() {
+            corrected = candidate;
+            continue;
+        }
+
+        let name = name.to_str()?;
+        let parent = if corrected.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            corrected.as_path()
+        };
+
+        let mut entries: Vec<String> = fs::read_dir(parent)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect();
+        entries.sort();
+
+        let best = entries
+            .into_iter()
+            .map(|entry| (edit_distance(name, &entry), entry))
+            .filter(|(distance, _)| *distance <= 1)
+            .min_by_key(|(distance, _)| *distance)?;
+
+        corrected.push(best.1);
+    }
+
+    Some(corrected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("local", "local", 0)]
+    #[case("loal", "local", 1)]
+    #[case("lcoal", "local", 1)]
+    #[case("locale", "local", 1)]
+    #[case("lokal", "local", 1)]
+    #[case("", "abc", 3)]
+    #[case("cargo", "crago", 1)]
+    #[case("git", "gti", 1)]
+    #[case("kitten", "sitting", 3)]
+    fn edit_distance_test(#[case] a: &str, #[case] b: &str, #[case] expected: usize) {
+        assert_eq!(edit_distance(a, b), expected);
+        assert_eq!(edit_distance(b, a), expected);
+    }
+
+    #[rstest]
+    #[case("crago", vec!["cargo"])]
+    #[case("gti", vec!["git"])]
+    #[case("ca", vec!["cat", "cd"])]
+    #[case("carg", vec!["cargo"])]
+    #[case("grp", vec!["grep"])]
+    #[case("rufmt", vec![])]
+    #[case("rusttfmtt", vec!["rustfmt"])]
+    #[case("cargo", vec![])]
+    #[case("zzz", vec![])]
+    fn suggestions_test(#[case] name: &str, #[case] expected: Vec<&str>) {
+        let candidates = ["cargo", "cat", "cd", "git", "grep", "cargo", "rustfmt"];
+        assert_eq!(suggestions(name, candidates), expected);
+    }
+
+    #[rstest]
+    #[case(vec![], "")]
+    #[case(vec!["cargo"], "\ndid you mean 'cargo'?")]
+    #[case(vec!["cat", "cd", "cp"], "\ndid you mean 'cat', 'cd' or 'cp'?")]
+    fn did_you_mean_test(#[case] suggestions: Vec<&str>, #[case] expected: &str) {
+        let suggestions: Vec<String> = suggestions.into_iter().map(String::from).collect();
+        assert_eq!(did_you_mean(&suggestions), expected);
+    }
+
+    #[rstest]
+    #[case("src", Some("src"))]
+    #[case("scr", Some("src"))]
+    #[case("targt", Some("target"))]
+    #[case("doccs", Some("docs"))]
+    #[case("scr/lxer", Some("src/lexer"))]
+    #[case("src/../tagret", Some("src/../target"))]
+    #[case("zzz", None)]
+    #[case("scr/zzz", None)]
+    fn correct_dir_test(#[case] path: &str, #[case] expected: Option<&str>) {
+        let dir = tempfile::tempdir().unwrap();
+        for sub in ["src/lexer", "target", "docs"] {
+            fs::create_dir_all(dir.path().join(sub)).unwrap();
+        }
+        // as close as src, but not a directory
+        fs::write(dir.path().join("sc"), "").unwrap();
+
+        let corrected = correct_dir(&dir.path().join(path).to_string_lossy());
+        assert_eq!(corrected, expected.map(|path| dir.path().join(path)));
+    }
+}
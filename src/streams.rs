@@ -0,0 +1,349 @@
+use crate::transcript::Transcript;
+use std::io::{self, IsTerminal, Write};
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::{fs, mem};
+
+const RESET: &str = "\x1b[0m";
+
+/// A writer shared by everything printing to one of the shell's output
+/// streams, e.g. the pipelines it runs. Where it writes can be changed for
+/// every clone at once, as `exec >file` does.
+#[derive(Clone)]
+pub struct Sink(Arc<Mutex<Destination>>);
+
+enum Destination {
+    /// A stream of the shell process.
+    Process(ProcessStream),
+    File(fs::File),
+    Writer(Arc<Mutex<Box<dyn Write + Send>>>),
+    /// Another destination, with a copy of everything written to it going
+    /// into a transcript.
+    Recorded(Box<Destination>, Transcript),
+}
+
+#[derive(Clone, Copy)]
+enum ProcessStream {
+    Stdout,
+    Stderr,
+}
+
+impl Sink {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self::to(Destination::Writer(Arc::new(Mutex::new(Box::new(writer)))))
+    }
+
+    pub fn stdout() -> Self {
+        Self::to(Destination::Process(ProcessStream::Stdout))
+    }
+
+    pub fn stderr() -> Self {
+        Self::to(Destination::Process(ProcessStream::Stderr))
+    }
+
+    fn to(destination: Destination) -> Self {
+        Self(Arc::new(Mutex::new(destination)))
+    }
+
+    /// The stream of the shell process it writes to, if it does.
+    fn process(&self) -> Option<ProcessStream> {
+        match *self.0.lock().unwrap() {
+            Destination::Process(stream) => Some(stream),
+            _ => None,
+        }
+    }
+
+    /// Whether it writes to the shell's own stderr, and that is a terminal.
+    pub(crate) fn is_terminal_stderr(&self) -> bool {
+        let mut destination = &*self.0.lock().unwrap();
+        while let Destination::Recorded(recorded, _) = destination {
+            destination = recorded;
+        }
+        matches!(destination, Destination::Process(ProcessStream::Stderr))
+            && io::stderr().is_terminal()
+    }
+
+    /// Where `output` writes now, to write there too.
+    fn destination_of(output: Output) -> io::Result<Destination> {
+        Ok(match output {
+            Output::File(file) => Destination::File(file),
+            Output::Sink(sink) => sink.0.lock().unwrap().try_clone()?,
+        })
+    }
+
+    /// Makes it copy everything written to it into `transcript` too, until
+    /// [`Sink::stop_recording`].
+    fn record(&self, transcript: Transcript) {
+        let mut destination = self.0.lock().unwrap();
+        let placeholder = Destination::Process(ProcessStream::Stdout);
+        let recorded = mem::replace(&mut *destination, placeholder);
+        *destination = Destination::Recorded(Box::new(recorded), transcript);
+    }
+
+    fn stop_recording(&self) -> Option<Transcript> {
+        let mut destination = self.0.lock().unwrap();
+        if !matches!(*destination, Destination::Recorded(..)) {
+            return None;
+        }
+
+        let placeholder = Destination::Process(ProcessStream::Stdout);
+        let Destination::Recorded(recorded, transcript) =
+            mem::replace(&mut *destination, placeholder)
+        else {
+            unreachable!();
+        };
+        *destination = *recorded;
+        Some(transcript)
+    }
+
+    fn transcript(&self) -> Option<Transcript> {
+        match &*self.0.lock().unwrap() {
+            Destination::Recorded(_, transcript) => Some(transcript.clone()),
+            _ => None,
+        }
+    }
+
+    fn set_destination(&self, destination: Destination) -> io::Result<()> {
+        let mut current = self.0.lock().unwrap();
+        current.flush()?;
+        *current = destination;
+        Ok(())
+    }
+
+    /// A sink writing into this one with every write in `color`. What is
+    /// copied into it goes through the shell, never spliced past it.
+    pub(crate) fn colored(&self, color: &'static str) -> Self {
+        Self::new(Colored {
+            sink: self.clone(),
+            color,
+        })
+    }
+}
+
+/// Wraps each write in a color and a reset, so nothing printed between
+/// them, like the prompt, is left colored.
+struct Colored {
+    sink: Sink,
+    color: &'static str,
+}
+
+impl Write for Colored {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut colored = Vec::with_capacity(self.color.len() + buf.len() + RESET.len());
+        colored.extend_from_slice(self.color.as_bytes());
+        colored.extend_from_slice(buf);
+        colored.extend_from_slice(RESET.as_bytes());
+        // in one write, for other writers of the sink not to land inside
+        self.sink.write_all(&colored)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+impl Destination {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Destination::Process(stream) => Destination::Process(*stream),
+            Destination::File(file) => Destination::File(file.try_clone()?),
+            Destination::Writer(writer) => Destination::Writer(Arc::clone(writer)),
+            Destination::Recorded(recorded, transcript) => {
+                Destination::Recorded(Box::new(recorded.try_clone()?), transcript.clone())
+            }
+        })
+    }
+}
+
+impl Write for Destination {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Destination::Process(ProcessStream::Stdout) => io::stdout().write(buf),
+            Destination::Process(ProcessStream::Stderr) => io::stderr().write(buf),
+            Destination::File(file) => file.write(buf),
+            Destination::Writer(writer) => writer.lock().unwrap().write(buf),
+            Destination::Recorded(recorded, transcript) => {
+                let written = recorded.write(buf)?;
+                transcript.write_all(&buf[..written])?;
+                Ok(written)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Destination::Process(ProcessStream::Stdout) => io::stdout().flush(),
+            Destination::Process(ProcessStream::Stderr) => io::stderr().flush(),
+            Destination::File(file) => file.flush(),
+            Destination::Writer(writer) => writer.lock().unwrap().flush(),
+            Destination::Recorded(recorded, transcript) => {
+                recorded.flush()?;
+                transcript.flush()
+            }
+        }
+    }
+}
+
+/// Where a command's output goes: one of the shell's streams, or the file
+/// it is redirected to.
+pub enum Output {
+    Sink(Sink),
+    File(fs::File),
+}
+
+impl Output {
+    /// Another handle to the same output, for redirecting a second stream
+    /// into it, like `2>&1`.
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Output::Sink(sink) => Output::Sink(sink.clone()),
+            Output::File(file) => Output::File(file.try_clone()?),
+        })
+    }
+
+    /// The file descriptor written to, for copying into it without going
+    /// through the shell's memory. Only once flushed, for a sink.
+    #[cfg(unix)]
+    pub(crate) fn as_raw_fd(&self) -> Option<RawFd> {
+        match self {
+            Output::Sink(sink) => match sink.process()? {
+                ProcessStream::Stdout => Some(io::stdout().as_raw_fd()),
+                ProcessStream::Stderr => Some(io::stderr().as_raw_fd()),
+            },
+            Output::File(file) => Some(file.as_raw_fd()),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Sink(sink) => sink.write(buf),
+            Output::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Sink(sink) => sink.flush(),
+            Output::File(file) => file.flush(),
+        }
+    }
+}
+
+/// Where one of a command's output streams goes.
+pub(crate) enum Target {
+    Output(Output),
+    /// Into the next command of its pipeline.
+    Pipe,
+}
+
+impl Target {
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Target::Output(output) => Target::Output(output.try_clone()?),
+            Target::Pipe => Target::Pipe,
+        })
+    }
+}
+
+/// What a command's input is redirected from.
+pub(crate) enum Input {
+    File(fs::File),
+    /// The body of a here-document.
+    Text(String),
+}
+
+/// Where a command's streams go once its redirections are applied.
+pub(crate) struct Redirections {
+    pub(crate) stdin: Option<Input>,
+    pub(crate) stdout: Target,
+    pub(crate) stderr: Target,
+    /// Whether stderr was last made to go where stdout goes, or the other
+    /// way around, so both can be written into one pipe and stay in order.
+    pub(crate) shared: bool,
+}
+
+/// Where commands write what they print, unless it is redirected.
+#[derive(Clone)]
+pub struct Streams {
+    pub stdout: Sink,
+    pub stderr: Sink,
+}
+
+impl Streams {
+    /// Makes the shell's stdout and stderr, in every clone of them, write
+    /// where `stdout` and `stderr` go from now on, as `exec >file 2>&1`
+    /// does. Both are looked up before either changes, so `2>&1 >file`
+    /// leaves stderr where stdout was.
+    pub(crate) fn redirect(&self, stdout: Output, stderr: Output) -> io::Result<()> {
+        let stdout = Sink::destination_of(stdout)?;
+        let stderr = Sink::destination_of(stderr)?;
+        self.stdout.set_destination(stdout)?;
+        self.stderr.set_destination(stderr)
+    }
+
+    /// Copies what both streams print into `transcript` from now on, which
+    /// keeps commands from writing to the terminal directly.
+    pub(crate) fn record(&self, transcript: Transcript) {
+        self.stdout.record(transcript.clone());
+        self.stderr.record(transcript);
+    }
+
+    /// Stops copying into the transcript, and returns it.
+    pub(crate) fn stop_recording(&self) -> Option<Transcript> {
+        let stdout = self.stdout.stop_recording();
+        let stderr = self.stderr.stop_recording();
+        stdout.or(stderr)
+    }
+
+    /// The transcript being recorded, if any.
+    pub(crate) fn transcript(&self) -> Option<Transcript> {
+        self.stdout
+            .transcript()
+            .or_else(|| self.stderr.transcript())
+    }
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        Self {
+            stdout: Sink::stdout(),
+            stderr: Sink::stderr(),
+        }
+    }
+}
+
+/// A buffer collecting what is written to every clone of it.
+#[derive(Clone, Default)]
+pub struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Capture {
+    /// Everything written so far, emptying the buffer.
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
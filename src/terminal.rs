@@ -0,0 +1,189 @@
+use crate::prompt;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Sets the terminal window or tab title (OSC 0).
+pub fn set_title(title: &str) {
+    let title: String = title.chars().filter(|char| !char.is_control()).collect();
+    write_osc(&format!("0;{title}"));
+}
+
+/// Tells the terminal the working directory (OSC 7), so it can open new
+/// tabs and windows in it.
+pub fn report_cwd(dir: &Path) {
+    write_osc(&format!("7;{}", file_url(&prompt::hostname(), dir)));
+}
+
+fn write_osc(command: &str) {
+    let mut stdout = io::stdout();
+    // escape sequences would end up in redirected or piped output
+    if !stdout.is_terminal() {
+        return;
+    }
+    // the terminal may be gone, and this is only cosmetic
+    let _ = write!(stdout, "\x1b]{command}\x07");
+    let _ = stdout.flush();
+}
+
+/// The width and height of the terminal in columns and lines, if the
+/// shell's stdin, stdout or stderr is one.
+#[cfg(unix)]
+pub fn size() -> Option<(u16, u16)> {
+    [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO]
+        .into_iter()
+        .find_map(window_size)
+}
+
+#[cfg(not(unix))]
+pub fn size() -> Option<(u16, u16)> {
+    None
+}
+
+#[cfg(unix)]
+fn window_size(fd: libc::c_int) -> Option<(u16, u16)> {
+    // SAFETY: TIOCGWINSZ only fills in `size`.
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } == 0;
+    (ok && size.ws_col > 0).then_some((size.ws_col, size.ws_row))
+}
+
+/// Settings of the terminal on stdin, to put back after running something
+/// in between that changes them.
+#[cfg(unix)]
+pub struct Mode(libc::termios);
+
+#[cfg(unix)]
+impl Mode {
+    /// `None` when stdin isn't a terminal.
+    pub fn current() -> Option<Self> {
+        // SAFETY: tcgetattr only fills in `termios`.
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        let ok = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } == 0;
+        ok.then_some(Self(termios))
+    }
+
+    /// Switches the terminal back to these settings once pending output is
+    /// written.
+    pub fn apply(&self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.0) };
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Mode;
+
+#[cfg(not(unix))]
+impl Mode {
+    pub fn current() -> Option<Self> {
+        None
+    }
+
+    pub fn apply(&self) {}
+}
+
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+/// Starts noting SIGWINCH, for [`take_resized`]. While a line is edited the
+/// editor handles it instead, redrawing the line for the new width.
+#[cfg(unix)]
+pub fn watch_resize() {
+    static INSTALLED: std::sync::Once = std::sync::Once::new();
+    INSTALLED.call_once(|| {
+        // SAFETY: the handler only stores to an atomic.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_sigwinch as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGWINCH, &action, std::ptr::null_mut());
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn watch_resize() {}
+
+#[cfg(unix)]
+extern "C" fn on_sigwinch(_signal: libc::c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+}
+
+/// Whether the terminal was resized since this was last asked.
+pub fn take_resized() -> bool {
+    RESIZED.swap(false, Ordering::SeqCst)
+}
+
+/// A `file://` URL for `path` on `host`, with everything but unreserved
+/// characters and `/` percent-encoded.
+fn file_url(host: &str, path: &Path) -> String {
+    let mut url = format!("file://{host}");
+    for &byte in path.as_os_str().as_encoded_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            url.push(byte as char);
+        } else {
+            url.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("/home/me", "file://box/home/me")]
+    #[case("/tmp/a b", "file://box/tmp/a%20b")]
+    #[case("/srv/ünï", "file://box/srv/%C3%BCn%C3%AF")]
+    fn file_url_test(#[case] path: &str, #[case] expected: &str) {
+        assert_eq!(file_url("box", Path::new(path)), expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn window_size_test() {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::net::UnixStream;
+
+        let (mut leader, mut follower) = (0, 0);
+        let size = libc::winsize {
+            ws_row: 24,
+            ws_col: 100,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: openpty fills in the two descriptors, which are closed
+        // at the end.
+        unsafe {
+            let opened = libc::openpty(
+                &mut leader,
+                &mut follower,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &size,
+            );
+            assert_eq!(opened, 0, "{}", io::Error::last_os_error());
+            assert_eq!(window_size(follower), Some((100, 24)));
+            libc::close(leader);
+            libc::close(follower);
+        }
+
+        let (socket, _peer) = UnixStream::pair().unwrap();
+        assert_eq!(window_size(socket.as_raw_fd()), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn take_resized_notes_sigwinch() {
+        watch_resize();
+        take_resized();
+        // SAFETY: the handler only stores to an atomic.
+        unsafe { libc::raise(libc::SIGWINCH) };
+        assert!(take_resized());
+        assert!(!take_resized());
+    }
+}
@@ -0,0 +1,368 @@
+use crate::completion::{CompletionProvider, Pair};
+use crate::named_dirs;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+static GIT_SUBCOMMANDS: &[&str] = &[
+    "add",
+    "bisect",
+    "blame",
+    "branch",
+    "checkout",
+    "cherry-pick",
+    "clone",
+    "commit",
+    "diff",
+    "fetch",
+    "init",
+    "log",
+    "merge",
+    "mv",
+    "pull",
+    "push",
+    "rebase",
+    "reset",
+    "restore",
+    "revert",
+    "rm",
+    "show",
+    "stash",
+    "status",
+    "switch",
+    "tag",
+];
+
+/// git subcommands taking a branch or other ref as their argument.
+static GIT_REF_SUBCOMMANDS: &[&str] = &[
+    "branch",
+    "checkout",
+    "cherry-pick",
+    "diff",
+    "log",
+    "merge",
+    "rebase",
+    "reset",
+    "show",
+    "switch",
+];
+
+static CARGO_SUBCOMMANDS: &[&str] = &[
+    "add",
+    "bench",
+    "build",
+    "check",
+    "clean",
+    "clippy",
+    "doc",
+    "fix",
+    "fmt",
+    "init",
+    "install",
+    "metadata",
+    "new",
+    "publish",
+    "remove",
+    "run",
+    "search",
+    "test",
+    "tree",
+    "uninstall",
+    "update",
+];
+
+static SIGNALS: &[&str] = &[
+    "HUP", "INT", "QUIT", "KILL", "USR1", "USR2", "TERM", "CONT", "STOP", "TSTP",
+];
+
+/// The first non-option word after the command name.
+fn subcommand<'a>(args: &[&'a str]) -> Option<&'a str> {
+    args.iter()
+        .skip(1)
+        .find(|arg| !arg.starts_with('-'))
+        .copied()
+}
+
+fn words(words: &[&str]) -> Vec<Pair> {
+    words
+        .iter()
+        .map(|word| Pair::new(word.to_string()))
+        .collect()
+}
+
+/// The providers for the commands completed without an external completer.
+pub fn providers() -> Vec<(String, Box<dyn CompletionProvider>)> {
+    vec![
+        (String::from("git"), Box::new(Git)),
+        (String::from("cargo"), Box::new(Cargo)),
+        (String::from("ssh"), Box::new(Ssh)),
+        (String::from("kill"), Box::new(Kill)),
+    ]
+}
+
+pub struct Git;
+
+impl CompletionProvider for Git {
+    fn candidates(&self, args: &[&str], _word: &str) -> Option<Vec<Pair>> {
+        match subcommand(args) {
+            None => Some(words(GIT_SUBCOMMANDS)),
+            Some(subcommand) if GIT_REF_SUBCOMMANDS.contains(&subcommand) => git_refs(),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Local and remote branch names; `None` outside a repository so paths are
+/// completed instead.
+fn git_refs() -> Option<Vec<Pair>> {
+    let output = Command::new("git")
+        .args([
+            "for-each-ref",
+            "--format=%(refname:short)",
+            "refs/heads",
+            "refs/remotes",
+            "refs/tags",
+        ])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let refs = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|name| Pair::new(name.to_string()))
+        .collect();
+    Some(refs)
+}
+
+pub struct Cargo;
+
+impl CompletionProvider for Cargo {
+    fn candidates(&self, args: &[&str], _word: &str) -> Option<Vec<Pair>> {
+        match subcommand(args) {
+            None => Some(words(CARGO_SUBCOMMANDS)),
+            Some(_) => None,
+        }
+    }
+}
+
+pub struct Ssh;
+
+impl CompletionProvider for Ssh {
+    fn candidates(&self, args: &[&str], word: &str) -> Option<Vec<Pair>> {
+        if subcommand(args).is_some() || word.starts_with('-') {
+            return None;
+        }
+
+        let home = named_dirs::home_dir()?;
+        let config = fs::read_to_string(home.join(".ssh/config")).ok()?;
+        Some(ssh_hosts(&config).into_iter().map(Pair::new).collect())
+    }
+}
+
+/// Concrete names from `Host` lines, skipping wildcard patterns.
+fn ssh_hosts(config: &str) -> Vec<String> {
+    config
+        .lines()
+        .filter_map(|line| {
+            let (keyword, value) = line.trim().split_once(char::is_whitespace)?;
+            keyword.eq_ignore_ascii_case("host").then_some(value)
+        })
+        .flat_map(str::split_whitespace)
+        .filter(|host| !host.contains(['*', '?', '!']))
+        .map(String::from)
+        .collect()
+}
+
+pub struct Kill;
+
+impl CompletionProvider for Kill {
+    fn candidates(&self, _args: &[&str], word: &str) -> Option<Vec<Pair>> {
+        if word.starts_with('-') {
+            let signals = SIGNALS
+                .iter()
+                .map(|signal| Pair::new(format!("-{signal}")))
+                .collect();
+            return Some(signals);
+        }
+
+        let processes = fs::read_dir("/proc")
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let pid = entry.file_name().into_string().ok()?;
+                pid.parse::<u32>().ok()?;
+                let comm = fs::read_to_string(entry.path().join("comm")).ok()?;
+                Some(Pair::annotated(pid, comm.trim_end()))
+            })
+            .collect();
+        Some(processes)
+    }
+}
+
+/// Where bash-completion is usually installed.
+static BASH_COMPLETION_SCRIPTS: &[&str] = &[
+    "/usr/share/bash-completion/bash_completion",
+    "/etc/bash_completion",
+];
+
+/// Loads the bash-completion script, then the completion of the command in
+/// `$1` the way bash would, and runs its `complete -F` function for the
+/// words in the other arguments, printing what it puts in COMPREPLY.
+const BASH_COMPLETE: &str = r#"
+source "$0" >/dev/null 2>&1 || exit 1
+COMP_WORDS=("$@")
+COMP_CWORD=$(( $# - 1 ))
+COMP_LINE="${COMP_WORDS[*]}"
+COMP_POINT=${#COMP_LINE}
+__load_completion "$1" >/dev/null 2>&1 || _completion_loader "$1" >/dev/null 2>&1
+spec=$(complete -p "$1" 2>/dev/null) || exit 1
+[[ $spec == *" -F "* ]] || exit 1
+function=${spec##* -F }
+function=${function%% *}
+"$function" "$1" "${COMP_WORDS[COMP_CWORD]}" "${COMP_WORDS[COMP_CWORD-1]}" >/dev/null 2>&1
+printf '%s\n' "${COMPREPLY[@]}"
+"#;
+
+/// Completes commands no provider knows with the functions bash-completion
+/// has for them, run in a bash process.
+pub struct BashCompletion {
+    /// The bash-completion script to source, if it is installed.
+    script: Option<PathBuf>,
+}
+
+impl Default for BashCompletion {
+    fn default() -> Self {
+        let script = BASH_COMPLETION_SCRIPTS
+            .iter()
+            .map(Path::new)
+            .find(|path| path.is_file());
+        Self {
+            script: script.map(Path::to_path_buf),
+        }
+    }
+}
+
+impl CompletionProvider for BashCompletion {
+    fn candidates(&self, args: &[&str], word: &str) -> Option<Vec<Pair>> {
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(BASH_COMPLETE)
+            .arg(self.script.as_ref()?)
+            .args(args)
+            .arg(word)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+
+        let candidates: Vec<Pair> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| Pair::new(line.to_string()))
+            .collect();
+        (!candidates.is_empty()).then_some(candidates)
+    }
+}
+
+/// Completes commands no provider knows with carapace, if it is installed,
+/// asking for its fish output: a candidate per line, followed by a tab and
+/// its description if it has one.
+pub struct Carapace;
+
+impl CompletionProvider for Carapace {
+    fn candidates(&self, args: &[&str], word: &str) -> Option<Vec<Pair>> {
+        let output = Command::new("carapace")
+            .args([args[0], "fish"])
+            .args(args)
+            .arg(word)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+
+        let candidates = fish_candidates(&String::from_utf8_lossy(&output.stdout));
+        (!candidates.is_empty()).then_some(candidates)
+    }
+}
+
+fn fish_candidates(output: &str) -> Vec<Pair> {
+    output
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.split_once('\t') {
+            Some((value, description)) if !description.is_empty() => {
+                Pair::annotated(value.to_string(), description)
+            }
+            Some((value, _)) => Pair::new(value.to_string()),
+            None => Pair::new(line.to_string()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("Host work\n  HostName 10.0.0.1\n", vec!["work"])]
+    #[case("Host a b\nHost *.internal\nhost c\n", vec!["a", "b", "c"])]
+    #[case("Host !bad good\n# Host commented\n", vec!["good"])]
+    fn ssh_hosts_test(#[case] config: &str, #[case] expected: Vec<&str>) {
+        assert_eq!(ssh_hosts(config), expected);
+    }
+
+    #[test]
+    fn fish_candidates_test() {
+        let candidates = fish_candidates("main\tlocal branch\nv1.0\t\nHEAD\n");
+        let pairs: Vec<_> = candidates
+            .iter()
+            .map(|pair| (pair.display.as_str(), pair.replacement.as_str()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("main (local branch)", "main "),
+                ("v1.0", "v1.0 "),
+                ("HEAD", "HEAD ")
+            ]
+        );
+    }
+
+    #[test]
+    fn bash_completion_runs_complete_functions() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("bash_completion");
+        fs::write(
+            &script,
+            "_greet() { COMPREPLY=($(compgen -W \"hello help $3\" -- \"$2\")); }\n\
+             complete -F _greet greet\n",
+        )
+        .unwrap();
+        let completion = BashCompletion {
+            script: Some(script.clone()),
+        };
+
+        let candidates = completion.candidates(&["greet", "prev"], "he");
+        let unknown = completion.candidates(&["nosuch"], "");
+
+        let displays: Vec<_> = candidates
+            .unwrap()
+            .into_iter()
+            .map(|pair| pair.display)
+            .collect();
+        assert_eq!(displays, vec!["hello", "help"]);
+        assert!(unknown.is_none());
+    }
+}
@@ -0,0 +1,90 @@
+use crate::prompt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The file `record` copies everything the shell prints into, along with the
+/// prompts and what was entered at them, like script(1) makes one. Every
+/// clone writes into the same file.
+#[derive(Clone)]
+pub struct Transcript {
+    path: PathBuf,
+    file: Arc<Mutex<fs::File>>,
+}
+
+impl Transcript {
+    /// Opens `path`, emptying it unless `append`, and notes when the
+    /// recording started.
+    pub fn start(path: &Path, append: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)?;
+        let mut transcript = Self {
+            path: path.to_path_buf(),
+            file: Arc::new(Mutex::new(file)),
+        };
+        writeln!(transcript, "Script started on {}", prompt::date_time())?;
+        Ok(transcript)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records `input` as it was entered at `prompt`, its lines after the
+    /// first at `continuation_prompt`.
+    pub fn input(
+        &mut self,
+        prompt: &str,
+        continuation_prompt: &str,
+        input: &str,
+    ) -> io::Result<()> {
+        let input = input.replace('\n', &format!("\n{continuation_prompt}"));
+        writeln!(self, "{prompt}{input}")
+    }
+
+    /// Notes when the recording ended.
+    pub fn finish(&mut self) -> io::Result<()> {
+        write!(self, "\nScript done on {}\n", prompt::date_time())
+    }
+}
+
+impl Write for Transcript {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.lock().unwrap().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn records_input_between_start_and_finish() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("typescript");
+        let mut transcript = Transcript::start(&path, false).unwrap();
+        transcript.input("$ ", "> ", "echo 'a\nb'").unwrap();
+        transcript.write_all(b"a\nb\n").unwrap();
+        transcript.finish().unwrap();
+        Transcript::start(&path, true).unwrap().finish().unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 10);
+        assert!(lines[0].starts_with("Script started on "));
+        assert_eq!(lines[1..5], ["$ echo 'a", "> b'", "a", "b"]);
+        assert!(lines[6].starts_with("Script done on "));
+        assert!(lines[7].starts_with("Script started on "));
+    }
+}
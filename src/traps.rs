@@ -0,0 +1,100 @@
+/// What a trap set with `trap` runs on: events of the shell itself, rather
+/// than signals.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trap {
+    /// Before every command.
+    Debug,
+    /// After a command failed, before `errexit` exits.
+    Err,
+}
+
+impl Trap {
+    pub const ALL: [Trap; 2] = [Trap::Debug, Trap::Err];
+
+    /// The trap named `name`, in any case, as `trap` takes it.
+    pub fn parse(name: &str) -> Option<Trap> {
+        Self::ALL
+            .into_iter()
+            .find(|trap| trap.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Trap::Debug => "DEBUG",
+            Trap::Err => "ERR",
+        }
+    }
+}
+
+/// The commands set to run on every [`Trap`], and whether one is running.
+#[derive(Default)]
+pub struct Traps {
+    debug: Option<String>,
+    err: Option<String>,
+    running: bool,
+}
+
+impl Traps {
+    /// What runs on `trap`. Set to an empty string, nothing does but it is
+    /// still listed, like an ignored signal.
+    pub fn get(&self, trap: Trap) -> Option<&str> {
+        match trap {
+            Trap::Debug => self.debug.as_deref(),
+            Trap::Err => self.err.as_deref(),
+        }
+    }
+
+    /// Sets what runs on `trap`, or resets it with `None`.
+    pub fn set(&mut self, trap: Trap, action: Option<String>) {
+        match trap {
+            Trap::Debug => self.debug = action,
+            Trap::Err => self.err = action,
+        }
+    }
+
+    /// The commands to run on `trap` now, marking a trap as running until
+    /// [`Traps::finish`]. None while one runs already, so the commands of a
+    /// trap don't set one off again.
+    pub(crate) fn start(&mut self, trap: Trap) -> Option<String> {
+        if self.running {
+            return None;
+        }
+
+        let action = self.get(trap).filter(|action| !action.is_empty())?;
+        let action = action.to_string();
+        self.running = true;
+        Some(action)
+    }
+
+    pub(crate) fn finish(&mut self) {
+        self.running = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("DEBUG", Some(Trap::Debug))]
+    #[case("err", Some(Trap::Err))]
+    #[case("INT", None)]
+    fn parse_test(#[case] name: &str, #[case] expected: Option<Trap>) {
+        assert_eq!(Trap::parse(name), expected);
+    }
+
+    #[test]
+    fn traps_dont_nest() {
+        let mut traps = Traps::default();
+        traps.set(Trap::Debug, Some(String::from("echo debug")));
+        traps.set(Trap::Err, Some(String::new()));
+
+        assert_eq!(traps.start(Trap::Err), None);
+        assert_eq!(traps.start(Trap::Debug).as_deref(), Some("echo debug"));
+        assert_eq!(traps.start(Trap::Debug), None);
+        traps.finish();
+        assert_eq!(traps.start(Trap::Debug).as_deref(), Some("echo debug"));
+    }
+}
@@ -0,0 +1,181 @@
+use std::time::Duration;
+
+/// What the commands of a pipeline used, summed over them as wait4(2)
+/// reports it when they exit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub user: Duration,
+    pub system: Duration,
+    /// The largest resident set size of any of them, in kilobytes.
+    pub max_rss: u64,
+    /// Page faults that needed I/O.
+    pub major_faults: u64,
+    /// Page faults served without I/O.
+    pub minor_faults: u64,
+}
+
+impl Usage {
+    #[cfg(unix)]
+    pub(crate) fn from_rusage(rusage: &libc::rusage) -> Self {
+        let duration = |time: libc::timeval| {
+            Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+        };
+        // bytes on macOS, kilobytes everywhere else
+        let max_rss = if cfg!(target_os = "macos") {
+            rusage.ru_maxrss as u64 / 1024
+        } else {
+            rusage.ru_maxrss as u64
+        };
+
+        Self {
+            user: duration(rusage.ru_utime),
+            system: duration(rusage.ru_stime),
+            max_rss,
+            major_faults: rusage.ru_majflt as u64,
+            minor_faults: rusage.ru_minflt as u64,
+        }
+    }
+
+    /// Adds what another command used.
+    pub fn add(&mut self, other: &Usage) {
+        self.user += other.user;
+        self.system += other.system;
+        self.max_rss = self.max_rss.max(other.max_rss);
+        self.major_faults += other.major_faults;
+        self.minor_faults += other.minor_faults;
+    }
+
+    /// CPU time as a percentage of the `real` time taken.
+    fn cpu_percent(&self, real: Duration) -> u64 {
+        if real.is_zero() {
+            return 0;
+        }
+
+        ((self.user + self.system).as_secs_f64() * 100.0 / real.as_secs_f64()).round() as u64
+    }
+
+    /// The report of `time`, like bash's.
+    pub fn report(&self, real: Duration) -> String {
+        let minutes =
+            |time: Duration| format!("{}m{:.3}s", time.as_secs() / 60, time.as_secs_f64() % 60.0);
+        format!(
+            "\nreal\t{}\nuser\t{}\nsys\t{}\n",
+            minutes(real),
+            minutes(self.user),
+            minutes(self.system)
+        )
+    }
+
+    /// The report of `time -p`, in the POSIX format.
+    pub fn portable_report(&self, real: Duration) -> String {
+        format!(
+            "real {:.2}\nuser {:.2}\nsys {:.2}\n",
+            real.as_secs_f64(),
+            self.user.as_secs_f64(),
+            self.system.as_secs_f64()
+        )
+    }
+
+    /// The report of `time -v`, like GNU time's.
+    pub fn verbose_report(&self, command: &str, real: Duration, status: i32) -> String {
+        let secs = real.as_secs();
+        let elapsed = if secs >= 3600 {
+            format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+        } else {
+            format!("{}:{:05.2}", secs / 60, real.as_secs_f64() % 60.0)
+        };
+        format!(
+            "\tCommand being timed: \"{command}\"\n\
+             \tUser time (seconds): {:.2}\n\
+             \tSystem time (seconds): {:.2}\n\
+             \tPercent of CPU this job got: {}%\n\
+             \tElapsed (wall clock) time (h:mm:ss or m:ss): {elapsed}\n\
+             \tMaximum resident set size (kbytes): {}\n\
+             \tMajor (requiring I/O) page faults: {}\n\
+             \tMinor (reclaiming a frame) page faults: {}\n\
+             \tExit status: {status}\n",
+            self.user.as_secs_f64(),
+            self.system.as_secs_f64(),
+            self.cpu_percent(real),
+            self.max_rss,
+            self.major_faults,
+            self.minor_faults,
+        )
+    }
+
+    /// The line REPORTTIME prints after a slow command, like zsh's.
+    pub fn summary(&self, command: &str, real: Duration) -> String {
+        format!(
+            "{command}  {:.2}s user {:.2}s system {}% cpu {:.3} total {}k max rss\n",
+            self.user.as_secs_f64(),
+            self.system.as_secs_f64(),
+            self.cpu_percent(real),
+            real.as_secs_f64(),
+            self.max_rss
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn usage() -> Usage {
+        Usage {
+            user: Duration::from_millis(1250),
+            system: Duration::from_millis(250),
+            max_rss: 2048,
+            major_faults: 1,
+            minor_faults: 90,
+        }
+    }
+
+    #[test]
+    fn add_sums_times_and_keeps_the_largest_rss() {
+        let mut total = usage();
+        total.add(&Usage {
+            user: Duration::from_millis(750),
+            max_rss: 1024,
+            minor_faults: 10,
+            ..Usage::default()
+        });
+        assert_eq!(
+            total,
+            Usage {
+                user: Duration::from_secs(2),
+                minor_faults: 100,
+                ..usage()
+            }
+        );
+    }
+
+    #[test]
+    fn reports() {
+        let real = Duration::from_millis(63_500);
+        assert_eq!(
+            usage().report(real),
+            "\nreal\t1m3.500s\nuser\t0m1.250s\nsys\t0m0.250s\n"
+        );
+        assert_eq!(
+            usage().portable_report(real),
+            "real 63.50\nuser 1.25\nsys 0.25\n"
+        );
+        assert_eq!(
+            usage().summary("make", Duration::from_secs(3)),
+            "make  1.25s user 0.25s system 50% cpu 3.000 total 2048k max rss\n"
+        );
+        assert_eq!(
+            usage().verbose_report("make all", real, 2),
+            "\tCommand being timed: \"make all\"\n\
+             \tUser time (seconds): 1.25\n\
+             \tSystem time (seconds): 0.25\n\
+             \tPercent of CPU this job got: 2%\n\
+             \tElapsed (wall clock) time (h:mm:ss or m:ss): 1:03.50\n\
+             \tMaximum resident set size (kbytes): 2048\n\
+             \tMajor (requiring I/O) page faults: 1\n\
+             \tMinor (reclaiming a frame) page faults: 90\n\
+             \tExit status: 2\n"
+        );
+    }
+}